@@ -0,0 +1,619 @@
+//! The runtime side of [`sonde`](https://docs.rs/sonde): `sonde` is a
+//! build dependency generating the Rust API of the probes, and
+//! `sonde-api` is the small regular dependency holding what this API
+//! uses at runtime, shared instead of being generated in every crate.
+//! The generated code only uses it with `Builder::use_sonde_api`.
+
+pub mod mock;
+#[cfg(all(feature = "observe", target_os = "linux"))]
+pub mod observe;
+
+pub use sonde_macros::{trace, ProbeArgs};
+
+use std::{
+    any::Any,
+    borrow::Cow,
+    ffi::{CStr, CString, OsStr},
+    fmt,
+    os::raw::c_char,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A struct whose fields are the arguments of a probe, usually derived
+/// with [`#[derive(sonde::ProbeArgs)]`](derive@ProbeArgs).
+pub trait ProbeArgs {
+    /// Fire the probe, with the fields as its arguments.
+    fn fire_probe(&self);
+}
+
+/// Fire the probe of `args`, see [`ProbeArgs`].
+pub fn probe_from<A: ProbeArgs + ?Sized>(args: &A) {
+    args.fire_probe();
+}
+
+/// Include the Rust API generated by `Builder::compile` in a module,
+/// instead of writing `mod tracing { include!(env!("SONDE_RUST_API_FILE")); }`:
+///
+/// ```rust,ignore
+/// sonde::include_api!(mod tracing);
+///
+/// fn main() {
+///     tracing::hello::world();
+/// }
+/// ```
+///
+/// The module can have attributes and a visibility. The name of the
+/// environment variable pointing to the generated file can be given
+/// too, e.g. for a single provider with `Builder::split_output`:
+///
+/// ```rust,ignore
+/// sonde::include_api!(pub(crate) mod hello_tracing, "SONDE_RUST_API_FILE_HELLO");
+/// ```
+#[macro_export]
+macro_rules! include_api {
+    ($(#[$attribute:meta])* $visibility:vis mod $name:ident) => {
+        $crate::include_api!($(#[$attribute])* $visibility mod $name, "SONDE_RUST_API_FILE");
+    };
+
+    ($(#[$attribute:meta])* $visibility:vis mod $name:ident, $variable:literal) => {
+        $(#[$attribute])*
+        $visibility mod $name {
+            #![allow(unused)]
+
+            include!(env!($variable));
+        }
+    };
+}
+
+/// Convert an `OsStr` to a C string, truncated at the first NUL byte.
+/// With `lossy`, or on non-Unix platforms, the string is converted to
+/// UTF-8 first, replacing the invalid sequences.
+pub fn os_str_to_c_string(string: &OsStr, lossy: bool) -> CString {
+    let mut bytes = if lossy {
+        string.to_string_lossy().into_owned().into_bytes()
+    } else {
+        #[cfg(unix)]
+        {
+            std::os::unix::ffi::OsStrExt::as_bytes(string).to_vec()
+        }
+
+        #[cfg(not(unix))]
+        {
+            string.to_string_lossy().into_owned().into_bytes()
+        }
+    };
+
+    if let Some(nul) = bytes.iter().position(|byte| *byte == 0) {
+        bytes.truncate(nul);
+    }
+
+    CString::new(bytes).expect("The string has no NUL byte")
+}
+
+/// Copy an `OsStr` to `buffer` as a C string, truncated at the first
+/// NUL byte or to fit the buffer, without splitting a UTF-8 character,
+/// and return a pointer to the buffer. The conversion is the one of
+/// [`os_str_to_c_string`], without allocating when the string is
+/// given as is.
+///
+/// # Panics
+///
+/// Panics if `buffer` is empty.
+pub fn os_str_to_c_buffer(string: &OsStr, lossy: bool, buffer: &mut [u8]) -> *mut c_char {
+    let bytes: Cow<'_, [u8]> = if lossy {
+        match string.to_string_lossy() {
+            Cow::Borrowed(string) => Cow::Borrowed(string.as_bytes()),
+            Cow::Owned(string) => Cow::Owned(string.into_bytes()),
+        }
+    } else {
+        #[cfg(unix)]
+        {
+            Cow::Borrowed(std::os::unix::ffi::OsStrExt::as_bytes(string))
+        }
+
+        #[cfg(not(unix))]
+        {
+            Cow::Owned(string.to_string_lossy().into_owned().into_bytes())
+        }
+    };
+    let bytes = match bytes.iter().position(|byte| *byte == 0) {
+        Some(nul) => &bytes[..nul],
+        None => &bytes[..],
+    };
+    let mut length = bytes
+        .len()
+        .min(buffer.len().checked_sub(1).expect("The buffer is empty"));
+
+    if let Ok(string) = std::str::from_utf8(bytes) {
+        while !string.is_char_boundary(length) {
+            length -= 1;
+        }
+    }
+
+    buffer[..length].copy_from_slice(&bytes[..length]);
+    buffer[length] = 0;
+
+    buffer.as_mut_ptr() as *mut c_char
+}
+
+/// A string argument of a probe, NUL-terminated as the probes expect,
+/// converted from a `&str`, a `String`, a `&CStr`, etc. A `&CStr` is
+/// borrowed, the others are copied, and truncated at their first NUL
+/// byte if any. The string lives as long as the `ProbeStr`, e.g. until
+/// the end of the statement for a temporary:
+///
+/// ```rust,ignore
+/// use sonde_api::ProbeStr;
+///
+/// tracing::hello::you(ProbeStr::from("Gordon").as_ptr(), 6);
+///
+/// let who = ProbeStr::from(name);
+/// tracing::hello::you(who.as_ptr(), who.len() as _);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeStr<'a> {
+    string: Cow<'a, CStr>,
+}
+
+impl ProbeStr<'_> {
+    /// The pointer to give to a `char *` argument. The probes don't
+    /// write through it, despite the `*mut`.
+    pub fn as_ptr(&self) -> *mut c_char {
+        self.string.as_ptr() as *mut c_char
+    }
+
+    /// The length of the string in bytes, without the NUL byte, e.g.
+    /// for a length argument following the string.
+    pub fn len(&self) -> usize {
+        self.string.to_bytes().len()
+    }
+
+    /// Whether the string is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The string, as a `&CStr`.
+    pub fn as_c_str(&self) -> &CStr {
+        &self.string
+    }
+}
+
+impl<'a> From<&'a CStr> for ProbeStr<'a> {
+    fn from(string: &'a CStr) -> Self {
+        Self {
+            string: Cow::Borrowed(string),
+        }
+    }
+}
+
+impl From<CString> for ProbeStr<'_> {
+    fn from(string: CString) -> Self {
+        Self {
+            string: Cow::Owned(string),
+        }
+    }
+}
+
+impl From<&str> for ProbeStr<'_> {
+    fn from(string: &str) -> Self {
+        Self::from(os_str_to_c_string(OsStr::new(string), false))
+    }
+}
+
+impl From<String> for ProbeStr<'_> {
+    fn from(string: String) -> Self {
+        Self::from(string.as_str())
+    }
+}
+
+impl From<&String> for ProbeStr<'_> {
+    fn from(string: &String) -> Self {
+        Self::from(string.as_str())
+    }
+}
+
+impl From<&OsStr> for ProbeStr<'_> {
+    fn from(string: &OsStr) -> Self {
+        Self::from(os_str_to_c_string(string, false))
+    }
+}
+
+type EnabledChangeCallback = (fn() -> bool, bool, Box<dyn Fn(bool) + Send>);
+
+/// The callbacks called when a tracer attaches to or detaches from a
+/// probe, as used by the generated `on_enabled_change` and
+/// `poll_enabled_changes` functions of each provider.
+pub struct EnabledRegistry {
+    callbacks: Mutex<Vec<EnabledChangeCallback>>,
+}
+
+impl EnabledRegistry {
+    /// Create an empty registry, e.g. in a `static`.
+    pub const fn new() -> Self {
+        Self {
+            callbacks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Register a callback, called with the new state returned by
+    /// `is_enabled` when it changes.
+    pub fn register<F>(&self, is_enabled: fn() -> bool, callback: F)
+    where
+        F: Fn(bool) + Send + 'static,
+    {
+        self.callbacks
+            .lock()
+            .unwrap()
+            .push((is_enabled, false, Box::new(callback)));
+    }
+
+    /// Check the states, and call the callbacks of the states that have
+    /// changed since the last check. The callbacks are called without
+    /// holding the lock of the registry, so they can register other
+    /// callbacks, which are checked by the next poll.
+    pub fn poll(&self) {
+        let mut callbacks = std::mem::take(&mut *self.callbacks.lock().unwrap());
+
+        for (is_enabled, last_state, callback) in callbacks.iter_mut() {
+            let state = is_enabled();
+
+            if state != *last_state {
+                *last_state = state;
+                callback(state);
+            }
+        }
+
+        // Put the callbacks back, before the ones registered meanwhile.
+        let mut registered = self.callbacks.lock().unwrap();
+        callbacks.append(&mut registered);
+        *registered = callbacks;
+    }
+}
+
+impl Default for EnabledRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What happens when a sink other than the probes themselves fails,
+/// e.g. the OpenTelemetry events of `Builder::opentelemetry_feature`,
+/// or an `observe::Observer` whose events can't be delivered. The
+/// probes are always fired, whatever the policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Disable the sink at its first error, silently.
+    Disable,
+
+    /// Log the first error of the sink to the standard error, and keep
+    /// the sink.
+    #[default]
+    LogOnce,
+
+    /// Panic in debug builds, and log the first error in release builds.
+    PanicInDebug,
+}
+
+static ERROR_POLICY: AtomicU8 = AtomicU8::new(ErrorPolicy::LogOnce as u8);
+
+/// Set how the sinks react to their errors, for the whole process, see
+/// [`ErrorPolicy`].
+pub fn set_error_policy(policy: ErrorPolicy) {
+    ERROR_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// The policy set with [`set_error_policy`].
+pub fn error_policy() -> ErrorPolicy {
+    match ERROR_POLICY.load(Ordering::Relaxed) {
+        0 => ErrorPolicy::Disable,
+        2 => ErrorPolicy::PanicInDebug,
+        _ => ErrorPolicy::LogOnce,
+    }
+}
+
+/// A sink mirroring the fired probes, whose errors are handled by the
+/// [`ErrorPolicy`].
+#[derive(Debug)]
+pub struct Sink {
+    name: &'static str,
+    disabled: AtomicBool,
+    logged: AtomicBool,
+}
+
+impl Sink {
+    /// Create a sink, e.g. in a `static`. `name` is used in the logs.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            disabled: AtomicBool::new(false),
+            logged: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the sink hasn't been disabled by an error.
+    pub fn is_enabled(&self) -> bool {
+        !self.disabled.load(Ordering::Relaxed)
+    }
+
+    /// Report an error of the sink.
+    ///
+    /// # Panics
+    ///
+    /// Panics in debug builds with [`ErrorPolicy::PanicInDebug`].
+    pub fn report(&self, error: &dyn fmt::Display) {
+        match error_policy() {
+            ErrorPolicy::Disable => self.disabled.store(true, Ordering::Relaxed),
+            ErrorPolicy::PanicInDebug if cfg!(debug_assertions) => {
+                panic!("sonde: the `{}` sink failed: {}", self.name, error)
+            }
+            ErrorPolicy::LogOnce | ErrorPolicy::PanicInDebug => {
+                if !self.logged.swap(true, Ordering::Relaxed) {
+                    eprintln!("sonde: the `{}` sink failed: {}", self.name, error);
+                }
+            }
+        }
+    }
+
+    /// Run `event` unless the sink is disabled, and report it if it
+    /// panics, instead of unwinding through the caller of the probe.
+    pub fn catch<F: FnOnce()>(&self, event: F) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(event)) {
+            self.report(&panic_message(&*payload));
+        }
+    }
+}
+
+/// The message of a panic, if it's a string.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("panicked")
+}
+
+/// A Rust integer converted, with `as`, to the integer type of a probe
+/// argument.
+pub trait CastInto<T> {
+    fn cast_into(self) -> T;
+}
+
+macro_rules! cast_into {
+    ($($to:ty)*) => {
+        $(cast_into!(@from $to, i8 i16 i32 i64 i128 isize u8 u16 u32 u64 u128 usize);)*
+    };
+    (@from $to:ty, $($from:ty)*) => {
+        $(impl CastInto<$to> for $from {
+            #[inline(always)]
+            fn cast_into(self) -> $to {
+                self as $to
+            }
+        })*
+    };
+}
+
+cast_into!(i8 i16 i32 i64 isize u8 u16 u32 u64 usize);
+
+/// Whether a call of a generated `_sampled` function fires its probe:
+/// once every `every` calls, counted by `calls`. With `every` being 0
+/// or 1, all the calls fire it.
+// `u64::is_multiple_of` needs Rust 1.87.
+#[allow(clippy::manual_is_multiple_of)]
+pub fn sampled(calls: &AtomicU64, every: u64) -> bool {
+    every <= 1 || calls.fetch_add(1, Ordering::Relaxed) % every == 0
+}
+
+/// Whether a call of a generated `_rate_limited` function fires its
+/// probe: at most `per_second` times per second. `window` holds the
+/// current second in its high 32 bits, and the number of fired probes
+/// in this second in its low 32 bits.
+pub fn rate_limited(window: &AtomicU64, per_second: u32) -> bool {
+    if per_second == 0 {
+        return false;
+    }
+
+    let second = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as u32)
+        .unwrap_or(0) as u64;
+    let mut current = window.load(Ordering::Relaxed);
+
+    loop {
+        let next = if current >> 32 != second {
+            (second << 32) | 1
+        } else if current & 0xffff_ffff < per_second as u64 {
+            current + 1
+        } else {
+            return false;
+        };
+
+        match window.compare_exchange_weak(current, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return true,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn test_os_str_to_c_string() {
+        assert_eq!(
+            os_str_to_c_string(OsStr::new("hello\0world"), false).as_bytes(),
+            b"hello"
+        );
+        assert_eq!(
+            os_str_to_c_string(OsStr::new("hello"), true).as_bytes(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn test_os_str_to_c_buffer() {
+        let mut buffer = [0xffu8; 6];
+
+        let string = os_str_to_c_buffer(OsStr::new("hello\0world"), false, &mut buffer);
+        assert_eq!(unsafe { CStr::from_ptr(string) }.to_bytes(), b"hello");
+
+        let string = os_str_to_c_buffer(OsStr::new("hello world"), false, &mut buffer);
+        assert_eq!(unsafe { CStr::from_ptr(string) }.to_bytes(), b"hello");
+
+        // `é` is 2 bytes long, and isn't split.
+        let string = os_str_to_c_buffer(OsStr::new("hé"), true, &mut buffer[..3]);
+        assert_eq!(unsafe { CStr::from_ptr(string) }.to_bytes(), b"h");
+        let string = os_str_to_c_buffer(OsStr::new("hellé"), true, &mut buffer);
+        assert_eq!(unsafe { CStr::from_ptr(string) }.to_bytes(), b"hell");
+    }
+
+    #[test]
+    fn test_probe_str() {
+        let string = ProbeStr::from("Gordon");
+
+        assert_eq!(string.len(), 6);
+        assert_eq!(
+            unsafe { CStr::from_ptr(string.as_ptr()) }.to_bytes(),
+            b"Gordon"
+        );
+
+        assert_eq!(
+            ProbeStr::from(String::from("a\0b")).as_c_str().to_bytes(),
+            b"a"
+        );
+        assert!(ProbeStr::from("").is_empty());
+
+        let c_string = CString::new("Gordon").unwrap();
+        let borrowed = ProbeStr::from(c_string.as_c_str());
+
+        assert_eq!(borrowed.as_ptr() as *const c_char, c_string.as_ptr());
+        assert_eq!(borrowed, string);
+    }
+
+    #[test]
+    fn test_enabled_registry() {
+        static ENABLED: AtomicBool = AtomicBool::new(false);
+        static REGISTRY: EnabledRegistry = EnabledRegistry::new();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        {
+            let calls = calls.clone();
+            REGISTRY.register(
+                || ENABLED.load(Ordering::Relaxed),
+                move |_| {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                },
+            );
+        }
+
+        REGISTRY.poll();
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+        ENABLED.store(true, Ordering::Relaxed);
+        REGISTRY.poll();
+        REGISTRY.poll();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_enabled_registry_reentrancy() {
+        static REGISTRY: EnabledRegistry = EnabledRegistry::new();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        {
+            let calls = calls.clone();
+            REGISTRY.register(
+                || true,
+                move |_| {
+                    let calls = calls.clone();
+                    REGISTRY.register(
+                        || true,
+                        move |_| {
+                            calls.fetch_add(1, Ordering::Relaxed);
+                        },
+                    );
+                },
+            );
+        }
+
+        REGISTRY.poll();
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+        REGISTRY.poll();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_error_policy() {
+        assert_eq!(error_policy(), ErrorPolicy::LogOnce);
+
+        let sink = Sink::new("test");
+
+        sink.catch(|| panic!("disk full"));
+        sink.catch(|| panic!("disk full"));
+        assert!(sink.is_enabled());
+
+        set_error_policy(ErrorPolicy::Disable);
+        sink.report(&"disk full");
+        assert!(!sink.is_enabled());
+
+        let fired = AtomicBool::new(false);
+        sink.catch(|| fired.store(true, Ordering::Relaxed));
+        assert!(!fired.load(Ordering::Relaxed));
+
+        set_error_policy(ErrorPolicy::PanicInDebug);
+        let panicked = panic::catch_unwind(|| Sink::new("test").report(&"disk full")).is_err();
+        assert_eq!(panicked, cfg!(debug_assertions));
+
+        set_error_policy(ErrorPolicy::LogOnce);
+    }
+
+    #[test]
+    fn test_cast_into() {
+        let value: std::os::raw::c_int = 42u64.cast_into();
+
+        assert_eq!(value, 42);
+    }
+
+    #[test]
+    fn test_sampled() {
+        let calls = AtomicU64::new(0);
+
+        assert_eq!(
+            (0..7).map(|_| sampled(&calls, 3)).collect::<Vec<_>>(),
+            vec![true, false, false, true, false, false, true]
+        );
+        assert!((0..3).all(|_| sampled(&calls, 1)));
+    }
+
+    #[test]
+    fn test_rate_limited() {
+        let window = AtomicU64::new(0);
+        let fired = (0..10).filter(|_| rate_limited(&window, 3)).count();
+
+        // The second may change between the calls.
+        assert!((3..=6).contains(&fired));
+
+        // An old window is reset.
+        let window = AtomicU64::new(5);
+
+        assert!(rate_limited(&window, 1));
+        assert!(!rate_limited(&AtomicU64::new(0), 0));
+    }
+}
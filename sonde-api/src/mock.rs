@@ -0,0 +1,85 @@
+//! The recorder of the fired probes used by the mock backend, see
+//! `Backend::Mock`.
+
+use std::{
+    sync::Mutex,
+    thread::{self, ThreadId},
+};
+
+/// A probe fired with the mock backend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fired {
+    /// The name of the probe, as declared in the `.d` file.
+    pub probe: &'static str,
+
+    /// The arguments of the probe, formatted with `Debug`, or read as
+    /// strings for the `(pointer, length)` pairs. The other pointers
+    /// are formatted as addresses.
+    pub arguments: Vec<String>,
+}
+
+/// The probes fired by all the threads, taken per thread.
+pub struct Recorder {
+    fired: Mutex<Vec<(ThreadId, Fired)>>,
+}
+
+impl Recorder {
+    /// Create an empty recorder, e.g. in a `static`.
+    pub const fn new() -> Self {
+        Self {
+            fired: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Record a probe fired by the current thread.
+    pub fn record(&self, probe: &'static str, arguments: Vec<String>) {
+        self.fired
+            .lock()
+            .unwrap()
+            .push((thread::current().id(), Fired { probe, arguments }));
+    }
+
+    /// Take the probes fired by the current thread since the last call,
+    /// in the order they have been fired.
+    pub fn take(&self) -> Vec<Fired> {
+        let current = thread::current().id();
+        let mut fired = self.fired.lock().unwrap();
+        let (taken, kept) = fired
+            .drain(..)
+            .partition::<Vec<_>, _>(|(thread, _)| *thread == current);
+
+        *fired = kept;
+
+        taken.into_iter().map(|(_, fired)| fired).collect()
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_per_thread() {
+        static RECORDER: Recorder = Recorder::new();
+
+        RECORDER.record("you", vec!["42".to_string()]);
+        thread::spawn(|| RECORDER.record("me", Vec::new()))
+            .join()
+            .unwrap();
+
+        assert_eq!(
+            RECORDER.take(),
+            [Fired {
+                probe: "you",
+                arguments: vec!["42".to_string()],
+            }]
+        );
+        assert!(RECORDER.take().is_empty());
+    }
+}
@@ -0,0 +1,890 @@
+//! Observe the probes of the current process from inside it, with
+//! [BCC](https://github.com/iovisor/bcc), e.g. to serve the last fired
+//! probes from a debug endpoint, without an external tracer. It
+//! requires the `observe` feature, links against `libbcc`, and needs
+//! the privileges to load eBPF programs, e.g. `CAP_BPF` and
+//! `CAP_PERFMON`, or root.
+//!
+//! The probes are given by the metadata of the generated Rust API,
+//! see `Builder::probe_metadata`:
+//!
+//! ```rust,ignore
+//! use sonde_api::observe::Observer;
+//!
+//! let mut observer = Observer::new();
+//! observer.add_probe(
+//!     tracing::hello::you::PROVIDER,
+//!     tracing::hello::you::NAME,
+//!     tracing::hello::you::ARG_TYPES,
+//! )?;
+//!
+//! let events = observer.attach()?;
+//!
+//! for event in events.iter() {
+//!     println!("{}:{} {:?}", event.provider, event.probe, event.arguments);
+//! }
+//! # Ok::<(), sonde_api::observe::Error>(())
+//! ```
+//!
+//! When the events can't be polled anymore, or when the receiver is
+//! dropped, the observer reacts as set by
+//! [`set_error_policy`](crate::set_error_policy), e.g. it stops polling
+//! with [`ErrorPolicy::Disable`](crate::ErrorPolicy::Disable).
+
+use crate::Sink;
+use std::{
+    borrow::Cow,
+    ffi::{CStr, CString},
+    fmt, fs, io,
+    os::raw::{c_char, c_int, c_uint, c_ulonglong, c_void},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+
+/// The maximum length of the string arguments, in bytes, including
+/// the NUL byte: the longer strings are truncated.
+pub const MAX_STRING_LENGTH: usize = 64;
+
+/// The number of events waiting to be received by default, see
+/// [`Observer::capacity`].
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// The number of pages of the perf buffer of each CPU.
+const PAGE_COUNT: c_int = 64;
+
+/// How long the polling thread waits for events before checking
+/// whether the observer is dropped, in milliseconds.
+const POLL_TIMEOUT: c_int = 100;
+
+/// `BPF_PROG_TYPE_KPROBE`, the type of the programs attached to
+/// uprobes.
+const BPF_PROG_TYPE_KPROBE: c_int = 2;
+
+/// `BPF_PROBE_ENTRY`.
+const BPF_PROBE_ENTRY: c_int = 0;
+
+type RawCallback = extern "C" fn(cookie: *mut c_void, raw: *mut c_void, size: c_int);
+type LostCallback = extern "C" fn(cookie: *mut c_void, lost: u64);
+type UprobeCallback =
+    extern "C" fn(binary_path: *const c_char, function: *const c_char, address: u64, pid: c_int);
+
+#[link(name = "bcc")]
+extern "C" {
+    fn bcc_usdt_new_frompid(pid: c_int, path: *const c_char) -> *mut c_void;
+    fn bcc_usdt_enable_fully_specified_probe(
+        usdt: *mut c_void,
+        provider: *const c_char,
+        probe: *const c_char,
+        function: *const c_char,
+    ) -> c_int;
+    fn bcc_usdt_genargs(usdt: *mut *mut c_void, len: c_int) -> *const c_char;
+    fn bcc_usdt_foreach_uprobe(usdt: *mut c_void, callback: UprobeCallback);
+    fn bcc_usdt_close(usdt: *mut c_void);
+
+    fn bpf_module_create_c_from_string(
+        text: *const c_char,
+        flags: c_uint,
+        cflags: *mut *const c_char,
+        ncflags: c_int,
+        allow_rlimit: bool,
+        dev_name: *const c_char,
+    ) -> *mut c_void;
+    fn bpf_module_destroy(module: *mut c_void);
+    fn bpf_module_license(module: *mut c_void) -> *const c_char;
+    fn bpf_module_kern_version(module: *mut c_void) -> c_uint;
+    fn bpf_function_start(module: *mut c_void, name: *const c_char) -> *mut c_void;
+    fn bpf_function_size(module: *mut c_void, name: *const c_char) -> usize;
+    fn bpf_table_fd(module: *mut c_void, name: *const c_char) -> c_int;
+
+    fn bcc_prog_load(
+        prog_type: c_int,
+        name: *const c_char,
+        insns: *const c_void,
+        prog_len: c_int,
+        license: *const c_char,
+        kern_version: c_uint,
+        log_level: c_int,
+        log_buf: *mut c_char,
+        log_buf_size: c_uint,
+    ) -> c_int;
+    fn bpf_attach_uprobe(
+        progfd: c_int,
+        attach_type: c_int,
+        ev_name: *const c_char,
+        binary_path: *const c_char,
+        offset: u64,
+        pid: c_int,
+        ref_ctr_offset: u32,
+    ) -> c_int;
+    fn bpf_detach_uprobe(ev_name: *const c_char) -> c_int;
+    fn bpf_update_elem(
+        fd: c_int,
+        key: *mut c_void,
+        value: *mut c_void,
+        flags: c_ulonglong,
+    ) -> c_int;
+
+    fn bpf_open_perf_buffer(
+        raw_cb: RawCallback,
+        lost_cb: LostCallback,
+        cb_cookie: *mut c_void,
+        pid: c_int,
+        cpu: c_int,
+        page_cnt: c_int,
+    ) -> *mut c_void;
+    fn perf_reader_fd(reader: *mut c_void) -> c_int;
+    fn perf_reader_poll(num_readers: c_int, readers: *mut *mut c_void, timeout: c_int) -> c_int;
+    fn perf_reader_free(reader: *mut c_void);
+}
+
+extern "C" {
+    fn close(fd: c_int) -> c_int;
+}
+
+/// An error of the observer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The name contains a NUL byte.
+    InvalidName(String),
+
+    /// The process doesn't contain the probe, as `provider:probe`.
+    ProbeNotFound(String),
+
+    /// Probes can't be added to an attached observer.
+    AlreadyAttached,
+
+    /// BCC has failed, e.g. to compile or to load the eBPF program.
+    Bcc(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidName(name) => write!(f, "the name `{}` contains a NUL byte", name),
+            Self::ProbeNotFound(probe) => {
+                write!(f, "the probe `{}` isn't found in the process", probe)
+            }
+            Self::AlreadyAttached => write!(f, "the observer is already attached"),
+            Self::Bcc(operation) => write!(f, "BCC failed to {}", operation),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The value of an argument of a fired probe.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// An integer, or a pointer other than a string. The signed
+    /// integers are sign-extended.
+    Integer(i64),
+
+    /// A string, i.e. a `char *`, truncated to [`MAX_STRING_LENGTH`]
+    /// bytes and converted to UTF-8.
+    String(String),
+}
+
+/// A fired probe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// The provider, as seen by the tracers.
+    pub provider: String,
+
+    /// The probe, as seen by the tracers, e.g. `you-me`.
+    pub probe: String,
+
+    /// The monotonic time at which the probe has been fired, in
+    /// nanoseconds.
+    pub timestamp: u64,
+
+    /// The process ID.
+    pub pid: u32,
+
+    /// The thread ID.
+    pub tid: u32,
+
+    /// The arguments, decoded according to their D types.
+    pub arguments: Vec<Value>,
+}
+
+/// A probe to observe.
+#[derive(Debug, Clone)]
+struct ObservedProbe {
+    provider: String,
+    name: String,
+    strings: Vec<bool>,
+}
+
+impl ObservedProbe {
+    /// The size of the events of this probe: a 24-byte header, then the
+    /// arguments.
+    fn event_size(&self) -> usize {
+        24 + self
+            .strings
+            .iter()
+            .map(|is_string| if *is_string { MAX_STRING_LENGTH } else { 8 })
+            .sum::<usize>()
+    }
+
+    /// Decode an event of this probe, see `bpf_program`.
+    fn decode(&self, raw: &[u8]) -> Option<Event> {
+        if raw.len() < self.event_size() {
+            return None;
+        }
+
+        let u64_at = |offset: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&raw[offset..offset + 8]);
+
+            u64::from_ne_bytes(bytes)
+        };
+        let u32_at = |offset: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&raw[offset..offset + 4]);
+
+            u32::from_ne_bytes(bytes)
+        };
+
+        let mut offset = 24;
+        let arguments = self
+            .strings
+            .iter()
+            .map(|is_string| {
+                if *is_string {
+                    let bytes = &raw[offset..offset + MAX_STRING_LENGTH];
+                    let length = bytes
+                        .iter()
+                        .position(|byte| *byte == 0)
+                        .unwrap_or(bytes.len());
+                    offset += MAX_STRING_LENGTH;
+
+                    Value::String(String::from_utf8_lossy(&bytes[..length]).into_owned())
+                } else {
+                    offset += 8;
+
+                    Value::Integer(u64_at(offset - 8) as i64)
+                }
+            })
+            .collect();
+
+        Some(Event {
+            provider: self.provider.clone(),
+            probe: self.name.replace("__", "-"),
+            timestamp: u64_at(0),
+            pid: u32_at(12),
+            tid: u32_at(16),
+            arguments,
+        })
+    }
+}
+
+/// What the callbacks of the perf buffers receive.
+struct Consumer {
+    probes: Vec<ObservedProbe>,
+    sender: Mutex<mpsc::SyncSender<Event>>,
+    lost: AtomicU64,
+    disconnected: Arc<AtomicBool>,
+}
+
+extern "C" fn on_event(cookie: *mut c_void, raw: *mut c_void, size: c_int) {
+    let consumer = unsafe { &*(cookie as *const Consumer) };
+    let raw = unsafe { std::slice::from_raw_parts(raw as *const u8, size as usize) };
+
+    if raw.len() < 12 {
+        return;
+    }
+
+    let mut probe = [0; 4];
+    probe.copy_from_slice(&raw[8..12]);
+
+    let event = consumer
+        .probes
+        .get(u32::from_ne_bytes(probe) as usize)
+        .and_then(|probe| probe.decode(raw));
+
+    if let Some(event) = event {
+        // The receiver may be dropped: the events are discarded then,
+        // and the polling thread reports it.
+        match consumer.sender.lock().unwrap().try_send(event) {
+            Err(mpsc::TrySendError::Full(_)) => {
+                consumer.lost.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                consumer.disconnected.store(true, Ordering::Relaxed);
+            }
+            Ok(()) => (),
+        }
+    }
+}
+
+extern "C" fn on_lost(cookie: *mut c_void, lost: u64) {
+    let consumer = unsafe { &*(cookie as *const Consumer) };
+    consumer.lost.fetch_add(lost, Ordering::Relaxed);
+}
+
+/// The number of observers attached so far, numbering the uprobe
+/// events of each observer.
+static OBSERVERS: AtomicUsize = AtomicUsize::new(0);
+
+/// The locations of the probes found by `bcc_usdt_foreach_uprobe`, which
+/// has no cookie, as `(binary path, function, address, pid)`.
+static UPROBES: Mutex<Vec<(CString, String, u64, c_int)>> = Mutex::new(Vec::new());
+
+extern "C" fn on_uprobe(
+    binary_path: *const c_char,
+    function: *const c_char,
+    address: u64,
+    pid: c_int,
+) {
+    let binary_path = unsafe { CStr::from_ptr(binary_path) }.to_owned();
+    let function = unsafe { CStr::from_ptr(function) }
+        .to_string_lossy()
+        .into_owned();
+
+    UPROBES
+        .lock()
+        .unwrap()
+        .push((binary_path, function, address, pid));
+}
+
+/// The perf readers, moved to the polling thread.
+struct Readers(Vec<*mut c_void>);
+
+// The readers are only used by the polling thread, and freed once it's
+// joined.
+unsafe impl Send for Readers {}
+
+/// The resources of an attached observer.
+struct Attached {
+    usdt: *mut c_void,
+    module: *mut c_void,
+    program_fds: Vec<c_int>,
+    uprobes: Vec<(CString, c_int)>,
+    readers: Vec<*mut c_void>,
+    consumer: Option<Box<Consumer>>,
+    stop: Arc<AtomicBool>,
+    poller: Option<thread::JoinHandle<()>>,
+}
+
+impl Attached {
+    fn new(usdt: *mut c_void) -> Self {
+        Self {
+            usdt,
+            module: ptr::null_mut(),
+            program_fds: Vec::new(),
+            uprobes: Vec::new(),
+            readers: Vec::new(),
+            consumer: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            poller: None,
+        }
+    }
+}
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        if let Some(poller) = self.poller.take() {
+            let _ = poller.join();
+        }
+
+        unsafe {
+            for (event_name, fd) in &self.uprobes {
+                close(*fd);
+                bpf_detach_uprobe(event_name.as_ptr());
+            }
+
+            for reader in &self.readers {
+                perf_reader_free(*reader);
+            }
+
+            for fd in &self.program_fds {
+                close(*fd);
+            }
+
+            if !self.module.is_null() {
+                bpf_module_destroy(self.module);
+            }
+
+            bcc_usdt_close(self.usdt);
+        }
+    }
+}
+
+/// An observer of the probes of the current process. The probes are
+/// detached when it's dropped.
+pub struct Observer {
+    probes: Vec<ObservedProbe>,
+    capacity: usize,
+    attached: Option<Attached>,
+}
+
+// The raw pointers of BCC are only used to detach the probes, and by
+// the polling thread.
+unsafe impl Send for Observer {}
+
+impl Observer {
+    /// Create an observer. Its probes must be added before it's
+    /// attached.
+    pub fn new() -> Self {
+        Self {
+            probes: Vec::new(),
+            capacity: DEFAULT_CAPACITY,
+            attached: None,
+        }
+    }
+
+    /// Set the number of events waiting to be received, by default
+    /// [`DEFAULT_CAPACITY`]. The events fired while the receiver is
+    /// full are lost, see [`Observer::lost_events`].
+    pub fn capacity(&mut self, capacity: usize) -> &mut Self {
+        self.capacity = capacity;
+
+        self
+    }
+
+    /// Add a probe, with the D types of its arguments, e.g. the
+    /// `PROVIDER`, `NAME` and `ARG_TYPES` constants of its metadata
+    /// module in the generated Rust API.
+    pub fn add_probe(
+        &mut self,
+        provider: &str,
+        name: &str,
+        arg_types: &[&str],
+    ) -> Result<(), Error> {
+        if self.attached.is_some() {
+            return Err(Error::AlreadyAttached);
+        }
+
+        for name in &[provider, name] {
+            if name.contains('\0') {
+                return Err(Error::InvalidName(name.to_string()));
+            }
+        }
+
+        self.probes.push(ObservedProbe {
+            provider: provider.to_string(),
+            // The probes are named with `__` in the ELF notes.
+            name: name.replace('-', "__"),
+            strings: arg_types.iter().map(|ty| is_string_type(ty)).collect(),
+        });
+
+        Ok(())
+    }
+
+    /// Attach to the probes, and return the receiver of their events.
+    /// They are received by a background thread, until the observer
+    /// is dropped.
+    pub fn attach(&mut self) -> Result<mpsc::Receiver<Event>, Error> {
+        if self.attached.is_some() {
+            return Err(Error::AlreadyAttached);
+        }
+
+        let usdt = unsafe { bcc_usdt_new_frompid(std::process::id() as c_int, ptr::null()) };
+
+        if usdt.is_null() {
+            return Err(Error::Bcc("read the probes of the process"));
+        }
+
+        let mut attached = Attached::new(usdt);
+
+        for (nth, probe) in self.probes.iter().enumerate() {
+            let provider = CString::new(probe.provider.as_str()).unwrap();
+            let name = CString::new(probe.name.as_str()).unwrap();
+            let function = CString::new(program_name(nth)).unwrap();
+
+            if unsafe {
+                bcc_usdt_enable_fully_specified_probe(
+                    usdt,
+                    provider.as_ptr(),
+                    name.as_ptr(),
+                    function.as_ptr(),
+                )
+            } != 0
+            {
+                return Err(Error::ProbeNotFound(format!(
+                    "{}:{}",
+                    probe.provider, probe.name
+                )));
+            }
+        }
+
+        let mut usdts = [usdt];
+        let arguments = unsafe { bcc_usdt_genargs(usdts.as_mut_ptr(), 1) };
+
+        if arguments.is_null() {
+            return Err(Error::Bcc("generate the code reading the arguments"));
+        }
+
+        let program = CString::new(format!(
+            "{}\n{}",
+            unsafe { CStr::from_ptr(arguments) }.to_string_lossy(),
+            bpf_program(&self.probes)
+        ))
+        .unwrap();
+
+        attached.module = unsafe {
+            bpf_module_create_c_from_string(
+                program.as_ptr(),
+                0,
+                ptr::null_mut(),
+                0,
+                true,
+                ptr::null(),
+            )
+        };
+
+        if attached.module.is_null() {
+            return Err(Error::Bcc("compile the eBPF program"));
+        }
+
+        let module = attached.module;
+
+        for nth in 0..self.probes.len() {
+            let name = CString::new(program_name(nth)).unwrap();
+            let fd = unsafe {
+                bcc_prog_load(
+                    BPF_PROG_TYPE_KPROBE,
+                    name.as_ptr(),
+                    bpf_function_start(module, name.as_ptr()),
+                    bpf_function_size(module, name.as_ptr()) as c_int,
+                    bpf_module_license(module),
+                    bpf_module_kern_version(module),
+                    0,
+                    ptr::null_mut(),
+                    0,
+                )
+            };
+
+            if fd < 0 {
+                return Err(Error::Bcc("load the eBPF program"));
+            }
+
+            attached.program_fds.push(fd);
+        }
+
+        let observer = OBSERVERS.fetch_add(1, Ordering::Relaxed);
+        let uprobes = {
+            let mut uprobes = UPROBES.lock().unwrap();
+            uprobes.clear();
+            unsafe { bcc_usdt_foreach_uprobe(usdt, on_uprobe) };
+
+            uprobes.drain(..).collect::<Vec<_>>()
+        };
+
+        for (nth, (binary_path, function, address, pid)) in uprobes.iter().enumerate() {
+            let probe = (0..self.probes.len()).find(|nth| program_name(*nth) == *function);
+            let program_fd = match probe {
+                Some(probe) => attached.program_fds[probe],
+                None => continue,
+            };
+            let event_name =
+                CString::new(format!("sonde_{}_{}_{}", std::process::id(), observer, nth)).unwrap();
+            let fd = unsafe {
+                bpf_attach_uprobe(
+                    program_fd,
+                    BPF_PROBE_ENTRY,
+                    event_name.as_ptr(),
+                    binary_path.as_ptr(),
+                    *address,
+                    *pid,
+                    0,
+                )
+            };
+
+            if fd < 0 {
+                return Err(Error::Bcc("attach the uprobe"));
+            }
+
+            attached.uprobes.push((event_name, fd));
+        }
+
+        let (sender, receiver) = mpsc::sync_channel(self.capacity);
+        let disconnected = Arc::new(AtomicBool::new(false));
+        let consumer = Box::new(Consumer {
+            probes: self.probes.clone(),
+            sender: Mutex::new(sender),
+            lost: AtomicU64::new(0),
+            disconnected: disconnected.clone(),
+        });
+        let cookie = &*consumer as *const Consumer as *mut c_void;
+        attached.consumer = Some(consumer);
+
+        let events = CString::new("events").unwrap();
+        let events_fd = unsafe { bpf_table_fd(module, events.as_ptr()) };
+
+        for cpu in online_cpus() {
+            let reader =
+                unsafe { bpf_open_perf_buffer(on_event, on_lost, cookie, -1, cpu, PAGE_COUNT) };
+
+            if reader.is_null() {
+                return Err(Error::Bcc("open the perf buffer"));
+            }
+
+            attached.readers.push(reader);
+
+            let mut cpu = cpu;
+            let mut reader_fd = unsafe { perf_reader_fd(reader) };
+
+            if unsafe {
+                bpf_update_elem(
+                    events_fd,
+                    &mut cpu as *mut c_int as *mut c_void,
+                    &mut reader_fd as *mut c_int as *mut c_void,
+                    0,
+                )
+            } != 0
+            {
+                return Err(Error::Bcc("open the perf buffer"));
+            }
+        }
+
+        let mut readers = Readers(attached.readers.clone());
+        let stop = attached.stop.clone();
+
+        // The errors are reported by the polling thread, not by the
+        // callbacks, which can't unwind through BCC.
+        attached.poller = Some(thread::spawn(move || {
+            let readers = &mut readers;
+            let sink = Sink::new("observe");
+
+            while !stop.load(Ordering::Relaxed) && sink.is_enabled() {
+                let polled = unsafe {
+                    perf_reader_poll(
+                        readers.0.len() as c_int,
+                        readers.0.as_mut_ptr(),
+                        POLL_TIMEOUT,
+                    )
+                };
+
+                if polled < 0 {
+                    let error = io::Error::last_os_error();
+
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        sink.report(&error);
+                    }
+                }
+
+                if disconnected.load(Ordering::Relaxed) {
+                    sink.report(&"the receiver of the events is dropped");
+                }
+            }
+        }));
+
+        self.attached = Some(attached);
+
+        Ok(receiver)
+    }
+
+    /// The number of events lost, because they were fired faster than
+    /// they were received, by the perf buffers or by the receiver once
+    /// it holds [`Observer::capacity`] events.
+    pub fn lost_events(&self) -> u64 {
+        self.attached
+            .as_ref()
+            .and_then(|attached| attached.consumer.as_ref())
+            .map(|consumer| consumer.lost.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+}
+
+impl Default for Observer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The name of the eBPF function of the `nth` probe.
+fn program_name(nth: usize) -> String {
+    format!("sonde_probe_{}", nth)
+}
+
+/// Decay the arrays of a D type into pointers, e.g. `char *` for
+/// `char[16]`, like `sonde` does.
+fn decayed(d_type: &str) -> Cow<'_, str> {
+    match d_type.find('[') {
+        Some(n) if d_type[..n].trim_end().ends_with('*') => {
+            format!("{}*", d_type[..n].trim_end()).into()
+        }
+        Some(n) => format!("{} *", d_type[..n].trim_end()).into(),
+        None => d_type.into(),
+    }
+}
+
+/// Whether a D type is a string, i.e. a `char *`.
+fn is_string_type(ty: &str) -> bool {
+    decayed(ty)
+        .split(|c: char| c.is_whitespace() || c == '*')
+        .filter(|word| !word.is_empty() && *word != "const")
+        .eq(["char"].iter().copied())
+        && decayed(ty).matches('*').count() == 1
+}
+
+/// The online CPUs, as listed by `/sys/devices/system/cpu/online`,
+/// e.g. `0-3,5`.
+fn online_cpus() -> Vec<c_int> {
+    let online = fs::read_to_string("/sys/devices/system/cpu/online").unwrap_or_default();
+
+    online
+        .trim()
+        .split(',')
+        .filter_map(|range| {
+            let mut bounds = range.splitn(2, '-').map(|bound| bound.parse::<c_int>());
+
+            match (bounds.next(), bounds.next()) {
+                (Some(Ok(start)), None) => Some(start..=start),
+                (Some(Ok(start)), Some(Ok(end))) => Some(start..=end),
+                _ => None,
+            }
+        })
+        .flatten()
+        .collect()
+}
+
+/// Generate the eBPF program: a function per probe, sending an event
+/// with a 24-byte header (the timestamp, the index of the probe, the
+/// PID and the TID), then the arguments, as 8-byte integers or
+/// [`MAX_STRING_LENGTH`]-byte strings. The events are built in a
+/// per-CPU array, as they can exceed the stack of eBPF programs.
+fn bpf_program(probes: &[ObservedProbe]) -> String {
+    let mut program = String::from("#include <uapi/linux/ptrace.h>\n\nBPF_PERF_OUTPUT(events);\n");
+
+    for (nth, probe) in probes.iter().enumerate() {
+        let fields = probe
+            .strings
+            .iter()
+            .enumerate()
+            .map(|(argument, is_string)| {
+                if *is_string {
+                    format!("    char arg{}[{}];\n", argument, MAX_STRING_LENGTH)
+                } else {
+                    format!("    u64 arg{};\n", argument)
+                }
+            })
+            .collect::<String>();
+        let reads = probe
+            .strings
+            .iter()
+            .enumerate()
+            .map(|(argument, is_string)| {
+                if *is_string {
+                    format!(
+                        "    u64 arg{argument} = 0;\n    bpf_usdt_readarg({position}, ctx, &arg{argument});\n    bpf_probe_read_user_str(&event->arg{argument}, sizeof(event->arg{argument}), (void *)arg{argument});\n",
+                        argument = argument,
+                        position = argument + 1,
+                    )
+                } else {
+                    format!(
+                        "    bpf_usdt_readarg({}, ctx, &event->arg{});\n",
+                        argument + 1,
+                        argument
+                    )
+                }
+            })
+            .collect::<String>();
+
+        program.push_str(&format!(
+            r#"
+struct event_{nth} {{
+    u64 timestamp;
+    u32 probe;
+    u32 pid;
+    u32 tid;
+    u32 padding;
+{fields}}};
+
+BPF_PERCPU_ARRAY(scratch_{nth}, struct event_{nth}, 1);
+
+int {function}(struct pt_regs *ctx) {{
+    int zero = 0;
+    struct event_{nth} *event = scratch_{nth}.lookup(&zero);
+
+    if (!event) {{
+        return 0;
+    }}
+
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    event->timestamp = bpf_ktime_get_ns();
+    event->probe = {nth};
+    event->pid = pid_tgid >> 32;
+    event->tid = (u32)pid_tgid;
+{reads}
+    events.perf_submit(ctx, event, sizeof(*event));
+
+    return 0;
+}}
+"#,
+            nth = nth,
+            function = program_name(nth),
+            fields = fields,
+            reads = reads,
+        ));
+    }
+
+    program
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe() -> ObservedProbe {
+        ObservedProbe {
+            provider: "hello".to_string(),
+            name: "you__me".to_string(),
+            strings: vec![true, false],
+        }
+    }
+
+    #[test]
+    fn test_is_string_type() {
+        assert!(is_string_type("char *"));
+        assert!(is_string_type("const char*"));
+        assert!(is_string_type("char[16]"));
+        assert!(!is_string_type("char"));
+        assert!(!is_string_type("char **"));
+        assert!(!is_string_type("unsigned char *"));
+    }
+
+    #[test]
+    fn test_bpf_program() {
+        let program = bpf_program(&[probe()]);
+
+        assert!(program.contains(
+            "struct event_0 {\n    u64 timestamp;\n    u32 probe;\n    u32 pid;\n    u32 tid;\n    u32 padding;\n    char arg0[64];\n    u64 arg1;\n};"
+        ));
+        assert!(program.contains("int sonde_probe_0(struct pt_regs *ctx) {"));
+        assert!(program.contains("    bpf_usdt_readarg(2, ctx, &event->arg1);\n"));
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&42u64.to_ne_bytes());
+        raw.extend_from_slice(&0u32.to_ne_bytes());
+        raw.extend_from_slice(&7u32.to_ne_bytes());
+        raw.extend_from_slice(&8u32.to_ne_bytes());
+        raw.extend_from_slice(&0u32.to_ne_bytes());
+        raw.extend_from_slice(b"Gordon\0");
+        raw.resize(24 + MAX_STRING_LENGTH, 0xff);
+        raw.extend_from_slice(&(-6i64).to_ne_bytes());
+
+        assert_eq!(
+            probe().decode(&raw),
+            Some(Event {
+                provider: "hello".to_string(),
+                probe: "you-me".to_string(),
+                timestamp: 42,
+                pid: 7,
+                tid: 8,
+                arguments: vec![Value::String("Gordon".to_string()), Value::Integer(-6)],
+            })
+        );
+        assert_eq!(probe().decode(&raw[..30]), None);
+    }
+}
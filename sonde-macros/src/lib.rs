@@ -0,0 +1,358 @@
+//! Procedural macros for `sonde`. They are re-exported by the `sonde`
+//! and `sonde-api` crates, e.g. `#[sonde::trace]`; don't depend on this
+//! crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, quote_spanned};
+use syn::{
+    parse::{ParseStream, Parser},
+    Data, DeriveInput, Expr, ItemFn, Member, Path, Token,
+};
+
+/// Fire a probe when the function is called, and another one when it
+/// returns, including early returns and panics. The probes are the
+/// functions generated by `sonde` for probes without arguments, e.g.
+/// with `call__entry()` and `call__return()` probes in the `hello`
+/// provider:
+///
+/// ```rust,ignore
+/// #[sonde::trace(entry = tracing::hello::call_entry, exit = tracing::hello::call_return)]
+/// fn call() {
+///     // …
+/// }
+/// ```
+///
+/// `entry` and `exit` can also be used alone.
+#[proc_macro_attribute]
+pub fn trace(attribute: TokenStream, item: TokenStream) -> TokenStream {
+    expand_trace(attribute.into(), item.into())
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_trace(attribute: TokenStream2, item: TokenStream2) -> syn::Result<TokenStream2> {
+    let mut entry: Option<Path> = None;
+    let mut exit: Option<Path> = None;
+
+    syn::meta::parser(|meta| {
+        if meta.path.is_ident("entry") {
+            entry = Some(meta.value()?.parse()?);
+
+            Ok(())
+        } else if meta.path.is_ident("exit") {
+            exit = Some(meta.value()?.parse()?);
+
+            Ok(())
+        } else {
+            Err(meta.error("expected `entry` or `exit`"))
+        }
+    })
+    .parse2(attribute)?;
+
+    if entry.is_none() && exit.is_none() {
+        return Err(syn::Error::new(
+            Span::call_site(),
+            "expected an `entry` probe, an `exit` probe, or both, e.g. `#[sonde::trace(entry = hello::call_entry)]`",
+        ));
+    }
+
+    let mut function = syn::parse2::<ItemFn>(item)?;
+    let block = &function.block;
+
+    let entry = entry.map(|entry| quote! { #entry(); });
+
+    // The exit probe is fired by a guard, so that it's fired whatever
+    // the way the function returns.
+    let exit = exit.map(|exit| {
+        quote_spanned! { Span::mixed_site() =>
+            let exit_guard = {
+                struct ExitGuard<F: FnMut()>(F);
+
+                impl<F: FnMut()> Drop for ExitGuard<F> {
+                    fn drop(&mut self) {
+                        (self.0)();
+                    }
+                }
+
+                ExitGuard(|| #exit())
+            };
+        }
+    });
+
+    function.block = syn::parse_quote!({
+        #entry
+        #exit
+        #block
+    });
+
+    Ok(quote! { #function })
+}
+
+/// Fire a probe from the fields of a struct, with `sonde::probe_from`.
+/// The probe is a function generated by `sonde`, given in a `#[probe]`
+/// attribute on the struct. The fields are its arguments, in their
+/// order, converted with `as _`:
+///
+/// ```rust,ignore
+/// #[derive(sonde::ProbeArgs)]
+/// #[probe(tracing::hello::request_start)]
+/// struct Request {
+///     #[probe(with = CStr::as_ptr)]
+///     method: CString,
+///     id: u64,
+///     #[probe(skip)]
+///     body: Vec<u8>,
+/// }
+///
+/// sonde::probe_from(&request);
+/// ```
+///
+/// The fields accept these attributes:
+///
+/// * `skip`: the field isn't an argument,
+/// * `with = function`: the argument is `function(&field)`, e.g. to get
+///   a pointer,
+/// * `arg = N`: the field is the `N`-th argument, starting from 0. It
+///   must be given to all the arguments, or to none.
+///
+/// The derived trait is `sonde::ProbeArgs`. When only `sonde-api` is a
+/// regular dependency, the crate is given after the probe, e.g.
+/// `#[probe(tracing::hello::request_start, crate = sonde_api)]`.
+#[proc_macro_derive(ProbeArgs, attributes(probe))]
+pub fn derive_probe_args(item: TokenStream) -> TokenStream {
+    expand_probe_args(item.into())
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_probe_args(item: TokenStream2) -> syn::Result<TokenStream2> {
+    let input = syn::parse2::<DeriveInput>(item)?;
+
+    let (probe, krate) = input
+        .attrs
+        .iter()
+        .find(|attribute| attribute.path().is_ident("probe"))
+        .ok_or_else(|| {
+            syn::Error::new(
+                Span::call_site(),
+                "expected the probe to fire, e.g. `#[probe(tracing::hello::you)]`",
+            )
+        })?
+        .parse_args_with(|input: ParseStream| {
+            let probe = input.parse::<Path>()?;
+            let mut krate = None;
+
+            if input.parse::<Option<Token![,]>>()?.is_some() {
+                input.parse::<Token![crate]>()?;
+                input.parse::<Token![=]>()?;
+                krate = Some(input.parse::<Path>()?);
+            }
+
+            Ok((probe, krate))
+        })?;
+    let krate = krate.unwrap_or_else(|| syn::parse_quote!(::sonde));
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "`ProbeArgs` can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut arguments = Vec::new();
+
+    for (nth, field) in fields.iter().enumerate() {
+        let mut skip = false;
+        let mut with: Option<Expr> = None;
+        let mut index: Option<(usize, Span)> = None;
+
+        for attribute in field.attrs.iter().filter(|a| a.path().is_ident("probe")) {
+            attribute.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    skip = true;
+
+                    Ok(())
+                } else if meta.path.is_ident("with") {
+                    with = Some(meta.value()?.parse()?);
+
+                    Ok(())
+                } else if meta.path.is_ident("arg") {
+                    let literal = meta.value()?.parse::<syn::LitInt>()?;
+                    index = Some((literal.base10_parse()?, literal.span()));
+
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `skip`, `with` or `arg`"))
+                }
+            })?;
+        }
+
+        if skip {
+            continue;
+        }
+
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(nth.into()),
+        };
+        let value = match with {
+            Some(with) => quote! { (#with)(&self.#member) as _ },
+            None => quote! { self.#member as _ },
+        };
+
+        arguments.push((index, value));
+    }
+
+    // The arguments are in the order of the fields, unless they all
+    // have an index.
+    if arguments.iter().any(|(index, _)| index.is_some()) {
+        if let Some((_, value)) = arguments.iter().find(|(index, _)| index.is_none()) {
+            return Err(syn::Error::new_spanned(
+                value,
+                "`arg = N` must be given to all the arguments, or to none",
+            ));
+        }
+
+        arguments.sort_by_key(|(index, _)| index.map(|(index, _)| index));
+
+        for (nth, (index, _)) in arguments.iter().enumerate() {
+            if let Some((index, span)) = index {
+                if *index != nth {
+                    return Err(syn::Error::new(
+                        *span,
+                        format!("expected `arg = {}`: the arguments must be numbered from 0, without gaps", nth),
+                    ));
+                }
+            }
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    let arguments = arguments.iter().map(|(_, value)| value);
+
+    Ok(quote! {
+        impl #impl_generics #krate::ProbeArgs for #name #type_generics #where_clause {
+            fn fire_probe(&self) {
+                #probe(#(#arguments),*);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_trace() {
+        let expanded = expand_trace(
+            quote! { entry = hello::call_entry, exit = hello::call_return },
+            quote! { fn call() -> u8 { 42 } },
+        )
+        .unwrap()
+        .to_string();
+
+        assert!(expanded.starts_with("fn call () -> u8 { hello :: call_entry () ;"));
+        assert!(expanded.contains("ExitGuard (| | hello :: call_return ())"));
+        assert!(expanded.ends_with("{ 42 } }"));
+    }
+
+    #[test]
+    fn test_expand_probe_args() {
+        let expanded = expand_probe_args(quote! {
+            #[probe(tracing::hello::you)]
+            struct Greeting<'a> {
+                #[probe(with = CStr::as_ptr)]
+                who: &'a CStr,
+                #[probe(skip)]
+                cache: Vec<u8>,
+                length: usize,
+            }
+        })
+        .unwrap()
+        .to_string();
+
+        assert_eq!(
+            expanded,
+            quote! {
+                impl<'a> ::sonde::ProbeArgs for Greeting<'a> {
+                    fn fire_probe(&self) {
+                        tracing::hello::you((CStr::as_ptr)(&self.who) as _, self.length as _);
+                    }
+                }
+            }
+            .to_string()
+        );
+
+        let expanded = expand_probe_args(quote! {
+            #[probe(hello::you)]
+            struct Greeting(#[probe(arg = 1)] u8, #[probe(arg = 0)] u16);
+        })
+        .unwrap()
+        .to_string();
+
+        assert!(expanded.contains("hello :: you (self . 1 as _ , self . 0 as _) ;"));
+
+        let expanded = expand_probe_args(quote! {
+            #[probe(hello::you, crate = sonde_api)]
+            struct Greeting(u8);
+        })
+        .unwrap()
+        .to_string();
+
+        assert!(expanded.starts_with("impl sonde_api :: ProbeArgs for Greeting"));
+    }
+
+    #[test]
+    fn test_expand_probe_args_errors() {
+        let error = |item| expand_probe_args(item).unwrap_err().to_string();
+
+        assert_eq!(
+            error(quote! { struct Greeting { length: usize } }),
+            "expected the probe to fire, e.g. `#[probe(tracing::hello::you)]`"
+        );
+        assert_eq!(
+            error(quote! { #[probe(hello::you)] enum Greeting { A } }),
+            "`ProbeArgs` can only be derived for structs"
+        );
+        assert_eq!(
+            error(
+                quote! { #[probe(hello::you)] struct Greeting { #[probe(cast = u8)] length: usize } }
+            ),
+            "expected `skip`, `with` or `arg`"
+        );
+        assert_eq!(
+            error(quote! { #[probe(hello::you)] struct Greeting(#[probe(arg = 0)] u8, u8); }),
+            "`arg = N` must be given to all the arguments, or to none"
+        );
+        assert_eq!(
+            error(
+                quote! { #[probe(hello::you)] struct Greeting(#[probe(arg = 0)] u8, #[probe(arg = 2)] u8); }
+            ),
+            "expected `arg = 1`: the arguments must be numbered from 0, without gaps"
+        );
+    }
+
+    #[test]
+    fn test_expand_trace_errors() {
+        let error = |attribute| {
+            expand_trace(attribute, quote! { fn call() {} })
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            error(quote! {}),
+            "expected an `entry` probe, an `exit` probe, or both, e.g. `#[sonde::trace(entry = hello::call_entry)]`"
+        );
+        assert_eq!(
+            error(quote! { enter = hello::call_entry }),
+            "expected `entry` or `exit`"
+        );
+    }
+}
@@ -2,5 +2,9 @@ fn main() {
     sonde::Builder::new()
         .file("./providerA.d")
         .file("./providerB.d")
+        .is_enabled_functions(true)
+        .os_str_functions(true)
+        .use_sonde_api(true)
+        .split_output(true)
         .compile();
 }
@@ -1,14 +1,39 @@
-mod tracing {
-    #![allow(unused)]
-
-    include!(env!("SONDE_RUST_API_FILE"));
-}
+sonde_api::include_api!(mod tracing);
+sonde_api::include_api!(
+    /// The probes of the `Salut` provider only.
+    pub(crate) mod salut_tracing,
+    "SONDE_RUST_API_FILE_SALUT"
+);
 
 fn main() {
+    tracing::hello::on_enabled_change("you", |enabled| {
+        if enabled {
+            println!("A tracer is attached to `hello:::you`.");
+        }
+    });
+    tracing::hello::poll_enabled_changes();
+
     {
         let who = std::ffi::CString::new("Gordon").unwrap();
         tracing::hello::you(who.as_ptr() as *mut _, who.as_bytes().len() as _);
     }
 
-    println!("Hello, World!");
+    {
+        let who = sonde_api::ProbeStr::from("Gordon");
+        tracing::hello::you(who.as_ptr(), who.len() as _);
+    }
+
+    {
+        let path = std::path::Path::new("/tmp/gordon");
+        tracing::hello::you_os(path, path.as_os_str().len() as _);
+    }
+
+    {
+        let _greeting = tracing::hello::greeting_span(1);
+
+        println!("Hello, World!");
+    }
+
+    tracing::salut::toi();
+    salut_tracing::salut::moi();
 }
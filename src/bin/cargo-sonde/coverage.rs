@@ -0,0 +1,389 @@
+//! `cargo sonde coverage`: cross-reference the declared probes with
+//! the probes used by DTrace, bpftrace and SystemTap scripts.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// A declared probe.
+#[derive(Debug, PartialEq)]
+struct Declared {
+    provider: String,
+    probe: String,
+}
+
+impl Declared {
+    /// DTrace exposes `__` as `-` in probe names.
+    fn dtrace_name(&self) -> String {
+        self.probe.replace("__", "-")
+    }
+}
+
+/// A probe referenced by a script.
+#[derive(Debug, PartialEq)]
+struct Reference {
+    line: usize,
+    spec: String,
+
+    /// `None` means any provider.
+    provider: Option<String>,
+    probe: String,
+
+    /// Whether the reference is a USDT one for sure, or whether it can
+    /// target another kind of provider (e.g. `syscall` in DTrace).
+    is_usdt: bool,
+}
+
+impl Reference {
+    fn matches(&self, declared: &Declared) -> bool {
+        let provider_matches = match &self.provider {
+            Some(provider) => matches_provider(provider, &declared.provider),
+            None => true,
+        };
+
+        provider_matches
+            && (glob(&self.probe, &declared.probe) || glob(&self.probe, &declared.dtrace_name()))
+    }
+}
+
+pub fn run(arguments: &[String]) -> io::Result<bool> {
+    let mut arguments = arguments.iter();
+    let scripts_directory = match arguments.next() {
+        Some(directory) => PathBuf::from(directory),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "`coverage` expects a directory of scripts",
+            ))
+        }
+    };
+    let d_files = super::d_files(&arguments.collect::<Vec<_>>())?;
+
+    let mut declared = Vec::new();
+
+    for d_file in &d_files {
+        let source = fs::read_to_string(d_file)?;
+        let script = sonde::cst::parse(&source);
+
+        for provider in script
+            .nodes()
+            .filter(|node| node.kind == sonde::cst::NodeKind::Provider)
+        {
+            for probe in provider.nodes() {
+                if let (Some(provider), Some(probe)) = (provider.name(), probe.name()) {
+                    declared.push(Declared {
+                        provider: provider.to_string(),
+                        probe: probe.to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    let mut scripts = Vec::new();
+
+    for extension in &["d", "bt", "stp"] {
+        super::collect_files(&scripts_directory, extension, &mut scripts)?;
+    }
+
+    scripts.sort();
+
+    let mut used = vec![false; declared.len()];
+    let mut unknown = Vec::new();
+
+    for script in &scripts {
+        let source = fs::read_to_string(script)?;
+
+        for reference in references(script, &source) {
+            let mut found = false;
+
+            for (nth, declared) in declared.iter().enumerate() {
+                if reference.matches(declared) {
+                    used[nth] = true;
+                    found = true;
+                }
+            }
+
+            let is_ours = reference.is_usdt
+                || declared.iter().any(|declared| match &reference.provider {
+                    Some(provider) => matches_provider(provider, &declared.provider),
+                    None => false,
+                });
+
+            if !found && is_ours {
+                unknown.push((script.clone(), reference));
+            }
+        }
+    }
+
+    let unused = declared
+        .iter()
+        .zip(used)
+        .filter_map(|(declared, used)| if used { None } else { Some(declared) })
+        .collect::<Vec<_>>();
+
+    if !unused.is_empty() {
+        println!("Probes without consumer scripts:");
+
+        for declared in &unused {
+            println!("    {}:::{}", declared.provider, declared.probe);
+        }
+    }
+
+    if !unknown.is_empty() {
+        println!("Scripts referencing undeclared probes:");
+
+        for (script, reference) in &unknown {
+            println!(
+                "    {}:{}: {}",
+                script.display(),
+                reference.line,
+                reference.spec
+            );
+        }
+    }
+
+    if unused.is_empty() && unknown.is_empty() {
+        println!(
+            "All {} probes have a consumer script, and all scripts reference declared probes.",
+            declared.len()
+        );
+    }
+
+    Ok(unused.is_empty() && unknown.is_empty())
+}
+
+/// Extract the probe references from a script, based on its extension.
+fn references(path: &Path, source: &str) -> Vec<Reference> {
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    let mut references = Vec::new();
+
+    for (nth, line) in source.lines().enumerate() {
+        let line_number = nth + 1;
+
+        match extension {
+            "stp" => references.extend(stap_references(line, line_number)),
+            _ => {
+                for word in
+                    line.split(|c: char| !(c.is_ascii_alphanumeric() || "_-*?$:./[]".contains(c)))
+                {
+                    let reference = if extension == "bt" {
+                        bpftrace_reference(word, line_number)
+                    } else {
+                        dtrace_reference(word, line_number)
+                    };
+
+                    references.extend(reference);
+                }
+            }
+        }
+    }
+
+    references
+}
+
+/// `provider:module:function:name`, e.g. `hello*:::world`.
+fn dtrace_reference(word: &str, line: usize) -> Option<Reference> {
+    let parts = word.split(':').collect::<Vec<_>>();
+
+    if parts.len() != 4 || parts[3].is_empty() {
+        return None;
+    }
+
+    let provider = parts[0];
+
+    Some(Reference {
+        line,
+        spec: word.to_string(),
+        provider: if provider.is_empty() {
+            None
+        } else {
+            Some(provider.to_string())
+        },
+        probe: parts[3].to_string(),
+        is_usdt: provider.ends_with('*')
+            || provider.ends_with("$target")
+            || provider.ends_with(|c: char| c.is_ascii_digit()),
+    })
+}
+
+/// `usdt:path:provider:probe` or `usdt:path:probe`.
+fn bpftrace_reference(word: &str, line: usize) -> Option<Reference> {
+    let parts = word.split(':').collect::<Vec<_>>();
+
+    if !matches!(parts.first(), Some(&"usdt") | Some(&"U")) {
+        return None;
+    }
+
+    let (provider, probe) = match parts.len() {
+        3 => (None, parts[2]),
+        4 => (Some(parts[2].to_string()), parts[3]),
+        _ => return None,
+    };
+
+    Some(Reference {
+        line,
+        spec: word.to_string(),
+        provider,
+        probe: probe.to_string(),
+        is_usdt: true,
+    })
+}
+
+/// `process("…").provider("…").mark("…")`.
+fn stap_references(line: &str, line_number: usize) -> Vec<Reference> {
+    let argument = |rest: &str| rest.split('"').nth(1).map(ToString::to_string);
+
+    line.match_indices(".mark(")
+        .filter_map(|(index, _)| {
+            let probe = argument(&line[index..])?;
+            let start = line[..index].rfind("process").unwrap_or(0);
+            let provider = line[start..index]
+                .find(".provider(")
+                .and_then(|offset| argument(&line[start + offset..]));
+
+            // The spec ends after the `)` following the closing quote of
+            // the probe name, or where the probe name ends.
+            let opening = index + line[index..].find('"')? + 1;
+            let closing = line[opening..]
+                .find('"')
+                .map_or(line.len(), |offset| opening + offset + 1);
+            let end = match line[closing..].trim_start().strip_prefix(')') {
+                Some(rest) => line.len() - rest.len(),
+                None => closing,
+            };
+
+            Some(Reference {
+                line: line_number,
+                spec: line[start..end].trim().to_string(),
+                provider,
+                probe,
+                is_usdt: true,
+            })
+        })
+        .collect()
+}
+
+/// DTrace providers are suffixed by the PID (e.g. `hello123`), and
+/// are often matched case-insensitively by users.
+fn matches_provider(pattern: &str, provider: &str) -> bool {
+    let pattern = pattern.trim_end_matches("$target");
+    let pattern = pattern.trim_end_matches(|c: char| c.is_ascii_digit());
+
+    glob(&pattern.to_lowercase(), &provider.to_lowercase())
+}
+
+/// Match `subject` against `pattern`, where `*` matches any sequence
+/// and `?` matches any character.
+fn glob(pattern: &str, subject: &str) -> bool {
+    match pattern.chars().next() {
+        None => subject.is_empty(),
+        Some('*') => {
+            let pattern = &pattern[1..];
+
+            subject
+                .char_indices()
+                .map(|(index, _)| index)
+                .chain(Some(subject.len()))
+                .any(|index| glob(pattern, &subject[index..]))
+        }
+        Some(c) => match subject.chars().next() {
+            Some(s) if c == '?' || c == s => {
+                glob(&pattern[c.len_utf8()..], &subject[s.len_utf8()..])
+            }
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("hello", "hello"));
+        assert!(glob("hel*", "hello"));
+        assert!(glob("*", ""));
+        assert!(glob("h?llo", "hello"));
+        assert!(!glob("hel", "hello"));
+        assert!(!glob("*x", "hello"));
+    }
+
+    #[test]
+    fn test_dtrace_references() {
+        let references = references(
+            Path::new("a.d"),
+            "Hello*:::you\n{ printf(\"a:b\"); }\nsyscall::read:entry, hello$target:::you-me {}",
+        );
+
+        assert_eq!(
+            references
+                .iter()
+                .map(|r| (r.line, r.provider.as_deref(), r.probe.as_str(), r.is_usdt))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, Some("Hello*"), "you", true),
+                (3, Some("syscall"), "entry", false),
+                (3, Some("hello$target"), "you-me", true),
+            ]
+        );
+
+        let declared = Declared {
+            provider: "Hello".to_string(),
+            probe: "you__me".to_string(),
+        };
+
+        assert!(references[2].matches(&declared));
+        assert!(!references[0].matches(&declared));
+    }
+
+    #[test]
+    fn test_bpftrace_references() {
+        let references = references(
+            Path::new("a.bt"),
+            "usdt:./target/release/app:hello:world { }\nU:/bin/app:you {}\nkprobe:vfs_read {}",
+        );
+
+        assert_eq!(
+            references
+                .iter()
+                .map(|r| (r.line, r.provider.as_deref(), r.probe.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(1, Some("hello"), "world"), (2, None, "you")]
+        );
+    }
+
+    #[test]
+    fn test_stap_references() {
+        let references = references(
+            Path::new("a.stp"),
+            r#"probe process("app").provider("hello").mark("world") { }"#,
+        );
+
+        assert_eq!(references.len(), 1);
+        assert_eq!(references[0].provider.as_deref(), Some("hello"));
+        assert_eq!(references[0].probe, "world");
+        assert_eq!(
+            references[0].spec,
+            r#"process("app").provider("hello").mark("world")"#
+        );
+
+        let references = super::references(
+            Path::new("a.stp"),
+            "probe process(\"app\").mark( \"wörld\" ) { }\nprobe process(\"app\").mark(\"you",
+        );
+
+        assert_eq!(
+            references
+                .iter()
+                .map(|r| (r.probe.as_str(), r.spec.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("wörld", r#"process("app").mark( "wörld" )"#),
+                ("you", r#"process("app").mark("you"#),
+            ]
+        );
+    }
+}
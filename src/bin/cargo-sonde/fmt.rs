@@ -0,0 +1,32 @@
+//! `cargo sonde fmt`: format `.d` files.
+
+use std::{fs, io};
+
+/// Format the files. With `--check`, files are not written, and
+/// `Ok(false)` is returned if at least one of them isn't formatted.
+pub fn run(arguments: &[String]) -> io::Result<bool> {
+    let check = arguments.iter().any(|argument| argument == "--check");
+    let files = arguments
+        .iter()
+        .filter(|argument| !argument.starts_with("--"))
+        .collect::<Vec<_>>();
+    let mut formatted = true;
+
+    for file in super::d_files(&files)? {
+        let source = fs::read_to_string(&file)?;
+        let output = sonde::fmt::format(&source);
+
+        if output == source {
+            continue;
+        }
+
+        if check {
+            println!("{} is not formatted", file.display());
+            formatted = false;
+        } else {
+            fs::write(&file, output)?;
+        }
+    }
+
+    Ok(formatted)
+}
@@ -0,0 +1,46 @@
+//! `cargo sonde gen`: generate the header, the C shim and the Rust API
+//! ahead of time, in a directory to be checked in and used by
+//! `Builder::use_pregenerated`, so that `dtrace` doesn't run during
+//! the build.
+
+use std::{env, io, path::PathBuf};
+
+/// The directory of the generated files, if none is given.
+const DEFAULT_OUTPUT: &str = "sonde-generated";
+
+pub fn run(arguments: &[String]) -> io::Result<bool> {
+    let mut output = PathBuf::from(DEFAULT_OUTPUT);
+    let mut files = Vec::new();
+    let mut arguments = arguments.iter();
+
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--output" | "-o" => output = arguments.next().map(PathBuf::from).unwrap_or(output),
+            // The target only matters to the default backend, e.g. on
+            // `wasm32`, and to the C preprocessor.
+            "--target" => {
+                if let Some(target) = arguments.next() {
+                    env::set_var("TARGET", target);
+                }
+            }
+            _ => files.push(argument),
+        }
+    }
+
+    let mut builder = sonde::Builder::new();
+
+    for file in super::d_files(&files)? {
+        builder.file(file);
+    }
+
+    for warning in builder.pregenerate(&output) {
+        eprintln!("warning: {}", warning);
+    }
+
+    eprintln!(
+        "Generated in `{}`; use it with `Builder::use_pregenerated`.",
+        output.display()
+    );
+
+    Ok(true)
+}
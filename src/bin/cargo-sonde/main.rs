@@ -0,0 +1,121 @@
+//! `cargo sonde`, a companion tool to work with `.d` files.
+//!
+//! Install it with `cargo install sonde`, then run `cargo sonde help`.
+
+mod coverage;
+mod fmt;
+mod gen;
+mod migrate;
+mod perfetto;
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+const USAGE: &str = "Usage: cargo sonde <command> [<options>]
+
+Commands:
+    fmt [--check] [<file>…]    Format `.d` files; all `.d` files in the
+                               current directory if none is given.
+    coverage <dir> [<file>…]   Report the probes declared in `.d` files
+                               without a consumer script in `<dir>`, and
+                               the scripts referencing undeclared probes.
+    gen [-o <dir>] [--target <triple>] [<file>…]
+                               Run `dtrace` and generate the files used by
+                               `Builder::use_pregenerated` in `<dir>`
+                               (`sonde-generated` by default), from the
+                               `.d` files; all `.d` files in the current
+                               directory if none is given.
+    migrate [-o <file>] [--symbol-prefix <prefix>] [--namespace <name>]
+            [--rename-provider <provider>=<module>]
+            [--rename-probe <provider>:<probe>=<function>] [<dir>]
+                               Generate a `.d` file from the existing
+                               `DTRACE_PROBE*`/`STAP_PROBE*` call sites in
+                               `<dir>`, and suggest their replacements,
+                               named like the builder's options do.
+    perfetto [-o <file>] <trace> [<file>…]
+                               Convert a trace of the probes, with a line
+                               per probe as `<timestamp> <pid> <tid>
+                               <provider>:<probe> [<argument>…]`, to the
+                               JSON format of Perfetto and
+                               `chrome://tracing`; `-` reads the trace
+                               from the standard input.
+    help                       Print this message.";
+
+fn main() {
+    let mut arguments = env::args().skip(1).peekable();
+
+    // When run as `cargo sonde`, Cargo passes `sonde` as the first argument.
+    if arguments.peek().map(String::as_str) == Some("sonde") {
+        arguments.next();
+    }
+
+    let command = arguments.next();
+    let arguments = arguments.collect::<Vec<_>>();
+
+    let result = match command.as_deref() {
+        Some("fmt") => fmt::run(&arguments),
+        Some("coverage") => coverage::run(&arguments),
+        Some("gen") => gen::run(&arguments),
+        Some("migrate") => migrate::run(&arguments),
+        Some("perfetto") => perfetto::run(&arguments),
+        Some("help") | Some("--help") | Some("-h") => {
+            println!("{}", USAGE);
+
+            Ok(true)
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+
+            process::exit(2);
+        }
+    };
+
+    match result {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(error) => {
+            eprintln!("error: {}", error);
+
+            process::exit(2);
+        }
+    }
+}
+
+/// Return the given files, or all the `.d` files found recursively in
+/// the current directory if none is given.
+fn d_files(files: &[&String]) -> io::Result<Vec<PathBuf>> {
+    if !files.is_empty() {
+        return Ok(files.iter().map(PathBuf::from).collect());
+    }
+
+    let mut found = Vec::new();
+    collect_files(Path::new("."), "d", &mut found)?;
+    found.sort();
+
+    Ok(found)
+}
+
+/// Collect all files with the given `extension` in `directory`,
+/// recursively. Hidden directories and `target` are skipped.
+fn collect_files(directory: &Path, extension: &str, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+
+        if path.is_dir() {
+            if !name.starts_with('.') && name != "target" {
+                collect_files(&path, extension, found)?;
+            }
+        } else if path.extension().and_then(|ext| ext.to_str()) == Some(extension) {
+            found.push(path);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,384 @@
+//! `cargo sonde migrate`: find existing `DTRACE_PROBE*` and
+//! `STAP_PROBE*` call sites (and `probe!` from the `probe` crate),
+//! synthesize a starter `.d` file declaring the probes, and suggest
+//! the replacement call sites.
+
+use sonde::{ast::Provider, NamingConfig};
+use std::{collections::BTreeMap, fs, io, path::PathBuf};
+
+/// The argument type used in the starter `.d` file. The SDT macros
+/// cast all the arguments to a pointer-sized integer.
+const DEFAULT_ARGUMENT_TYPE: &str = "uintptr_t";
+
+/// An existing call site.
+#[derive(Debug, PartialEq)]
+struct CallSite {
+    line: usize,
+    call: String,
+    provider: String,
+    probe: String,
+    arguments: Vec<String>,
+}
+
+pub fn run(arguments: &[String]) -> io::Result<bool> {
+    let mut output = None;
+    let mut directory = PathBuf::from(".");
+    let mut naming = NamingConfig::default();
+    let mut arguments = arguments.iter();
+
+    // The naming options are the ones of the builder, so that the
+    // suggested call sites match the generated functions.
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--output" | "-o" => output = arguments.next().map(PathBuf::from),
+            "--symbol-prefix" => {
+                if let Some(prefix) = arguments.next() {
+                    naming.c_prefix = prefix.clone();
+                }
+            }
+            "--namespace" => {
+                if let Some(namespace) = arguments.next() {
+                    naming.namespace = namespace.clone();
+                }
+            }
+            "--rename-provider" => {
+                if let Some((provider, module)) =
+                    arguments.next().and_then(|rename| rename.split_once('='))
+                {
+                    naming
+                        .provider_renames
+                        .push((provider.to_string(), module.to_string()));
+                }
+            }
+            "--rename-probe" => {
+                if let Some((provider, (probe, function))) = arguments
+                    .next()
+                    .and_then(|rename| rename.split_once(':'))
+                    .and_then(|(provider, rest)| Some((provider, rest.split_once('=')?)))
+                {
+                    naming.probe_renames.push((
+                        provider.to_string(),
+                        probe.to_string(),
+                        function.to_string(),
+                    ));
+                }
+            }
+            _ => directory = PathBuf::from(argument),
+        }
+    }
+
+    let mut files = Vec::new();
+
+    for extension in &["c", "h", "cc", "cpp", "hpp", "rs"] {
+        super::collect_files(&directory, extension, &mut files)?;
+    }
+
+    files.sort();
+
+    let mut call_sites = Vec::new();
+
+    for file in files {
+        let source = fs::read_to_string(&file)?;
+        let is_rust = file.extension().and_then(|ext| ext.to_str()) == Some("rs");
+
+        for call_site in call_sites_in(&source, is_rust) {
+            call_sites.push((file.clone(), is_rust, call_site));
+        }
+    }
+
+    if call_sites.is_empty() {
+        eprintln!("No probe call site found in `{}`.", directory.display());
+
+        return Ok(true);
+    }
+
+    let d = d_source(call_sites.iter().map(|(_, _, call_site)| call_site));
+
+    match &output {
+        Some(output) => fs::write(output, &d)?,
+        None => print!("{}", d),
+    }
+
+    eprintln!("Replace the call sites with the functions generated by sonde:");
+
+    for (file, is_rust, call_site) in &call_sites {
+        eprintln!(
+            "    {file}:{line}:\n        {call}\n     => {replacement}",
+            file = file.display(),
+            line = call_site.line,
+            call = call_site.call,
+            replacement = replacement(&naming, call_site, *is_rust),
+        );
+    }
+
+    Ok(true)
+}
+
+/// The call of the function generated by sonde replacing a call site,
+/// named with `naming`.
+fn replacement(naming: &NamingConfig, call_site: &CallSite, is_rust: bool) -> String {
+    if is_rust {
+        format!(
+            "tracing::r#{module}::r#{function}({arguments});",
+            module = naming.rust_module(&call_site.provider),
+            function = naming.rust_function(&call_site.provider, &call_site.probe),
+            arguments = call_site
+                .arguments
+                .iter()
+                .map(|argument| format!("{} as _", argument))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    } else {
+        let provider = Provider {
+            name: naming.namespaced(&call_site.provider),
+            ..Default::default()
+        };
+
+        format!(
+            "{function}({arguments});",
+            function = naming.c_function(&provider, &call_site.probe),
+            arguments = call_site.arguments.join(", ")
+        )
+    }
+}
+
+/// Generate the starter `.d` source declaring all the probes.
+fn d_source<'a, I>(call_sites: I) -> String
+where
+    I: Iterator<Item = &'a CallSite>,
+{
+    let mut providers = BTreeMap::<&str, BTreeMap<&str, usize>>::new();
+
+    for call_site in call_sites {
+        let arity = providers
+            .entry(&call_site.provider)
+            .or_default()
+            .entry(&call_site.probe)
+            .or_default();
+
+        *arity = (*arity).max(call_site.arguments.len());
+    }
+
+    let mut d = String::from(
+        "/* Generated by `cargo sonde migrate`. The argument types are\n   guesses, refine them. */\n",
+    );
+
+    for (provider, probes) in providers {
+        d.push_str(&format!("\nprovider {} {{\n", provider));
+
+        for (probe, arity) in probes {
+            d.push_str(&format!(
+                "    probe {}({});\n",
+                probe,
+                vec![DEFAULT_ARGUMENT_TYPE; arity].join(", ")
+            ));
+        }
+
+        d.push_str("};\n");
+    }
+
+    d
+}
+
+/// Find the call sites in a source.
+fn call_sites_in(source: &str, is_rust: bool) -> Vec<CallSite> {
+    let mut macros = vec!["DTRACE_PROBE", "STAP_PROBE"];
+
+    if is_rust {
+        macros.extend(&["probe!", "probe_lazy!"]);
+    }
+
+    let mut call_sites = Vec::new();
+
+    for name in macros {
+        for (index, _) in source.match_indices(name) {
+            // Skip identifiers merely ending with the name.
+            if source[..index]
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_alphanumeric() || c == '_')
+            {
+                continue;
+            }
+
+            let rest = &source[index + name.len()..];
+            let rest = rest.trim_start_matches(|c: char| c.is_ascii_digit());
+            let start = source.len() - rest.len();
+
+            if !rest.starts_with('(') {
+                continue;
+            }
+
+            let (arguments, length) = match split_arguments(rest) {
+                Some(split) => split,
+                None => continue,
+            };
+
+            let mut arguments = arguments.into_iter();
+
+            if let (Some(provider), Some(probe)) = (arguments.next(), arguments.next()) {
+                call_sites.push(CallSite {
+                    line: source[..index].matches('\n').count() + 1,
+                    call: source[index..start + length].to_string(),
+                    provider,
+                    probe,
+                    arguments: arguments.collect(),
+                });
+            }
+        }
+    }
+
+    call_sites.sort_by_key(|call_site| call_site.line);
+
+    call_sites
+}
+
+/// Split the arguments of a call, starting at `(`. Return the
+/// arguments and the length of the call up to `)` included.
+fn split_arguments(input: &str) -> Option<(Vec<String>, usize)> {
+    let mut arguments = Vec::new();
+    let mut depth = 0;
+    let mut start = 1;
+    let mut in_string = None;
+    let mut escaped = false;
+
+    for (index, c) in input.char_indices() {
+        if let Some(delimiter) = in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                c if c == delimiter => in_string = None,
+                _ => (),
+            }
+
+            continue;
+        }
+
+        match c {
+            '"' | '\'' => in_string = Some(c),
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => {
+                depth -= 1;
+
+                if depth == 0 {
+                    arguments.push(input[start..index].trim().to_string());
+                    arguments.retain(|argument: &String| !argument.is_empty());
+
+                    return Some((arguments, index + 1));
+                }
+            }
+            ',' if depth == 1 => {
+                arguments.push(input[start..index].trim().to_string());
+                start = index + 1;
+            }
+            _ => (),
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call_sites_in_c() {
+        let call_sites = call_sites_in(
+            "void f() {\n  DTRACE_PROBE(app, start);\n  STAP_PROBE2(app, request__done, g(a, b), \"x,y\");\n  MY_DTRACE_PROBE(a, b);\n}",
+            false,
+        );
+
+        assert_eq!(
+            call_sites,
+            vec![
+                CallSite {
+                    line: 2,
+                    call: "DTRACE_PROBE(app, start)".to_string(),
+                    provider: "app".to_string(),
+                    probe: "start".to_string(),
+                    arguments: vec![],
+                },
+                CallSite {
+                    line: 3,
+                    call: "STAP_PROBE2(app, request__done, g(a, b), \"x,y\")".to_string(),
+                    provider: "app".to_string(),
+                    probe: "request__done".to_string(),
+                    arguments: vec!["g(a, b)".to_string(), "\"x,y\"".to_string()],
+                },
+            ]
+        );
+
+        assert_eq!(
+            replacement(&NamingConfig::default(), &call_sites[1], false),
+            "app_probe_request_done(g(a, b), \"x,y\");"
+        );
+    }
+
+    #[test]
+    fn test_call_sites_in_rust() {
+        let call_sites = call_sites_in("probe!(app, begin, x.len());", true);
+
+        assert_eq!(call_sites.len(), 1);
+        assert_eq!(
+            replacement(&NamingConfig::default(), &call_sites[0], true),
+            "tracing::r#app::r#begin(x.len() as _);"
+        );
+    }
+
+    #[test]
+    fn test_replacement_naming() {
+        let naming = NamingConfig {
+            c_prefix: "my_".to_string(),
+            namespace: "mycrate".to_string(),
+            provider_renames: vec![("my-svc".to_string(), "svc".to_string())],
+            probe_renames: vec![(
+                "my-svc".to_string(),
+                "gc__begin".to_string(),
+                "gc_start".to_string(),
+            )],
+            ..Default::default()
+        };
+        let call_sites = call_sites_in(
+            "probe!(my-svc, gc__begin, n);\nprobe!(my-svc, gc__end, n);",
+            true,
+        );
+
+        assert_eq!(
+            replacement(&naming, &call_sites[0], true),
+            "tracing::r#svc::r#gc_start(n as _);"
+        );
+        assert_eq!(
+            replacement(&naming, &call_sites[1], true),
+            "tracing::r#svc::r#gc_end(n as _);"
+        );
+        assert_eq!(
+            replacement(&naming, &call_sites[0], false),
+            "my_mycrate_my_svc_probe_gc_begin(n);"
+        );
+    }
+
+    #[test]
+    fn test_d_source() {
+        let call_sites = call_sites_in(
+            "DTRACE_PROBE1(b, x, 1); DTRACE_PROBE(a, y); DTRACE_PROBE2(b, x, 1, 2);",
+            false,
+        );
+
+        assert_eq!(
+            d_source(call_sites.iter()),
+            "/* Generated by `cargo sonde migrate`. The argument types are
+   guesses, refine them. */
+
+provider a {
+    probe y();
+};
+
+provider b {
+    probe x(uintptr_t, uintptr_t);
+};
+"
+        );
+    }
+}
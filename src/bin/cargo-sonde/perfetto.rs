@@ -0,0 +1,352 @@
+//! `cargo sonde perfetto`: convert a raw trace of the probes, with a
+//! line per fired probe as `<timestamp> <pid> <tid> <probe>
+//! [<argument>…]`, to the Chrome `trace_event` JSON format, opened by
+//! Perfetto (<https://ui.perfetto.dev>) and `chrome://tracing`.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Read},
+    path::PathBuf,
+};
+
+/// The suffixes of the probes delimiting a duration, like the spans
+/// generated by `Builder`.
+const SPAN_SUFFIXES: &[(&str, &str)] = &[("__start", "__end"), ("__entry", "__return")];
+
+/// A declared probe.
+#[derive(Debug)]
+struct Declared {
+    provider: String,
+    probe: String,
+    arguments: Vec<String>,
+}
+
+/// How a fired probe is rendered in the timeline.
+#[derive(Debug, PartialEq)]
+enum Phase<'d> {
+    /// The beginning of a duration named after the base of the probe,
+    /// e.g. `query` for `query__start`.
+    Begin(&'d str),
+
+    /// The end of a duration.
+    End(&'d str),
+
+    /// An instant event.
+    Instant,
+}
+
+impl Declared {
+    fn matches(&self, provider: &str, probe: &str) -> bool {
+        // DTrace suffixes the providers with the PID, and exposes `__`
+        // as `-` in the probe names.
+        let provider = provider.trim_end_matches(|c: char| c.is_ascii_digit());
+
+        provider.eq_ignore_ascii_case(&self.provider) && probe.replace('-', "__") == self.probe
+    }
+
+    /// The phase of the probe: a duration needs both probes of the
+    /// pair to be declared.
+    fn phase(&self, declared: &[Declared]) -> Phase<'_> {
+        let is_declared = |probe: String| {
+            declared
+                .iter()
+                .any(|other| other.provider == self.provider && other.probe == probe)
+        };
+
+        for (start, end) in SPAN_SUFFIXES {
+            if let Some(base) = self.probe.strip_suffix(start) {
+                if is_declared(format!("{}{}", base, end)) {
+                    return Phase::Begin(base);
+                }
+            }
+
+            if let Some(base) = self.probe.strip_suffix(end) {
+                if is_declared(format!("{}{}", base, start)) {
+                    return Phase::End(base);
+                }
+            }
+        }
+
+        Phase::Instant
+    }
+}
+
+pub fn run(arguments: &[String]) -> io::Result<bool> {
+    let mut output = None;
+    let mut trace = None;
+    let mut files = Vec::new();
+    let mut arguments = arguments.iter();
+
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--output" | "-o" => output = arguments.next().map(PathBuf::from),
+            _ if trace.is_none() => trace = Some(argument.clone()),
+            _ => files.push(argument),
+        }
+    }
+
+    let trace = match trace {
+        Some(trace) => trace,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "`perfetto` expects a trace file, or `-` for the standard input",
+            ))
+        }
+    };
+
+    let mut declared = Vec::new();
+
+    for d_file in super::d_files(&files)? {
+        let source = fs::read_to_string(&d_file)?;
+        let script = sonde::parser::parse(&source).map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                error.render(&d_file.display().to_string()),
+            )
+        })?;
+
+        for provider in &script.providers {
+            for probe in &provider.probes {
+                declared.push(Declared {
+                    provider: provider.name.clone(),
+                    probe: probe.name.clone(),
+                    arguments: probe.names(),
+                });
+            }
+        }
+    }
+
+    let mut source = String::new();
+
+    if trace == "-" {
+        io::stdin().read_to_string(&mut source)?;
+    } else {
+        fs::read_to_string(&trace).map(|trace| source = trace)?;
+    }
+
+    let (json, events, skipped) = trace_events(&declared, &source);
+
+    match &output {
+        Some(output) => fs::write(output, &json)?,
+        None => print!("{}", json),
+    }
+
+    eprintln!(
+        "Converted {} events; {} lines skipped, without a timestamp, a PID and a TID, or for an undeclared probe.",
+        events, skipped
+    );
+
+    Ok(true)
+}
+
+/// Convert a raw trace to the `trace_event` JSON format. Return the
+/// JSON, the number of events, and the number of skipped lines.
+fn trace_events(declared: &[Declared], trace: &str) -> (String, usize, usize) {
+    let mut events = Vec::new();
+    let mut skipped = 0;
+
+    for line in trace.lines().filter(|line| !line.trim().is_empty()) {
+        match trace_event(declared, line) {
+            Some(event) => events.push(event),
+            None => skipped += 1,
+        }
+    }
+
+    let json = format!(
+        "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":[\n{}\n]}}\n",
+        events.join(",\n")
+    );
+
+    (json, events.len(), skipped)
+}
+
+/// Convert a line of the trace to an event, see the module
+/// documentation for its format.
+fn trace_event(declared: &[Declared], line: &str) -> Option<String> {
+    let words = splitn_whitespace(line, 5);
+
+    if words.len() < 4 {
+        return None;
+    }
+
+    let timestamp = words[0].parse::<u64>().ok()?;
+    let pid = words[1].parse::<u64>().ok()?;
+    let tid = words[2].parse::<u64>().ok()?;
+    let spec = words[3];
+
+    let parts = spec.split(':').collect::<Vec<_>>();
+    let (provider, probe) = match parts.as_slice() {
+        ["usdt", _, provider, probe] => (*provider, *probe),
+        [provider, .., probe] => (*provider, *probe),
+        _ => return None,
+    };
+    let all_declared = declared;
+    let declared = declared
+        .iter()
+        .find(|declared| declared.matches(provider, probe))?;
+
+    // The last argument takes the rest of the line, e.g. a string with
+    // spaces.
+    let values = words
+        .get(4)
+        .map(|values| splitn_whitespace(values, declared.arguments.len()))
+        .unwrap_or_default();
+
+    let mut event = String::new();
+    let (name, phase) = match declared.phase(all_declared) {
+        Phase::Begin(base) => (format!("{}:{}", declared.provider, base), "B"),
+        Phase::End(base) => (format!("{}:{}", declared.provider, base), "E"),
+        Phase::Instant => (format!("{}:{}", declared.provider, declared.probe), "i"),
+    };
+
+    write!(
+        event,
+        "{{\"name\":{},\"cat\":{},\"ph\":\"{}\",\"ts\":{}.{:03},\"pid\":{},\"tid\":{}",
+        json_string(&name),
+        json_string(&declared.provider),
+        phase,
+        timestamp / 1000,
+        timestamp % 1000,
+        pid,
+        tid
+    )
+    .unwrap();
+
+    if phase == "i" {
+        event.push_str(",\"s\":\"t\"");
+    }
+
+    let args = declared
+        .arguments
+        .iter()
+        .zip(&values)
+        .map(|(name, value)| {
+            // The parsed value is written, e.g. `5` for `+5` and `7` for
+            // `007`, which aren't valid JSON numbers.
+            let value = match value.parse::<i64>() {
+                Ok(value) => value.to_string(),
+                Err(_) => json_string(value),
+            };
+
+            format!("{}:{}", json_string(name), value)
+        })
+        .collect::<Vec<_>>();
+
+    if !args.is_empty() {
+        write!(event, ",\"args\":{{{}}}", args.join(",")).unwrap();
+    }
+
+    event.push('}');
+
+    Some(event)
+}
+
+/// Split `string` on whitespace into at most `n` parts, the last one
+/// taking the rest of the string.
+fn splitn_whitespace(string: &str, n: usize) -> Vec<&str> {
+    let mut parts = Vec::with_capacity(n);
+    let mut rest = string.trim();
+
+    while parts.len() + 1 < n {
+        match rest.split_once(char::is_whitespace) {
+            Some((part, tail)) => {
+                parts.push(part);
+                rest = tail.trim_start();
+            }
+            None => break,
+        }
+    }
+
+    if !rest.is_empty() {
+        parts.push(rest);
+    }
+
+    parts
+}
+
+/// Render a JSON string.
+fn json_string(string: &str) -> String {
+    let mut json = String::with_capacity(string.len() + 2);
+    json.push('"');
+
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn declared() -> Vec<Declared> {
+        let script = sonde::parser::parse(
+            "provider Hello {
+                 probe query__start(int, char *sql);
+                 probe query__end();
+                 probe you(char *who);
+                 probe tick__start();
+             };",
+        )
+        .unwrap();
+        let provider = &script.providers[0];
+
+        provider
+            .probes
+            .iter()
+            .map(|probe| Declared {
+                provider: provider.name.clone(),
+                probe: probe.name.clone(),
+                arguments: probe.names(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_trace_events() {
+        let (json, events, skipped) = trace_events(
+            &declared(),
+            "1500 42 43 hello1234:app:main:query-start 7 SELECT \"a\" FROM b\n\
+             2000 42 43 usdt:/bin/app:Hello:you Gordon Freeman\n\
+             hello:you Gordon\n\
+             2600 42 43 Hello:query-end\n\
+             2700 42 43 Hello:unknown\n\
+             2800 42 44 Hello:tick-start\n\
+             2900 42 43 Hello:query-start +5 x\n\
+             3000 42 43 Hello:query-start 007 x\n",
+        );
+
+        assert_eq!(events, 6);
+        assert_eq!(skipped, 2);
+        assert_eq!(
+            json,
+            r#"{"displayTimeUnit":"ns","traceEvents":[
+{"name":"Hello:query","cat":"Hello","ph":"B","ts":1.500,"pid":42,"tid":43,"args":{"arg0":7,"sql":"SELECT \"a\" FROM b"}},
+{"name":"Hello:you","cat":"Hello","ph":"i","ts":2.000,"pid":42,"tid":43,"s":"t","args":{"who":"Gordon Freeman"}},
+{"name":"Hello:query","cat":"Hello","ph":"E","ts":2.600,"pid":42,"tid":43},
+{"name":"Hello:tick__start","cat":"Hello","ph":"i","ts":2.800,"pid":42,"tid":44,"s":"t"},
+{"name":"Hello:query","cat":"Hello","ph":"B","ts":2.900,"pid":42,"tid":43,"args":{"arg0":5,"sql":"x"}},
+{"name":"Hello:query","cat":"Hello","ph":"B","ts":3.000,"pid":42,"tid":43,"args":{"arg0":7,"sql":"x"}}
+]}
+"#
+        );
+    }
+
+    #[test]
+    fn test_splitn_whitespace() {
+        assert_eq!(splitn_whitespace("a  b c d", 3), vec!["a", "b", "c d"]);
+        assert_eq!(splitn_whitespace("a", 3), vec!["a"]);
+        assert_eq!(splitn_whitespace(" a b ", 1), vec!["a b"]);
+    }
+}
@@ -1,19 +1,254 @@
-use crate::d::{self, ast::Names};
+mod backends;
+mod cache;
+mod codegen;
+mod names;
+mod parse;
+mod scripts;
+mod shim;
+mod unused;
+
+pub use self::backends::Backend;
+use self::{
+    backends::{copy_header, dtrace::dtrace_failed, ShimContext, PROBES_OBJECT_FILE_NAME},
+    cache::{
+        cc_env_names, write_if_changed, FINGERPRINT_FILE_NAME, OBJECTS_FILE_NAME,
+        PREGENERATED_C_FILE_NAME, PREGENERATED_FINGERPRINT_FILE_NAME, PREGENERATED_RUST_FILE_NAME,
+    },
+    parse::debug_assertions,
+    scripts::{
+        benches, binary_path, bpftrace_script, dtrace_script, gdb_script, lldb_script, tapset,
+    },
+    shim::{ffi_header, GENERATED_DTRACE_HEADER_NAME},
+    unused::{rust_sources, unused_probes},
+};
+use crate::{
+    d::{
+        self,
+        ast::{DtraceOption, Names, Provider, Stability},
+    },
+    header::GeneratedHeader,
+    naming::NamingConfig,
+};
 use std::{
     env,
-    fs::{read_to_string, File},
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{self, read_to_string},
     io::prelude::*,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     process::Command,
 };
 
-const SONDE_RUST_API_FILE_ENV_NAME: &str = "SONDE_RUST_API_FILE";
+/// The environment variable skipping `dtrace` and `cc` by default, see
+/// `Builder::skip_if_env`.
+const SKIP_ENV_NAME: &str = "SONDE_SKIP";
+
+/// The environment variable enabling the verbose mode, see
+/// `Builder::verbose`.
+const DEBUG_ENV_NAME: &str = "SONDE_DEBUG";
+
+/// The file of the verbose mode, in `OUT_DIR`, see `Builder::verbose`.
+const DEBUG_LOG_FILE_NAME: &str = "sonde-debug.log";
+
+type CcHook = Box<dyn Fn(&mut cc::Build)>;
+
+/// What happens when a probe uses a construct that isn't supported,
+/// e.g. an unsupported argument type, or too many arguments.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Strictness {
+    /// Emit a Cargo warning for each problem, and skip the probe.
+    Warn,
+
+    /// Fail the build with the list of all the problems.
+    #[default]
+    Error,
+}
+
+impl fmt::Display for Strictness {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Warn => "warn",
+            Self::Error => "error",
+        })
+    }
+}
+
+/// How the C shim is linked into the crate, see [`Builder::link_mode`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LinkMode {
+    /// Link the static library of the shim: the linker only keeps the
+    /// probes called from the Rust code.
+    #[default]
+    Default,
+
+    /// Link the whole static library of the shim, with the
+    /// `+whole-archive` modifier, so that all the probes survive in a
+    /// `cdylib` or a `staticlib`.
+    WholeArchive,
+
+    /// Give the objects of the shim directly to the linker, with
+    /// `cargo:rustc-link-arg`. They are only linked into the binaries,
+    /// `cdylib`s, tests etc. of the crate itself, not of its
+    /// dependents.
+    Object,
+}
+
+impl fmt::Display for LinkMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Default => "default",
+            Self::WholeArchive => "whole-archive",
+            Self::Object => "object",
+        })
+    }
+}
+
+/// The sources generated by [`Builder::generate`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generated {
+    /// The Rust API, i.e. the file published in `SONDE_RUST_API_FILE`
+    /// by [`Builder::compile`].
+    pub rust: String,
+
+    /// The C shim. It includes the header generated by `dtrace -h` as
+    /// `probes.h`.
+    pub c: String,
+
+    /// The C header declaring the functions of the C shim, see
+    /// [`Builder::ffi_header`].
+    pub header: String,
+
+    /// The warnings, e.g. about the probes skipped with
+    /// [`Strictness::Warn`].
+    pub warnings: Vec<String>,
+}
+
+/// The providers read and parsed by a [`Builder`].
+pub(crate) struct Parsed {
+    /// The concatenated content of all the inputs, given to `dtrace`.
+    contents: String,
+
+    header: GeneratedHeader,
+    providers: Vec<Provider>,
+
+    /// The paths of the modules containing each provider, see
+    /// [`Builder::module_tree`].
+    modules: Vec<Vec<String>>,
+
+    /// The files and directories whose changes must rerun the build
+    /// script.
+    watched: Vec<PathBuf>,
+
+    /// The compiler options declared with `#pragma D option` in the
+    /// `.d` files, given to `dtrace` on the command line.
+    options: Vec<DtraceOption>,
+
+    warnings: Vec<String>,
+}
+
+/// A provider defined by [`Builder::provider`].
+pub struct ProviderBuilder<'b> {
+    provider: &'b mut Provider,
+}
+
+impl ProviderBuilder<'_> {
+    /// Add a probe, with the D types of its arguments, optionally
+    /// followed by their names, e.g. `char *` or `char *method`. It's
+    /// checked like a declaration of a `.d` file.
+    pub fn probe<N>(&mut self, name: N, arguments: &[&str]) -> Result<&mut Self, d::parser::Error>
+    where
+        N: AsRef<str>,
+    {
+        let declaration = format!(
+            "provider {} {{ probe {}({}); }};",
+            self.provider.name,
+            name.as_ref(),
+            arguments
+                .iter()
+                .map(|argument| argument.trim())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let mut script = d::parser::parse(&declaration)?;
+
+        match script.providers.as_mut_slice() {
+            [provider] if provider.probes.len() == 1 => {
+                self.provider.probes.append(&mut provider.probes);
+
+                Ok(self)
+            }
+            _ => Err(d::parser::Error::new(
+                &declaration,
+                0,
+                "expected a single probe".to_string(),
+            )),
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct Builder {
     d_files: Vec<PathBuf>,
+    d_globs: Vec<String>,
+    d_sources: Vec<(String, String)>,
+    defined_providers: Vec<Provider>,
+    test_d_files: Vec<PathBuf>,
     keep_h_file: bool,
     keep_c_file: bool,
+    h_out: Option<PathBuf>,
+    c_out: Option<PathBuf>,
+    dtrace_arch: Option<String>,
+    dtrace_path: Option<PathBuf>,
+    dtrace_args: Vec<OsString>,
+    trampolines: bool,
+    cc_hooks: Vec<CcHook>,
+    split_output: bool,
+    module_tree: bool,
+    ffi_header: Option<PathBuf>,
+    naming: NamingConfig,
+    given_naming: NamingConfig,
+    provider_renames: Vec<(String, String)>,
+    probe_renames: Vec<(String, String, String)>,
+    symbol_prefix: Option<String>,
+    namespace: Option<String>,
+    benches: Option<PathBuf>,
+    scripts_out: Option<PathBuf>,
+    tapset_out: Option<PathBuf>,
+    debugger_scripts_out: Option<PathBuf>,
+    opentelemetry_feature: Option<String>,
+    report_unused_probes: bool,
+    strictness: Strictness,
+    is_enabled_functions: bool,
+    args_structs: bool,
+    probe_metadata: bool,
+    os_str_functions: bool,
+    bytes_functions: bool,
+    lossy_os_str: bool,
+    max_string_len: Option<usize>,
+    debug_checks: bool,
+    allow_unknown_types: bool,
+    allow_empty: bool,
+    pack_arguments: bool,
+    preprocess: bool,
+    invocation_counters: bool,
+    defines: Vec<(String, String)>,
+    provider_versions: Vec<(String, String)>,
+    lib_name: Option<String>,
+    link_mode: LinkMode,
+    skip_env: Option<String>,
+    export_c_abi: bool,
+    format_output: bool,
+    include_dirs: Vec<PathBuf>,
+    wasmer_imports: bool,
+    backend: Option<Backend>,
+    verbose: bool,
+    dispatch: bool,
+    integer_casts: bool,
+    sampling: bool,
+    use_sonde_api: bool,
+    pregenerated: Option<PathBuf>,
+    header_file: Option<PathBuf>,
+    stability_policy: Option<(Stability, Strictness)>,
 }
 
 impl Builder {
@@ -44,6 +279,133 @@ impl Builder {
         self
     }
 
+    /// Add a D source directly, e.g. generated by the build script.
+    /// `name` replaces the file name in the error messages and in the
+    /// header of the generated files.
+    pub fn source<N, S>(&mut self, name: N, source: S) -> &mut Self
+    where
+        N: Into<String>,
+        S: Into<String>,
+    {
+        self.d_sources.push((name.into(), source.into()));
+
+        self
+    }
+
+    /// Define a provider without any `.d` file, and return it to add
+    /// probes. Calling it again with the same name returns the same
+    /// provider:
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), sonde::parser::Error> {
+    /// sonde::Builder::new()
+    ///     .provider("myapp")
+    ///     .probe("request_start", &["char *", "uint64_t"])?
+    ///     .probe("request_end", &["uint64_t id"])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn provider<N>(&mut self, name: N) -> ProviderBuilder<'_>
+    where
+        N: Into<String>,
+    {
+        let name = name.into();
+
+        let nth = match self
+            .defined_providers
+            .iter()
+            .position(|provider| provider.name == name)
+        {
+            Some(nth) => nth,
+            None => {
+                self.defined_providers.push(Provider {
+                    name,
+                    ..Default::default()
+                });
+
+                self.defined_providers.len() - 1
+            }
+        };
+
+        ProviderBuilder {
+            provider: &mut self.defined_providers[nth],
+        }
+    }
+
+    /// Add all the `.d` files matching a glob pattern, e.g.
+    /// `src/probes/**/*.d`. The pattern is expanded by `compile`, and
+    /// the build script is rerun when a file is added to the matched
+    /// directories.
+    pub fn files_glob<P>(&mut self, pattern: P) -> &mut Self
+    where
+        P: Into<String>,
+    {
+        self.d_globs.push(pattern.into());
+
+        self
+    }
+
+    /// Add a `.d` file whose probes are only compiled when debug
+    /// assertions are enabled, i.e. for the `dev` and `test` profiles
+    /// by default. It's useful for test instrumentation, which must
+    /// not be shipped in release binaries.
+    pub fn test_file<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.test_d_files.push(path.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// Add a directory where the files included by the `.d` files,
+    /// with `#include "common.d"`, are searched, after the directory
+    /// of the including file.
+    pub fn include_dir<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.include_dirs.push(path.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// Run the C preprocessor over the `.d` files before parsing them,
+    /// with `cc` for `sonde`, and `dtrace -C` for `dtrace`.
+    pub fn preprocess(&mut self, preprocess: bool) -> &mut Self {
+        self.preprocess = preprocess;
+
+        self
+    }
+
+    /// Define a macro for the C preprocessor, see
+    /// [`Builder::preprocess`].
+    pub fn define<N, V>(&mut self, name: N, value: V) -> &mut Self
+    where
+        N: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.defines
+            .push((name.as_ref().to_string(), value.as_ref().to_string()));
+
+        self
+    }
+
+    /// Set the version of a provider, overriding the `@version`
+    /// annotation of the `.d` file. The version is exposed as a
+    /// `VERSION` constant in the provider's module, and can suffix
+    /// the C functions, see [`NamingConfig::versioned_symbols`].
+    pub fn provider_version<P, V>(&mut self, provider: P, version: V) -> &mut Self
+    where
+        P: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.provider_versions
+            .push((provider.as_ref().to_string(), version.as_ref().to_string()));
+
+        self
+    }
+
     pub fn keep_h_file(&mut self, keep: bool) -> &mut Self {
         self.keep_h_file = keep;
 
@@ -56,224 +418,1593 @@ impl Builder {
         self
     }
 
-    pub fn compile(&self) {
-        let out_dir = env::var("OUT_DIR")
-            .map_err(|_| "The Cargo `OUT_DIR` variable is missing")
-            .unwrap();
-        let mut contents = String::new();
-        let mut providers = Vec::with_capacity(self.d_files.len());
+    /// Copy the `.h` file generated by `dtrace` to `path`, e.g. to
+    /// use the probe macros from C code of the same project.
+    pub fn h_out<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.h_out = Some(path.as_ref().to_path_buf());
 
-        // Tell Cargo to rerun the build script if one of the `.d` files has changed.
-        {
-            for d_file in &self.d_files {
-                println!(
-                    "cargo:rerun-if-changed={file}",
-                    file = d_file.as_path().display()
-                );
-            }
-        }
+        self
+    }
 
-        // Collect all contents of the `.d` files, and parse the declared providers.
-        {
-            for d_file in &self.d_files {
-                let content = read_to_string(d_file).unwrap();
-                contents.push_str(&content);
+    /// Copy the generated FFI `.c` file to `path`. With
+    /// [`Builder::h_out`], it includes the copied `.h` file.
+    pub fn c_out<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.c_out = Some(path.as_ref().to_path_buf());
 
-                let script = d::parser::parse(&content).unwrap();
+        self
+    }
 
-                for provider in script.providers {
-                    providers.push(provider);
-                }
-            }
-        }
+    /// Use this `dtrace` binary instead of the one found in `PATH`.
+    pub fn dtrace_path<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.dtrace_path = Some(path.as_ref().to_path_buf());
 
-        // Let's get a unique `.h` file from the `.d` files.
-        let h_file = tempfile::Builder::new()
-            .prefix("sonde-")
-            .suffix(".h")
-            .tempfile_in(&out_dir)
-            .unwrap();
+        self
+    }
 
-        let h_file_name = h_file.path();
+    /// Pass an extra argument to `dtrace`, e.g. `-xnolibs`.
+    pub fn dtrace_arg<A>(&mut self, arg: A) -> &mut Self
+    where
+        A: AsRef<OsStr>,
+    {
+        self.dtrace_args.push(arg.as_ref().to_os_string());
 
-        {
-            let mut d_file = tempfile::Builder::new()
-                .prefix("sonde-")
-                .suffix(".d")
-                .tempfile_in(&out_dir)
-                .unwrap();
-            d_file.write_all(contents.as_bytes()).unwrap();
+        self
+    }
 
-            Command::new("dtrace")
-                .arg("-arch")
-                .arg(match env::var("CARGO_CFG_TARGET_ARCH").unwrap().as_str() {
-                    "aarch64" => "arm64",
-                    arch => arch,
-                })
-                .arg("-o")
-                .arg(h_file_name.as_os_str())
-                .arg("-h")
-                .arg("-s")
-                .arg(&d_file.path().as_os_str())
-                .status()
-                .unwrap();
-        }
+    /// Generate an `extern "C"` trampoline for each probe, e.g.
+    /// `hello::you_trampoline`, that can be given to C code as a
+    /// callback firing the probe.
+    pub fn trampolines(&mut self, generate: bool) -> &mut Self {
+        self.trampolines = generate;
 
-        // Generate the FFI `.c` file. The probes are defined behind C
-        // macros; they can't be call from Rust, so we need to wrap
-        // them inside C functions.
-        let mut ffi_file = tempfile::Builder::new()
-            .prefix("sonde-ffi")
-            .suffix(".c")
-            .tempfile_in(&out_dir)
-            .unwrap();
+        self
+    }
 
-        {
-            let ffi = format!(
-                r#"#include {header_file:?}
-
-{wrappers}"#,
-                header_file = h_file_name,
-                wrappers = providers
-                    .iter()
-                    .map(|provider| {
-                        provider
-                            .probes
-                            .iter()
-                            .map(|probe| {
-                                format!(
-                                    r#"
-void {prefix}_probe_{suffix}({arguments}) {{
-    {macro_prefix}_{macro_suffix}({argument_names});
-}}
-"#,
-                                    prefix = provider.name_for_c(),
-                                    suffix = probe.name_for_c(),
-                                    macro_prefix = provider.name_for_c_macro(),
-                                    macro_suffix = probe.name_for_c_macro(),
-                                    arguments = probe.arguments_for_c(),
-                                    argument_names = probe
-                                        .arguments
-                                        .iter()
-                                        .enumerate()
-                                        .map(|(nth, _)| { format!("arg{nth}", nth = nth) })
-                                        .collect::<Vec<String>>()
-                                        .join(", ")
-                                )
-                            })
-                            .collect::<Vec<String>>()
-                            .join("")
-                    })
-                    .collect::<Vec<String>>()
-                    .join("\n")
-            );
+    /// Generate a function per probe telling whether a tracer is
+    /// attached to it, e.g. `hello::you_is_enabled`, and the
+    /// `on_enabled_change` and `poll_enabled_changes` functions per
+    /// provider, to react when a tracer attaches or detaches.
+    pub fn is_enabled_functions(&mut self, generate: bool) -> &mut Self {
+        self.is_enabled_functions = generate;
 
-            ffi_file.write_all(ffi.as_bytes()).unwrap();
-        }
+        self
+    }
 
-        // Let's compile the FFI `.c` file to a `.a` file.
-        {
-            cc::Build::new().file(&ffi_file).compile("sonde-ffi");
-        }
+    /// Generate a struct holding the arguments of each probe, e.g.
+    /// `hello::YouArgs`, and a variant of the probe taking it, e.g.
+    /// `hello::you_with`. The struct is always generated for the probes
+    /// packing their arguments, see [`Builder::pack_arguments`].
+    pub fn args_structs(&mut self, generate: bool) -> &mut Self {
+        self.args_structs = generate;
 
-        // Finally, let's generate the nice API for Rust.
-        let mut rs_path = PathBuf::new();
-        rs_path.push(&out_dir);
-        rs_path.push("sonde.rs");
-        let mut rs_file = File::create(&rs_path).unwrap();
+        self
+    }
 
-        {
-            let rs = format!(
-                r#"/// Bindings from Rust to the C FFI small library that calls the
-/// probes.
-
-#[allow(unused)]
-use std::os::raw::*;
-
-extern "C" {{
-{externs}
-}}
-
-{wrappers}
-"#,
-                externs = providers
-                    .iter()
-                    .map(|provider| {
-                        provider
-                            .probes
-                            .iter()
-                            .map(|probe| {
-                                format!(
-                                    r#"    #[doc(hidden)]
-    fn {ffi_prefix}_probe_{ffi_suffix}({arguments});"#,
-                                    ffi_prefix = provider.name_for_c(),
-                                    ffi_suffix = probe.name_for_c(),
-                                    arguments = probe.arguments_for_c_from_rust(),
-                                )
-                            })
-                            .collect::<Vec<String>>()
-                            .join("\n\n")
-                    })
-                    .collect::<Vec<String>>()
-                    .join("\n\n"),
-                wrappers = providers
-                    .iter()
-                    .map(|provider| {
-                        format!(
-                            r#"/// Probes for the `{provider_name}` provider.
-pub mod r#{provider_name} {{
-    #[allow(unused)]
-    use std::os::raw::*;
-
-{probes}
-}}"#,
-                            provider_name = provider.name_for_rust(),
-                            probes = provider
-                                .probes
-                                .iter()
-                                .map(|probe| {
-                                    format!(
-                                        r#"    /// Call the `{probe_name}` probe of the `{provider_name}` provider.
-    pub fn r#{probe_name}({arguments}) {{
-        unsafe {{ super::{ffi_prefix}_probe_{ffi_suffix}({argument_names}) }};
-    }}"#,
-                                        provider_name = provider.name_for_rust(),
-                                        probe_name = probe.name_for_rust(),
-                                        ffi_prefix = provider.name_for_c(),
-                                        ffi_suffix = probe.name_for_c(),
-                                        arguments = probe.arguments_for_c_from_rust(),
-                                        argument_names = probe
-                                            .arguments
-                                            .iter()
-                                            .enumerate()
-                                            .map(|(nth, _)| { format!("arg{nth}", nth = nth) })
-                                            .collect::<Vec<String>>()
-                                            .join(", ")
-                                    )
-                                })
-                                .collect::<Vec<String>>()
-                                .join("\n\n")
-                        )
-                    })
-                    .collect::<Vec<String>>()
-                    .join("\n\n")
-            );
+    /// Generate a module per probe holding its metadata as constants,
+    /// e.g. `hello::you::NAME`, `hello::you::PROVIDER` and
+    /// `hello::you::ARG_TYPES`, as used by `sonde_api::observe`.
+    pub fn probe_metadata(&mut self, generate: bool) -> &mut Self {
+        self.probe_metadata = generate;
 
-            println!(
-                "cargo:rustc-env={name}={value}",
-                name = SONDE_RUST_API_FILE_ENV_NAME,
-                value = rs_path.as_path().display(),
-            );
+        self
+    }
 
-            rs_file.write_all(rs.as_bytes()).unwrap();
-        }
+    /// Generate a variant of each probe with `char *` arguments taking
+    /// them as `impl AsRef<OsStr>`, e.g. `hello::you_os`. The strings
+    /// paired with their length aren't converted.
+    pub fn os_str_functions(&mut self, generate: bool) -> &mut Self {
+        self.os_str_functions = generate;
 
-        if self.keep_h_file {
-            h_file.keep().unwrap();
+        self
+    }
+
+    /// Generate a variant of each probe with a pointer to bytes followed
+    /// by its length, taking the pair as a single `impl AsRef<[u8]>`,
+    /// e.g. `net::send_bytes` for `probe send(int fd, char *data,
+    /// size_t len)`.
+    pub fn bytes_functions(&mut self, generate: bool) -> &mut Self {
+        self.bytes_functions = generate;
+
+        self
+    }
+
+    /// Generate a `_cast` variant of each probe with integer arguments,
+    /// e.g. `hello::you_cast`, accepting any Rust integer for them, e.g.
+    /// a `usize` for an `int`. The values are converted with `as`, i.e.
+    /// they wrap or are truncated when they don't fit.
+    pub fn integer_casts(&mut self, generate: bool) -> &mut Self {
+        self.integer_casts = generate;
+
+        self
+    }
+
+    /// Generate a `_sampled` and a `_rate_limited` variant of each probe,
+    /// e.g. `hello::you_sampled(every, …)` and
+    /// `hello::you_rate_limited(per_second, …)`, for the hot paths.
+    pub fn sampling(&mut self, generate: bool) -> &mut Self {
+        self.sampling = generate;
+
+        self
+    }
+
+    /// Make the generated Rust API use the runtime helpers of the
+    /// `sonde-api` crate, which must then be a dependency, instead of
+    /// generating them in each provider module.
+    pub fn use_sonde_api(&mut self, use_sonde_api: bool) -> &mut Self {
+        self.use_sonde_api = use_sonde_api;
+
+        self
+    }
+
+    /// Customize the `cc::Build` used to compile the FFI shim, e.g. to
+    /// add include paths, flags, or to select a specific compiler.
+    pub fn configure_cc<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&mut cc::Build) + 'static,
+    {
+        self.cc_hooks.push(Box::new(hook));
+
+        self
+    }
+
+    /// Write one Rust file per provider, published in
+    /// `SONDE_RUST_API_FILE_<PROVIDER>`, in addition to the main file
+    /// including all of them.
+    pub fn split_output(&mut self, split: bool) -> &mut Self {
+        self.split_output = split;
+
+        self
+    }
+
+    /// Pretty-print the generated Rust files with `prettyplease`, so
+    /// that they are readable when debugging, and diff-friendly when
+    /// vendored.
+    pub fn format_output(&mut self, format: bool) -> &mut Self {
+        self.format_output = format;
+
+        self
+    }
+
+    /// Write a [Criterion](https://docs.rs/criterion) benchmark file
+    /// measuring the overhead of each probe. It must exist before the
+    /// first build, and be declared as a bench with `harness = false`.
+    pub fn emit_benches<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.benches = Some(path.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// Write ready-to-run tracing scripts in the given directory: a
+    /// bpftrace script (`hello.bt`) and a DTrace script (`hello.d`)
+    /// per provider, printing the arguments of all its probes.
+    pub fn scripts_out<P>(&mut self, directory: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.scripts_out = Some(directory.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// Write a SystemTap tapset, declaring a probe alias per probe,
+    /// e.g. `hello.you` for the `you` probe of the `hello` provider,
+    /// with its arguments as named variables, plus the usual `name`
+    /// and `argstr` variables.
+    pub fn tapset_out<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.tapset_out = Some(path.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// Write Python helpers for GDB (`sonde_gdb.py`) and LLDB
+    /// (`sonde_lldb.py`) in `directory`, to print the probes while
+    /// debugging, without a tracer.
+    pub fn debugger_scripts_out<P>(&mut self, directory: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.debugger_scripts_out = Some(directory.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// Mirror the probes into OpenTelemetry events of the active span
+    /// when the given Cargo feature of the crate, enabling an
+    /// `opentelemetry` dependency, is enabled.
+    pub fn opentelemetry_feature<F>(&mut self, feature: F) -> &mut Self
+    where
+        F: Into<String>,
+    {
+        self.opentelemetry_feature = Some(feature.into());
+
+        self
+    }
+
+    /// Emit a Cargo warning for each probe that is never fired by the
+    /// `.rs` files of `src/`. This is a textual search: calls through
+    /// macros, or from other crates, aren't seen.
+    pub fn report_unused_probes(&mut self, report: bool) -> &mut Self {
+        self.report_unused_probes = report;
+
+        self
+    }
+
+    /// Change how the generated C functions and Rust items are named,
+    /// see [`NamingConfig`].
+    /// The renames, the symbol prefix and the namespace given to the
+    /// builder apply on top of it, whichever is called first.
+    pub fn naming(&mut self, naming: NamingConfig) -> &mut Self {
+        self.given_naming = naming;
+        self.update_naming();
+
+        self
+    }
+
+    /// Compute the naming strategy used by the generation: the one given
+    /// to [`Builder::naming`], with the renames, the symbol prefix and
+    /// the namespace given to the builder.
+    fn update_naming(&mut self) {
+        let mut naming = self.given_naming.clone();
+
+        naming
+            .provider_renames
+            .extend(self.provider_renames.iter().cloned());
+        naming
+            .probe_renames
+            .extend(self.probe_renames.iter().cloned());
+
+        if let Some(prefix) = &self.symbol_prefix {
+            naming.c_prefix = prefix.clone();
         }
 
-        if self.keep_c_file {
-            ffi_file.keep().unwrap();
+        if let Some(namespace) = &self.namespace {
+            naming.namespace = namespace.clone();
         }
+
+        self.naming = naming;
+    }
+
+    /// Name the Rust module of `provider` `module`, instead of deriving
+    /// it from the name of the provider, e.g. `rename_provider("my-svc",
+    /// "svc")` for `tracing::svc::…`. The tracers still see `my-svc`.
+    /// It adds to [`NamingConfig::provider_renames`].
+    pub fn rename_provider<P, M>(&mut self, provider: P, module: M) -> &mut Self
+    where
+        P: Into<String>,
+        M: Into<String>,
+    {
+        self.provider_renames.push((provider.into(), module.into()));
+        self.update_naming();
+
+        self
+    }
+
+    /// Name the Rust function firing the probe `probe` of `provider`
+    /// `function`, e.g. `rename_probe("my-svc", "gc__begin",
+    /// "gc_start")`. The tracers still see `gc-begin`.
+    pub fn rename_probe<P, N, F>(&mut self, provider: P, probe: N, function: F) -> &mut Self
+    where
+        P: Into<String>,
+        N: Into<String>,
+        F: Into<String>,
+    {
+        self.probe_renames
+            .push((provider.into(), probe.into(), function.into()));
+        self.update_naming();
+
+        self
+    }
+
+    /// Prefix all the generated C functions, e.g. with `mycrate_`, so
+    /// that two crates declaring the same provider can be linked together.
+    ///
+    /// Panics if the prefix can't start a C identifier.
+    pub fn symbol_prefix<P>(&mut self, prefix: P) -> &mut Self
+    where
+        P: Into<String>,
+    {
+        let prefix = prefix.into();
+
+        assert!(
+            prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && !prefix.starts_with(|c: char| c.is_ascii_digit()),
+            "The symbol prefix `{}` must be made of ASCII letters, digits and `_`, and must not start with a digit",
+            prefix
+        );
+
+        self.symbol_prefix = Some(prefix);
+        self.update_naming();
+
+        self
+    }
+
+    /// Prefix the names of the providers with `namespace` and `_`, e.g.
+    /// `namespace(env!("CARGO_PKG_NAME"))`, so that the providers of
+    /// several crates don't clash. The Rust modules keep their names.
+    ///
+    /// Panics if the namespace can't start a provider name.
+    pub fn namespace<N>(&mut self, namespace: N) -> &mut Self
+    where
+        N: AsRef<str>,
+    {
+        let namespace = namespace.as_ref().replace('-', "_");
+
+        assert!(
+            !namespace.is_empty()
+                && namespace
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && !namespace.starts_with(|c: char| c.is_ascii_digit()),
+            "The namespace `{}` must be made of ASCII letters, digits, `_` and `-`, and must not start with a digit",
+            namespace
+        );
+
+        self.namespace = Some(namespace);
+        self.update_naming();
+
+        self
+    }
+
+    /// Export the probes as `#[no_mangle] extern "C"` functions prefixed
+    /// with `sonde_`, declared in the [`Builder::h_out`] header, so that
+    /// C code fires the same probes.
+    pub fn export_c_abi(&mut self, export: bool) -> &mut Self {
+        self.export_c_abi = export;
+
+        self
+    }
+
+    /// Name the static library of the FFI shim, e.g. `myapp-probes`. It's
+    /// `sonde-ffi-` followed by the crate's name by default.
+    ///
+    /// Panics if the name is empty, or contains a path separator.
+    pub fn lib_name<N>(&mut self, name: N) -> &mut Self
+    where
+        N: Into<String>,
+    {
+        let name = name.into();
+
+        assert!(
+            !name.is_empty() && !name.contains(['/', '\\']),
+            "The library name `{}` must be a non-empty file name",
+            name
+        );
+
+        self.lib_name = Some(name);
+
+        self
+    }
+
+    /// Choose how the C shim is linked, e.g. [`LinkMode::WholeArchive`]
+    /// so that the probes aren't dropped from a `cdylib`, see
+    /// [`LinkMode`].
+    pub fn link_mode(&mut self, mode: LinkMode) -> &mut Self {
+        self.link_mode = mode;
+
+        self
+    }
+
+    /// Select where the probes go, see [`Backend`]. By default, it
+    /// depends on the target, see [`Backend::default_for`].
+    pub fn backend(&mut self, backend: Backend) -> &mut Self {
+        self.backend = Some(backend);
+
+        self
+    }
+
+    /// Explain what the build script does, as Cargo warnings and in
+    /// `OUT_DIR/sonde-debug.log`, and keep the temporary files. Setting
+    /// `SONDE_DEBUG` to anything but `0` enables it too.
+    pub fn verbose(&mut self, verbose: bool) -> &mut Self {
+        self.verbose = verbose;
+
+        self
+    }
+
+    /// Use the files written by [`Builder::pregenerate`] in `directory`
+    /// instead of running `dtrace`, e.g. for hermetic builds. The build
+    /// fails if they are outdated.
+    pub fn use_pregenerated<P>(&mut self, directory: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.pregenerated = Some(directory.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// Use the header at `path` for the C shim, instead of generating it
+    /// with `dtrace -h`, e.g. on a machine without `dtrace`.
+    pub fn with_header<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.header_file = Some(path.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// The backend used for `target`, see [`Builder::backend`].
+    fn backend_for(&self, target: &str) -> Backend {
+        self.backend.unwrap_or_else(|| Backend::default_for(target))
+    }
+
+    /// Skip `dtrace` and `cc` when the environment variable `name`
+    /// (`SONDE_SKIP` by default) is set to anything but `0`, and generate
+    /// probes doing nothing instead.
+    pub fn skip_if_env<N>(&mut self, name: N) -> &mut Self
+    where
+        N: Into<String>,
+    {
+        self.skip_env = Some(name.into());
+
+        self
+    }
+
+    /// Choose what happens when a probe uses an unsupported construct,
+    /// e.g. an unsupported argument type, see [`Strictness`].
+    pub fn strictness(&mut self, strictness: Strictness) -> &mut Self {
+        self.strictness = strictness;
+
+        self
+    }
+
+    /// Convert the `&OsStr` arguments of the `_os` functions, see
+    /// [`Builder::os_str_functions`], to UTF-8, replacing invalid sequences with `U+FFFD`. By default,
+    /// the bytes are given as is to the probes on Unix.
+    pub fn lossy_os_str(&mut self, lossy: bool) -> &mut Self {
+        self.lossy_os_str = lossy;
+
+        self
+    }
+
+    /// Truncate the strings given to the `_os` and `_bytes` functions to
+    /// `length` bytes, without splitting a UTF-8 character, so that they
+    /// fit the limits of the tracers. The `char *` pointers aren't
+    /// truncated.
+    pub fn max_string_len(&mut self, length: usize) -> &mut Self {
+        self.max_string_len = Some(length);
+
+        self
+    }
+
+    /// Check the arguments of the probes with `debug_assert!`, e.g. that
+    /// the string pointers aren't null, or that the strings given to the
+    /// `_os` functions have no NUL bytes.
+    pub fn debug_checks(&mut self, check: bool) -> &mut Self {
+        self.debug_checks = check;
+
+        self
+    }
+
+    /// Pass the arguments of unknown types through, as `*mut c_void` or
+    /// `usize`, with a Cargo warning for each, instead of rejecting their
+    /// probes.
+    pub fn allow_unknown_types(&mut self, allow: bool) -> &mut Self {
+        self.allow_unknown_types = allow;
+
+        self
+    }
+
+    /// Allow the `.d` files and the sources to declare no provider at
+    /// all, generating an empty module. By default, the build fails,
+    /// as it's usually a mistake, e.g. a wrong path or a typo in the
+    /// `provider` keyword.
+    pub fn allow_empty(&mut self, allow: bool) -> &mut Self {
+        self.allow_empty = allow;
+
+        self
+    }
+
+    /// Pack the arguments of the probes declaring more arguments than
+    /// DTrace supports in a `#[repr(C)]` struct, defined in the
+    /// [`Builder::h_out`] header, instead of rejecting them.
+    pub fn pack_arguments(&mut self, pack: bool) -> &mut Self {
+        self.pack_arguments = pack;
+
+        self
+    }
+
+    /// Count how many times each probe is fired, in a `stats()` function
+    /// per provider, e.g. to assert it in tests. The counters only exist
+    /// with debug assertions.
+    pub fn invocation_counters(&mut self, count: bool) -> &mut Self {
+        self.invocation_counters = count;
+
+        self
+    }
+
+    /// Emit a Cargo warning for each provider component declared, with
+    /// `#pragma D attributes`, with a name or data stability below
+    /// `minimum`, e.g. `Stability::Evolving`. The components without
+    /// attributes aren't checked.
+    pub fn warn_on_stability_below(&mut self, minimum: Stability) -> &mut Self {
+        self.stability_policy = Some((minimum, Strictness::Warn));
+
+        self
+    }
+
+    /// Like [`Builder::warn_on_stability_below`], but fail the build.
+    pub fn deny_stability_below(&mut self, minimum: Stability) -> &mut Self {
+        self.stability_policy = Some((minimum, Strictness::Error));
+
+        self
+    }
+
+    /// Parse the `.d` files and generate the sources, without writing
+    /// anything, nor running `dtrace` or `cc`, e.g. for snapshot tests.
+    ///
+    /// Panics on the same errors as [`Builder::compile`].
+    pub fn generate(&self) -> Generated {
+        let target = env::var("TARGET").unwrap_or_default();
+        let host = env::var("HOST").unwrap_or_default();
+
+        let Parsed {
+            header,
+            providers,
+            modules,
+            warnings,
+            ..
+        } = self.parse(&target, &host);
+
+        let backend = self.backend_for(&target);
+
+        Generated {
+            rust: self.rust_source(&header, &providers, &modules, backend),
+            c: self.ffi_source(&header, &providers, Path::new(GENERATED_DTRACE_HEADER_NAME)),
+            header: ffi_header(
+                &header,
+                &self.naming,
+                &self.ffi_providers(&providers),
+                &self.packed_structs(&providers),
+            ),
+            warnings,
+        }
+    }
+
+    /// Generate the header, the C shim and the Rust API in `directory`,
+    /// to be checked in and used with [`Builder::use_pregenerated`], and
+    /// return the warnings. It's what `cargo sonde gen` does.
+    ///
+    /// Panics on the same errors as [`Builder::compile`], or if `dtrace`
+    /// fails.
+    pub fn pregenerate<P>(&self, directory: P) -> Vec<String>
+    where
+        P: AsRef<Path>,
+    {
+        let directory = directory.as_ref();
+        let target = env::var("TARGET").unwrap_or_default();
+        let host = env::var("HOST").unwrap_or_default();
+
+        assert!(
+            !self.split_output,
+            "The Rust API can't be split with pregenerated files"
+        );
+
+        let Parsed {
+            contents,
+            header,
+            providers,
+            modules,
+            options,
+            warnings,
+            ..
+        } = self.parse(&target, &host);
+
+        let backend = self.backend_for(&target);
+
+        fs::create_dir_all(directory).unwrap_or_else(|error| {
+            panic!("Failed to create `{}`: {}", directory.display(), error)
+        });
+
+        if let Some(shim) = backend.implementation().shim() {
+            let h_path = directory.join(GENERATED_DTRACE_HEADER_NAME);
+
+            if let Some(header_file) = &self.header_file {
+                copy_header(header_file, &h_path, &providers);
+            } else {
+                let mut d_file = tempfile::Builder::new()
+                    .prefix("sonde-")
+                    .suffix(".d")
+                    .tempfile()
+                    .unwrap();
+                d_file.write_all(contents.as_bytes()).unwrap();
+
+                let context = ShimContext {
+                    builder: self,
+                    header: &header,
+                    providers: &self.ffi_providers(&providers),
+                    target: &target,
+                    options: &options,
+                    d_file: d_file.path(),
+                    log: &DebugLog::new(false, ""),
+                };
+
+                if let Err(error) = shim.write_header(&context, &h_path) {
+                    dtrace_failed(error, d_file);
+                }
+            }
+
+            write_if_changed(
+                &directory.join(PREGENERATED_C_FILE_NAME),
+                &self.ffi_source(&header, &providers, Path::new(GENERATED_DTRACE_HEADER_NAME)),
+            );
+        }
+
+        write_if_changed(
+            &directory.join(PREGENERATED_RUST_FILE_NAME),
+            &self.rust_source(&header, &providers, &modules, backend),
+        );
+        write_if_changed(
+            &directory.join(PREGENERATED_FINGERPRINT_FILE_NAME),
+            &self.pregenerated_fingerprint(&contents, &providers, &modules, backend),
+        );
+
+        warnings
+    }
+
+    pub fn compile(&self) {
+        let out_dir = env::var("OUT_DIR")
+            .map_err(|_| "The Cargo `OUT_DIR` variable is missing")
+            .unwrap();
+        // Cargo gives the host as `TARGET` to the build scripts of the
+        // crates compiled for it, e.g. the proc-macro crates.
+        let target = env::var("TARGET")
+            .map_err(|_| "The Cargo `TARGET` variable is missing")
+            .unwrap();
+        let host = env::var("HOST")
+            .map_err(|_| "The Cargo `HOST` variable is missing")
+            .unwrap();
+
+        println!("cargo:rerun-if-env-changed={}", DEBUG_ENV_NAME);
+
+        let log = DebugLog::new(
+            self.verbose
+                || env::var(DEBUG_ENV_NAME).is_ok_and(|value| !value.is_empty() && value != "0"),
+            &out_dir,
+        );
+
+        log.log(format_args!("target `{}`, host `{}`", target, host));
+
+        let skip_env_name = self.skip_env.as_deref().unwrap_or(SKIP_ENV_NAME);
+        let skip = env::var(skip_env_name).is_ok_and(|value| !value.is_empty() && value != "0");
+
+        println!("cargo:rerun-if-env-changed={}", skip_env_name);
+
+        // When skipping, no toolchain is needed, not even the C
+        // preprocessor.
+        let Parsed {
+            contents,
+            header,
+            providers,
+            modules: provider_modules,
+            watched,
+            options,
+            warnings,
+        } = self.parse_with(&target, &host, self.preprocess && !skip, debug_assertions());
+
+        // Tell Cargo to rerun the build script if one of the `.d` files,
+        // or of the files they include, has changed.
+        for path in &watched {
+            println!("cargo:rerun-if-changed={}", path.display());
+            log.log(format_args!("read `{}`", path.display()));
+        }
+
+        log.log(format_args!(
+            "{} provider(s): {}",
+            providers.len(),
+            providers
+                .iter()
+                .map(|provider| format!("`{}` ({} probe(s))", provider.name, provider.probes.len()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+
+        for warning in &warnings {
+            println!("cargo:warning={}", warning);
+        }
+
+        if self.report_unused_probes {
+            let sources = env::var_os("CARGO_MANIFEST_DIR")
+                .map(|manifest_dir| rust_sources(&Path::new(&manifest_dir).join("src")))
+                .unwrap_or_default();
+
+            for (provider, probe) in unused_probes(&self.naming, &providers, &sources) {
+                println!(
+                    "cargo:warning=The probe `{}` of the provider `{}` is never fired in `src/`.",
+                    probe.name, provider.name
+                );
+            }
+        }
+
+        // The FFI header and the benchmarks don't depend on `dtrace`
+        // or `cc`, so they are always written, even when the cache is
+        // used.
+        if let Some(ffi_header_path) = &self.ffi_header {
+            write_if_changed(
+                ffi_header_path,
+                &ffi_header(
+                    &header,
+                    &self.naming,
+                    &self.ffi_providers(&providers),
+                    &self.packed_structs(&providers),
+                ),
+            );
+
+            let header_name = ffi_header_path
+                .file_name()
+                .expect("The FFI header path must be a file path")
+                .to_string_lossy();
+
+            write_if_changed(
+                &ffi_header_path.with_file_name("module.modulemap"),
+                &format!(
+                    "module SondeProbes {{\n    header {header_name:?}\n    export *\n}}\n",
+                    header_name = header_name,
+                ),
+            );
+        }
+
+        if let Some(benches_path) = &self.benches {
+            write_if_changed(
+                benches_path,
+                &benches(&header, &self.naming, &providers, self.is_enabled_functions),
+            );
+        }
+
+        if let Some(tapset_path) = &self.tapset_out {
+            write_if_changed(
+                tapset_path,
+                &tapset(
+                    &header,
+                    &self.ffi_providers(&providers),
+                    binary_path(&out_dir).as_deref(),
+                ),
+            );
+        }
+
+        if let Some(scripts_directory) = &self.scripts_out {
+            fs::create_dir_all(scripts_directory).unwrap_or_else(|error| {
+                panic!(
+                    "Failed to create `{}`: {}",
+                    scripts_directory.display(),
+                    error
+                )
+            });
+
+            let binary = binary_path(&out_dir);
+
+            for provider in self.ffi_providers(&providers).iter() {
+                write_if_changed(
+                    &scripts_directory.join(format!("{}.bt", provider.name_for_c())),
+                    &bpftrace_script(provider, binary.as_deref()),
+                );
+                write_if_changed(
+                    &scripts_directory.join(format!("{}.d", provider.name_for_c())),
+                    &dtrace_script(provider),
+                );
+            }
+        }
+
+        if let Some(scripts_directory) = &self.debugger_scripts_out {
+            fs::create_dir_all(scripts_directory).unwrap_or_else(|error| {
+                panic!(
+                    "Failed to create `{}`: {}",
+                    scripts_directory.display(),
+                    error
+                )
+            });
+
+            let providers = self.ffi_providers(&providers);
+
+            write_if_changed(
+                &scripts_directory.join("sonde_gdb.py"),
+                &gdb_script(&header, &self.naming, &providers),
+            );
+            write_if_changed(
+                &scripts_directory.join("sonde_lldb.py"),
+                &lldb_script(&header, &self.naming, &providers),
+            );
+        }
+
+        let mut rs_path = PathBuf::new();
+        rs_path.push(&out_dir);
+        rs_path.push("sonde.rs");
+
+        let backend = self.backend_for(&target);
+
+        log.log(format_args!("backend `{}`", backend));
+
+        // Without a shim, e.g. on `wasm32` where the probes are imported
+        // from the host, neither `dtrace` nor `cc` are run.
+        let shim = match backend.implementation().shim() {
+            Some(shim) => shim,
+            None => {
+                log.log("no shim: `dtrace` and the C compiler aren't run");
+                self.write_rust_api(
+                    &out_dir,
+                    &rs_path,
+                    &header,
+                    &providers,
+                    &provider_modules,
+                    backend,
+                );
+
+                return;
+            }
+        };
+
+        let fingerprint_path = Path::new(&out_dir).join(FINGERPRINT_FILE_NAME);
+
+        if let Some(header_file) = &self.header_file {
+            println!("cargo:rerun-if-changed={}", header_file.display());
+        }
+
+        // The stubs overwrite the Rust API, so the cache is invalidated
+        // for the next run without skipping.
+        if skip {
+            let _ = fs::remove_file(&fingerprint_path);
+
+            log.log(format_args!(
+                "`{}` is set: `dtrace` and the C compiler aren't run, the probes do nothing",
+                skip_env_name
+            ));
+
+            self.write_rust_api(
+                &out_dir,
+                &rs_path,
+                &header,
+                &providers,
+                &provider_modules,
+                Backend::Noop,
+            );
+
+            return;
+        }
+
+        if let Some(directory) = &self.pregenerated {
+            self.compile_pregenerated(
+                directory,
+                &contents,
+                &providers,
+                &provider_modules,
+                backend,
+                shim,
+                &target,
+                &host,
+                &log,
+            );
+
+            return;
+        }
+
+        // If nothing has changed since the last run, the previously
+        // generated files are reused, and `dtrace` and `cc` aren't run.
+        // The `cc` hooks can't be fingerprinted, so there is no cache
+        // when there are some.
+        let fingerprint = self.fingerprint(&contents, &target, &host);
+
+        for name in cc_env_names(&target) {
+            println!("cargo:rerun-if-env-changed={}", name);
+        }
+
+        let probes_object_path = Path::new(&out_dir).join(PROBES_OBJECT_FILE_NAME);
+        let objects_path = Path::new(&out_dir).join(OBJECTS_FILE_NAME);
+        let lib_name = self.ffi_library_name();
+        let links_objects = shim.links_objects(&target);
+
+        if self.cc_hooks.is_empty()
+            && rs_path.exists()
+            && Path::new(&out_dir)
+                .join(format!("lib{}.a", lib_name))
+                .exists()
+            && (!links_objects || probes_object_path.exists())
+            && (self.link_mode != LinkMode::Object || objects_path.exists())
+            && self.h_out.as_deref().is_none_or(Path::exists)
+            && self.c_out.as_deref().is_none_or(Path::exists)
+            && read_to_string(&fingerprint_path).ok().as_deref() == Some(fingerprint.as_str())
+        {
+            let objects = match self.link_mode {
+                LinkMode::Object => read_to_string(&objects_path)
+                    .unwrap()
+                    .lines()
+                    .map(PathBuf::from)
+                    .collect(),
+                _ => Vec::new(),
+            };
+
+            log.log(format_args!(
+                "nothing has changed since the previous run: reusing the files in `{}`",
+                out_dir
+            ));
+
+            self.emit_link_directives(&out_dir, &lib_name, &objects, shim);
+
+            if links_objects {
+                println!("cargo:rustc-link-arg={}", probes_object_path.display());
+            }
+
+            self.emit_rust_api_env(&out_dir, &rs_path, &providers);
+            log.log(format_args!("Rust API in `{}`", rs_path.display()));
+
+            return;
+        }
+
+        let _ = fs::remove_file(&fingerprint_path);
+
+        // Let's get a unique `.h` file from the `.d` files.
+        let h_file = tempfile::Builder::new()
+            .prefix("sonde-")
+            .suffix(".h")
+            .tempfile_in(&out_dir)
+            .unwrap();
+
+        let h_file_name = h_file.path();
+
+        let mut d_file = tempfile::Builder::new()
+            .prefix("sonde-")
+            .suffix(".d")
+            .tempfile_in(&out_dir)
+            .unwrap();
+        d_file.write_all(contents.as_bytes()).unwrap();
+
+        log.log(format_args!(
+            "merged `.d` file in `{}`",
+            d_file.path().display()
+        ));
+
+        let ffi_providers = self.ffi_providers(&providers);
+        let context = ShimContext {
+            builder: self,
+            header: &header,
+            providers: &ffi_providers,
+            target: &target,
+            options: &options,
+            d_file: d_file.path(),
+            log: &log,
+        };
+
+        if let Some(header_file) = &self.header_file {
+            copy_header(header_file, h_file_name, &providers);
+
+            log.log(format_args!(
+                "header copied from `{}` to `{}`",
+                header_file.display(),
+                h_file_name.display()
+            ));
+        } else if let Err(error) = shim.write_header(&context, h_file_name) {
+            dtrace_failed(error, d_file);
+        }
+
+        // Generate the FFI `.c` file. The probes are defined behind C
+        // macros; they can't be call from Rust, so we need to wrap
+        // them inside C functions.
+        let mut ffi_file = tempfile::Builder::new()
+            .prefix("sonde-ffi")
+            .suffix(".c")
+            .tempfile_in(&out_dir)
+            .unwrap();
+
+        {
+            let ffi = |header_file: &Path| self.ffi_source(&header, &providers, header_file);
+
+            ffi_file.write_all(ffi(h_file_name).as_bytes()).unwrap();
+
+            log.log(format_args!("C shim in `{}`", ffi_file.path().display()));
+
+            // The exported files are only written when they change, so
+            // that other build systems don't rebuild for nothing.
+            if let Some(h_out) = &self.h_out {
+                let mut h_content = read_to_string(h_file_name).unwrap();
+
+                if self.export_c_abi {
+                    h_content.push_str(&self.exported_declarations(&providers));
+                }
+
+                // The tracing scripts need the packed structs to decode
+                // the arguments, e.g. with `dtrace -C`.
+                h_content.push_str(&self.packed_structs(&providers));
+
+                write_if_changed(h_out, &h_content);
+                log.log(format_args!("exported header in `{}`", h_out.display()));
+            }
+
+            // The exported `.c` file includes the exported `.h` file
+            // relatively to itself, so that it can be moved around
+            // together with it.
+            if let Some(c_out) = &self.c_out {
+                let header_file = match &self.h_out {
+                    Some(h_out) => relative_path(
+                        &absolute_path(c_out.parent().unwrap_or_else(|| Path::new(""))),
+                        &absolute_path(h_out),
+                    ),
+                    None => h_file_name.to_path_buf(),
+                };
+
+                write_if_changed(c_out, &ffi(&header_file));
+                log.log(format_args!("exported C shim in `{}`", c_out.display()));
+            }
+        }
+
+        // Let's compile the FFI `.c` file to a `.a` file. The link
+        // directives depend on the link mode, so they are emitted by
+        // `emit_link_directives` rather than by `cc`.
+        {
+            let mut build = cc::Build::new();
+            build
+                .target(&target)
+                .host(&host)
+                .cargo_metadata(false)
+                .file(&ffi_file);
+
+            for hook in &self.cc_hooks {
+                hook(&mut build);
+            }
+
+            if log.enabled() {
+                log.command(
+                    "compiling the C shim with",
+                    &build.get_compiler().to_command(),
+                );
+            }
+
+            let objects = if links_objects || self.link_mode == LinkMode::Object {
+                let objects = build.compile_intermediates();
+
+                if links_objects {
+                    if let Err(error) = shim.link_objects(&context, &objects, &probes_object_path) {
+                        dtrace_failed(error, d_file);
+                    }
+                }
+
+                cc::Build::new()
+                    .target(&target)
+                    .host(&host)
+                    .cargo_metadata(false)
+                    .objects(&objects)
+                    .compile(&lib_name);
+
+                objects
+            } else {
+                build.compile(&lib_name);
+
+                Vec::new()
+            };
+
+            if self.link_mode == LinkMode::Object {
+                fs::write(
+                    &objects_path,
+                    objects
+                        .iter()
+                        .map(|object| format!("{}\n", object.display()))
+                        .collect::<String>(),
+                )
+                .unwrap();
+            }
+
+            self.emit_link_directives(&out_dir, &lib_name, &objects, shim);
+
+            // Nothing references the probes object, so it can't be part
+            // of the static library: the linker would drop it.
+            if links_objects {
+                println!("cargo:rustc-link-arg={}", probes_object_path.display());
+            }
+
+            log.log(format_args!(
+                "static library `lib{}.a` in `{}`, linked with `{}`",
+                lib_name, out_dir, self.link_mode
+            ));
+        }
+
+        // Finally, let's generate the nice API for Rust.
+        self.write_rust_api(
+            &out_dir,
+            &rs_path,
+            &header,
+            &providers,
+            &provider_modules,
+            backend,
+        );
+
+        log.log(format_args!("Rust API in `{}`", rs_path.display()));
+
+        // The temporary files are kept in verbose mode, to be inspected.
+        if self.keep_h_file || log.enabled() {
+            h_file.keep().unwrap();
+        }
+
+        if self.keep_c_file || log.enabled() {
+            ffi_file.keep().unwrap();
+        }
+
+        if log.enabled() {
+            d_file.keep().unwrap();
+        }
+
+        fs::write(&fingerprint_path, fingerprint).unwrap();
+    }
+
+    /// Read and parse the `.d` files and the inline sources, and
+    /// validate the declared providers. `target` and `host` are only
+    /// used by the C preprocessor, see [`Builder::preprocess`].
+    fn parse(&self, target: &str, host: &str) -> Parsed {
+        self.parse_with(target, host, self.preprocess, debug_assertions())
+    }
+}
+
+/// The log of the verbose mode, see [`Builder::verbose`]: each message
+/// is emitted as a Cargo warning, and appended to the log file.
+pub(crate) struct DebugLog {
+    file: Option<fs::File>,
+}
+
+impl DebugLog {
+    /// Create the log, truncating the log file in `out_dir`. Nothing is
+    /// logged if it isn't `enabled`.
+    fn new(enabled: bool, out_dir: &str) -> Self {
+        Self {
+            file: enabled.then(|| {
+                let path = Path::new(out_dir).join(DEBUG_LOG_FILE_NAME);
+
+                println!("cargo:warning=[sonde] log in `{}`", path.display());
+
+                fs::File::create(&path).unwrap_or_else(|error| {
+                    panic!("Failed to create `{}`: {}", path.display(), error)
+                })
+            }),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    fn log<M>(&self, message: M)
+    where
+        M: fmt::Display,
+    {
+        if let Some(mut file) = self.file.as_ref() {
+            println!("cargo:warning=[sonde] {}", message);
+            let _ = writeln!(file, "{}", message);
+        }
+    }
+
+    /// Log the command line of `command`, with the program and
+    /// arguments quoted when needed, ready to be copied to a shell.
+    fn command(&self, description: &str, command: &Command) {
+        if self.enabled() {
+            self.log(format_args!(
+                "{} `{}`",
+                description,
+                std::iter::once(command.get_program())
+                    .chain(command.get_args())
+                    .map(shell_quoted)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ));
+        }
+    }
+}
+
+/// Quote `argument` for a POSIX shell, if needed.
+fn shell_quoted(argument: &OsStr) -> String {
+    let argument = argument.to_string_lossy();
+
+    if !argument.is_empty()
+        && argument
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+    {
+        argument.into_owned()
+    } else {
+        format!("'{}'", argument.replace('\'', "'\\''"))
+    }
+}
+
+/// Make a path absolute, relatively to the current directory.
+fn absolute_path(path: &Path) -> PathBuf {
+    env::current_dir()
+        .expect("The current directory is inaccessible")
+        .join(path)
+}
+
+/// Compute the path of `to` relatively to the `from` directory, both
+/// being absolute, e.g. `../include/probes.h` from `/crate/src` to
+/// `/crate/include/probes.h`.
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+    let common = from
+        .iter()
+        .zip(&to)
+        .take_while(|(from, to)| from == to)
+        .count();
+
+    from[common..]
+        .iter()
+        .map(|_| Component::ParentDir)
+        .chain(to[common..].iter().copied())
+        .collect()
+}
+
+/// The `target_os` of a target triple, e.g. `macos` for
+/// `aarch64-apple-darwin`, or `linux` for `x86_64-unknown-linux-gnu`.
+fn target_os(target: &str) -> &str {
+    let mut parts = target.split('-').skip(1);
+    let (vendor, os) = match (parts.next(), parts.next()) {
+        (Some(vendor), Some(os)) => (vendor, os),
+        (Some(os), None) => ("", os),
+        _ => return "",
+    };
+
+    match (vendor, os) {
+        ("apple", "darwin") => "macos",
+        // Triples without a vendor, e.g. `aarch64-linux-android`.
+        ("linux", "android") => "android",
+        ("linux", _) => "linux",
+        _ => os,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(
+            relative_path(
+                Path::new("/crate/src"),
+                Path::new("/crate/include/probes.h")
+            ),
+            Path::new("../include/probes.h")
+        );
+        assert_eq!(
+            relative_path(Path::new("/crate"), Path::new("/crate/probes.h")),
+            Path::new("probes.h")
+        );
+    }
+
+    #[test]
+    fn test_symbol_prefix() {
+        let mut builder = Builder::new();
+        builder.symbol_prefix("mycrate_");
+
+        assert_eq!(builder.naming.c_prefix, "mycrate_");
+
+        builder
+            .rename_provider("hello", "greetings")
+            .rename_probe("hello", "you", "greet")
+            .namespace("mycrate")
+            .naming(NamingConfig {
+                preserve_case: true,
+                c_prefix: "ignored_".to_string(),
+                ..NamingConfig::default()
+            });
+
+        assert!(builder.naming.preserve_case);
+        assert_eq!(builder.naming.c_prefix, "mycrate_");
+        assert_eq!(builder.naming.namespace, "mycrate");
+        assert_eq!(
+            builder.naming.provider_renames,
+            [("hello".to_string(), "greetings".to_string())]
+        );
+        assert_eq!(
+            builder.naming.probe_renames,
+            [("hello".to_string(), "you".to_string(), "greet".to_string())]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The symbol prefix `my-crate` must be made of")]
+    fn test_symbol_prefix_invalid() {
+        Builder::new().symbol_prefix("my-crate");
+    }
+
+    #[test]
+    fn test_generate() {
+        let mut builder = Builder::new();
+        builder
+            .source(
+                "hello",
+                "provider hello { probe you(char *); probe me(string); };",
+            )
+            .strictness(Strictness::Warn);
+
+        let generated = builder.generate();
+
+        assert!(generated.rust.contains("pub mod r#hello {"));
+        assert!(generated.rust.contains("pub fn r#you(arg0: *mut c_char)"));
+        assert!(generated.c.contains("#include \"probes.h\"\n"));
+        assert!(generated
+            .c
+            .contains("void hello_probe_you(char * arg0) {\n    HELLO_YOU(arg0);\n}"));
+        assert!(generated
+            .header
+            .contains("void hello_probe_you(char * arg0);"));
+        assert_eq!(generated.warnings.len(), 1);
+        assert!(!generated.rust.contains("fn r#me("));
+    }
+
+    #[test]
+    fn test_strictness_warn() {
+        let mut builder = Builder::new();
+        builder
+            .source(
+                "hello",
+                "provider hello { probe you(char *); probe me(string); probe them(struct x, int); };",
+            )
+            .strictness(Strictness::Warn);
+
+        let generated = builder.generate();
+
+        assert_eq!(
+            generated.warnings,
+            vec![
+                "The probe `me` of the provider `hello` (in `hello`) has an argument of type `string`, which isn't supported yet. The probe is skipped.".to_string(),
+                "The probe `them` of the provider `hello` (in `hello`) has an argument of type `struct x`, which isn't supported yet. The probe is skipped.".to_string(),
+            ]
+        );
+        assert!(generated.rust.contains("pub fn r#you(arg0: *mut c_char)"));
+        assert!(!generated.rust.contains("fn r#them("));
+        assert!(!generated.c.contains("hello_probe_them"));
+        assert!(!generated.header.contains("hello_probe_them"));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "The `.d` files contain unsupported constructs:\n\n  - The probe `me` of the provider `hello` (in `hello`) has an argument of type `string`, which isn't supported yet.\n  - The probe `them` of the provider `hello` (in `hello`) has an argument of type `struct x`, which isn't supported yet."
+    )]
+    fn test_strictness_error() {
+        Builder::new()
+            .source(
+                "hello",
+                "provider hello { probe you(char *); probe me(string); probe them(struct x, int); };",
+            )
+            .generate();
+    }
+
+    #[test]
+    fn test_hyphenated_names() {
+        let mut builder = Builder::new();
+        builder.source("hello", "provider my-app { probe request-start(int); };");
+
+        let generated = builder.generate();
+
+        assert!(generated.rust.contains("pub mod r#my_app {"));
+        assert!(generated
+            .rust
+            .contains("    pub fn r#request_start(arg0: c_int) {\n        unsafe { super::my_app_probe_request_start(arg0) };\n    }"));
+        assert!(generated.c.contains(
+            "void my_app_probe_request_start(int arg0) {\n    MY_APP_REQUEST_START(arg0);\n}"
+        ));
+        // The original spelling is kept in the comments of the header.
+        assert!(generated.header.contains(
+            "/* Fire the `request-start` probe of the `my-app` provider. */\nvoid my_app_probe_request_start(int arg0);"
+        ));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "No provider is declared in the scanned inputs:\n\n  - a.d\n  - b.d\n\nCheck the `provider` blocks"
+    )]
+    fn test_no_provider() {
+        Builder::new()
+            .source("a.d", "/* provider hello { probe you(); }; */")
+            .source("b.d", "provder hello {};")
+            .allow_empty(false)
+            .generate();
+    }
+
+    #[test]
+    fn test_allow_empty() {
+        let generated = Builder::new()
+            .source("a.d", "#pragma D option quiet")
+            .allow_empty(true)
+            .generate();
+
+        assert!(!generated.rust.contains("pub mod"));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "The probe `change` of the provider `hello` (in `hello`) has an argument of type `enum state`, which isn't declared in the `.d` files."
+    )]
+    fn test_enums_undeclared() {
+        Builder::new()
+            .source("hello", "provider hello { probe change(enum state); };")
+            .generate();
+    }
+
+    #[test]
+    fn test_namespace() {
+        let mut builder = Builder::new();
+        builder
+            .source(
+                "hello",
+                "provider hello { probe you(char *, int); };\n#pragma D attributes Evolving/Evolving/ISA provider hello provider",
+            )
+            .source("more", "provider hello { probe me(); };")
+            .rename_probe("hello", "me", "myself")
+            .namespace("my-crate");
+
+        let parsed = builder.parse("", "");
+
+        assert!(parsed.contents.contains("provider my_crate_hello {"));
+        assert!(parsed.contents.contains(
+            "#pragma D attributes Evolving/Evolving/ISA provider my_crate_hello provider"
+        ));
+        assert_eq!(parsed.contents.matches("provider hello").count(), 0);
+        assert!(parsed.warnings.is_empty());
+
+        let generated = builder.generate();
+
+        assert!(generated.rust.contains("pub mod r#hello {"));
+        assert!(generated.rust.contains("pub fn r#myself() {"));
+        assert!(generated
+            .rust
+            .contains("unsafe { super::my_crate_hello_probe_you(arg0, arg1) };"));
+        assert!(generated
+            .c
+            .contains("void my_crate_hello_probe_you(char * arg0, int arg1) {"));
+        assert!(generated.c.contains("MY_CRATE_HELLO_YOU("));
+    }
+
+    #[test]
+    #[should_panic(expected = "The namespace `2pc` must be made")]
+    fn test_namespace_invalid() {
+        Builder::new().namespace("2pc");
+    }
+
+    #[test]
+    fn test_renames() {
+        let generated = Builder::new()
+            .source(
+                "svc",
+                "provider my__svc { probe gc__begin(int); probe gc__end(int); };",
+            )
+            .rename_provider("my__svc", "svc")
+            .rename_probe("my__svc", "gc__begin", "collect")
+            .rename_probe("my__svc", "nope", "nope")
+            .is_enabled_functions(true)
+            .args_structs(true)
+            .generate();
+
+        assert!(generated.rust.contains("pub mod r#svc {"));
+        assert!(generated.rust.contains("pub fn r#collect(arg0: c_int) {"));
+        assert!(generated.rust.contains("pub struct CollectArgs {"));
+        assert!(generated
+            .rust
+            .contains("pub fn r#collect_with(args: CollectArgs) {"));
+        assert!(generated
+            .rust
+            .contains("pub fn r#collect_is_enabled() -> bool {"));
+        assert!(generated.rust.contains("pub fn r#gc_end(arg0: c_int) {"));
+        assert!(generated
+            .c
+            .contains("void my_svc_probe_gc_begin(int arg0) {"));
+        assert_eq!(
+            generated.warnings,
+            ["The renamed probe `nope` of the provider `my__svc` isn't declared."]
+        );
+    }
+
+    #[test]
+    fn test_shell_quoted() {
+        assert_eq!(shell_quoted(OsStr::new("-o")), "-o");
+        assert_eq!(
+            shell_quoted(OsStr::new("/tmp/out/probes.h")),
+            "/tmp/out/probes.h"
+        );
+        assert_eq!(shell_quoted(OsStr::new("")), "''");
+        assert_eq!(shell_quoted(OsStr::new("my dir")), "'my dir'");
+        assert_eq!(shell_quoted(OsStr::new("it's")), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_target_os() {
+        assert_eq!(target_os("x86_64-unknown-linux-gnu"), "linux");
+        assert_eq!(target_os("aarch64-linux-android"), "android");
+        assert_eq!(target_os("x86_64-linux-gnu"), "linux");
+        assert_eq!(target_os("aarch64-apple-darwin"), "macos");
+        assert_eq!(target_os("x86_64-unknown-freebsd"), "freebsd");
+        assert_eq!(target_os("x86_64-pc-windows-gnu"), "windows");
+        assert_eq!(target_os("sparcv9-sun-solaris"), "solaris");
+    }
+
+    #[test]
+    fn test_stability_policy() {
+        let source = "provider hello { probe world(); };
+#pragma D attributes Evolving/Evolving/ISA provider hello provider
+#pragma D attributes Evolving/Unstable/ISA provider hello args
+";
+        let mut builder = Builder::new();
+        builder.source("a.d", source);
+
+        assert!(builder.parse("", "").warnings.is_empty());
+
+        builder.warn_on_stability_below(Stability::Evolving);
+
+        assert_eq!(
+            builder.parse("", "").warnings,
+            vec!["The provider `hello` (in `a.d`) declares `Evolving/Unstable/ISA` attributes for its `args`, below the `Evolving` stability policy."]
+        );
+
+        builder.warn_on_stability_below(Stability::Unstable);
+
+        assert!(builder.parse("", "").warnings.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "below the stability policy:\n\n  - The provider `hello`")]
+    fn test_stability_policy_panic() {
+        Builder::new()
+            .source(
+                "a.d",
+                "provider hello { probe world(); };\n#pragma D attributes Private/Private/Common provider hello name\n",
+            )
+            .deny_stability_below(Stability::Stable)
+            .parse("", "");
+    }
+
+    #[test]
+    fn test_defined_providers() {
+        let mut builder = Builder::new();
+        builder
+            .source("a.d", "provider myapp { probe ready(); };")
+            .provider("myapp")
+            .probe("request_start", &["char *", "uint64_t id"])
+            .unwrap();
+        builder.provider("myapp").probe("request_end", &[]).unwrap();
+        builder.provider("other").probe("tick", &["int"]).unwrap();
+
+        let error = builder
+            .provider("other")
+            .probe("tock", &["int)", "char *"])
+            .err()
+            .unwrap();
+
+        assert_eq!(error.line, 1);
+        assert!(error.snippet.contains("probe tock(int), char *);"));
+        assert!(builder.provider("other").probe("two words", &[]).is_err());
+        assert_eq!(
+            builder
+                .provider("other")
+                .probe("a(); probe b", &[])
+                .err()
+                .unwrap()
+                .message,
+            "expected a single probe"
+        );
+
+        // The probes of `myapp` are merged into the provider of `a.d`.
+        assert_eq!(
+            builder.parse("", "").contents,
+            "provider myapp { probe ready();     probe request_start(char *, uint64_t id);\n    probe request_end();\n};\nprovider other {\n    probe tick(int);\n};\n"
+        );
+
+        let rust = builder.generate().rust;
+
+        assert!(rust.contains("pub fn r#ready()"));
+        assert!(rust.contains("pub fn r#request_start(arg0: *mut c_char, id: u64)"));
+        assert!(rust.contains("pub fn r#request_end()"));
+        assert!(rust.contains("pub fn r#tick(arg0: c_int)"));
     }
 }
@@ -0,0 +1,438 @@
+//! The backends, firing the probes of the C shim and of the generated
+//! Rust API, see [`Backend`].
+
+pub(crate) mod dtrace;
+pub(crate) mod etw;
+pub(crate) mod mock;
+pub(crate) mod noop;
+pub(crate) mod systemtap;
+pub(crate) mod wasm;
+
+use self::dtrace::DTRACE_TARGET_OSES;
+use super::{target_os, Builder, DebugLog};
+use crate::{
+    d::ast::{DtraceOption, Names, Probe, Provider},
+    header::GeneratedHeader,
+};
+use std::{
+    fmt,
+    fs::{self, read_to_string},
+    path::{Path, PathBuf},
+};
+
+/// The object generated by [`Shim::link_objects`], linked into the final
+/// binary.
+pub(crate) const PROBES_OBJECT_FILE_NAME: &str = "sonde-probes.o";
+
+/// Where the probes of the generated Rust API go, see
+/// [`Builder::backend`](super::Builder::backend).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// The probes of the header generated by `dtrace -h`, called by the
+    /// C shim. It's the default, except on `wasm32`.
+    DTraceHeader,
+
+    /// The probes of `<sys/sdt.h>`, from SystemTap, called by the C
+    /// shim. The header is generated by `sonde` instead of `dtrace -h`,
+    /// so `dtrace` isn't needed, only `<sys/sdt.h>`. Linux only.
+    SystemTapSdt,
+
+    /// The events of ETW, written with TraceLogging by the C shim. The
+    /// GUID of each provider is derived from its name, like .NET's
+    /// `EventSource` does. It's the default on Windows with MSVC.
+    Etw,
+
+    /// The probes of the Wasm host, imported by the crates compiled to
+    /// `wasm32`, see [`Builder::wasmer_imports`](super::Builder::wasmer_imports). It's the default on
+    /// `wasm32`.
+    Wasm,
+
+    /// No probes: the generated functions do nothing, and no tracer is
+    /// ever attached. Neither `dtrace` nor `cc` are run. It's also the
+    /// backend used with [`Builder::skip_if_env`](super::Builder::skip_if_env).
+    Noop,
+
+    /// The fired probes are recorded in memory, per thread, and taken
+    /// with the generated `take_fired()` function of each provider, e.g.
+    /// to assert in tests which probes have been fired. A tracer is
+    /// always attached. Neither `dtrace` nor `cc` are run.
+    Mock,
+}
+
+impl Backend {
+    /// The backend used for `target` when none has been selected with
+    /// [`Builder::backend`](super::Builder::backend). On the other
+    /// targets, it's [`Backend::Noop`], so that the instrumented crates
+    /// build everywhere.
+    pub fn default_for(target: &str) -> Self {
+        if target.starts_with("wasm32") {
+            Self::Wasm
+        } else if DTRACE_TARGET_OSES.contains(&target_os(target)) {
+            Self::DTraceHeader
+        } else if target.ends_with("-windows-msvc") {
+            Self::Etw
+        } else {
+            Self::Noop
+        }
+    }
+
+    /// How this backend fires the probes.
+    pub(crate) fn implementation(self) -> &'static dyn Implementation {
+        match self {
+            Self::DTraceHeader => &dtrace::DTraceHeader,
+            Self::SystemTapSdt => &systemtap::SystemTapSdt,
+            Self::Etw => &etw::Etw,
+            Self::Wasm => &wasm::Wasm,
+            Self::Noop => &noop::Noop,
+            Self::Mock => &mock::Mock,
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::DTraceHeader => "dtrace-header",
+            Self::SystemTapSdt => "systemtap-sdt",
+            Self::Etw => "etw",
+            Self::Wasm => "wasm",
+            Self::Noop => "noop",
+            Self::Mock => "mock",
+        })
+    }
+}
+
+/// What the C shim needs to get the header of the probe macros, see
+/// [`Shim::write_header`].
+pub(crate) struct ShimContext<'a> {
+    pub(crate) builder: &'a Builder,
+    pub(crate) header: &'a GeneratedHeader,
+    /// The providers as seen by the C shim, see
+    /// [`Builder::ffi_providers`].
+    pub(crate) providers: &'a [Provider],
+    pub(crate) target: &'a str,
+    pub(crate) options: &'a [DtraceOption],
+    /// The merged `.d` file.
+    pub(crate) d_file: &'a Path,
+    pub(crate) log: &'a DebugLog,
+}
+
+/// How a [`Backend`] fires the probes.
+pub(crate) trait Implementation {
+    /// The C shim calling the probes, if any. Without one, neither
+    /// `dtrace` nor `cc` are run.
+    fn shim(&self) -> Option<&dyn Shim> {
+        None
+    }
+
+    /// Declare the functions calling the probes, as used by the Rust API:
+    /// the functions of the shim where it exists, and stubs doing nothing
+    /// elsewhere by default.
+    fn ffi_declarations(
+        &self,
+        builder: &Builder,
+        providers: &[Provider],
+        modules: &[Vec<String>],
+    ) -> String {
+        let shim = match self.shim() {
+            Some(shim) => shim,
+            None => return noop::stubs(builder, providers, modules, ""),
+        };
+
+        let target_cfg = format!(
+            "any({})",
+            shim.target_oses()
+                .iter()
+                .map(|target_os| format!("target_os = {:?}", target_os))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        format!(
+            "#[cfg({target_cfg})]\n{externs}\n\n{stubs}",
+            target_cfg = target_cfg,
+            externs = extern_declarations(builder, providers, modules),
+            stubs = noop::stubs(
+                builder,
+                providers,
+                modules,
+                &format!("#[cfg(not({}))]\n", target_cfg)
+            ),
+        )
+    }
+
+    /// The items added to the module of each provider.
+    fn provider_items(&self, _use_sonde_api: bool) -> String {
+        String::new()
+    }
+
+    /// The names of the values and of the types defined by
+    /// [`Implementation::provider_items`].
+    fn provider_item_names(&self) -> (&'static [&'static str], &'static [&'static str]) {
+        (&[], &[])
+    }
+}
+
+/// The C shim of a backend, wrapping the probe macros of its header in
+/// functions.
+pub(crate) trait Shim {
+    /// The `target_os` values where the probes exist. Elsewhere, the
+    /// generated Rust API falls back to probes doing nothing.
+    fn target_oses(&self) -> &'static [&'static str];
+
+    /// Write the header defining the probe macros at `path`, e.g.
+    /// `HELLO_YOU` and `HELLO_YOU_ENABLED` for the `you` probe of the
+    /// `hello` provider. Return the error of the tool generating it, if
+    /// any.
+    fn write_header(&self, context: &ShimContext, path: &Path) -> Result<(), String>;
+
+    /// Whether the objects of the compiled shim must be processed by
+    /// [`Shim::link_objects`] on `target`.
+    fn links_objects(&self, _target: &str) -> bool {
+        false
+    }
+
+    /// Process the objects of the compiled shim, and write the extra
+    /// object to link into the final binary at `output`.
+    fn link_objects(
+        &self,
+        _context: &ShimContext,
+        _objects: &[PathBuf],
+        _output: &Path,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// The Cargo directives linking what the probes need, besides the
+    /// shim.
+    fn link_directives(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Generate one declaration per probe, with `declaration` called with
+/// the provider, the path of its module, the probe, the name of the
+/// function and its arguments.
+pub(crate) fn declarations<F>(
+    builder: &Builder,
+    providers: &[Provider],
+    modules: &[Vec<String>],
+    declaration: F,
+) -> String
+where
+    F: Fn(&Provider, &[String], &Probe, &str, &str) -> String,
+{
+    let declaration = &declaration;
+
+    providers
+        .iter()
+        .zip(modules)
+        .flat_map(|(provider, modules)| {
+            provider.probes.iter().map(move |probe| {
+                declaration(
+                    provider,
+                    modules,
+                    probe,
+                    &builder.naming.c_function(provider, &probe.name),
+                    &probe.arguments_for_c_from_rust(),
+                )
+            })
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+/// Declare the functions of the C shim, or imported from the Wasm host,
+/// in an `extern "C"` block.
+pub(crate) fn extern_declarations(
+    builder: &Builder,
+    providers: &[Provider],
+    modules: &[Vec<String>],
+) -> String {
+    format!(
+        "extern \"C\" {{\n{}\n}}",
+        declarations(
+            builder,
+            providers,
+            modules,
+            |_, _, _, ffi_function, arguments| format!(
+                r#"    #[doc(hidden)]
+    pub(crate) fn {ffi_function}({arguments});
+
+    #[doc(hidden)]
+    pub(crate) fn {ffi_function}_enabled() -> c_int;"#,
+                ffi_function = ffi_function,
+                arguments = arguments,
+            )
+        )
+    )
+}
+
+/// The names of the function-like macros defined by a C header, e.g.
+/// `HELLO_YOU` for `#define\tHELLO_YOU(arg0) \`, as written by `dtrace
+/// -h`, or for `# define HELLO_YOU(arg0)`.
+pub(crate) fn function_macros(content: &str) -> impl Iterator<Item = &str> {
+    content.lines().filter_map(|line| {
+        let rest = line.trim_start().strip_prefix('#')?.trim_start();
+        let rest = rest.strip_prefix("define")?;
+        let name = rest.trim_start();
+
+        if name.len() == rest.len() {
+            return None;
+        }
+
+        let length = name
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(name.len());
+
+        name[length..].starts_with('(').then(|| &name[..length])
+    })
+}
+
+/// Copy the header given to [`Builder::with_header`](super::Builder::with_header) from `from` to
+/// `to`. It must define the macros of all the probes, e.g. `HELLO_YOU`
+/// and `HELLO_YOU_ENABLED` for the `you` probe of the `hello` provider.
+pub(crate) fn copy_header(from: &Path, to: &Path, providers: &[Provider]) {
+    let content = read_to_string(from)
+        .unwrap_or_else(|error| panic!("Failed to read `{}`: {}", from.display(), error));
+    let defined = function_macros(&content).collect::<Vec<_>>();
+
+    let missing = providers
+        .iter()
+        .flat_map(|provider| {
+            provider.probes.iter().flat_map(move |probe| {
+                let name = format!(
+                    "{}_{}",
+                    provider.name_for_c_macro(),
+                    probe.name_for_c_macro()
+                );
+
+                vec![name.clone(), format!("{}_ENABLED", name)]
+            })
+        })
+        .filter(|name| !defined.contains(&name.as_str()))
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        panic!(
+            "The header `{}` doesn't match the `.d` files: the `{}` macros are missing. Generate \
+             it again with `dtrace -h`.",
+            from.display(),
+            missing.join("`, `")
+        );
+    }
+
+    fs::write(to, content)
+        .unwrap_or_else(|error| panic!("Failed to write `{}`: {}", to.display(), error));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{builder::Builder, d, header::GeneratedHeader};
+
+    #[test]
+    fn test_copy_header() {
+        let directory = tempfile::tempdir().unwrap();
+        let from = directory.path().join("from.h");
+        let to = directory.path().join("to.h");
+        let script = d::parser::parse("provider hello { probe you(int); };").unwrap();
+
+        fs::write(
+            &from,
+            "#define HELLO_YOU(arg0) __dtrace_hello___you(arg0)\n#define HELLO_YOU_ENABLED() 0\n",
+        )
+        .unwrap();
+        copy_header(&from, &to, &script.providers);
+
+        assert_eq!(read_to_string(&to).unwrap(), read_to_string(&from).unwrap());
+
+        // As written by `dtrace -h`.
+        fs::write(
+            &from,
+            "#define\tHELLO_YOU(arg0) \\\ndo { \\\n\t__dtrace_probe$hello$you$v1$696e74(arg0); \\\n} while (0)\n#define\tHELLO_YOU_ENABLED() \\\n\t({ int _r = __dtrace_isenabled$hello$you$v1(); \\\n\t\t__asm__ volatile(\"\"); \\\n\t\t_r; })\n",
+        )
+        .unwrap();
+        copy_header(&from, &to, &script.providers);
+
+        assert_eq!(read_to_string(&to).unwrap(), read_to_string(&from).unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "the `HELLO_YOU_ENABLED` macros are missing")]
+    fn test_copy_header_mismatch() {
+        let directory = tempfile::tempdir().unwrap();
+        let from = directory.path().join("from.h");
+        let script = d::parser::parse("provider hello { probe you(int); };").unwrap();
+
+        fs::write(
+            &from,
+            "#define HELLO_YOU(arg0)\n#define HELLO_YOU_ENABLED 0\n",
+        )
+        .unwrap();
+        copy_header(&from, &directory.path().join("to.h"), &script.providers);
+    }
+
+    #[test]
+    fn test_stubs() {
+        let script = d::parser::parse("provider hello { probe you(char *, int); };").unwrap();
+        let rust = Builder::new().rust_api(
+            &GeneratedHeader::new(),
+            &script.providers,
+            &[Vec::new()],
+            Backend::Noop,
+        );
+
+        assert!(!rust.contains("extern \"C\""));
+        assert!(rust.contains(
+            "#[allow(unused_variables)]\nunsafe fn hello_probe_you(arg0: *mut c_char, arg1: c_int) {}"
+        ));
+        assert!(rust.contains("unsafe fn hello_probe_you_enabled() -> c_int {\n    0\n}"));
+        assert!(rust.contains("unsafe { super::hello_probe_you(arg0, arg1) };"));
+    }
+
+    #[test]
+    fn test_default_backend() {
+        assert_eq!(Backend::default_for("wasm32-wasi"), Backend::Wasm);
+        assert_eq!(
+            Backend::default_for("x86_64-unknown-linux-gnu"),
+            Backend::DTraceHeader
+        );
+        assert_eq!(
+            Backend::default_for("aarch64-apple-darwin"),
+            Backend::DTraceHeader
+        );
+        assert_eq!(
+            Backend::default_for("x86_64-unknown-illumos"),
+            Backend::DTraceHeader
+        );
+        assert_eq!(Backend::default_for("x86_64-pc-windows-msvc"), Backend::Etw);
+        assert_eq!(Backend::default_for("x86_64-pc-windows-gnu"), Backend::Noop);
+        assert_eq!(Backend::default_for("aarch64-apple-ios"), Backend::Noop);
+    }
+
+    #[test]
+    fn test_target_cfg() {
+        let script = d::parser::parse("provider hello { probe you(int); };").unwrap();
+        let rust = Builder::new().rust_api(
+            &GeneratedHeader::new(),
+            &script.providers,
+            &[Vec::new()],
+            Backend::SystemTapSdt,
+        );
+
+        assert!(rust.contains("#[cfg(any(target_os = \"linux\"))]\nextern \"C\" {"));
+        assert!(rust.contains(
+            "#[cfg(not(any(target_os = \"linux\")))]\n#[doc(hidden)]\n#[allow(unused_variables)]\nunsafe fn hello_probe_you(arg0: c_int) {}"
+        ));
+
+        let rust = Builder::new().rust_api(
+            &GeneratedHeader::new(),
+            &script.providers,
+            &[Vec::new()],
+            Backend::Etw,
+        );
+
+        assert!(rust.contains("#[cfg(any(target_os = \"windows\"))]\nextern \"C\" {"));
+    }
+}
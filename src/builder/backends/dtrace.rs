@@ -0,0 +1,402 @@
+//! The `dtrace -h` backend, see [`Backend::DTraceHeader`](super::Backend::DTraceHeader).
+
+use super::{Implementation, Shim, ShimContext};
+use crate::{
+    builder::{target_os, Builder, DebugLog},
+    d::ast::DtraceOption,
+};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// The `target_os` values supporting the probes of `dtrace -h`.
+/// Elsewhere, the probes do nothing.
+pub(crate) const DTRACE_TARGET_OSES: &[&str] =
+    &["linux", "macos", "freebsd", "netbsd", "illumos", "solaris"];
+
+pub(crate) struct DTraceHeader;
+
+impl Implementation for DTraceHeader {
+    fn shim(&self) -> Option<&dyn Shim> {
+        Some(self)
+    }
+}
+
+impl Shim for DTraceHeader {
+    fn target_oses(&self) -> &'static [&'static str] {
+        DTRACE_TARGET_OSES
+    }
+
+    fn write_header(&self, context: &ShimContext, path: &Path) -> Result<(), String> {
+        let mut dtrace = context
+            .builder
+            .dtrace_command(context.target, context.options);
+        dtrace
+            .arg("-o")
+            .arg(path)
+            .arg("-h")
+            .arg("-s")
+            .arg(context.d_file);
+
+        run_dtrace(&mut dtrace, "dtrace -h", context.log)?;
+        context
+            .log
+            .log(format_args!("header in `{}`", path.display()));
+
+        Ok(())
+    }
+
+    fn links_objects(&self, target: &str) -> bool {
+        dtrace_links_objects(target)
+    }
+
+    /// On FreeBSD, illumos and Solaris, the probes only exist once
+    /// `dtrace -G` has processed the compiled objects: it patches the
+    /// probe sites in them, and generates an extra object.
+    fn link_objects(
+        &self,
+        context: &ShimContext,
+        objects: &[PathBuf],
+        output: &Path,
+    ) -> Result<(), String> {
+        let mut dtrace = context
+            .builder
+            .dtrace_command(context.target, context.options);
+        dtrace
+            .arg("-G")
+            .arg("-o")
+            .arg(output)
+            .arg("-s")
+            .arg(context.d_file)
+            .args(objects);
+
+        run_dtrace(&mut dtrace, "dtrace -G", context.log)?;
+        context
+            .log
+            .log(format_args!("probes object in `{}`", output.display()));
+
+        Ok(())
+    }
+}
+
+/// Run `dtrace`, described by `step`, e.g. `dtrace -h`. Return its own
+/// error message if it fails, e.g. on a `.d` file it rejects, instead of
+/// letting the C compiler fail later on a missing or empty header.
+pub(crate) fn run_dtrace(dtrace: &mut Command, step: &str, log: &DebugLog) -> Result<(), String> {
+    log.command("running", dtrace);
+
+    let output = dtrace.output().map_err(|error| {
+        format!(
+            "Failed to run `{}`: {}. Is `dtrace` installed? Its path can be given with `Builder::dtrace_path`.",
+            dtrace.get_program().to_string_lossy(),
+            error
+        )
+    })?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    log.log(format_args!("`{}` {}", step, output.status));
+
+    for (name, text) in [("stdout", &stdout), ("stderr", &stderr)] {
+        if !text.trim().is_empty() {
+            log.log(format_args!("`{}` {}:\n{}", step, name, text.trim_end()));
+        }
+    }
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let mut error = format!("`{}` has failed ({})", step, output.status);
+
+    for text in [&stderr, &stdout] {
+        if !text.trim().is_empty() {
+            error.push_str(":\n\n");
+            error.push_str(text.trim_end());
+            break;
+        }
+    }
+
+    Err(error)
+}
+
+/// Fail the build with an error of `dtrace`, keeping `d_file`, the `.d`
+/// file it was given, which its error messages refer to.
+pub(crate) fn dtrace_failed(error: String, d_file: tempfile::NamedTempFile) -> ! {
+    match d_file.keep() {
+        Ok((_, path)) => panic!(
+            "{}\n\nThe `.d` file given to `dtrace`, to which the line numbers refer, is kept in `{}`.",
+            error,
+            path.display()
+        ),
+        Err(_) => panic!("{}", error),
+    }
+}
+
+/// Compute the `dtrace -arch` value for a target triple, e.g.
+/// `arm64` for `aarch64-apple-darwin`. Only Apple targets have one.
+pub(crate) fn dtrace_arch(target: &str) -> Option<String> {
+    let mut parts = target.split('-');
+    let arch = parts.next()?;
+
+    if parts.next() != Some("apple") {
+        return None;
+    }
+
+    Some(
+        match arch {
+            "aarch64" => "arm64",
+            "i386" | "i586" | "i686" => "i386",
+            arch => arch,
+        }
+        .to_string(),
+    )
+}
+
+/// The pointer width of a target, in bits: the one given by Cargo to
+/// the build script if it's the target being built, or else the one
+/// guessed from the target triple.
+pub(crate) fn pointer_width(target: &str) -> Option<u32> {
+    match env::var("CARGO_CFG_TARGET_POINTER_WIDTH") {
+        Ok(width) if env::var("TARGET").as_deref() == Ok(target) => width.parse().ok(),
+        _ => target_pointer_width(target),
+    }
+}
+
+/// Guess the pointer width of a target triple, in bits, e.g. 32 for
+/// `i686-unknown-linux-gnu` or `armv7-unknown-linux-gnueabihf`. The
+/// 32-bit ABIs of 64-bit architectures, e.g. `x32`, are 32 bits.
+pub(crate) fn target_pointer_width(target: &str) -> Option<u32> {
+    let arch = target.split('-').next()?;
+    let abi = target.rsplit('-').next().unwrap_or_default();
+
+    if abi.ends_with("x32") || abi.ends_with("ilp32") || abi.ends_with("abin32") {
+        return Some(32);
+    }
+
+    if arch == "arm64_32" {
+        Some(32)
+    } else if arch.starts_with("x86_64")
+        || arch.starts_with("aarch64")
+        || arch.starts_with("arm64")
+        || arch.starts_with("powerpc64")
+        || arch.starts_with("mips64")
+        || arch.starts_with("riscv64")
+        || arch.starts_with("loongarch64")
+        || arch.starts_with("wasm64")
+        || arch == "sparc64"
+        || arch == "sparcv9"
+        || arch == "s390x"
+    {
+        Some(64)
+    } else if arch.starts_with('i') && arch.ends_with("86")
+        || arch.starts_with("arm")
+        || arch.starts_with("thumb")
+        || arch.starts_with("mips")
+        || arch.starts_with("powerpc")
+        || arch.starts_with("riscv32")
+        || arch.starts_with("wasm32")
+        || arch == "sparc"
+        || arch == "hexagon"
+        || arch == "m68k"
+    {
+        Some(32)
+    } else if arch == "avr" || arch == "msp430" {
+        Some(16)
+    } else {
+        None
+    }
+}
+
+/// Compute the data model option of `dtrace` for a target, i.e. `-32`
+/// or `-64`, which is used when generating the objects with `dtrace
+/// -G`. Only the `dtrace` of FreeBSD, NetBSD, illumos and Solaris have
+/// it; the one of SystemTap doesn't, and the Apple one has `-arch`.
+pub(crate) fn dtrace_data_model(target: &str, pointer_width: Option<u32>) -> Option<&'static str> {
+    if !matches!(
+        target_os(target),
+        "freebsd" | "netbsd" | "illumos" | "solaris"
+    ) {
+        return None;
+    }
+
+    match pointer_width? {
+        32 => Some("-32"),
+        64 => Some("-64"),
+        _ => None,
+    }
+}
+
+/// Check whether the probes must be linked with `dtrace -G` for a
+/// target triple, instead of being defined by the `.h` file only.
+pub(crate) fn dtrace_links_objects(target: &str) -> bool {
+    target.ends_with("-freebsd") || target.ends_with("-illumos") || target.ends_with("-solaris")
+}
+
+impl Builder {
+    /// Override the architecture passed to `dtrace -arch` by the Apple
+    /// `dtrace`, which is otherwise computed from the Cargo `TARGET`.
+    pub fn dtrace_arch<A>(&mut self, arch: A) -> &mut Self
+    where
+        A: Into<String>,
+    {
+        self.dtrace_arch = Some(arch.into());
+
+        self
+    }
+
+    /// Create the `dtrace` command, with the options shared by all
+    /// its invocations.
+    pub(crate) fn dtrace_command(&self, target: &str, options: &[DtraceOption]) -> Command {
+        let mut dtrace = Command::new(
+            self.dtrace_path
+                .as_deref()
+                .unwrap_or_else(|| Path::new("dtrace")),
+        );
+
+        // Only the Apple `dtrace` has an `-arch` option. The others
+        // take the data model, i.e. the pointer width, instead.
+        if let Some(arch) = self.dtrace_arch.clone().or_else(|| dtrace_arch(target)) {
+            dtrace.arg("-arch").arg(arch);
+        } else if let Some(data_model) = dtrace_data_model(target, pointer_width(target)) {
+            dtrace.arg(data_model);
+        }
+
+        if self.preprocess {
+            dtrace.arg("-C").args(self.preprocessor_flags());
+        }
+
+        // Some compiler options, e.g. `incdir` or `define`, apply before
+        // the `.d` files are read: a `#pragma D option` is too late.
+        for option in options {
+            dtrace.arg("-x").arg(match &option.value {
+                Some(value) => format!("{}={}", option.name, value),
+                None => option.name.clone(),
+            });
+        }
+
+        dtrace.args(&self.dtrace_args);
+
+        dtrace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dtrace_arch() {
+        assert_eq!(
+            dtrace_arch("aarch64-apple-darwin"),
+            Some("arm64".to_string())
+        );
+        assert_eq!(
+            dtrace_arch("x86_64-apple-darwin"),
+            Some("x86_64".to_string())
+        );
+        assert_eq!(dtrace_arch("i686-apple-darwin"), Some("i386".to_string()));
+        assert_eq!(dtrace_arch("arm64e-apple-ios"), Some("arm64e".to_string()));
+        assert_eq!(dtrace_arch("x86_64-unknown-linux-gnu"), None);
+        assert_eq!(dtrace_arch("x86_64-unknown-freebsd"), None);
+    }
+
+    #[test]
+    fn test_dtrace_links_objects() {
+        assert!(dtrace_links_objects("x86_64-unknown-freebsd"));
+        assert!(dtrace_links_objects("x86_64-unknown-illumos"));
+        assert!(dtrace_links_objects("sparcv9-sun-solaris"));
+        assert!(!dtrace_links_objects("x86_64-unknown-linux-gnu"));
+        assert!(!dtrace_links_objects("aarch64-apple-darwin"));
+    }
+
+    #[test]
+    fn test_run_dtrace() {
+        let log = DebugLog::new(false, "");
+
+        assert_eq!(
+            run_dtrace(
+                Command::new("sh").args(["-c", "echo ok"]),
+                "dtrace -h",
+                &log
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            run_dtrace(
+                Command::new("sh").args([
+                    "-c",
+                    "echo 'dtrace: failed to compile script x.d: line 2: syntax error' >&2; exit 1"
+                ]),
+                "dtrace -h",
+                &log
+            ),
+            Err("`dtrace -h` has failed (exit status: 1):\n\ndtrace: failed to compile script x.d: line 2: syntax error".to_string())
+        );
+        assert!(
+            run_dtrace(&mut Command::new("sonde-no-such-dtrace"), "dtrace -G", &log)
+                .unwrap_err()
+                .starts_with("Failed to run `sonde-no-such-dtrace`: ")
+        );
+    }
+
+    #[test]
+    fn test_target_pointer_width() {
+        assert_eq!(target_pointer_width("x86_64-unknown-linux-gnu"), Some(64));
+        assert_eq!(
+            target_pointer_width("x86_64-unknown-linux-gnux32"),
+            Some(32)
+        );
+        assert_eq!(target_pointer_width("aarch64-apple-darwin"), Some(64));
+        assert_eq!(
+            target_pointer_width("aarch64_be-unknown-linux-gnu_ilp32"),
+            Some(32)
+        );
+        assert_eq!(target_pointer_width("arm64_32-apple-watchos"), Some(32));
+        assert_eq!(target_pointer_width("i686-unknown-freebsd"), Some(32));
+        assert_eq!(target_pointer_width("i586-unknown-linux-gnu"), Some(32));
+        assert_eq!(
+            target_pointer_width("armv7-unknown-linux-gnueabihf"),
+            Some(32)
+        );
+        assert_eq!(
+            target_pointer_width("thumbv7neon-linux-androideabi"),
+            Some(32)
+        );
+        assert_eq!(
+            target_pointer_width("mips64-unknown-linux-gnuabin32"),
+            Some(32)
+        );
+        assert_eq!(
+            target_pointer_width("mips64el-unknown-linux-gnuabi64"),
+            Some(64)
+        );
+        assert_eq!(target_pointer_width("powerpc-unknown-linux-gnu"), Some(32));
+        assert_eq!(target_pointer_width("sparcv9-sun-solaris"), Some(64));
+        assert_eq!(target_pointer_width("wasm32-unknown-unknown"), Some(32));
+        assert_eq!(target_pointer_width("avr-unknown-gnu-atmega328"), Some(16));
+        assert_eq!(target_pointer_width("unknown"), None);
+    }
+
+    #[test]
+    fn test_dtrace_data_model() {
+        assert_eq!(
+            dtrace_data_model("i686-unknown-freebsd", Some(32)),
+            Some("-32")
+        );
+        assert_eq!(
+            dtrace_data_model("x86_64-unknown-illumos", Some(64)),
+            Some("-64")
+        );
+        assert_eq!(
+            dtrace_data_model("sparcv9-sun-solaris", Some(64)),
+            Some("-64")
+        );
+        assert_eq!(dtrace_data_model("x86_64-unknown-freebsd", None), None);
+        assert_eq!(dtrace_data_model("i686-unknown-linux-gnu", Some(32)), None);
+        assert_eq!(dtrace_data_model("i686-apple-darwin", Some(32)), None);
+    }
+}
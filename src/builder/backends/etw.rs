@@ -0,0 +1,408 @@
+//! The ETW backend, see [`Backend::Etw`](super::Backend::Etw).
+
+use super::{Implementation, Shim, ShimContext};
+use crate::{
+    builder::cache::write_if_changed,
+    d::ast::{Names, Probe, Provider},
+    header::GeneratedHeader,
+};
+use std::path::Path;
+
+/// The namespace of the GUIDs derived from the provider names, the one
+/// of .NET's `EventSource`, so that tools find the providers by name,
+/// e.g. with `*hello`.
+const GUID_NAMESPACE: [u8; 16] = [
+    0x48, 0x2c, 0x2d, 0xb2, 0xc3, 0x90, 0x47, 0xc8, 0x87, 0xf8, 0x1a, 0x15, 0xbf, 0xc1, 0x30, 0xfb,
+];
+
+pub(crate) struct Etw;
+
+impl Implementation for Etw {
+    fn shim(&self) -> Option<&dyn Shim> {
+        Some(self)
+    }
+}
+
+impl Shim for Etw {
+    fn target_oses(&self) -> &'static [&'static str] {
+        &["windows"]
+    }
+
+    fn write_header(&self, context: &ShimContext, path: &Path) -> Result<(), String> {
+        write_if_changed(path, &etw_header(context.header, context.providers));
+
+        context
+            .log
+            .log(format_args!("TraceLogging header in `{}`", path.display()));
+
+        Ok(())
+    }
+
+    fn link_directives(&self) -> Vec<String> {
+        vec!["rustc-link-lib=advapi32".to_string()]
+    }
+}
+
+/// Generate the header defining the probe macros with the ones of
+/// TraceLogging. Each provider is registered when one of its probes is
+/// first fired or checked, and unregistered at exit.
+fn etw_header(header: &GeneratedHeader, providers: &[Provider]) -> String {
+    format!(
+        r#"{header}
+#ifndef _SONDE_ETW_H
+#define _SONDE_ETW_H
+
+#include <stdlib.h>
+#include <windows.h>
+#include <winmeta.h>
+#include <TraceLoggingProvider.h>
+
+// MSVC only has `_Static_assert` in C11 mode.
+#if defined(_MSC_VER) && !defined(__clang__) && !defined(__STDC_VERSION__)
+#define SONDE_CONCAT_(a, b) a##b
+#define SONDE_CONCAT(a, b) SONDE_CONCAT_(a, b)
+#define _Static_assert(condition, message) \
+    typedef char SONDE_CONCAT(sonde_static_assert_, __COUNTER__)[(condition) ? 1 : -1]
+#endif
+{providers}
+#endif
+"#,
+        header = header,
+        providers = providers
+            .iter()
+            .map(etw_provider)
+            .collect::<Vec<String>>()
+            .join(""),
+    )
+}
+
+/// Define the TraceLogging provider of a provider, and the macros of its
+/// probes.
+fn etw_provider(provider: &Provider) -> String {
+    let handle = format!("sonde_etw_{}", provider.name_for_c());
+    let guid = provider_guid(&provider.name);
+
+    format!(
+        r#"
+// The `{name}` provider, {{{guid}}}.
+TRACELOGGING_DEFINE_PROVIDER(
+    {handle},
+    {name:?},
+    ({guid_fields}));
+
+static LONG volatile {handle}_registered = 0;
+
+static void {handle}_unregister(void) {{
+    TraceLoggingUnregister({handle});
+}}
+
+// A probe fired by another thread while the provider is being registered
+// is lost.
+static void {handle}_register(void) {{
+    if (InterlockedCompareExchange(&{handle}_registered, 1, 0) == 0) {{
+        TraceLoggingRegister({handle});
+        atexit({handle}_unregister);
+    }}
+}}
+{probes}"#,
+        name = provider.name,
+        handle = handle,
+        guid = format_guid(&guid),
+        guid_fields = guid_fields(&guid),
+        probes = provider
+            .probes
+            .iter()
+            .map(|probe| {
+                let level = etw_level(probe);
+                let parameters = (0..probe.arguments.len())
+                    .map(|nth| format!("arg{}", nth))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                let fields = probe
+                    .arguments_for_rust()
+                    .iter()
+                    .zip(probe.names())
+                    .enumerate()
+                    .map(|(nth, ((_, ty), name))| {
+                        format!(
+                            ", \\\n            {}",
+                            etw_field(ty, &format!("arg{}", nth), &name)
+                        )
+                    })
+                    .collect::<String>();
+
+                format!(
+                    r#"
+#define {provider_macro}_{probe_macro}({parameters}) \
+    do {{ \
+        {handle}_register(); \
+        TraceLoggingWrite( \
+            {handle}, \
+            {event:?}, \
+            TraceLoggingLevel({level}){fields}); \
+    }} while (0)
+#define {provider_macro}_{probe_macro}_ENABLED() \
+    ({handle}_register(), TraceLoggingProviderEnabled({handle}, {level}, 0))
+"#,
+                    provider_macro = provider.name_for_c_macro(),
+                    probe_macro = probe.name_for_c_macro(),
+                    parameters = parameters,
+                    handle = handle,
+                    // DTrace exposes `__` as `-` in the probe names.
+                    event = probe.name.replace("__", "-"),
+                    level = level,
+                    fields = fields,
+                )
+            })
+            .collect::<String>(),
+    )
+}
+
+/// The ETW level of a probe, after its `@level`. The probes without one
+/// are verbose.
+fn etw_level(probe: &Probe) -> &'static str {
+    match probe.level.as_deref() {
+        Some("error") => "WINEVENT_LEVEL_ERROR",
+        Some("warn") => "WINEVENT_LEVEL_WARNING",
+        Some("info") => "WINEVENT_LEVEL_INFO",
+        _ => "WINEVENT_LEVEL_VERBOSE",
+    }
+}
+
+/// The TraceLogging field of an argument passed as the Rust type `ty`.
+/// The C strings are logged as strings, and the other pointers as
+/// addresses. `long` is 32 bits on Windows.
+fn etw_field(ty: &str, argument: &str, name: &str) -> String {
+    let (macro_name, c_type) = match ty {
+        "*mut c_char" => ("TraceLoggingString", "const char *"),
+        ty if ty.starts_with("*mut ") => ("TraceLoggingPointer", "const void *"),
+        "i8" | "c_char" | "c_schar" => ("TraceLoggingInt8", "INT8"),
+        "u8" | "c_uchar" => ("TraceLoggingUInt8", "UINT8"),
+        "i16" | "c_short" => ("TraceLoggingInt16", "INT16"),
+        "u16" | "c_ushort" => ("TraceLoggingUInt16", "UINT16"),
+        "i32" | "c_int" | "c_long" => ("TraceLoggingInt32", "INT32"),
+        "u32" | "c_uint" | "c_ulong" => ("TraceLoggingUInt32", "UINT32"),
+        "i64" | "c_longlong" => ("TraceLoggingInt64", "INT64"),
+        "u64" | "c_ulonglong" => ("TraceLoggingUInt64", "UINT64"),
+        "isize" => ("TraceLoggingIntPtr", "INT_PTR"),
+        "bool" => ("TraceLoggingBoolean", "BOOL"),
+        "c_float" => ("TraceLoggingFloat32", "float"),
+        "c_double" => ("TraceLoggingFloat64", "double"),
+        _ => ("TraceLoggingUIntPtr", "UINT_PTR"),
+    };
+
+    format!(
+        "{macro_name}(({c_type}) ({argument}), {name:?})",
+        macro_name = macro_name,
+        c_type = c_type,
+        argument = argument,
+        name = name,
+    )
+}
+
+/// Derive the GUID of a provider from its name, like .NET's
+/// `EventSource` and `TraceLoggingDynamic` do: a version 5 UUID of the
+/// uppercased name, in UTF-16BE, with the bytes of the first three
+/// fields in little-endian order.
+fn provider_guid(name: &str) -> [u8; 16] {
+    let mut input = GUID_NAMESPACE.to_vec();
+
+    for unit in name.to_uppercase().encode_utf16() {
+        input.extend_from_slice(&unit.to_be_bytes());
+    }
+
+    let mut guid = [0; 16];
+    guid.copy_from_slice(&sha1(&input)[..16]);
+    guid[7] = (guid[7] & 0x0f) | 0x50;
+
+    guid
+}
+
+/// Format a GUID as `0866B2B8-5CEF-5DB9-2612-0C0FFD814A44`.
+fn format_guid(guid: &[u8; 16]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        guid_data1(guid),
+        u16::from_le_bytes([guid[4], guid[5]]),
+        u16::from_le_bytes([guid[6], guid[7]]),
+        guid[8],
+        guid[9],
+        guid[10..]
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<String>(),
+    )
+}
+
+/// The fields of a GUID, as given to `TRACELOGGING_DEFINE_PROVIDER`.
+fn guid_fields(guid: &[u8; 16]) -> String {
+    format!(
+        "0x{:08x}, 0x{:04x}, 0x{:04x}, {}",
+        guid_data1(guid),
+        u16::from_le_bytes([guid[4], guid[5]]),
+        u16::from_le_bytes([guid[6], guid[7]]),
+        guid[8..]
+            .iter()
+            .map(|byte| format!("0x{:02x}", byte))
+            .collect::<Vec<String>>()
+            .join(", "),
+    )
+}
+
+fn guid_data1(guid: &[u8; 16]) -> u32 {
+    u32::from_le_bytes([guid[0], guid[1], guid[2], guid[3]])
+}
+
+/// Hash `data` with SHA-1, as needed by [`provider_guid`].
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+
+    message.push(0x80);
+
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+
+        for (nth, word) in block.chunks(4).enumerate() {
+            words[nth] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+
+        for nth in 16..80 {
+            words[nth] = (words[nth - 3] ^ words[nth - 8] ^ words[nth - 14] ^ words[nth - 16])
+                .rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+
+        for (nth, word) in words.iter().enumerate() {
+            let (f, k) = match nth {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (value, new) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(new);
+        }
+    }
+
+    let mut hash = [0; 20];
+
+    for (bytes, value) in hash.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d;
+
+    #[test]
+    fn test_sha1() {
+        let hex = |hash: [u8; 20]| {
+            hash.iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        };
+
+        assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex(sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn test_provider_guid() {
+        assert_eq!(
+            format_guid(&provider_guid("System.Buffers.ArrayPoolEventSource")),
+            "0866B2B8-5CEF-5DB9-2612-0C0FFD814A44"
+        );
+        assert_eq!(
+            format_guid(&provider_guid("system.buffers.arraypooleventsource")),
+            "0866B2B8-5CEF-5DB9-2612-0C0FFD814A44"
+        );
+        assert_eq!(
+            guid_fields(&provider_guid("System.Buffers.ArrayPoolEventSource")),
+            "0x0866b2b8, 0x5cef, 0x5db9, 0x26, 0x12, 0x0c, 0x0f, 0xfd, 0x81, 0x4a, 0x44"
+        );
+    }
+
+    #[test]
+    fn test_etw_header() {
+        let script = d::parser::parse(
+            "provider hello {
+                 probe you(char *who, int, uint64_t, struct request *, bool);
+                 /** @level error */
+                 probe request__failed();
+             };",
+        )
+        .unwrap();
+        let header = etw_header(&GeneratedHeader::new(), &script.providers);
+
+        assert!(header.contains("#include <TraceLoggingProvider.h>\n"));
+        assert!(header.contains(&format!(
+            "TRACELOGGING_DEFINE_PROVIDER(\n    sonde_etw_hello,\n    \"hello\",\n    ({}));",
+            guid_fields(&provider_guid("hello"))
+        )));
+        assert!(header.contains(
+            "#define HELLO_YOU(arg0, arg1, arg2, arg3, arg4) \\\n    do { \\\n        sonde_etw_hello_register(); \\\n        TraceLoggingWrite( \\\n            sonde_etw_hello, \\\n            \"you\", \\\n            TraceLoggingLevel(WINEVENT_LEVEL_VERBOSE), \\\n            TraceLoggingString((const char *) (arg0), \"who\"), \\\n            TraceLoggingInt32((INT32) (arg1), \"arg1\"), \\\n            TraceLoggingUInt64((UINT64) (arg2), \"arg2\"), \\\n            TraceLoggingPointer((const void *) (arg3), \"arg3\"), \\\n            TraceLoggingBoolean((BOOL) (arg4), \"arg4\")); \\\n    } while (0)\n"
+        ));
+        assert!(header.contains(
+            "#define HELLO_YOU_ENABLED() \\\n    (sonde_etw_hello_register(), TraceLoggingProviderEnabled(sonde_etw_hello, WINEVENT_LEVEL_VERBOSE, 0))\n"
+        ));
+        assert!(header.contains(
+            "            \"request-failed\", \\\n            TraceLoggingLevel(WINEVENT_LEVEL_ERROR)); \\\n"
+        ));
+    }
+
+    #[test]
+    fn test_etw_field() {
+        assert_eq!(
+            etw_field("c_ulong", "arg0", "count"),
+            "TraceLoggingUInt32((UINT32) (arg0), \"count\")"
+        );
+        assert_eq!(
+            etw_field("usize", "arg1", "len"),
+            "TraceLoggingUIntPtr((UINT_PTR) (arg1), \"len\")"
+        );
+        assert_eq!(
+            etw_field("*mut *mut c_char", "arg2", "argv"),
+            "TraceLoggingPointer((const void *) (arg2), \"argv\")"
+        );
+        assert_eq!(
+            etw_field("c_double", "arg3", "ratio"),
+            "TraceLoggingFloat64((double) (arg3), \"ratio\")"
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! The mock backend, see [`Backend::Mock`](super::Backend::Mock).
+
+use super::{declarations, Implementation};
+use crate::{
+    builder::{codegen::byte_slice_arguments, Builder},
+    d::ast::{Probe, Provider},
+};
+
+pub(crate) struct Mock;
+
+impl Implementation for Mock {
+    /// Functions recording the fired probes, in the module of their
+    /// provider.
+    fn ffi_declarations(
+        &self,
+        builder: &Builder,
+        providers: &[Provider],
+        modules: &[Vec<String>],
+    ) -> String {
+        declarations(
+            builder,
+            providers,
+            modules,
+            |provider, modules, probe, ffi_function, arguments| {
+                format!(
+                    r#"#[doc(hidden)]
+unsafe fn {ffi_function}({arguments}) {{
+    self::{module_path}r#{provider_module}::record_fired({probe_name:?}, vec![{recorded}]);
+}}
+
+#[doc(hidden)]
+unsafe fn {ffi_function}_enabled() -> c_int {{
+    1
+}}"#,
+                    ffi_function = ffi_function,
+                    arguments = arguments,
+                    module_path = modules
+                        .iter()
+                        .map(|module| format!("{}::", module))
+                        .collect::<String>(),
+                    provider_module = builder.naming.rust_module(&provider.name),
+                    probe_name = builder.naming.name(&probe.name),
+                    recorded = mock_recorded_arguments(probe),
+                )
+            },
+        )
+    }
+
+    fn provider_items(&self, use_sonde_api: bool) -> String {
+        mock_recorder(use_sonde_api)
+    }
+
+    fn provider_item_names(&self) -> (&'static [&'static str], &'static [&'static str]) {
+        (&["take_fired", "record_fired", "FIRED"], &["Fired"])
+    }
+}
+
+/// Generate the recorder of the fired probes of a provider.
+fn mock_recorder(use_sonde_api: bool) -> String {
+    if use_sonde_api {
+        return r#"
+
+    pub use ::sonde_api::mock::Fired;
+
+    static FIRED: ::sonde_api::mock::Recorder = ::sonde_api::mock::Recorder::new();
+
+    /// Take the probes fired by the current thread since the last call,
+    /// in the order they have been fired.
+    pub fn take_fired() -> Vec<Fired> {
+        FIRED.take()
+    }
+
+    #[doc(hidden)]
+    pub fn record_fired(probe: &'static str, arguments: Vec<String>) {
+        FIRED.record(probe, arguments);
+    }"#
+        .to_string();
+    }
+
+    r#"
+
+    /// A probe fired with the mock backend.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Fired {
+        /// The name of the probe, as declared in the `.d` file.
+        pub probe: &'static str,
+
+        /// The arguments of the probe, formatted with `Debug`, or read
+        /// as strings for the `(pointer, length)` pairs. The other
+        /// pointers are formatted as addresses.
+        pub arguments: Vec<String>,
+    }
+
+    thread_local! {
+        static FIRED: std::cell::RefCell<Vec<Fired>> = std::cell::RefCell::new(Vec::new());
+    }
+
+    /// Take the probes fired by the current thread since the last call,
+    /// in the order they have been fired.
+    pub fn take_fired() -> Vec<Fired> {
+        FIRED.with(|fired| std::mem::take(&mut *fired.borrow_mut()))
+    }
+
+    #[doc(hidden)]
+    pub fn record_fired(probe: &'static str, arguments: Vec<String>) {
+        FIRED.with(|fired| fired.borrow_mut().push(Fired { probe, arguments }));
+    }"#
+    .to_string()
+}
+
+/// The arguments recorded when a probe is fired. The `(pointer, length)` pairs are read as strings, and the other
+/// pointers are recorded as addresses: they are never dereferenced, as
+/// a dangling pointer given to a probe is harmless with the other
+/// backends.
+fn mock_recorded_arguments(probe: &Probe) -> String {
+    let arguments = probe.arguments_for_rust();
+    let pointers = byte_slice_arguments(probe);
+
+    arguments
+        .iter()
+        .enumerate()
+        .map(|(nth, (name, ty))| {
+            if pointers.contains(&nth) {
+                format!(
+                    "if {name}.is_null() {{ String::from(\"NULL\") }} else {{ String::from_utf8_lossy(unsafe {{ std::slice::from_raw_parts({name} as *const u8, {length} as usize) }}).into_owned() }}",
+                    name = name,
+                    length = arguments[nth + 1].0,
+                )
+            } else if ty.starts_with("*mut ") {
+                format!("format!(\"{{:p}}\", {})", name)
+            } else {
+                format!("format!(\"{{:?}}\", {})", name)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        builder::{Backend, Builder},
+        d,
+        header::GeneratedHeader,
+    };
+
+    #[test]
+    fn test_mock_backend() {
+        let script = d::parser::parse(
+            "provider hello { probe you(char *, int); probe send(char *buf, size_t len); };",
+        )
+        .unwrap();
+        let rust = Builder::new().rust_api(
+            &GeneratedHeader::new(),
+            &script.providers,
+            &[vec!["tracing".to_string()]],
+            Backend::Mock,
+        );
+
+        assert!(!rust.contains("extern \"C\""));
+        assert!(rust.contains("self::tracing::r#hello::record_fired(\"you\", vec!["));
+        assert!(rust.contains("format!(\"{:?}\", arg1)"));
+        assert!(rust.contains("vec![format!(\"{:p}\", arg0), format!(\"{:?}\", arg1)]"));
+        assert!(rust.contains("std::slice::from_raw_parts(buf as *const u8, len as usize)"));
+        assert!(!rust.contains("CStr::from_ptr"));
+        assert!(rust.contains("unsafe fn hello_probe_you_enabled() -> c_int {\n    1\n}"));
+        assert!(rust.contains("pub fn take_fired() -> Vec<Fired>"));
+    }
+}
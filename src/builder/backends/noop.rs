@@ -0,0 +1,38 @@
+//! The backend without probes, see [`Backend::Noop`](super::Backend::Noop).
+
+use super::{declarations, Implementation};
+use crate::{builder::Builder, d::ast::Provider};
+
+pub(crate) struct Noop;
+
+impl Implementation for Noop {}
+
+/// Generate stubs doing nothing instead of the functions calling the
+/// probes. `cfg` is an attribute prefixing each stub.
+pub(crate) fn stubs(
+    builder: &Builder,
+    providers: &[Provider],
+    modules: &[Vec<String>],
+    cfg: &str,
+) -> String {
+    declarations(
+        builder,
+        providers,
+        modules,
+        |_, _, _, ffi_function, arguments| {
+            format!(
+                r#"{cfg}#[doc(hidden)]
+#[allow(unused_variables)]
+unsafe fn {ffi_function}({arguments}) {{}}
+
+{cfg}#[doc(hidden)]
+unsafe fn {ffi_function}_enabled() -> c_int {{
+    0
+}}"#,
+                cfg = cfg,
+                ffi_function = ffi_function,
+                arguments = arguments,
+            )
+        },
+    )
+}
@@ -0,0 +1,108 @@
+//! The SystemTap backend, see [`Backend::SystemTapSdt`](super::Backend::SystemTapSdt).
+
+use super::{Implementation, Shim, ShimContext};
+use crate::{
+    builder::cache::write_if_changed,
+    d::ast::{Names, Provider},
+    header::GeneratedHeader,
+};
+use std::path::Path;
+
+pub(crate) struct SystemTapSdt;
+
+impl Implementation for SystemTapSdt {
+    fn shim(&self) -> Option<&dyn Shim> {
+        Some(self)
+    }
+}
+
+impl Shim for SystemTapSdt {
+    fn target_oses(&self) -> &'static [&'static str] {
+        &["linux"]
+    }
+
+    fn write_header(&self, context: &ShimContext, path: &Path) -> Result<(), String> {
+        write_if_changed(path, &systemtap_header(context.header, context.providers));
+
+        context
+            .log
+            .log(format_args!("`<sys/sdt.h>` header in `{}`", path.display()));
+
+        Ok(())
+    }
+}
+
+/// Generate the header defining the probe macros with the ones of
+/// SystemTap's `<sys/sdt.h>`, instead of `dtrace -h`. The semaphores
+/// are weak, so that they can be defined once per provider and still
+/// link.
+fn systemtap_header(header: &GeneratedHeader, providers: &[Provider]) -> String {
+    format!(
+        r#"{header}
+#ifndef _SONDE_SDT_H
+#define _SONDE_SDT_H
+
+#define _SDT_HAS_SEMAPHORES 1
+#include <sys/sdt.h>
+{probes}
+#endif
+"#,
+        header = header,
+        probes = providers
+            .iter()
+            .flat_map(|provider| {
+                provider.probes.iter().map(move |probe| {
+                    // `<sys/sdt.h>` pastes the raw names.
+                    let semaphore = format!("{}_{}_semaphore", provider.name, probe.name);
+                    let arguments = probe.names().join(", ");
+
+                    format!(
+                        r#"
+__extension__ unsigned short {semaphore} __attribute__((unused, weak, section(".probes")));
+#define {macro_prefix}_{macro_suffix}_ENABLED() __builtin_expect({semaphore}, 0)
+#define {macro_prefix}_{macro_suffix}({arguments}) {stap_probe}({provider}, {probe}{separator}{arguments})
+"#,
+                        semaphore = semaphore,
+                        macro_prefix = provider.name_for_c_macro(),
+                        macro_suffix = probe.name_for_c_macro(),
+                        arguments = arguments,
+                        stap_probe = match probe.arguments.len() {
+                            0 => "STAP_PROBE".to_string(),
+                            count => format!("STAP_PROBE{}", count),
+                        },
+                        provider = provider.name,
+                        probe = probe.name,
+                        separator = if probe.arguments.is_empty() { "" } else { ", " },
+                    )
+                })
+            })
+            .collect::<String>()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d;
+
+    #[test]
+    fn test_systemtap_header() {
+        let script = d::parser::parse(
+            "provider hello { probe world(); probe you__me(char *, int); probe dump(my_t *items); };",
+        )
+        .unwrap();
+        let header = systemtap_header(&GeneratedHeader::new(), &script.providers);
+
+        assert!(header.contains("#define _SDT_HAS_SEMAPHORES 1\n#include <sys/sdt.h>\n"));
+        assert!(header.contains(
+            "__extension__ unsigned short hello_world_semaphore __attribute__((unused, weak, section(\".probes\")));\n\
+             #define HELLO_WORLD_ENABLED() __builtin_expect(hello_world_semaphore, 0)\n\
+             #define HELLO_WORLD() STAP_PROBE(hello, world)\n"
+        ));
+        assert!(header.contains(
+            "#define HELLO_YOU_ME(arg0, arg1) STAP_PROBE2(hello, you__me, arg0, arg1)\n"
+        ));
+        assert!(header.contains("__builtin_expect(hello_you__me_semaphore, 0)"));
+        assert!(header.contains("#define HELLO_DUMP(items) STAP_PROBE1(hello, dump, items)\n"));
+    }
+}
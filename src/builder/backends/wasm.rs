@@ -0,0 +1,235 @@
+//! The Wasm backend, see [`Backend::Wasm`](super::Backend::Wasm).
+
+use super::{extern_declarations, Implementation};
+use crate::{
+    builder::{
+        codegen::{allow_deprecated, byte_slice_arguments, C_STRING_TYPE},
+        Builder,
+    },
+    d::ast::Provider,
+    naming::NamingConfig,
+};
+
+/// The Wasm module from which the probes are imported on `wasm32`.
+pub(crate) const WASM_IMPORT_MODULE: &str = "sonde";
+
+pub(crate) struct Wasm;
+
+impl Implementation for Wasm {
+    /// The functions imported from the Wasm host.
+    fn ffi_declarations(
+        &self,
+        builder: &Builder,
+        providers: &[Provider],
+        modules: &[Vec<String>],
+    ) -> String {
+        format!(
+            "#[link(wasm_import_module = {:?})]\n{}",
+            WASM_IMPORT_MODULE,
+            extern_declarations(builder, providers, modules)
+        )
+    }
+}
+
+/// Generate the `wasmer_imports` function, creating the host functions
+/// imported by the crates compiled to `wasm32` and firing the real
+/// probes. The probe isn't fired if its pointers aren't in the guest's
+/// memory.
+pub(crate) fn wasmer_imports(naming: &NamingConfig, providers: &[Provider]) -> String {
+    let functions = providers
+        .iter()
+        .flat_map(|provider| {
+            provider.probes.iter().map(move |probe| {
+                let arguments = probe.arguments_for_rust();
+                let pointers = byte_slice_arguments(probe);
+
+                format!(
+                    r#"
+    namespace.insert(
+        "{ffi_function}",
+        wasmer::Function::new_typed_with_env(
+            store,
+            env,
+            |mut env: wasmer::FunctionEnvMut<WasmerEnv>{parameters}| {{
+                #[allow(unused)]
+                let (env, store) = env.data_and_store_mut();
+{conversions}
+                r#{provider_name}::r#{function_name}({argument_names});
+            }},
+        ),
+    );
+    namespace.insert(
+        "{ffi_function}_enabled",
+        wasmer::Function::new_typed(store, || -> i32 {{
+            unsafe {{ {ffi_function}_enabled() as i32 }}
+        }}),
+    );"#,
+                    ffi_function = naming.c_function(provider, &probe.name),
+                    provider_name = naming.rust_module(&provider.name),
+                    function_name = naming.rust_function(&provider.name, &probe.name),
+                    parameters = arguments
+                        .iter()
+                        .map(|(name, ty)| format!(", {}: {}", name, wasm_type(ty)))
+                        .collect::<String>(),
+                    conversions = arguments
+                        .iter()
+                        .enumerate()
+                        .map(|(nth, (name, ty))| {
+                            let conversion = if ty == C_STRING_TYPE {
+                                format!(
+                                    "match guest_c_string(env, &store, {}) {{\n                    Some(pointer) => pointer as _,\n                    None => return,\n                }}",
+                                    name
+                                )
+                            } else if ty.starts_with("*mut ") {
+                                format!(
+                                    "match guest_pointer(env, &store, {}, {}) {{\n                    Some(pointer) => pointer as _,\n                    None => return,\n                }}",
+                                    name,
+                                    if pointers.contains(&nth) {
+                                        format!("{} as u64", arguments[nth + 1].0)
+                                    } else {
+                                        "1".to_string()
+                                    }
+                                )
+                            } else if ty == "bool" {
+                                format!("{} != 0", name)
+                            } else {
+                                format!("{} as _", name)
+                            };
+
+                            format!("                let {} = {};\n", name, conversion)
+                        })
+                        .collect::<String>(),
+                    argument_names = probe.argument_names_for_rust(),
+                )
+            })
+        })
+        .collect::<Vec<String>>()
+        .join("");
+
+    format!(
+        r#"
+/// The environment of the functions created by `wasmer_imports`.
+#[derive(Default, Clone)]
+pub struct WasmerEnv {{
+    /// The memory of the guest, which must be set once the instance is
+    /// created, to read the arguments passed by pointer.
+    pub memory: Option<wasmer::Memory>,
+}}
+
+#[allow(unused)]
+fn memory_view<'a>(env: &'a WasmerEnv, store: &'a impl wasmer::AsStoreRef) -> wasmer::MemoryView<'a> {{
+    env.memory
+        .as_ref()
+        .expect("The memory of the guest isn't set in the `WasmerEnv`")
+        .view(store)
+}}
+
+/// Translate the offset of `length` bytes in the memory of the guest to
+/// a host pointer, if they are all in the memory.
+#[allow(unused)]
+fn guest_pointer(env: &WasmerEnv, store: &impl wasmer::AsStoreRef, offset: u32, length: u64) -> Option<*mut u8> {{
+    let view = memory_view(env, store);
+    let end = u64::from(offset).checked_add(length)?;
+
+    (end <= view.data_size()).then(|| unsafe {{ view.data_ptr().add(offset as usize) }})
+}}
+
+/// Translate the offset of a C string in the memory of the guest to a
+/// host pointer, if the string and its NUL byte are in the memory.
+#[allow(unused)]
+fn guest_c_string(env: &WasmerEnv, store: &impl wasmer::AsStoreRef, offset: u32) -> Option<*mut u8> {{
+    let view = memory_view(env, store);
+
+    (u64::from(offset)..view.data_size())
+        .any(|nth| unsafe {{ *view.data_ptr().add(nth as usize) }} == 0)
+        .then(|| unsafe {{ view.data_ptr().add(offset as usize) }})
+}}
+
+/// Create the host functions imported by the crates compiled to `wasm32`,
+/// in the `{module}` namespace. They fire the probes of this crate.
+{allow_deprecated}pub fn wasmer_imports(
+    store: &mut impl wasmer::AsStoreMut,
+    env: &wasmer::FunctionEnv<WasmerEnv>,
+) -> wasmer::Imports {{
+    #[allow(unused_mut)]
+    let mut namespace = wasmer::Exports::new();
+{functions}
+
+    let mut imports = wasmer::Imports::new();
+    imports.register_namespace({module:?}, namespace);
+
+    imports
+}}
+"#,
+        module = WASM_IMPORT_MODULE,
+        functions = functions,
+        allow_deprecated = allow_deprecated(providers),
+    )
+}
+
+/// Map a Rust type of the generated API to the type of its value in
+/// the `wasm32` ABI, e.g. `*mut c_char` to `u32`.
+pub(crate) fn wasm_type(ty: &str) -> &'static str {
+    match ty {
+        ty if ty.starts_with("*mut ") => "u32",
+        "i64" | "c_longlong" => "i64",
+        "u64" | "c_ulonglong" => "u64",
+        "c_float" => "f32",
+        "c_double" => "f64",
+        "u8" | "u16" | "u32" | "usize" | "c_uchar" | "c_ushort" | "c_uint" | "c_ulong" => "u32",
+        _ => "i32",
+    }
+}
+
+impl Builder {
+    /// Generate a `wasmer_imports` function, for Wasmer embedders
+    /// running instrumented crates compiled to `wasm32`. It creates the
+    /// host functions imported by the guests (in the `sonde` namespace),
+    /// which fire the real probes. The crate must depend on `wasmer`.
+    pub fn wasmer_imports(&mut self, generate: bool) -> &mut Self {
+        self.wasmer_imports = generate;
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d;
+
+    #[test]
+    fn test_wasm_type() {
+        assert_eq!(wasm_type("*mut c_char"), "u32");
+        assert_eq!(wasm_type("c_int"), "i32");
+        assert_eq!(wasm_type("c_long"), "i32");
+        assert_eq!(wasm_type("usize"), "u32");
+        assert_eq!(wasm_type("c_longlong"), "i64");
+        assert_eq!(wasm_type("c_ulonglong"), "u64");
+        assert_eq!(wasm_type("c_uint"), "u32");
+        assert_eq!(wasm_type("u64"), "u64");
+        assert_eq!(wasm_type("bool"), "i32");
+        assert_eq!(wasm_type("c_double"), "f64");
+    }
+
+    #[test]
+    fn test_wasmer_imports() {
+        let script = d::parser::parse(
+            "provider hello { probe you(char *who, int); probe send(uint8_t *data, size_t len, int *count); };",
+        )
+        .unwrap();
+        let rust = wasmer_imports(&NamingConfig::default(), &script.providers);
+
+        assert!(rust.contains(
+            "                let who = match guest_c_string(env, &store, who) {\n                    Some(pointer) => pointer as _,\n                    None => return,\n                };\n"
+        ));
+        assert!(rust.contains(
+            "                let data = match guest_pointer(env, &store, data, len as u64) {\n"
+        ));
+        assert!(rust.contains(
+            "                let count = match guest_pointer(env, &store, count, 1) {\n"
+        ));
+        assert!(rust.contains("                let len = len as _;\n"));
+        assert!(!rust.contains("data_ptr().add(who"));
+    }
+}
@@ -0,0 +1,479 @@
+//! The fingerprints skipping the rebuilds of unchanged probes, and the
+//! pregenerated files of [`Builder::pregenerate`].
+
+use super::{
+    absolute_path, backends::Shim, shim::GENERATED_DTRACE_HEADER_NAME, Backend, Builder, DebugLog,
+    LinkMode,
+};
+use crate::{
+    d::ast::Provider,
+    header::{fnv1a64, GeneratedHeader},
+    naming::NamingConfig,
+};
+use std::{
+    collections::hash_map::DefaultHasher,
+    env,
+    fs::{self, read_to_string},
+    hash::{Hash, Hasher},
+    path::Path,
+};
+
+pub(crate) const FINGERPRINT_FILE_NAME: &str = "sonde.fingerprint";
+
+/// The file listing the objects of the C shim, linked one by one with
+/// [`LinkMode::Object`].
+pub(crate) const OBJECTS_FILE_NAME: &str = "sonde-objects.txt";
+
+/// The files written by `Builder::pregenerate`: the C shim, the Rust
+/// API, and the fingerprint of what they have been generated from.
+pub(crate) const PREGENERATED_C_FILE_NAME: &str = "sonde-ffi.c";
+
+pub(crate) const PREGENERATED_RUST_FILE_NAME: &str = "sonde.rs";
+
+pub(crate) const PREGENERATED_FINGERPRINT_FILE_NAME: &str = "sonde.fingerprint";
+
+/// Environment variables read by `cc` that change the compiled shim,
+/// see [`cc_env_names`].
+pub(crate) const CC_ENV_NAMES: &[&str] = &["CC", "CFLAGS", "AR", "ARFLAGS"];
+
+/// The environment variables read by `cc` to compile for `target`, e.g.
+/// `CC`, `TARGET_CC`, `CC_x86_64-unknown-linux-gnu` and
+/// `CC_x86_64_unknown_linux_gnu`.
+pub(crate) fn cc_env_names(target: &str) -> Vec<String> {
+    let underscored_target = target.replace('-', "_");
+
+    CC_ENV_NAMES
+        .iter()
+        .flat_map(|name| {
+            [
+                name.to_string(),
+                format!("TARGET_{}", name),
+                format!("HOST_{}", name),
+                format!("{}_{}", name, target),
+                format!("{}_{}", name, underscored_target),
+            ]
+        })
+        .collect()
+}
+
+/// Write a file, unless it already has this content, so that Cargo
+/// doesn't rebuild what depends on it.
+pub(crate) fn write_if_changed(path: &Path, content: &str) {
+    if read_to_string(path).ok().as_deref() != Some(content) {
+        fs::write(path, content)
+            .unwrap_or_else(|error| panic!("Failed to write `{}`: {}", path.display(), error));
+    }
+}
+
+impl Builder {
+    /// Compile the C shim written by [`Builder::pregenerate`] in
+    /// `directory`, and use its Rust API, see
+    /// [`Builder::use_pregenerated`].
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn compile_pregenerated(
+        &self,
+        directory: &Path,
+        contents: &str,
+        providers: &[Provider],
+        modules: &[Vec<String>],
+        backend: Backend,
+        shim: &dyn Shim,
+        target: &str,
+        host: &str,
+        log: &DebugLog,
+    ) {
+        let out_dir = env::var("OUT_DIR").unwrap();
+        let fingerprint_path = directory.join(PREGENERATED_FINGERPRINT_FILE_NAME);
+
+        println!("cargo:rerun-if-changed={}", directory.display());
+
+        assert!(
+            !self.split_output,
+            "The Rust API can't be split with pregenerated files"
+        );
+        assert!(
+            !shim.links_objects(target),
+            "Pregenerated files can't be used for `{}`: `dtrace -G` must process the compiled shim",
+            target
+        );
+
+        if read_to_string(&fingerprint_path).ok().as_deref()
+            != Some(
+                self.pregenerated_fingerprint(contents, providers, modules, backend)
+                    .as_str(),
+            )
+        {
+            panic!(
+                "The files pregenerated in `{directory}` are missing or outdated: the `.d` files or \
+                 the options of the builder have changed. Run `cargo sonde gen --output {directory}` \
+                 again, or `Builder::pregenerate`.",
+                directory = directory.display()
+            );
+        }
+
+        log.log(format_args!(
+            "using the files pregenerated in `{}`",
+            directory.display()
+        ));
+
+        let lib_name = self.ffi_library_name();
+        let mut build = cc::Build::new();
+        build
+            .target(target)
+            .host(host)
+            .cargo_metadata(false)
+            .file(directory.join(PREGENERATED_C_FILE_NAME));
+
+        for hook in &self.cc_hooks {
+            hook(&mut build);
+        }
+
+        if log.enabled() {
+            log.command(
+                "compiling the C shim with",
+                &build.get_compiler().to_command(),
+            );
+        }
+
+        let objects = if self.link_mode == LinkMode::Object {
+            let objects = build.compile_intermediates();
+
+            cc::Build::new()
+                .target(target)
+                .host(host)
+                .cargo_metadata(false)
+                .objects(&objects)
+                .compile(&lib_name);
+
+            objects
+        } else {
+            build.compile(&lib_name);
+
+            Vec::new()
+        };
+
+        self.emit_link_directives(&out_dir, &lib_name, &objects, shim);
+        self.emit_rust_api_env(
+            &out_dir,
+            &absolute_path(&directory.join(PREGENERATED_RUST_FILE_NAME)),
+            providers,
+        );
+    }
+
+    /// The fingerprint of the files written by
+    /// [`Builder::pregenerate`]: the `.d` files, and the generated
+    /// sources, without their header listing the paths of the `.d`
+    /// files, so that they can be given differently.
+    pub(crate) fn pregenerated_fingerprint(
+        &self,
+        contents: &str,
+        providers: &[Provider],
+        modules: &[Vec<String>],
+        backend: Backend,
+    ) -> String {
+        let header = GeneratedHeader::new();
+
+        // The fingerprint is checked in with the pregenerated files: it's
+        // hashed with FNV-1a, stable across Rust versions. Each input is
+        // prefixed by its length, so that they can't be confused.
+        let mut inputs = Vec::new();
+
+        for input in [
+            env!("CARGO_PKG_VERSION").to_string(),
+            contents.to_string(),
+            backend.to_string(),
+            self.rust_source(&header, providers, modules, backend),
+            self.ffi_source(&header, providers, Path::new(GENERATED_DTRACE_HEADER_NAME)),
+        ] {
+            inputs.extend_from_slice(&(input.len() as u64).to_le_bytes());
+            inputs.extend_from_slice(input.as_bytes());
+        }
+
+        format!("{:016x}\n", fnv1a64(&inputs))
+    }
+
+    /// The options of the builder, as written in the header of the
+    /// generated files.
+    pub(crate) fn options(&self) -> Vec<(String, String)> {
+        let mut options = vec![
+            ("keep_h_file", self.keep_h_file.to_string()),
+            ("keep_c_file", self.keep_c_file.to_string()),
+            ("trampolines", self.trampolines.to_string()),
+            ("split_output", self.split_output.to_string()),
+            ("module_tree", self.module_tree.to_string()),
+            (
+                "is_enabled_functions",
+                self.is_enabled_functions.to_string(),
+            ),
+            ("args_structs", self.args_structs.to_string()),
+            ("probe_metadata", self.probe_metadata.to_string()),
+            ("os_str_functions", self.os_str_functions.to_string()),
+            ("bytes_functions", self.bytes_functions.to_string()),
+            ("lossy_os_str", self.lossy_os_str.to_string()),
+            ("debug_checks", self.debug_checks.to_string()),
+            ("strictness", self.strictness.to_string()),
+            ("link_mode", self.link_mode.to_string()),
+            ("allow_unknown_types", self.allow_unknown_types.to_string()),
+            ("allow_empty", self.allow_empty.to_string()),
+            ("pack_arguments", self.pack_arguments.to_string()),
+            ("preprocess", self.preprocess.to_string()),
+            ("invocation_counters", self.invocation_counters.to_string()),
+            ("export_c_abi", self.export_c_abi.to_string()),
+            ("format_output", self.format_output.to_string()),
+            ("wasmer_imports", self.wasmer_imports.to_string()),
+            ("dispatch", self.dispatch.to_string()),
+            (
+                "report_unused_probes",
+                self.report_unused_probes.to_string(),
+            ),
+            ("integer_casts", self.integer_casts.to_string()),
+            ("sampling", self.sampling.to_string()),
+            ("use_sonde_api", self.use_sonde_api.to_string()),
+            ("verbose", self.verbose.to_string()),
+        ];
+
+        if self.naming != NamingConfig::default() {
+            options.push(("naming", format!("{:?}", self.naming)));
+        }
+
+        for (provider, new_name) in &self.provider_renames {
+            options.push(("rename_provider", format!("{}={}", provider, new_name)));
+        }
+
+        for (provider, probe, new_name) in &self.probe_renames {
+            options.push((
+                "rename_probe",
+                format!("{}:{}={}", provider, probe, new_name),
+            ));
+        }
+
+        if let Some(symbol_prefix) = &self.symbol_prefix {
+            options.push(("symbol_prefix", symbol_prefix.clone()));
+        }
+
+        if let Some(namespace) = &self.namespace {
+            options.push(("namespace", namespace.clone()));
+        }
+
+        if let Some(pregenerated) = &self.pregenerated {
+            options.push(("use_pregenerated", pregenerated.display().to_string()));
+        }
+
+        if !self.cc_hooks.is_empty() {
+            options.push(("configure_cc", self.cc_hooks.len().to_string()));
+        }
+
+        if let Some(backend) = self.backend {
+            options.push(("backend", backend.to_string()));
+        }
+
+        if let Some(max_string_len) = self.max_string_len {
+            options.push(("max_string_len", max_string_len.to_string()));
+        }
+
+        if let Some(header_file) = &self.header_file {
+            options.push(("with_header", header_file.display().to_string()));
+        }
+
+        for d_glob in &self.d_globs {
+            options.push(("files_glob", d_glob.clone()));
+        }
+
+        for include_dir in &self.include_dirs {
+            options.push(("include_dir", include_dir.display().to_string()));
+        }
+
+        for (name, value) in &self.defines {
+            options.push(("define", format!("{}={}", name, value)));
+        }
+
+        if let Some(scripts_out) = &self.scripts_out {
+            options.push(("scripts_out", scripts_out.display().to_string()));
+        }
+
+        if let Some(debugger_scripts_out) = &self.debugger_scripts_out {
+            options.push((
+                "debugger_scripts_out",
+                debugger_scripts_out.display().to_string(),
+            ));
+        }
+
+        if let Some(tapset_out) = &self.tapset_out {
+            options.push(("tapset_out", tapset_out.display().to_string()));
+        }
+
+        if let Some(opentelemetry_feature) = &self.opentelemetry_feature {
+            options.push(("opentelemetry_feature", opentelemetry_feature.clone()));
+        }
+
+        if let Some(lib_name) = &self.lib_name {
+            options.push(("lib_name", lib_name.clone()));
+        }
+
+        if let Some((minimum, strictness)) = self.stability_policy {
+            options.push(("stability_policy", format!("{}:{}", strictness, minimum)));
+        }
+
+        for (provider, version) in &self.provider_versions {
+            options.push(("provider_version", format!("{}={}", provider, version)));
+        }
+
+        for test_d_file in &self.test_d_files {
+            options.push(("test_file", test_d_file.display().to_string()));
+        }
+
+        if let Some(h_out) = &self.h_out {
+            options.push(("h_out", h_out.display().to_string()));
+        }
+
+        if let Some(c_out) = &self.c_out {
+            options.push(("c_out", c_out.display().to_string()));
+        }
+
+        if let Some(ffi_header) = &self.ffi_header {
+            options.push(("ffi_header", ffi_header.display().to_string()));
+        }
+
+        if let Some(benches) = &self.benches {
+            options.push(("emit_benches", benches.display().to_string()));
+        }
+
+        if let Some(skip_env) = &self.skip_env {
+            options.push(("skip_if_env", skip_env.clone()));
+        }
+
+        if let Some(dtrace_arch) = &self.dtrace_arch {
+            options.push(("dtrace_arch", dtrace_arch.clone()));
+        }
+
+        if let Some(dtrace_path) = &self.dtrace_path {
+            options.push(("dtrace_path", dtrace_path.display().to_string()));
+        }
+
+        for dtrace_arg in &self.dtrace_args {
+            options.push(("dtrace_arg", dtrace_arg.to_string_lossy().into_owned()));
+        }
+
+        options
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    /// Compute a fingerprint of everything that affects the generated
+    /// files: the `.d` contents, the configuration, the target etc.
+    pub(crate) fn fingerprint(&self, contents: &str, target: &str, host: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        contents.hash(&mut hasher);
+        target.hash(&mut hasher);
+        host.hash(&mut hasher);
+        self.options().hash(&mut hasher);
+
+        if let Some(header_file) = &self.header_file {
+            fs::read(header_file).ok().hash(&mut hasher);
+        }
+
+        if let Some(directory) = &self.pregenerated {
+            fs::read(directory.join(PREGENERATED_FINGERPRINT_FILE_NAME))
+                .ok()
+                .hash(&mut hasher);
+        }
+
+        for name in cc_env_names(target) {
+            env::var_os(&name).hash(&mut hasher);
+        }
+
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::Parsed;
+
+    #[test]
+    fn test_pregenerate() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut builder = Builder::new();
+        builder
+            .source("hello", "provider hello { probe you(char *, int); };")
+            .backend(Backend::SystemTapSdt);
+
+        assert!(builder.pregenerate(directory.path()).is_empty());
+
+        let read = |name| read_to_string(directory.path().join(name)).unwrap();
+
+        assert!(read("probes.h").contains("STAP_PROBE2(hello, you, arg0, arg1)"));
+        assert!(read("sonde-ffi.c").contains("#include \"probes.h\""));
+        assert!(read("sonde.rs").contains("pub fn r#you("));
+
+        // The fingerprint doesn't depend on where the `.d` files are, but
+        // on what is generated from them.
+        let fingerprint = read("sonde.fingerprint");
+        let Parsed {
+            contents,
+            providers,
+            modules,
+            ..
+        } = builder.parse("", "");
+
+        assert_eq!(
+            builder.pregenerated_fingerprint(
+                &contents,
+                &providers,
+                &modules,
+                Backend::SystemTapSdt
+            ),
+            fingerprint
+        );
+        assert_ne!(
+            builder.integer_casts(true).pregenerated_fingerprint(
+                &contents,
+                &providers,
+                &modules,
+                Backend::SystemTapSdt
+            ),
+            fingerprint
+        );
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        // A target of its own, not to race with the other tests reading
+        // the environment.
+        let target = "x86_64-sonde-fingerprint";
+        let mut builder = Builder::new();
+        let fingerprint = builder.fingerprint("provider hello {};", target, "");
+
+        assert_eq!(
+            builder.fingerprint("provider hello {};", target, ""),
+            fingerprint
+        );
+
+        for name in [
+            "CC_x86_64-sonde-fingerprint",
+            "CFLAGS_x86_64_sonde_fingerprint",
+        ] {
+            env::set_var(name, "-O3");
+            assert_ne!(
+                builder.fingerprint("provider hello {};", target, ""),
+                fingerprint
+            );
+            env::remove_var(name);
+        }
+
+        assert_eq!(
+            builder.fingerprint("provider hello {};", target, ""),
+            fingerprint
+        );
+        assert_ne!(
+            builder
+                .use_pregenerated("probes")
+                .fingerprint("provider hello {};", target, ""),
+            fingerprint
+        );
+    }
+}
@@ -0,0 +1,2796 @@
+//! The generation of the Rust API of the probes.
+
+use super::{backends::wasm::wasmer_imports, shim::EXPORTED_FUNCTION_PREFIX, Backend, Builder};
+use crate::{
+    d::ast::{camel_case, rust_identifier, Enum, Names, Probe, Provider},
+    header::GeneratedHeader,
+    naming::NamingConfig,
+};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+pub(crate) const SONDE_RUST_API_FILE_ENV_NAME: &str = "SONDE_RUST_API_FILE";
+
+/// The largest buffer the `_os` functions allocate on the stack for a
+/// string argument, see `Builder::max_string_len`. Larger buffers are
+/// allocated on the heap, not to overflow the stack of the caller.
+pub(crate) const MAX_STACK_BUFFER_SIZE: usize = 1024;
+
+/// The suffixes of the pairs of probes delimiting a span, e.g.
+/// `query__start` and `query__end`.
+pub(crate) const SPAN_SUFFIXES: &[(&str, &str)] = &[("__start", "__end"), ("__entry", "__return")];
+
+/// The Rust type of the string arguments, i.e. `char *` in D.
+pub(crate) const C_STRING_TYPE: &str = "*mut c_char";
+
+/// The variant of the `Probe` enum generated by `Builder::dispatch`
+/// for a probe, e.g. `HelloYouMe` for the `you__me` probe of the
+/// `hello` provider.
+pub(crate) fn probe_variant(provider: &Provider, probe: &Probe) -> String {
+    format!(
+        "{}{}",
+        camel_case(&provider.name_for_rust()),
+        camel_case(&probe.name_for_rust())
+    )
+}
+
+/// Generate the `Probe` enum and the `fire` function of
+/// `Builder::dispatch`. `modules` are the paths of the modules
+/// containing each provider.
+pub(crate) fn dispatch(
+    naming: &NamingConfig,
+    providers: &[Provider],
+    modules: &[Vec<String>],
+) -> String {
+    let probes = providers
+        .iter()
+        .zip(modules)
+        .flat_map(|(provider, modules)| {
+            provider
+                .probes
+                .iter()
+                .map(move |probe| (provider, modules, probe))
+        })
+        .collect::<Vec<_>>();
+
+    let arms = |f: &dyn Fn(&Provider, &Probe) -> String| {
+        probes
+            .iter()
+            .map(|(provider, _, probe)| {
+                format!(
+                    "            Probe::{variant} => {value},",
+                    variant = probe_variant(provider, probe),
+                    value = f(provider, probe),
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    };
+
+    format!(
+        r#"
+/// All the probes, to fire one selected at runtime with [`fire`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Probe {{
+{variants}
+}}
+
+impl Probe {{
+    /// All the probes.
+    pub const ALL: &'static [Probe] = &[{all}];
+
+    /// The name of the provider of the probe, as seen by the tracers.
+    pub fn provider(self) -> &'static str {{
+        match self {{
+{providers}
+        }}
+    }}
+
+    /// The name of the probe, as seen by the tracers.
+    pub fn name(self) -> &'static str {{
+        match self {{
+{names}
+        }}
+    }}
+
+    /// The number of arguments of the probe.
+    pub fn arity(self) -> usize {{
+        match self {{
+{arities}
+        }}
+    }}
+}}
+
+/// An argument given to [`fire`]. It's converted to the type of the
+/// argument of the probe: the integers like with `as`, and the strings
+/// to pointers.
+#[derive(Debug, Clone, Copy)]
+pub enum Arg<'a> {{
+    /// A signed integer.
+    Int(i64),
+
+    /// An unsigned integer.
+    UInt(u64),
+
+    /// A floating-point number.
+    Float(f64),
+
+    /// A boolean, also accepted by the integer arguments.
+    Bool(bool),
+
+    /// A string, for the `char *` arguments.
+    Str(&'a std::ffi::CStr),
+
+    /// A pointer.
+    Ptr(*mut std::ffi::c_void),
+}}
+
+impl Arg<'_> {{
+    fn integer(&self, nth: usize) -> Result<i64, FireError> {{
+        match *self {{
+            Arg::Int(value) => Ok(value),
+            Arg::UInt(value) => Ok(value as i64),
+            Arg::Bool(value) => Ok(value as i64),
+            _ => Err(FireError::ArgumentType {{ nth }}),
+        }}
+    }}
+
+    fn float(&self, nth: usize) -> Result<f64, FireError> {{
+        match *self {{
+            Arg::Float(value) => Ok(value),
+            Arg::Int(value) => Ok(value as f64),
+            Arg::UInt(value) => Ok(value as f64),
+            _ => Err(FireError::ArgumentType {{ nth }}),
+        }}
+    }}
+
+    fn pointer(&self, nth: usize) -> Result<*mut std::ffi::c_void, FireError> {{
+        match *self {{
+            Arg::Str(value) => Ok(value.as_ptr() as *mut std::ffi::c_void),
+            Arg::Ptr(value) => Ok(value),
+            _ => Err(FireError::ArgumentType {{ nth }}),
+        }}
+    }}
+}}
+
+/// Why [`fire`] didn't fire a probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FireError {{
+    /// The probe has `expected` arguments, but `given` were given.
+    ArgumentCount {{ expected: usize, given: usize }},
+
+    /// The `nth` argument can't be converted to the type of the
+    /// argument of the probe.
+    ArgumentType {{ nth: usize }},
+}}
+
+impl std::fmt::Display for FireError {{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {{
+        match self {{
+            FireError::ArgumentCount {{ expected, given }} => {{
+                write!(f, "expected {{}} arguments, got {{}}", expected, given)
+            }}
+            FireError::ArgumentType {{ nth }} => {{
+                write!(f, "the argument #{{}} has the wrong type", nth)
+            }}
+        }}
+    }}
+}}
+
+impl std::error::Error for FireError {{}}
+
+/// Fire a probe selected at runtime, with its arguments.
+{allow_deprecated}pub fn fire(probe: Probe, args: &[Arg<'_>]) -> Result<(), FireError> {{
+    if args.len() != probe.arity() {{
+        return Err(FireError::ArgumentCount {{
+            expected: probe.arity(),
+            given: args.len(),
+        }});
+    }}
+
+    match probe {{
+{fires}
+    }}
+
+    Ok(())
+}}
+"#,
+        allow_deprecated = allow_deprecated(providers),
+        variants = probes
+            .iter()
+            .map(|(provider, _, probe)| {
+                format!(
+                    "    /// The `{probe}` probe of the `{provider}` provider.\n    {variant},",
+                    probe = naming.name(&probe.name),
+                    provider = naming.rust_module(&provider.name),
+                    variant = probe_variant(provider, probe),
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+        all = probes
+            .iter()
+            .map(|(provider, _, probe)| format!("Probe::{}", probe_variant(provider, probe)))
+            .collect::<Vec<String>>()
+            .join(", "),
+        providers = arms(&|provider, _| format!("{:?}", provider.name)),
+        // DTrace exposes `__` as `-` in the probe names.
+        names = arms(&|_, probe| format!("{:?}", probe.name.replace("__", "-"))),
+        arities = arms(&|_, probe| probe.arguments.len().to_string()),
+        fires = probes
+            .iter()
+            .map(|(provider, modules, probe)| {
+                format!(
+                    "        Probe::{variant} => {path}r#{provider}::r#{function}({arguments}),",
+                    variant = probe_variant(provider, probe),
+                    path = modules
+                        .iter()
+                        .map(|module| format!("{}::", module))
+                        .collect::<String>(),
+                    provider = naming.rust_module(&provider.name),
+                    function = naming.rust_function(&provider.name, &probe.name),
+                    arguments = probe
+                        .arguments_for_rust()
+                        .iter()
+                        .enumerate()
+                        .map(|(nth, (_, ty))| {
+                            if ty.starts_with("*mut ") {
+                                format!("args[{}].pointer({})? as _", nth, nth)
+                            } else if ty == "c_float" || ty == "c_double" {
+                                format!("args[{}].float({})? as _", nth, nth)
+                            } else if ty == "bool" {
+                                format!("args[{}].integer({})? != 0", nth, nth)
+                            } else {
+                                format!("args[{}].integer({})? as _", nth, nth)
+                            }
+                        })
+                        .collect::<Vec<String>>()
+                        .join(", "),
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+    )
+}
+
+/// Find the pairs of probes delimiting a span, e.g. `query__start` and
+/// `query__end`, as `(base name, start probe, end probe)`, e.g. `query`
+/// for the base name. The end probe must have no arguments, or the same
+/// argument types as the start probe.
+pub(crate) fn spans(provider: &Provider) -> Vec<(&str, &Probe, &Probe)> {
+    provider
+        .probes
+        .iter()
+        .filter_map(|start| {
+            SPAN_SUFFIXES.iter().find_map(|(start_suffix, end_suffix)| {
+                let base = start.name.strip_suffix(start_suffix)?;
+                let end_name = format!("{}{}", base, end_suffix);
+                let end = provider.probes.iter().find(|end| end.name == end_name)?;
+
+                let same_types = end
+                    .arguments
+                    .iter()
+                    .map(|argument| &argument.ty)
+                    .eq(start.arguments.iter().map(|argument| &argument.ty));
+
+                if end.arguments.is_empty() || same_types {
+                    Some((base, start, end))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+/// Nest items, e.g. provider modules, in the modules of their paths.
+/// The items of a module are joined with `separator`. The nested
+/// modules import everything from their parent, so that the items can
+/// still refer to the FFI functions with `super::`.
+pub(crate) fn module_tree(items: &[(&[String], String)], separator: &str) -> String {
+    let mut output = items
+        .iter()
+        .filter(|(path, _)| path.is_empty())
+        .map(|(_, item)| item.clone())
+        .collect::<Vec<_>>();
+
+    let mut modules: Vec<&String> = Vec::new();
+
+    for (path, _) in items {
+        if let Some(module) = path.first() {
+            if !modules.contains(&module) {
+                modules.push(module);
+            }
+        }
+    }
+
+    for module in modules {
+        let children = items
+            .iter()
+            .filter(|(path, _)| path.first() == Some(module))
+            .map(|(path, item)| (&path[1..], item.clone()))
+            .collect::<Vec<_>>();
+
+        output.push(format!(
+            "/// Probes from `{module}`.\npub mod {module} {{\n#[allow(unused)]\nuse super::*;\n\n{children}\n}}",
+            module = module,
+            children = module_tree(&children, separator),
+        ));
+    }
+
+    output.join(separator)
+}
+
+/// The name of the Rust enum of a D enum, e.g. `State` for `state`.
+pub(crate) fn rust_enum_name(enum_: &Enum) -> String {
+    rust_identifier(&camel_case(&enum_.name.to_lowercase()))
+}
+
+/// Generate the Rust enum of a D enum, e.g. `State` for `enum state`.
+/// The common prefix of the enumerators, the name of the enum, is left
+/// out, e.g. `STATE_BUSY` is `State::Busy`. The enumerators with the
+/// same value as a previous one are associated constants.
+pub(crate) fn rust_enum(enum_: &Enum) -> String {
+    let prefix = format!("{}_", enum_.name.to_uppercase());
+    let strip_prefix = enum_.variants.iter().all(|variant| {
+        variant
+            .name
+            .strip_prefix(&prefix)
+            .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_alphabetic()))
+    });
+    let variant_name = |name: &str| {
+        let name = if strip_prefix {
+            &name[prefix.len()..]
+        } else {
+            name
+        };
+
+        rust_identifier(&camel_case(&name.to_lowercase()))
+    };
+
+    let enum_name = rust_enum_name(enum_);
+    let mut variants = String::new();
+    let mut aliases = String::new();
+
+    for (nth, variant) in enum_.variants.iter().enumerate() {
+        match enum_.variants[..nth]
+            .iter()
+            .find(|other| other.value == variant.value)
+        {
+            Some(other) => aliases.push_str(&format!(
+                "        /// `{name}`, the same value as `{other}`.\n        pub const {alias}: Self = Self::{variant};\n",
+                name = variant.name,
+                other = other.name,
+                variant = variant_name(&other.name),
+                alias = variant_name(&variant.name),
+            )),
+            None => variants.push_str(&format!(
+                "        /// `{name}`.\n        {variant} = {value},\n",
+                name = variant.name,
+                variant = variant_name(&variant.name),
+                value = variant.value,
+            )),
+        }
+    }
+
+    format!(
+        r#"{doc}
+    ///
+    /// Its values are given to the probes as `c_int`, e.g. `{enum_name}::{first} as c_int`.
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum {enum_name} {{
+{variants}    }}
+{aliases}
+    impl From<{enum_name}> for c_int {{
+        fn from(value: {enum_name}) -> Self {{
+            value as c_int
+        }}
+    }}
+
+"#,
+        doc = doc_comment(
+            enum_.doc.as_deref(),
+            &format!("The `{}` enum of the `.d` files.", enum_.name),
+            "    "
+        ),
+        enum_name = enum_name,
+        first = enum_
+            .variants
+            .first()
+            .map_or_else(|| "…".to_string(), |variant| variant_name(&variant.name)),
+        variants = variants,
+        aliases = if aliases.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "\n    #[allow(non_upper_case_globals)]\n    impl {} {{\n{}    }}\n",
+                enum_name, aliases
+            )
+        },
+    )
+}
+
+/// The `#[allow(deprecated)]` attribute of the generated items calling
+/// the functions of all the probes, if some are deprecated.
+pub(crate) fn allow_deprecated(providers: &[Provider]) -> &'static str {
+    if providers
+        .iter()
+        .flat_map(|provider| &provider.probes)
+        .any(|probe| probe.deprecated.is_some())
+    {
+        "#[allow(deprecated)]\n"
+    } else {
+        ""
+    }
+}
+
+/// The `#[deprecated]` attribute of the functions of a probe declared
+/// with a `@deprecated` annotation, if any.
+pub(crate) fn deprecated_attribute(probe: &Probe) -> String {
+    match probe.deprecated.as_deref() {
+        Some("") => "    #[deprecated]\n".to_string(),
+        Some(note) => format!("    #[deprecated(note = {:?})]\n", note),
+        None => String::new(),
+    }
+}
+
+/// Render a documentation, or `default` if there is none, as Rust doc
+/// comments indented by `indent`.
+pub(crate) fn doc_comment(doc: Option<&str>, default: &str, indent: &str) -> String {
+    doc.unwrap_or(default)
+        .lines()
+        .map(|line| {
+            if line.is_empty() {
+                format!("{}///", indent)
+            } else {
+                format!("{}/// {}", indent, line)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Find the string arguments of a probe, i.e. `char *`, given as
+/// `impl AsRef<OsStr>` to the generated `_os` function, and return
+/// their positions. The strings paired with their length aren't: their
+/// length would be the one of the caller, see `byte_slice_arguments`.
+pub(crate) fn os_str_arguments_positions(probe: &Probe) -> Vec<usize> {
+    let pointers = byte_slice_arguments(probe);
+
+    probe
+        .arguments_for_rust()
+        .iter()
+        .enumerate()
+        .filter(|(nth, (_, ty))| ty == C_STRING_TYPE && !pointers.contains(nth))
+        .map(|(nth, _)| nth)
+        .collect()
+}
+
+/// Check whether a probe has a `_os` function, see
+/// `os_str_arguments_positions`.
+pub(crate) fn has_os_str_argument(probe: &Probe) -> bool {
+    !os_str_arguments_positions(probe).is_empty()
+}
+
+/// The Rust integer types of the generated API, i.e. the arguments
+/// accepted as any Rust integer by `Builder::integer_casts`.
+pub(crate) const INTEGER_TYPES: &[&str] = &[
+    "c_char",
+    "c_schar",
+    "c_uchar",
+    "c_short",
+    "c_ushort",
+    "c_int",
+    "c_uint",
+    "c_long",
+    "c_ulong",
+    "c_longlong",
+    "c_ulonglong",
+    "i8",
+    "i16",
+    "i32",
+    "i64",
+    "isize",
+    "u8",
+    "u16",
+    "u32",
+    "u64",
+    "usize",
+];
+
+/// The Rust types of the signed integers. `c_char` isn't one of them,
+/// its signedness depends on the platform.
+pub(crate) const SIGNED_INTEGER_TYPES: &[&str] = &[
+    "c_schar",
+    "c_short",
+    "c_int",
+    "c_long",
+    "c_longlong",
+    "i8",
+    "i16",
+    "i32",
+    "i64",
+    "isize",
+];
+
+/// The message of a failed check of an argument, see
+/// `Builder::debug_checks`.
+pub(crate) fn argument_check_message(
+    naming: &NamingConfig,
+    provider: &Provider,
+    probe: &Probe,
+    argument: &str,
+    problem: &str,
+) -> String {
+    format!(
+        "The `{}` argument of the `{}` probe of the `{}` provider {}.",
+        argument,
+        naming.name(&probe.name),
+        naming.rust_module(&provider.name),
+        problem
+    )
+}
+
+pub(crate) fn is_integer_type(ty: &str) -> bool {
+    INTEGER_TYPES.contains(&ty)
+}
+
+/// The Rust types of the pointers that can be given as a byte slice,
+/// when followed by their length.
+pub(crate) const BYTE_POINTER_TYPES: &[&str] = &[
+    "*mut c_char",
+    "*mut c_schar",
+    "*mut c_uchar",
+    "*mut i8",
+    "*mut u8",
+];
+
+/// Return the positions of the pointers to bytes of a probe paired
+/// with the next argument, a `size_t` or an integer named after a
+/// length, e.g. `data_len`.
+pub(crate) fn byte_slice_arguments(probe: &Probe) -> Vec<usize> {
+    let arguments = probe.arguments_for_rust();
+    let mut pointers = Vec::new();
+    let mut nth = 0;
+
+    while nth + 1 < arguments.len() {
+        let (pointer, pointer_type) = &arguments[nth];
+        let (length, length_type) = &arguments[nth + 1];
+        let named_length = ["len", "length", "size"]
+            .iter()
+            .any(|suffix| length == suffix || *length == format!("{}_{}", pointer, suffix));
+
+        if BYTE_POINTER_TYPES.contains(&pointer_type.as_str())
+            && (probe.arguments[nth + 1].ty.trim() == "size_t"
+                || (named_length && is_integer_type(length_type)))
+        {
+            pointers.push(nth);
+            nth += 2;
+        } else {
+            nth += 1;
+        }
+    }
+
+    pointers
+}
+
+/// The arguments of the function generated by `os_str_wrapper`, with
+/// the string arguments as `impl AsRef<OsStr>`.
+pub(crate) fn os_str_arguments(probe: &Probe) -> String {
+    let positions = os_str_arguments_positions(probe);
+
+    probe
+        .arguments_for_rust()
+        .iter()
+        .enumerate()
+        .map(|(nth, (name, ty))| {
+            if positions.contains(&nth) {
+                format!("{}: impl AsRef<std::ffi::OsStr>", name)
+            } else {
+                format!("{}: {}", name, ty)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub(crate) fn has_integer_argument(probe: &Probe) -> bool {
+    probe
+        .arguments_for_rust()
+        .iter()
+        .any(|(_, ty)| is_integer_type(ty))
+}
+
+/// Generate the `CastInto` trait used by the functions generated by
+/// `Builder::integer_cast_wrapper`, implemented with `as` between all
+/// the Rust integers. The C integer types are aliases of them.
+pub(crate) fn integer_cast_helper(use_sonde_api: bool) -> String {
+    if use_sonde_api {
+        return "\n\n    pub use ::sonde_api::CastInto;".to_string();
+    }
+
+    r#"
+
+    /// A Rust integer converted, with `as`, to the integer type of a probe
+    /// argument.
+    pub trait CastInto<T> {
+        fn cast_into(self) -> T;
+    }
+
+    macro_rules! cast_into {
+        ($($to:ty)*) => {
+            $(cast_into!(@from $to, i8 i16 i32 i64 i128 isize u8 u16 u32 u64 u128 usize);)*
+        };
+        (@from $to:ty, $($from:ty)*) => {
+            $(impl CastInto<$to> for $from {
+                #[inline(always)]
+                fn cast_into(self) -> $to {
+                    self as $to
+                }
+            })*
+        };
+    }
+
+    cast_into!(i8 i16 i32 i64 isize u8 u16 u32 u64 usize);"#
+        .to_string()
+}
+
+/// Generate the functions deciding whether the `_sampled` and
+/// `_rate_limited` variants fire their probe, see `Builder::sampling`.
+pub(crate) fn sampling_helper(use_sonde_api: bool) -> String {
+    if use_sonde_api {
+        return "\n\n    use ::sonde_api::{rate_limited as __sonde_rate_limited, sampled as __sonde_sampled};"
+            .to_string();
+    }
+
+    r#"
+
+    /// Whether a call of a `_sampled` function fires its probe: once every
+    /// `every` calls, counted by `calls`. With `every` being 0 or 1, all
+    /// the calls fire it.
+    fn __sonde_sampled(calls: &std::sync::atomic::AtomicU64, every: u64) -> bool {
+        every <= 1 || calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed) % every == 0
+    }
+
+    /// Whether a call of a `_rate_limited` function fires its probe: at
+    /// most `per_second` times per second. `window` holds the current
+    /// second in its high 32 bits, and the number of fired probes in this
+    /// second in its low 32 bits.
+    fn __sonde_rate_limited(window: &std::sync::atomic::AtomicU64, per_second: u32) -> bool {
+        use std::sync::atomic::Ordering;
+
+        if per_second == 0 {
+            return false;
+        }
+
+        let second = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as u32)
+            .unwrap_or(0) as u64;
+        let mut current = window.load(Ordering::Relaxed);
+
+        loop {
+            let next = if current >> 32 != second {
+                (second << 32) | 1
+            } else if current & 0xffff_ffff < per_second as u64 {
+                current + 1
+            } else {
+                return false;
+            };
+
+            match window.compare_exchange_weak(current, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+    }"#
+    .to_string()
+}
+
+/// Pretty-print Rust code with `prettyplease`. The leading `//`
+/// comments, i.e. the generated header, are kept as is: `prettyplease`
+/// drops the comments.
+pub(crate) fn format_rust(source: &str) -> String {
+    let code_start = source
+        .lines()
+        .take_while(|line| line.starts_with("//") && !line.starts_with("///"))
+        .map(|line| line.len() + 1)
+        .sum::<usize>()
+        .min(source.len());
+
+    let file = syn::parse_file(&source[code_start..]).unwrap_or_else(|error| {
+        panic!(
+            "The generated Rust code is invalid, it's a bug in `sonde`: {}",
+            error
+        )
+    });
+
+    format!(
+        "{header}\n{code}",
+        header = &source[..code_start],
+        code = prettyplease::unparse(&file)
+    )
+}
+
+/// The path of the Rust API file of a provider, when the output is split.
+pub(crate) fn provider_rs_path(out_dir: &str, provider: &Provider) -> PathBuf {
+    Path::new(out_dir).join(format!("sonde-{}.rs", provider.name_for_c()))
+}
+
+impl Builder {
+    /// Nest the provider modules in modules mirroring the layout of the
+    /// `.d` files, e.g. `storage::disk::…` for `probes/storage/disk.d`.
+    pub fn module_tree(&mut self, tree: bool) -> &mut Self {
+        self.module_tree = tree;
+
+        self
+    }
+
+    /// Generate a `Probe` enum listing all the probes, e.g.
+    /// `Probe::HelloYou`, and a `fire(probe, args)` function firing a
+    /// probe selected at runtime, with its arguments given as `Arg`s,
+    /// e.g. for plugin hosts or scripting layers.
+    pub fn dispatch(&mut self, generate: bool) -> &mut Self {
+        self.dispatch = generate;
+
+        self
+    }
+
+    /// Write the Rust API, in one file or split per provider, and tell
+    /// Cargo where it is. `modules` are the paths of the modules
+    /// containing each provider, see [`Builder::module_tree`].
+    /// `backend` tells where the probes come from.
+    pub(crate) fn write_rust_api(
+        &self,
+        out_dir: &str,
+        rs_path: &Path,
+        header: &GeneratedHeader,
+        providers: &[Provider],
+        modules: &[Vec<String>],
+        backend: Backend,
+    ) {
+        // The Wasmer imports cover all the providers, so they are
+        // written once, in the main file.
+        let wasmer_imports = if self.wasmer_imports && backend != Backend::Wasm {
+            wasmer_imports(&self.naming, providers)
+        } else {
+            String::new()
+        };
+
+        // The dispatch covers all the providers too.
+        let dispatch = if self.dispatch {
+            dispatch(&self.naming, providers, modules)
+        } else {
+            String::new()
+        };
+
+        if self.split_output {
+            let mut index = format!(
+                "{header}\n// Bindings from Rust to the C FFI small library that calls the\n// probes, split per provider.\n\n",
+                header = header,
+            );
+
+            let mut includes = Vec::with_capacity(providers.len());
+
+            for (provider, modules) in providers.iter().zip(modules) {
+                let provider_rs_path = provider_rs_path(out_dir, provider);
+
+                fs::write(
+                    &provider_rs_path,
+                    self.formatted(self.rust_api(
+                        header,
+                        std::slice::from_ref(provider),
+                        &[Vec::new()],
+                        backend,
+                    )),
+                )
+                .unwrap();
+
+                includes.push((
+                    modules.as_slice(),
+                    format!(
+                        "include!({path:?});",
+                        path = provider_rs_path.display().to_string()
+                    ),
+                ));
+            }
+
+            index.push_str(&module_tree(&includes, "\n"));
+            index.push('\n');
+
+            index.push_str(&wasmer_imports);
+            index.push_str(&dispatch);
+
+            fs::write(rs_path, self.formatted(index)).unwrap();
+        } else {
+            let mut rust_api = self.rust_api(header, providers, modules, backend);
+            rust_api.push_str(&wasmer_imports);
+            rust_api.push_str(&dispatch);
+
+            fs::write(rs_path, self.formatted(rust_api)).unwrap();
+        }
+
+        self.emit_rust_api_env(out_dir, rs_path, providers);
+    }
+
+    /// Generate the Rust API in one file, as returned by
+    /// [`Builder::generate`].
+    pub(crate) fn rust_source(
+        &self,
+        header: &GeneratedHeader,
+        providers: &[Provider],
+        modules: &[Vec<String>],
+        backend: Backend,
+    ) -> String {
+        let mut rust = self.rust_api(header, providers, modules, backend);
+
+        if self.wasmer_imports && backend != Backend::Wasm {
+            rust.push_str(&wasmer_imports(&self.naming, providers));
+        }
+
+        if self.dispatch {
+            rust.push_str(&dispatch(&self.naming, providers, modules));
+        }
+
+        self.formatted(rust)
+    }
+
+    /// Pretty-print generated Rust code, with [`Builder::format_output`].
+    pub(crate) fn formatted(&self, source: String) -> String {
+        if self.format_output {
+            format_rust(&source)
+        } else {
+            source
+        }
+    }
+
+    /// Generate the Rust API for the given providers, nested in the
+    /// given modules.
+    pub(crate) fn rust_api(
+        &self,
+        header: &GeneratedHeader,
+        providers: &[Provider],
+        modules: &[Vec<String>],
+        backend: Backend,
+    ) -> String {
+        format!(
+            r#"{header}
+// Bindings from Rust to the C FFI small library that calls the
+// probes.
+
+#[allow(unused)]
+use std::os::raw::*;
+
+{ffi}
+
+{wrappers}
+"#,
+            header = header,
+            ffi = backend.implementation().ffi_declarations(self, &self.ffi_providers(providers), modules),
+            wrappers = module_tree(&providers
+                .iter()
+                .zip(modules)
+                .map(|(provider, modules)| {
+                    (modules.as_slice(), format!(
+                        r#"{provider_doc}
+pub mod r#{provider_name} {{
+{allow_deprecated}    #[allow(unused)]
+    use std::os::raw::*;
+
+{version}{enums}{probes}{spans}{enabled_registry}{os_str_helper}{integer_cast_helper}{sampling_helper}{opentelemetry_sink}{stats}{backend_items}{exports}
+}}"#,
+                        provider_doc = doc_comment(
+                            provider.doc.as_deref(),
+                            &format!(
+                                "Probes for the `{}` provider.",
+                                self.naming.rust_module(&provider.name)
+                            ),
+                            "",
+                        ),
+                        provider_name = self.naming.rust_module(&provider.name),
+                        // The generated functions call the deprecated
+                        // probes: only the callers outside are warned.
+                        allow_deprecated = if provider.probes.iter().any(|probe| probe.deprecated.is_some()) {
+                            "    #![allow(deprecated)]\n\n"
+                        } else {
+                            ""
+                        },
+                        version = match &provider.version {
+                            Some(version) => format!(
+                                "    /// The version of the `{provider_name}` provider.\n    pub const VERSION: &str = {version:?};\n\n",
+                                provider_name = self.naming.rust_module(&provider.name),
+                                version = version,
+                            ),
+                            None => String::new(),
+                        },
+                        enums = provider.enums.iter().map(rust_enum).collect::<String>(),
+                        spans = spans(provider)
+                            .into_iter()
+                            .map(|(base, start, end)| self.span(provider, base, start, end))
+                            .collect::<String>(),
+                        enabled_registry = if self.is_enabled_functions && !provider.probes.is_empty() {
+                            self.enabled_registry(provider)
+                        } else {
+                            String::new()
+                        },
+                        os_str_helper = if provider.probes.iter().any(|probe| self.has_os_str_function(probe)) {
+                            self.os_str_helper()
+                        } else {
+                            String::new()
+                        },
+                        integer_cast_helper = if self.integer_casts && provider.probes.iter().any(has_integer_argument) {
+                            integer_cast_helper(self.use_sonde_api)
+                        } else {
+                            String::new()
+                        },
+                        sampling_helper = if self.sampling && !provider.probes.is_empty() {
+                            sampling_helper(self.use_sonde_api)
+                        } else {
+                            String::new()
+                        },
+                        exports = if self.export_c_abi {
+                            self.exports(provider)
+                        } else {
+                            String::new()
+                        },
+                        stats = if self.counts_invocations() {
+                            self.stats(provider)
+                        } else {
+                            String::new()
+                        },
+                        opentelemetry_sink = if provider.probes.is_empty() {
+                            String::new()
+                        } else {
+                            self.opentelemetry_sink()
+                        },
+                        backend_items = backend.implementation().provider_items(self.use_sonde_api),
+                        probes = provider
+                            .probes
+                            .iter()
+                            .enumerate()
+                            .map(|(nth, probe)| {
+                                format!(
+                                    r#"{probe_doc}
+{deprecated}    pub fn r#{function_name}({arguments}) {{
+{checks}{counter}{call}{opentelemetry_event}
+    }}{is_enabled}{arguments_struct}{os_str_wrapper}{bytes_wrapper}{integer_cast_wrapper}{sampling_wrappers}{trampoline}{metadata}"#,
+                                    probe_doc = doc_comment(
+                                        probe.doc.as_deref(),
+                                        &format!(
+                                            "Call the `{}` probe of the `{}` provider.",
+                                            self.naming.name(&probe.name),
+                                            self.naming.rust_module(&provider.name)
+                                        ),
+                                        "    ",
+                                    ),
+                                    function_name = self.naming.rust_function(&provider.name, &probe.name),
+                                    deprecated = deprecated_attribute(probe),
+                                    checks = self.argument_checks(provider, probe),
+                                    opentelemetry_event = self.opentelemetry_event(provider, probe),
+                                    counter = if self.counts_invocations() {
+                                        format!(
+                                            "        INVOCATIONS[{}].fetch_add(1, std::sync::atomic::Ordering::Relaxed);\n",
+                                            nth
+                                        )
+                                    } else {
+                                        String::new()
+                                    },
+                                    call = if self.packs(probe) {
+                                        format!(
+                                            "        let args = {struct_name} {{ {argument_names} }};\n        unsafe {{ super::{ffi_function}(&args as *const {struct_name} as *mut c_void) }};",
+                                            struct_name = self.naming.arguments_struct(&provider.name, &probe.name),
+                                            argument_names = probe.argument_names_for_rust(),
+                                            ffi_function = self.naming.c_function(provider, &probe.name),
+                                        )
+                                    } else {
+                                        format!(
+                                            "        unsafe {{ super::{ffi_function}({argument_names}) }};",
+                                            ffi_function = self.naming.c_function(provider, &probe.name),
+                                            argument_names = probe.argument_names_for_rust(),
+                                        )
+                                    },
+                                    is_enabled = if self.is_enabled_functions {
+                                        format!(
+                                            r#"
+
+    /// Check whether a tracer is attached to the `{probe_name}` probe of the
+    /// `{provider_name}` provider.
+    pub fn r#{function_name}_is_enabled() -> bool {{
+        unsafe {{ super::{ffi_function}_enabled() != 0 }}
+    }}"#,
+                                            provider_name = self.naming.rust_module(&provider.name),
+                                            probe_name = self.naming.name(&probe.name),
+                                            function_name = self.naming.rust_function(&provider.name, &probe.name),
+                                            ffi_function = self.naming.c_function(provider, &probe.name),
+                                        )
+                                    } else {
+                                        String::new()
+                                    },
+                                    arguments = probe.arguments_for_c_from_rust(),
+                                    os_str_wrapper = if self.has_os_str_function(probe) {
+                                        self.os_str_wrapper(provider, probe)
+                                    } else {
+                                        String::new()
+                                    },
+                                    bytes_wrapper = if self.has_bytes_function(probe) {
+                                        self.bytes_wrapper(provider, probe)
+                                    } else {
+                                        String::new()
+                                    },
+                                    integer_cast_wrapper = if self.integer_casts && has_integer_argument(probe) {
+                                        self.integer_cast_wrapper(provider, probe)
+                                    } else {
+                                        String::new()
+                                    },
+                                    sampling_wrappers = if self.sampling {
+                                        self.sampling_wrappers(provider, probe)
+                                    } else {
+                                        String::new()
+                                    },
+                                    arguments_struct = if !self.has_arguments_struct(probe) {
+                                        String::new()
+                                    } else {
+                                        format!(
+                                            r#"
+
+    /// The arguments of the `{probe_name}` probe.{repr}
+    #[derive(Debug, Clone, Copy)]
+    pub struct {struct_name} {{
+{fields}
+    }}{with}"#,
+                                            probe_name = self.naming.name(&probe.name),
+                                            struct_name = self.naming.arguments_struct(&provider.name, &probe.name),
+                                            repr = if self.packs(probe) {
+                                                format!(
+                                                    "\n    ///\n    /// They are packed in this struct, as `struct {}` in C, when the probe\n    /// is fired: DTrace doesn't support so many arguments.\n    #[repr(C)]",
+                                                    self.naming.packed_struct(&provider.name, &probe.name)
+                                                )
+                                            } else {
+                                                String::new()
+                                            },
+                                            fields = probe
+                                                .arguments_for_rust()
+                                                .iter()
+                                                .map(|(name, ty)| format!(
+                                                    "        pub {}: {},",
+                                                    name, ty
+                                                ))
+                                                .collect::<Vec<String>>()
+                                                .join("\n"),
+                                            with = if self.args_structs {
+                                                format!(
+                                                    r#"
+
+    /// Call the `{probe_name}` probe of the `{provider_name}` provider, with
+    /// its arguments given as a struct.
+{deprecated}    pub fn r#{function_name}_with(args: {struct_name}) {{
+        r#{function_name}({fields_access});
+    }}"#,
+                                                    provider_name = self.naming.rust_module(&provider.name),
+                                                    probe_name = self.naming.name(&probe.name),
+                                                    function_name = self.naming.rust_function(&provider.name, &probe.name),
+                                                    struct_name = self.naming.arguments_struct(&provider.name, &probe.name),
+                                                    deprecated = deprecated_attribute(probe),
+                                                    fields_access = probe
+                                                        .arguments_for_rust()
+                                                        .iter()
+                                                        .map(|(name, _)| format!("args.{}", name))
+                                                        .collect::<Vec<String>>()
+                                                        .join(", "),
+                                                )
+                                            } else {
+                                                String::new()
+                                            },
+                                        )
+                                    },
+                                    trampoline = if self.trampolines {
+                                        format!(
+                                            r#"
+
+    /// An `extern "C"` trampoline calling the `{probe_name}` probe of the
+    /// `{provider_name}` provider, to be given as a callback to C code.
+{deprecated}    pub extern "C" fn r#{function_name}_trampoline({arguments}) {{
+        r#{function_name}({argument_names});
+    }}"#,
+                                            provider_name = self.naming.rust_module(&provider.name),
+                                            probe_name = self.naming.name(&probe.name),
+                                            function_name = self.naming.rust_function(&provider.name, &probe.name),
+                                            deprecated = deprecated_attribute(probe),
+                                            arguments = probe.arguments_for_c_from_rust(),
+                                            argument_names = probe.argument_names_for_rust(),
+                                        )
+                                    } else {
+                                        String::new()
+                                    },
+                                    metadata = if self.probe_metadata {
+                                        self.metadata_module(provider, probe)
+                                    } else {
+                                        String::new()
+                                    },
+                                )
+                            })
+                            .collect::<Vec<String>>()
+                            .join("\n\n")
+                    ))
+                })
+                .collect::<Vec<_>>(), "\n\n")
+        )
+    }
+
+    /// Generate the guard type of a span, firing the `start` probe when
+    /// it's created, and the `end` probe when it's dropped.
+    pub(crate) fn span(
+        &self,
+        provider: &Provider,
+        base: &str,
+        start: &Probe,
+        end: &Probe,
+    ) -> String {
+        let arguments = start.arguments_for_rust();
+
+        // The end probe receives the same arguments as the start probe,
+        // if it has some.
+        let fields = if end.arguments.is_empty() {
+            vec![("_private".to_string(), "()".to_string())]
+        } else {
+            arguments.clone()
+        };
+
+        format!(
+            r#"
+
+    /// A span of the `{base}` probes of the `{provider_name}` provider: the
+    /// `{start_name}` probe is fired when the span is created, and the
+    /// `{end_name}` probe when it's dropped.
+    #[must_use = "The end probe is fired when the span is dropped"]
+    pub struct {struct_name} {{
+{fields}
+    }}
+
+    /// Fire the `{start_name}` probe of the `{provider_name}` provider, and
+    /// return a span firing the `{end_name}` probe when it's dropped.
+    pub fn r#{function_name}({arguments}) -> {struct_name} {{
+        r#{start_function}({argument_names});
+
+        {struct_name} {{ {fields_init} }}
+    }}
+
+    impl Drop for {struct_name} {{
+        fn drop(&mut self) {{
+            r#{end_function}({end_arguments});
+        }}
+    }}"#,
+            base = self.naming.name(base),
+            provider_name = self.naming.rust_module(&provider.name),
+            start_name = self.naming.name(&start.name),
+            end_name = self.naming.name(&end.name),
+            struct_name = format!("{}Span", camel_case(&self.naming.name(base))),
+            function_name = self
+                .naming
+                .rust_function(&provider.name, &format!("{}__span", base)),
+            start_function = self.naming.rust_function(&provider.name, &start.name),
+            end_function = self.naming.rust_function(&provider.name, &end.name),
+            arguments = start.arguments_for_c_from_rust(),
+            argument_names = start.argument_names_for_rust(),
+            fields = fields
+                .iter()
+                .map(|(name, ty)| format!("        {}: {},", name, ty))
+                .collect::<Vec<String>>()
+                .join("\n"),
+            fields_init = if end.arguments.is_empty() {
+                "_private: ()".to_string()
+            } else {
+                start.argument_names_for_rust()
+            },
+            end_arguments = if end.arguments.is_empty() {
+                String::new()
+            } else {
+                arguments
+                    .iter()
+                    .map(|(name, _)| format!("self.{}", name))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            },
+        )
+    }
+
+    /// The name of the function exported to C firing a probe, see
+    /// [`Builder::export_c_abi`].
+    pub(crate) fn exported_function(&self, provider: &Provider, probe: &Probe) -> String {
+        format!(
+            "{}{}",
+            EXPORTED_FUNCTION_PREFIX,
+            self.naming.c_function(provider, &probe.name)
+        )
+    }
+
+    /// Generate the functions exported to C for the probes of a
+    /// provider.
+    pub(crate) fn exports(&self, provider: &Provider) -> String {
+        provider
+            .probes
+            .iter()
+            .map(|probe| {
+                format!(
+                    r#"
+
+    /// Call the `{probe_name}` probe of the `{provider_name}` provider, from C.
+    #[no_mangle]
+    pub extern "C" fn {exported_function}({arguments}) {{
+        r#{function_name}({argument_names});
+    }}
+
+    /// Check whether a tracer is attached to the `{probe_name}` probe of the
+    /// `{provider_name}` provider, from C.
+    #[no_mangle]
+    pub extern "C" fn {exported_function}_enabled() -> c_int {{
+        unsafe {{ super::{ffi_function}_enabled() }}
+    }}"#,
+                    provider_name = self.naming.rust_module(&provider.name),
+                    probe_name = self.naming.name(&probe.name),
+                    function_name = self.naming.rust_function(&provider.name, &probe.name),
+                    exported_function = self.exported_function(provider, probe),
+                    ffi_function = self.naming.c_function(provider, &probe.name),
+                    arguments = probe.arguments_for_c_from_rust(),
+                    argument_names = probe.argument_names_for_rust(),
+                )
+            })
+            .collect()
+    }
+
+    /// Whether the probes are counted, see
+    /// [`Builder::invocation_counters`].
+    pub(crate) fn counts_invocations(&self) -> bool {
+        self.invocation_counters && env::var_os("CARGO_CFG_DEBUG_ASSERTIONS").is_some()
+    }
+
+    /// Generate the invocation counters of a provider, and the `stats`
+    /// and `reset_stats` functions.
+    pub(crate) fn stats(&self, provider: &Provider) -> String {
+        format!(
+            r#"
+
+    static INVOCATIONS: [std::sync::atomic::AtomicU64; {count}] = [
+{counters}
+    ];
+
+    /// The number of times each probe of the `{provider_name}` provider has
+    /// been fired, by probe name (as declared in the `.d` file).
+    pub fn stats() -> std::collections::HashMap<&'static str, u64> {{
+        [{names}]
+            .iter()
+            .zip(INVOCATIONS.iter())
+            .map(|(name, count)| (*name, count.load(std::sync::atomic::Ordering::Relaxed)))
+            .collect()
+    }}
+
+    /// Reset the counts returned by `stats`.
+    pub fn reset_stats() {{
+        for count in INVOCATIONS.iter() {{
+            count.store(0, std::sync::atomic::Ordering::Relaxed);
+        }}
+    }}"#,
+            provider_name = self.naming.rust_module(&provider.name),
+            count = provider.probes.len(),
+            counters = provider
+                .probes
+                .iter()
+                .map(|_| "        std::sync::atomic::AtomicU64::new(0),")
+                .collect::<Vec<_>>()
+                .join("\n"),
+            names = provider
+                .probes
+                .iter()
+                .map(|probe| format!("{:?}", probe.name))
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }
+
+    /// Generate the registry of the callbacks called when a tracer
+    /// attaches to or detaches from a probe of a provider.
+    pub(crate) fn enabled_registry(&self, provider: &Provider) -> String {
+        let arms = provider
+            .probes
+            .iter()
+            .map(|probe| {
+                format!(
+                    "            {name:?} => r#{function_name}_is_enabled,",
+                    name = probe.name,
+                    function_name = self.naming.rust_function(&provider.name, &probe.name),
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        if self.use_sonde_api {
+            return format!(
+                r#"
+
+    static ENABLED_CHANGE_CALLBACKS: ::sonde_api::EnabledRegistry =
+        ::sonde_api::EnabledRegistry::new();
+
+    /// Register a callback, called with the new state of the probe named
+    /// `probe` when a tracer attaches to it or detaches from it.
+    ///
+    /// Panics if the `{provider_name}` provider has no such probe.
+    pub fn on_enabled_change<F>(probe: &str, callback: F)
+    where
+        F: Fn(bool) + Send + 'static,
+    {{
+        let is_enabled: fn() -> bool = match probe {{
+{arms}
+            _ => panic!("The `{provider_name}` provider has no `{{}}` probe", probe),
+        }};
+
+        ENABLED_CHANGE_CALLBACKS.register(is_enabled, callback);
+    }}
+
+    /// Call the callbacks registered with `on_enabled_change` of the
+    /// probes whose state has changed, e.g. periodically from a thread.
+    pub fn poll_enabled_changes() {{
+        ENABLED_CHANGE_CALLBACKS.poll();
+    }}"#,
+                provider_name = self.naming.rust_module(&provider.name),
+                arms = arms,
+            );
+        }
+
+        format!(
+            r#"
+
+    type EnabledChangeCallback = (fn() -> bool, bool, Box<dyn Fn(bool) + Send>);
+
+    static ENABLED_CHANGE_CALLBACKS: std::sync::Mutex<Vec<EnabledChangeCallback>> =
+        std::sync::Mutex::new(Vec::new());
+
+    /// Register a callback, called with the new state of the probe named
+    /// `probe` when a tracer attaches to it or detaches from it.
+    ///
+    /// Panics if the `{provider_name}` provider has no such probe.
+    pub fn on_enabled_change<F>(probe: &str, callback: F)
+    where
+        F: Fn(bool) + Send + 'static,
+    {{
+        let is_enabled: fn() -> bool = match probe {{
+{arms}
+            _ => panic!("The `{provider_name}` provider has no `{{}}` probe", probe),
+        }};
+
+        ENABLED_CHANGE_CALLBACKS
+            .lock()
+            .unwrap()
+            .push((is_enabled, false, Box::new(callback)));
+    }}
+
+    /// Call the callbacks registered with `on_enabled_change` of the
+    /// probes whose state has changed, e.g. periodically from a thread.
+    pub fn poll_enabled_changes() {{
+        let mut callbacks = std::mem::take(&mut *ENABLED_CHANGE_CALLBACKS.lock().unwrap());
+
+        for (is_enabled, last_state, callback) in callbacks.iter_mut() {{
+            let state = is_enabled();
+
+            if state != *last_state {{
+                *last_state = state;
+                callback(state);
+            }}
+        }}
+
+        // Put the callbacks back, before the ones registered meanwhile.
+        let mut registered = ENABLED_CHANGE_CALLBACKS.lock().unwrap();
+        callbacks.append(&mut registered);
+        *registered = callbacks;
+    }}"#,
+            provider_name = self.naming.rust_module(&provider.name),
+            arms = arms,
+        )
+    }
+
+    /// Generate the module holding the metadata of a probe as constants,
+    /// see [`Builder::probe_metadata`].
+    pub(crate) fn metadata_module(&self, provider: &Provider, probe: &Probe) -> String {
+        format!(
+            r#"
+
+    /// Metadata of the `{probe_name}` probe.
+    pub mod r#{function_name} {{
+        /// The name of the probe, as seen by the tracers.
+        pub const NAME: &str = {name:?};
+
+        /// The name of the provider of the probe, as seen by the tracers.
+        pub const PROVIDER: &str = {provider:?};
+
+        /// The types of the arguments of the probe, as declared in the
+        /// `.d` file.
+        pub const ARG_TYPES: &[&str] = &[{arg_types}];{level}
+    }}"#,
+            probe_name = self.naming.name(&probe.name),
+            // DTrace exposes `__` as `-` in the probe names.
+            name = probe.name.replace("__", "-"),
+            provider = provider.name,
+            function_name = self.naming.rust_function(&provider.name, &probe.name),
+            arg_types = probe
+                .arguments
+                .iter()
+                .map(|argument| format!("{:?}", argument.ty))
+                .collect::<Vec<String>>()
+                .join(", "),
+            level = match &probe.level {
+                Some(level) => format!(
+                    r#"
+
+        /// The level of the probe, declared with `@level` in the `.d` file.
+        pub const LEVEL: &str = {:?};"#,
+                    level
+                ),
+                None => String::new(),
+            },
+        )
+    }
+
+    /// Whether the struct of the arguments of a probe is generated, see
+    /// [`Builder::args_structs`].
+    pub(crate) fn has_arguments_struct(&self, probe: &Probe) -> bool {
+        !probe.arguments.is_empty() && (self.args_structs || self.packs(probe))
+    }
+
+    /// Whether an `_os` function is generated for a probe, see
+    /// [`Builder::os_str_functions`].
+    pub(crate) fn has_os_str_function(&self, probe: &Probe) -> bool {
+        self.os_str_functions && has_os_str_argument(probe)
+    }
+
+    /// Whether a `_bytes` function is generated for a probe, see
+    /// [`Builder::bytes_functions`].
+    pub(crate) fn has_bytes_function(&self, probe: &Probe) -> bool {
+        self.bytes_functions && !byte_slice_arguments(probe).is_empty()
+    }
+
+    /// Generate the `debug_assert!` checks of the arguments of a probe,
+    /// see [`Builder::debug_checks`].
+    pub(crate) fn argument_checks(&self, provider: &Provider, probe: &Probe) -> String {
+        if !self.debug_checks {
+            return String::new();
+        }
+
+        let arguments = probe.arguments_for_rust();
+        let pointers = byte_slice_arguments(probe);
+        let mut checks = String::new();
+
+        for (nth, (name, ty)) in arguments.iter().enumerate() {
+            if pointers.contains(&nth) {
+                let (length, length_type) = &arguments[nth + 1];
+
+                checks.push_str(&format!(
+                    "        debug_assert!(!{name}.is_null() || {length} == 0, {message:?}, {length});\n",
+                    name = name,
+                    length = length,
+                    message = argument_check_message(
+                        &self.naming,
+                        provider,
+                        probe,
+                        name,
+                        "is null, with a length of {}"
+                    ),
+                ));
+
+                if SIGNED_INTEGER_TYPES.contains(&length_type.as_str()) {
+                    checks.push_str(&format!(
+                        "        debug_assert!({length} >= 0, {message:?}, {length});\n",
+                        length = length,
+                        message = argument_check_message(
+                            &self.naming,
+                            provider,
+                            probe,
+                            length,
+                            "is negative: {}"
+                        ),
+                    ));
+                }
+            } else if ty == C_STRING_TYPE {
+                checks.push_str(&format!(
+                    "        debug_assert!(!{name}.is_null(), {message:?});\n",
+                    name = name,
+                    message =
+                        argument_check_message(&self.naming, provider, probe, name, "is null"),
+                ));
+            }
+        }
+
+        checks
+    }
+
+    /// Generate a function calling a probe with the string arguments
+    /// given as `impl AsRef<OsStr>`, e.g. `&Path`, `&OsStr` or `&str`.
+    pub(crate) fn os_str_wrapper(&self, provider: &Provider, probe: &Probe) -> String {
+        let arguments = probe.arguments_for_rust();
+        let positions = os_str_arguments_positions(probe);
+
+        format!(
+            r#"
+
+    /// Call the `{probe_name}` probe of the `{provider_name}` provider, with
+    /// the string arguments given as `&Path`, `&OsStr`, `&str` etc.
+{deprecated}    pub fn r#{function_name}_os({arguments}) {{
+{conversions}
+        r#{function_name}({argument_names});
+    }}"#,
+            provider_name = self.naming.rust_module(&provider.name),
+            probe_name = self.naming.name(&probe.name),
+            function_name = self.naming.rust_function(&provider.name, &probe.name),
+            deprecated = deprecated_attribute(probe),
+            arguments = os_str_arguments(probe),
+            conversions = positions
+                .iter()
+                .map(|nth| {
+                    let name = &arguments[*nth].0;
+                    let checks = if self.debug_checks {
+                        format!(
+                            "        debug_assert!(!{name}.as_ref().to_string_lossy().contains('\\0'), {nul:?});\n",
+                            name = name,
+                            nul = argument_check_message(&self.naming, provider, probe, name, "contains a NUL byte"),
+                        )
+                    } else {
+                        String::new()
+                    };
+
+                    match self.max_string_len {
+                        Some(length) => format!(
+                            "{checks}        let mut {name}_buffer = {buffer};\n        let {name} = os_str_to_c_buffer({name}.as_ref(), &mut {name}_buffer);",
+                            checks = checks,
+                            name = name,
+                            buffer = if length < MAX_STACK_BUFFER_SIZE {
+                                format!("[0u8; {}]", length + 1)
+                            } else {
+                                format!("vec![0u8; {}]", length + 1)
+                            },
+                        ),
+                        None => format!(
+                            "{checks}        let {name} = os_str_to_c_string({name}.as_ref());",
+                            checks = checks,
+                            name = name
+                        ),
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+            argument_names = arguments
+                .iter()
+                .enumerate()
+                .map(|(nth, (name, _))| {
+                    if positions.contains(&nth) && self.max_string_len.is_none() {
+                        format!("{}.as_ptr() as *mut c_char", name)
+                    } else {
+                        name.clone()
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(", "),
+        )
+    }
+
+    /// Generate a function calling a probe with each `(pointer, length)`
+    /// pair of arguments given as a single `impl AsRef<[u8]>`, see
+    /// `byte_slice_arguments`.
+    pub(crate) fn bytes_wrapper(&self, provider: &Provider, probe: &Probe) -> String {
+        let arguments = probe.arguments_for_rust();
+        let pointers = byte_slice_arguments(probe);
+        let is_length = |nth: usize| nth > 0 && pointers.contains(&(nth - 1));
+
+        format!(
+            r#"
+
+    /// Call the `{probe_name}` probe of the `{provider_name}` provider, with
+    /// the `(pointer, length)` arguments given as `&[u8]`, `&str` etc.
+{deprecated}    pub fn r#{function_name}_bytes({arguments}) {{
+        r#{function_name}({argument_names});
+    }}"#,
+            provider_name = self.naming.rust_module(&provider.name),
+            probe_name = self.naming.name(&probe.name),
+            function_name = self.naming.rust_function(&provider.name, &probe.name),
+            deprecated = deprecated_attribute(probe),
+            arguments = arguments
+                .iter()
+                .enumerate()
+                .filter(|(nth, _)| !is_length(*nth))
+                .map(|(nth, (name, ty))| {
+                    if pointers.contains(&nth) {
+                        format!("{}: impl AsRef<[u8]>", name)
+                    } else {
+                        format!("{}: {}", name, ty)
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(", "),
+            argument_names = arguments
+                .iter()
+                .enumerate()
+                .map(|(nth, (name, _))| {
+                    if pointers.contains(&nth) {
+                        format!("{}.as_ref().as_ptr() as _", name)
+                    } else if is_length(nth) {
+                        match self.max_string_len {
+                            Some(length) => format!(
+                                "{}.as_ref().len().min({}) as _",
+                                arguments[nth - 1].0,
+                                length
+                            ),
+                            None => format!("{}.as_ref().len() as _", arguments[nth - 1].0),
+                        }
+                    } else {
+                        name.clone()
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(", "),
+        )
+    }
+
+    /// Generate a function calling a probe with the integer arguments
+    /// given as any Rust integer, see [`Builder::integer_casts`].
+    pub(crate) fn integer_cast_wrapper(&self, provider: &Provider, probe: &Probe) -> String {
+        let arguments = probe.arguments_for_rust();
+
+        format!(
+            r#"
+
+    /// Call the `{probe_name}` probe of the `{provider_name}` provider, with
+    /// the integer arguments given as any Rust integer, converted with `as`.
+{deprecated}    pub fn r#{function_name}_cast({arguments}) {{
+        r#{function_name}({argument_names});
+    }}"#,
+            provider_name = self.naming.rust_module(&provider.name),
+            probe_name = self.naming.name(&probe.name),
+            function_name = self.naming.rust_function(&provider.name, &probe.name),
+            deprecated = deprecated_attribute(probe),
+            arguments = arguments
+                .iter()
+                .map(|(name, ty)| {
+                    if is_integer_type(ty) {
+                        format!("{}: impl CastInto<{}>", name, ty)
+                    } else {
+                        format!("{}: {}", name, ty)
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(", "),
+            argument_names = arguments
+                .iter()
+                .map(|(name, ty)| {
+                    if is_integer_type(ty) {
+                        format!("{}.cast_into()", name)
+                    } else {
+                        name.clone()
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(", "),
+        )
+    }
+
+    /// Generate the `_sampled` and `_rate_limited` variants of a probe,
+    /// see [`Builder::sampling`].
+    /// The variants of `_os` and `_with` skip the call before converting
+    /// the arguments.
+    pub(crate) fn sampling_wrappers(&self, provider: &Provider, probe: &Probe) -> String {
+        let mut variants = vec![(
+            "",
+            "",
+            probe.arguments_for_c_from_rust(),
+            probe.argument_names_for_rust(),
+        )];
+
+        if self.has_os_str_function(probe) {
+            variants.push((
+                "_os",
+                "\n    ///\n    /// The string arguments are given as `&Path`, `&OsStr`, `&str` etc.,\n    /// and converted only when the probe is fired.",
+                os_str_arguments(probe),
+                probe.argument_names_for_rust(),
+            ));
+        }
+
+        if self.args_structs && !probe.arguments.is_empty() {
+            variants.push((
+                "_with",
+                "\n    ///\n    /// The arguments are given as a struct.",
+                format!(
+                    "args: {}",
+                    self.naming.arguments_struct(&provider.name, &probe.name)
+                ),
+                "args".to_string(),
+            ));
+        }
+
+        variants
+            .into_iter()
+            .map(|(suffix, given, arguments, argument_names)| {
+                format!(
+                    r#"
+
+    /// Call the `{probe_name}` probe of the `{provider_name}` provider once
+    /// every `every` calls.{given}
+{deprecated}    pub fn r#{function_name}{suffix}_sampled(every: u64{arguments}) {{
+        static CALLS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+        if __sonde_sampled(&CALLS, every) {{
+            r#{function_name}{suffix}({argument_names});
+        }}
+    }}
+
+    /// Call the `{probe_name}` probe of the `{provider_name}` provider at most
+    /// `per_second` times per second, skipping the other calls.{given}
+{deprecated}    pub fn r#{function_name}{suffix}_rate_limited(per_second: u32{arguments}) {{
+        static WINDOW: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+        if __sonde_rate_limited(&WINDOW, per_second) {{
+            r#{function_name}{suffix}({argument_names});
+        }}
+    }}"#,
+                    provider_name = self.naming.rust_module(&provider.name),
+                    probe_name = self.naming.name(&probe.name),
+                    function_name = self.naming.rust_function(&provider.name, &probe.name),
+                    deprecated = deprecated_attribute(probe),
+                    suffix = suffix,
+                    given = given,
+                    arguments = if arguments.is_empty() {
+                        arguments
+                    } else {
+                        format!(", {}", arguments)
+                    },
+                    argument_names = argument_names,
+                )
+            })
+            .collect()
+    }
+
+    /// Generate the statement adding an event to the active
+    /// OpenTelemetry span when a probe is fired, see
+    /// [`Builder::opentelemetry_feature`].
+    pub(crate) fn opentelemetry_event(&self, provider: &Provider, probe: &Probe) -> String {
+        let feature = match &self.opentelemetry_feature {
+            Some(feature) => feature,
+            None => return String::new(),
+        };
+
+        let attributes = probe
+            .arguments_for_rust()
+            .iter()
+            .map(|(name, ty)| {
+                // The arguments are not trusted: the pointers, including
+                // the C strings, are recorded as addresses, never read.
+                let value = if ty.starts_with('*') {
+                    format!("{} as usize as i64", name)
+                } else if ty == "bool" {
+                    name.clone()
+                } else if ty == "c_float" || ty == "c_double" {
+                    format!("{} as f64", name)
+                } else {
+                    format!("{} as i64", name)
+                };
+
+                format!(
+                    "\n                        ::opentelemetry::KeyValue::new({:?}, {}),",
+                    name.trim_start_matches("r#"),
+                    value
+                )
+            })
+            .collect::<String>();
+
+        format!(
+            r#"
+
+        #[cfg(feature = {feature:?})]
+        __SONDE_OPENTELEMETRY.catch(|| {{
+            ::opentelemetry::trace::get_active_span(|span| {{
+                span.add_event(
+                    "{provider}:{probe}",
+                    vec![{attributes}{newline}],
+                )
+            }})
+        }});"#,
+            feature = feature,
+            provider = provider.name,
+            // DTrace exposes `__` as `-` in the probe names.
+            probe = probe.name.replace("__", "-"),
+            attributes = attributes,
+            newline = if attributes.is_empty() {
+                ""
+            } else {
+                "\n                    "
+            },
+        )
+    }
+
+    /// Generate the sink of the OpenTelemetry events of a provider, see
+    /// [`Builder::opentelemetry_feature`]. A panic of an event doesn't
+    /// unwind through the caller of the probe.
+    pub(crate) fn opentelemetry_sink(&self) -> String {
+        let feature = match &self.opentelemetry_feature {
+            Some(feature) => feature,
+            None => return String::new(),
+        };
+
+        if self.use_sonde_api {
+            return format!(
+                r#"
+
+    #[cfg(feature = {feature:?})]
+    static __SONDE_OPENTELEMETRY: ::sonde_api::Sink = ::sonde_api::Sink::new("opentelemetry");"#,
+                feature = feature,
+            );
+        }
+
+        format!(
+            r#"
+
+    /// The sink of the OpenTelemetry events, logging their first panic.
+    #[cfg(feature = {feature:?})]
+    struct __SondeSink(std::sync::atomic::AtomicBool);
+
+    #[cfg(feature = {feature:?})]
+    impl __SondeSink {{
+        fn catch<F: FnOnce()>(&self, event: F) {{
+            if std::panic::catch_unwind(std::panic::AssertUnwindSafe(event)).is_err()
+                && !self.0.swap(true, std::sync::atomic::Ordering::Relaxed)
+            {{
+                eprintln!("sonde: the `opentelemetry` sink failed");
+            }}
+        }}
+    }}
+
+    #[cfg(feature = {feature:?})]
+    static __SONDE_OPENTELEMETRY: __SondeSink = __SondeSink(std::sync::atomic::AtomicBool::new(false));"#,
+            feature = feature,
+        )
+    }
+
+    /// Generate the function converting an `OsStr` to a C string, used
+    /// by the functions generated by `os_str_wrapper`.
+    pub(crate) fn os_str_helper(&self) -> String {
+        if self.max_string_len.is_some() {
+            return self.os_str_buffer_helper();
+        }
+
+        if self.use_sonde_api {
+            return format!(
+                r#"
+
+    /// Convert an `OsStr` to a C string, truncated at the first NUL byte.
+    fn os_str_to_c_string(string: &std::ffi::OsStr) -> std::ffi::CString {{
+        ::sonde_api::os_str_to_c_string(string, {lossy})
+    }}"#,
+                lossy = self.lossy_os_str
+            );
+        }
+
+        let bytes = if self.lossy_os_str {
+            "string.to_string_lossy().into_owned().into_bytes()"
+        } else {
+            r#"{
+            #[cfg(unix)]
+            {
+                std::os::unix::ffi::OsStrExt::as_bytes(string).to_vec()
+            }
+
+            #[cfg(not(unix))]
+            {
+                string.to_string_lossy().into_owned().into_bytes()
+            }
+        }"#
+        };
+
+        format!(
+            r#"
+
+    /// Convert an `OsStr` to a C string, truncated at the first NUL byte.
+    fn os_str_to_c_string(string: &std::ffi::OsStr) -> std::ffi::CString {{
+        let mut bytes = {bytes};
+
+        if let Some(nul) = bytes.iter().position(|byte| *byte == 0) {{
+            bytes.truncate(nul);
+        }}
+
+        std::ffi::CString::new(bytes).expect("The string has no NUL byte")
+    }}"#,
+            bytes = bytes
+        )
+    }
+
+    /// Generate the function copying the `&OsStr` arguments of the `_os`
+    /// functions to a buffer on the stack, see
+    /// [`Builder::max_string_len`].
+    pub(crate) fn os_str_buffer_helper(&self) -> String {
+        if self.use_sonde_api {
+            return format!(
+                r#"
+
+    /// Copy an `OsStr` to `buffer` as a C string, truncated at the first
+    /// NUL byte or to fit the buffer.
+    fn os_str_to_c_buffer(string: &std::ffi::OsStr, buffer: &mut [u8]) -> *mut c_char {{
+        ::sonde_api::os_str_to_c_buffer(string, {lossy}, buffer)
+    }}"#,
+                lossy = self.lossy_os_str
+            );
+        }
+
+        let bytes = if self.lossy_os_str {
+            "match string.to_string_lossy() {
+            std::borrow::Cow::Borrowed(string) => std::borrow::Cow::Borrowed(string.as_bytes()),
+            std::borrow::Cow::Owned(string) => std::borrow::Cow::Owned(string.into_bytes()),
+        }"
+        } else {
+            r#"{
+            #[cfg(unix)]
+            {
+                std::borrow::Cow::Borrowed(std::os::unix::ffi::OsStrExt::as_bytes(string))
+            }
+
+            #[cfg(not(unix))]
+            {
+                std::borrow::Cow::<[u8]>::Owned(string.to_string_lossy().into_owned().into_bytes())
+            }
+        }"#
+        };
+
+        format!(
+            r#"
+
+    /// Copy an `OsStr` to `buffer` as a C string, truncated at the first
+    /// NUL byte or to fit the buffer, without splitting a UTF-8
+    /// character.
+    fn os_str_to_c_buffer(string: &std::ffi::OsStr, buffer: &mut [u8]) -> *mut c_char {{
+        let bytes = {bytes};
+        let bytes = match bytes.iter().position(|byte| *byte == 0) {{
+            Some(nul) => &bytes[..nul],
+            None => &bytes[..],
+        }};
+        let mut length = bytes.len().min(buffer.len() - 1);
+
+        if let Ok(string) = std::str::from_utf8(bytes) {{
+            while !string.is_char_boundary(length) {{
+                length -= 1;
+            }}
+        }}
+
+        buffer[..length].copy_from_slice(&bytes[..length]);
+        buffer[length] = 0;
+
+        buffer.as_mut_ptr() as *mut c_char
+    }}"#,
+            bytes = bytes
+        )
+    }
+
+    /// Tell Cargo where the generated Rust API files are.
+    pub(crate) fn emit_rust_api_env(&self, out_dir: &str, rs_path: &Path, providers: &[Provider]) {
+        for (name, path) in self.rust_api_env(out_dir, rs_path, providers) {
+            println!("cargo:rustc-env={}={}", name, path.display());
+        }
+    }
+
+    /// The environment variables giving the paths of the generated Rust
+    /// API files: the main file, and with [`Builder::split_output`], the
+    /// file of each provider.
+    pub(crate) fn rust_api_env(
+        &self,
+        out_dir: &str,
+        rs_path: &Path,
+        providers: &[Provider],
+    ) -> Vec<(String, PathBuf)> {
+        let mut variables = vec![(
+            SONDE_RUST_API_FILE_ENV_NAME.to_string(),
+            rs_path.to_path_buf(),
+        )];
+
+        if self.split_output {
+            for provider in providers {
+                variables.push((
+                    format!(
+                        "{name}_{provider}",
+                        name = SONDE_RUST_API_FILE_ENV_NAME,
+                        provider = provider.name_for_c_macro(),
+                    ),
+                    provider_rs_path(out_dir, provider),
+                ));
+            }
+        }
+
+        variables
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        builder::{scripts::benches, Parsed},
+        d,
+    };
+    use std::fs::read_to_string;
+
+    #[test]
+    fn test_module_tree() {
+        let net = vec!["net".to_string()];
+        let disk = vec!["storage".to_string(), "disk".to_string()];
+
+        assert_eq!(
+            module_tree(
+                &[
+                    (&[], "mod a {}".to_string()),
+                    (&net, "mod b {}".to_string()),
+                    (&disk, "mod c {}".to_string()),
+                ],
+                "\n"
+            ),
+            "mod a {}
+/// Probes from `net`.
+pub mod net {
+#[allow(unused)]
+use super::*;
+
+mod b {}
+}
+/// Probes from `storage`.
+pub mod storage {
+#[allow(unused)]
+use super::*;
+
+/// Probes from `disk`.
+pub mod disk {
+#[allow(unused)]
+use super::*;
+
+mod c {}
+}
+}"
+        );
+    }
+
+    #[test]
+    fn test_opaque_pointers() {
+        let mut builder = Builder::new();
+        builder.source(
+            "hello",
+            "provider hello { probe req(struct request *, union value *r); };",
+        );
+
+        let generated = builder.generate();
+
+        assert!(generated
+            .rust
+            .contains("    pub fn r#req(arg0: *mut c_void, r: *mut c_void) {"));
+        // The structs are declared before the `dtrace` header, which
+        // uses them.
+        assert!(generated
+            .c
+            .contains("struct request;\n\nunion value;\n\n#include \"probes.h\""));
+        assert!(generated.c.contains(
+            "void hello_probe_req(struct request * arg0, union value * r) {\n    HELLO_REQ(arg0, r);\n}"
+        ));
+        assert!(generated
+            .header
+            .contains("void hello_probe_req(struct request * arg0, union value * r);"));
+        assert!(generated
+            .header
+            .contains("struct request;\n\nunion value;\n"));
+    }
+
+    #[test]
+    fn test_integer_casts() {
+        let mut builder = Builder::new();
+        builder.source(
+            "hello",
+            "provider hello { probe you(char *, int); probe me(float); };",
+        );
+
+        assert!(!builder.generate().rust.contains("CastInto"));
+
+        let rust = builder.integer_casts(true).generate().rust;
+
+        assert!(rust.contains(
+            "    pub fn r#you_cast(arg0: *mut c_char, arg1: impl CastInto<c_int>) {\n        r#you(arg0, arg1.cast_into());\n    }"
+        ));
+        assert!(!rust.contains("fn r#me_cast("));
+        assert_eq!(rust.matches("pub trait CastInto<T>").count(), 1);
+    }
+
+    #[test]
+    fn test_debug_checks() {
+        let mut builder = Builder::new();
+        builder.source(
+            "hello",
+            "provider hello { probe you(char *who, int); probe send(uint8_t *data, int len); probe me(); };",
+        );
+        builder.os_str_functions(true);
+
+        assert!(!builder.generate().rust.contains("debug_assert!"));
+
+        let rust = builder.debug_checks(true).generate().rust;
+
+        assert!(rust.contains(
+            "    pub fn r#you(who: *mut c_char, arg1: c_int) {\n        debug_assert!(!who.is_null(), \"The `who` argument of the `you` probe of the `hello` provider is null.\");\n        unsafe {"
+        ));
+        assert!(rust.contains(
+            "        debug_assert!(!data.is_null() || len == 0, \"The `data` argument of the `send` probe of the `hello` provider is null, with a length of {}.\", len);\n        debug_assert!(len >= 0, \"The `len` argument of the `send` probe of the `hello` provider is negative: {}.\", len);\n"
+        ));
+        assert!(rust.contains(
+            "        debug_assert!(!who.as_ref().to_string_lossy().contains('\\0'), \"The `who` argument of the `you` probe of the `hello` provider contains a NUL byte.\");\n        let who = os_str_to_c_string(who.as_ref());"
+        ));
+        assert!(!rust.contains("to_str().is_some()"));
+        assert!(rust.contains("    pub fn r#me() {\n        unsafe {"));
+    }
+
+    #[test]
+    fn test_opentelemetry_feature() {
+        let mut builder = Builder::new();
+        builder.source(
+            "hello",
+            "provider hello { probe you__me(char *who, int, double type, struct request *); probe world(); };",
+        );
+
+        assert!(!builder.generate().rust.contains("opentelemetry"));
+
+        let rust = builder.opentelemetry_feature("otel").generate().rust;
+
+        assert!(rust.contains(
+            "        #[cfg(feature = \"otel\")]\n        __SONDE_OPENTELEMETRY.catch(|| {\n            ::opentelemetry::trace::get_active_span(|span| {\n                span.add_event(\n                    \"hello:you-me\",\n                    vec![\n                        ::opentelemetry::KeyValue::new(\"who\", who as usize as i64),\n                        ::opentelemetry::KeyValue::new(\"arg1\", arg1 as i64),\n                        ::opentelemetry::KeyValue::new(\"type\", r#type as f64),\n                        ::opentelemetry::KeyValue::new(\"arg3\", arg3 as usize as i64),\n                    ],\n                )\n            })\n        });\n    }"
+        ));
+        assert!(rust.contains(
+            "                span.add_event(\n                    \"hello:world\",\n                    vec![],\n                )"
+        ));
+        assert!(!rust.contains("opentelemetry_string"));
+        assert!(rust.contains("static __SONDE_OPENTELEMETRY: __SondeSink"));
+
+        let rust = builder.use_sonde_api(true).generate().rust;
+
+        assert!(rust.contains(
+            "    #[cfg(feature = \"otel\")]\n    static __SONDE_OPENTELEMETRY: ::sonde_api::Sink = ::sonde_api::Sink::new(\"opentelemetry\");"
+        ));
+        assert!(!rust.contains("__SondeSink"));
+    }
+
+    #[test]
+    fn test_sampling() {
+        let mut builder = Builder::new();
+        builder.source(
+            "hello",
+            "provider hello { probe you(char *, int); probe me(); };",
+        );
+
+        assert!(!builder.generate().rust.contains("_sampled"));
+
+        let rust = builder
+            .os_str_functions(true)
+            .args_structs(true)
+            .sampling(true)
+            .generate()
+            .rust;
+
+        assert!(rust.contains(
+            "    pub fn r#you_sampled(every: u64, arg0: *mut c_char, arg1: c_int) {\n        static CALLS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);\n\n        if __sonde_sampled(&CALLS, every) {\n            r#you(arg0, arg1);\n        }\n    }"
+        ));
+        assert!(rust.contains("    pub fn r#me_rate_limited(per_second: u32) {"));
+        assert!(rust.contains(
+            "    pub fn r#you_os_sampled(every: u64, arg0: impl AsRef<std::ffi::OsStr>, arg1: c_int) {\n        static CALLS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);\n\n        if __sonde_sampled(&CALLS, every) {\n            r#you_os(arg0, arg1);\n        }\n    }"
+        ));
+        assert!(
+            rust.contains("    pub fn r#you_with_rate_limited(per_second: u32, args: YouArgs) {")
+        );
+        assert!(!rust.contains("r#me_os_sampled"));
+        assert!(!rust.contains("r#me_with_sampled"));
+        assert_eq!(rust.matches("    fn __sonde_rate_limited(").count(), 1);
+
+        let rust = builder.use_sonde_api(true).generate().rust;
+
+        assert!(rust.contains(
+            "    use ::sonde_api::{rate_limited as __sonde_rate_limited, sampled as __sonde_sampled};"
+        ));
+        assert!(!rust.contains("    fn __sonde_rate_limited("));
+    }
+
+    #[test]
+    fn test_trampolines() {
+        let mut builder = Builder::new();
+        builder.source(
+            "hello",
+            "provider hello { probe you(char *who, int); probe me(); };",
+        );
+
+        let rust = builder.generate().rust;
+
+        assert!(!rust.contains("_trampoline("));
+
+        let rust = builder.trampolines(true).generate().rust;
+
+        assert!(rust.contains(
+            "    pub extern \"C\" fn r#you_trampoline(who: *mut c_char, arg1: c_int) {\n        r#you(who, arg1);\n    }"
+        ));
+        assert!(
+            rust.contains("    pub extern \"C\" fn r#me_trampoline() {\n        r#me();\n    }")
+        );
+    }
+
+    #[test]
+    fn test_os_str_functions() {
+        let mut builder = Builder::new();
+        builder.source("hello", "provider hello { probe you(char *who, int); };");
+
+        let rust = builder.generate().rust;
+
+        assert!(!rust.contains("fn r#you_os("));
+        assert!(!rust.contains("fn os_str_to_c_string("));
+
+        let rust = builder.os_str_functions(true).generate().rust;
+
+        assert!(rust.contains(
+            "    pub fn r#you_os(who: impl AsRef<std::ffi::OsStr>, arg1: c_int) {\n        let who = os_str_to_c_string(who.as_ref());\n        r#you(who.as_ptr() as *mut c_char, arg1);\n    }"
+        ));
+    }
+
+    #[test]
+    fn test_enabled_changes() {
+        let mut builder = Builder::new();
+        builder
+            .source(
+                "hello",
+                "provider hello { probe you(char *who, int); probe me(); };",
+            )
+            .is_enabled_functions(true);
+
+        let generated = builder.generate();
+
+        assert!(generated
+            .c
+            .contains("int hello_probe_you_enabled(void) {\n    return HELLO_YOU_ENABLED();\n}"));
+        assert!(generated.rust.contains(
+            "    pub fn r#you_is_enabled() -> bool {\n        unsafe { super::hello_probe_you_enabled() != 0 }\n    }"
+        ));
+        assert!(generated.rust.contains(
+            "        let is_enabled: fn() -> bool = match probe {\n            \"you\" => r#you_is_enabled,\n            \"me\" => r#me_is_enabled,\n            _ => panic!(\"The `hello` provider has no `{}` probe\", probe),\n        };"
+        ));
+        assert!(generated.rust.contains(
+            "static ENABLED_CHANGE_CALLBACKS: std::sync::Mutex<Vec<EnabledChangeCallback>>"
+        ));
+        assert!(generated
+            .rust
+            .contains("    pub fn poll_enabled_changes() {"));
+
+        let rust = builder.use_sonde_api(true).generate().rust;
+
+        assert!(rust.contains(
+            "static ENABLED_CHANGE_CALLBACKS: ::sonde_api::EnabledRegistry =\n        ::sonde_api::EnabledRegistry::new();"
+        ));
+        assert!(rust.contains("        ENABLED_CHANGE_CALLBACKS.register(is_enabled, callback);"));
+        assert!(rust.contains("        ENABLED_CHANGE_CALLBACKS.poll();"));
+    }
+
+    #[test]
+    fn test_lossy_os_str() {
+        let mut builder = Builder::new();
+        builder
+            .source("hello", "provider hello { probe you(char *who); };")
+            .os_str_functions(true);
+
+        let rust = builder.generate().rust;
+
+        assert!(rust.contains("std::os::unix::ffi::OsStrExt::as_bytes(string).to_vec()"));
+        assert!(rust.contains(
+            "    fn os_str_to_c_string(string: &std::ffi::OsStr) -> std::ffi::CString {\n        let mut bytes = {\n            #[cfg(unix)]"
+        ));
+
+        let rust = builder.lossy_os_str(true).generate().rust;
+
+        assert!(!rust.contains("OsStrExt"));
+        assert!(rust.contains(
+            "        let mut bytes = string.to_string_lossy().into_owned().into_bytes();"
+        ));
+
+        let rust = builder.use_sonde_api(true).generate().rust;
+
+        assert!(rust.contains("        ::sonde_api::os_str_to_c_string(string, true)"));
+    }
+
+    #[test]
+    fn test_max_string_len() {
+        let mut builder = Builder::new();
+        builder.source(
+            "hello",
+            "provider hello { probe you(char *who, int); probe send(uint8_t *data, size_t); };",
+        );
+
+        let rust = builder
+            .os_str_functions(true)
+            .bytes_functions(true)
+            .max_string_len(63)
+            .generate()
+            .rust;
+
+        assert!(rust.contains(
+            "    pub fn r#you_os(who: impl AsRef<std::ffi::OsStr>, arg1: c_int) {\n        let mut who_buffer = [0u8; 64];\n        let who = os_str_to_c_buffer(who.as_ref(), &mut who_buffer);\n        r#you(who, arg1);\n    }"
+        ));
+        assert!(rust.contains("    fn os_str_to_c_buffer(string: &std::ffi::OsStr, buffer: &mut [u8]) -> *mut c_char {\n        let bytes = {"));
+        assert!(!rust.contains("fn os_str_to_c_string("));
+        assert!(rust.contains(
+            "        r#send(data.as_ref().as_ptr() as _, data.as_ref().len().min(63) as _);"
+        ));
+
+        let rust = builder.use_sonde_api(true).generate().rust;
+
+        assert!(rust.contains("        ::sonde_api::os_str_to_c_buffer(string, false, buffer)"));
+
+        let rust = builder.max_string_len(1023).generate().rust;
+
+        assert!(rust.contains("        let mut who_buffer = [0u8; 1024];\n"));
+
+        let rust = builder.max_string_len(4096).generate().rust;
+
+        assert!(rust.contains("        let mut who_buffer = vec![0u8; 4097];\n"));
+    }
+
+    #[test]
+    fn test_byte_slice_arguments() {
+        let rust = Builder::new()
+            .source(
+                "hello",
+                "provider hello {
+                    probe you(char *, int);
+                    probe send(int fd, uint8_t *data, size_t, char *name, int name_len);
+                    probe recv(unsigned char *buf, unsigned int len);
+                    probe count(char *, long count);
+                };",
+            )
+            .os_str_functions(true)
+            .bytes_functions(true)
+            .generate()
+            .rust;
+
+        assert!(!Builder::new()
+            .source(
+                "hello",
+                "provider hello { probe send(char *data, size_t len); };"
+            )
+            .generate()
+            .rust
+            .contains("fn r#send_bytes("));
+        assert!(!rust.contains("fn r#you_bytes("));
+        assert!(rust.contains(
+            "    pub fn r#send_bytes(fd: c_int, data: impl AsRef<[u8]>, name: impl AsRef<[u8]>) {\n        r#send(fd, data.as_ref().as_ptr() as _, data.as_ref().len() as _, name.as_ref().as_ptr() as _, name.as_ref().len() as _);\n    }"
+        ));
+        assert!(rust.contains(
+            "    pub fn r#recv_bytes(buf: impl AsRef<[u8]>) {\n        r#recv(buf.as_ref().as_ptr() as _, buf.as_ref().len() as _);\n    }"
+        ));
+        assert!(!rust.contains("fn r#count_bytes("));
+
+        // The strings paired with their length have no `_os` function.
+        assert!(!rust.contains("fn r#send_os("));
+        assert!(rust
+            .contains("    pub fn r#count_os(arg0: impl AsRef<std::ffi::OsStr>, count: c_long) {"));
+    }
+
+    #[test]
+    fn test_enums() {
+        let generated = Builder::new()
+            .source(
+                "hello",
+                "enum state { STATE_IDLE, STATE_BUSY = 4, STATE_ALIAS = STATE_IDLE };\n\
+                 enum unused { UNUSED };\n\
+                 provider hello { probe change(enum state); };",
+            )
+            .generate();
+
+        assert!(generated.rust.contains(
+            "    pub enum State {\n        /// `STATE_IDLE`.\n        Idle = 0,\n        /// `STATE_BUSY`.\n        Busy = 4,\n    }\n"
+        ));
+        assert!(generated
+            .rust
+            .contains("        pub const Alias: Self = Self::Idle;\n"));
+        assert!(generated.rust.contains("impl From<State> for c_int {"));
+        assert!(!generated.rust.contains("enum Unused"));
+        assert!(generated.rust.contains("pub fn r#change(arg0: c_int) {"));
+        assert!(generated.c.contains("void hello_probe_change(int arg0);\n"));
+
+        // The prototypes of the header of `dtrace` use the enum.
+        assert!(generated.c.contains(
+            "\nenum state {\n    STATE_IDLE = 0,\n    STATE_BUSY = 4,\n    STATE_ALIAS = 0,\n};\n"
+        ));
+        assert!(generated.c.find("enum state {") < generated.c.find("#include \""));
+        assert!(!generated.c.contains("enum unused"));
+    }
+
+    #[test]
+    fn test_generate_arrays() {
+        let generated = Builder::new()
+            .source(
+                "hello",
+                "provider hello { probe dump(char buf[64], int len); };",
+            )
+            .generate();
+
+        assert!(generated
+            .rust
+            .contains("pub fn r#dump(buf: *mut c_char, len: c_int)"));
+        assert!(generated.c.contains(
+            "void hello_probe_dump(char * buf, int len) {\n    HELLO_DUMP(buf, len);\n}"
+        ));
+        assert!(generated.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_use_sonde_api() {
+        let mut builder = Builder::new();
+        builder
+            .source(
+                "hello",
+                "provider hello { probe you(char *, int); probe me(); };",
+            )
+            .integer_casts(true)
+            .os_str_functions(true)
+            .is_enabled_functions(true)
+            .use_sonde_api(true);
+
+        let rust = builder.generate().rust;
+
+        assert!(rust.contains("static ENABLED_CHANGE_CALLBACKS: ::sonde_api::EnabledRegistry ="));
+        assert!(rust.contains("::sonde_api::os_str_to_c_string(string, false)"));
+        assert!(rust.contains("pub use ::sonde_api::CastInto;"));
+        assert!(!rust.contains("type EnabledChangeCallback"));
+        assert!(!rust.contains("pub trait CastInto<T>"));
+
+        let script = d::parser::parse("provider hello { probe you(int); };").unwrap();
+        let rust = builder.rust_api(
+            &GeneratedHeader::new(),
+            &script.providers,
+            &[Vec::new()],
+            Backend::Mock,
+        );
+
+        assert!(rust.contains("pub use ::sonde_api::mock::Fired;"));
+        assert!(!rust.contains("pub struct Fired"));
+    }
+
+    #[test]
+    fn test_pack_arguments() {
+        let big = "probe big(int a, char *b, int c, int d, int e, int f, int g, int h, int i, int j, size_t k);";
+        let mut builder = Builder::new();
+        builder
+            .source(
+                "hello",
+                format!("provider hello {{ {} probe small(int); }};", big),
+            )
+            .source(
+                "more",
+                format!("provider hello {{ {} }};", big.replace("big", "bigger")),
+            )
+            .namespace("my-crate")
+            .pack_arguments(true);
+
+        let Parsed { contents, .. } = builder.parse("", "");
+
+        assert!(contents.contains(
+            "provider my_crate_hello { probe big(struct my_crate_hello_big_args *args); probe small(int);     probe bigger(struct my_crate_hello_bigger_args *args);\n};"
+        ));
+
+        let generated = builder.generate();
+
+        assert!(generated.rust.contains(
+            "        let args = BigArgs { a, b, c, d, e, f, g, h, i, j, k };\n        unsafe { super::my_crate_hello_probe_big(&args as *const BigArgs as *mut c_void) };\n"
+        ));
+        assert!(generated.rust.contains(
+            "    #[repr(C)]\n    #[derive(Debug, Clone, Copy)]\n    pub struct BigArgs {"
+        ));
+        assert!(!generated
+            .rust
+            .contains("#[repr(C)]\n    #[derive(Debug, Clone, Copy)]\n    pub struct SmallArgs {"));
+        assert!(!generated.rust.contains("pub struct SmallArgs {"));
+        assert!(!generated.rust.contains("fn r#big_with("));
+        assert!(generated
+            .c
+            .contains("struct my_crate_hello_big_args {\n    int a;\n    char * b;\n"));
+        assert!(generated.c.contains("    size_t k;\n};\n"));
+        assert!(
+            generated.c.find("struct my_crate_hello_big_args {") < generated.c.find("#include \"")
+        );
+        assert!(generated.c.contains(
+            "void my_crate_hello_probe_big(struct my_crate_hello_big_args * args) {\n    MY_CRATE_HELLO_BIG(args);\n}"
+        ));
+        assert!(generated
+            .header
+            .contains("struct my_crate_hello_bigger_args {\n"));
+    }
+
+    #[test]
+    fn test_deprecated_probes() {
+        let rust = Builder::new()
+            .source(
+                "hello",
+                "provider hello {\n    /// @deprecated Use \"you\" instead.\n    probe me(char *);\n    // @deprecated\n    probe myself();\n    probe you();\n};",
+            )
+            .os_str_functions(true)
+            .generate()
+            .rust;
+
+        assert!(rust.contains("pub mod r#hello {\n    #![allow(deprecated)]\n"));
+        assert!(rust.contains(
+            "    #[deprecated(note = \"Use \\\"you\\\" instead.\")]\n    pub fn r#me(arg0: *mut c_char) {"
+        ));
+        assert!(rust.contains(
+            "    #[deprecated(note = \"Use \\\"you\\\" instead.\")]\n    pub fn r#me_os(arg0: impl AsRef<std::ffi::OsStr>) {"
+        ));
+        assert!(rust.contains("    #[deprecated]\n    pub fn r#myself() {"));
+        assert!(!rust.contains("#[deprecated]\n    pub fn r#you() {"));
+
+        // The generated items outside of the provider call the deprecated
+        // probes too.
+        let source = "provider hello {\n    // @deprecated\n    probe me();\n    probe you();\n};";
+        let rust = Builder::new()
+            .source("hello", source)
+            .dispatch(true)
+            .wasmer_imports(true)
+            .generate()
+            .rust;
+
+        assert!(rust.contains("#[allow(deprecated)]\npub fn fire("));
+        assert!(rust.contains("#[allow(deprecated)]\npub fn wasmer_imports("));
+
+        let script = d::parser::parse(source).unwrap();
+        let naming = NamingConfig::default();
+
+        assert!(
+            benches(&GeneratedHeader::new(), &naming, &script.providers, false)
+                .contains("#[allow(deprecated)]\nfn probes(c: &mut Criterion) {")
+        );
+
+        let script = d::parser::parse("provider hello { probe you(); };").unwrap();
+
+        assert!(
+            !benches(&GeneratedHeader::new(), &naming, &script.providers, false)
+                .contains("#[allow(deprecated)]")
+        );
+    }
+
+    #[test]
+    fn test_default_api() {
+        let rust = Builder::new()
+            .source(
+                "hello",
+                "provider hello { probe you(char *who, int); probe send(char *data, size_t len); };",
+            )
+            .generate()
+            .rust;
+
+        assert!(rust.contains("    pub fn r#you(who: *mut c_char, arg1: c_int) {"));
+        assert!(rust.contains("    pub fn r#send(data: *mut c_char, len: usize) {"));
+
+        for item in [
+            "fn r#you_is_enabled(",
+            "fn on_enabled_change<",
+            "pub struct YouArgs {",
+            "fn r#you_with(",
+            "fn r#you_os(",
+            "fn r#send_bytes(",
+            "pub mod r#you {",
+        ] {
+            assert!(!rust.contains(item), "`{}` is generated by default", item);
+        }
+    }
+
+    #[test]
+    fn test_probe_metadata() {
+        let mut builder = Builder::new();
+        builder.source(
+            "hello",
+            r#"provider hello {
+                probe world();
+
+                /* @level debug */
+                probe request__start(char *, int);
+            };"#,
+        );
+
+        assert!(!builder.generate().rust.contains("pub mod r#world {"));
+
+        let generated = builder.probe_metadata(true).generate();
+
+        assert!(generated.rust.contains(
+            r#"    pub mod r#world {
+        /// The name of the probe, as seen by the tracers.
+        pub const NAME: &str = "world";
+
+        /// The name of the provider of the probe, as seen by the tracers.
+        pub const PROVIDER: &str = "hello";
+
+        /// The types of the arguments of the probe, as declared in the
+        /// `.d` file.
+        pub const ARG_TYPES: &[&str] = &[];
+    }"#
+        ));
+        assert!(generated
+            .rust
+            .contains(r#"pub const NAME: &str = "request-start";"#));
+        assert!(generated
+            .rust
+            .contains(r#"pub const ARG_TYPES: &[&str] = &["char *", "int"];"#));
+        assert!(generated
+            .rust
+            .contains(r#"pub const LEVEL: &str = "debug";"#));
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let script = d::parser::parse(
+            "provider hello { probe world(); probe you__me(char *, int, bool, double); };",
+        )
+        .unwrap();
+        let rust = dispatch(
+            &NamingConfig::default(),
+            &script.providers,
+            &[vec!["net".to_string()]],
+        );
+
+        assert!(rust.contains("pub enum Probe {\n    /// The `world` probe of the `hello` provider.\n    HelloWorld,\n    /// The `you_me` probe of the `hello` provider.\n    HelloYouMe,\n}"));
+        assert!(rust.contains(
+            "pub const ALL: &'static [Probe] = &[Probe::HelloWorld, Probe::HelloYouMe];"
+        ));
+        assert!(rust.contains(r#"            Probe::HelloYouMe => "you-me","#));
+        assert!(rust.contains("            Probe::HelloYouMe => 4,"));
+        assert!(rust.contains("        Probe::HelloWorld => net::r#hello::r#world(),"));
+        assert!(rust.contains("        Probe::HelloYouMe => net::r#hello::r#you_me(args[0].pointer(0)? as _, args[1].integer(1)? as _, args[2].integer(2)? != 0, args[3].float(3)? as _),"));
+
+        let mut builder = Builder::new();
+        builder.dispatch(true);
+
+        assert_eq!(
+            builder.validate_names(
+                &d::parser::parse("provider a_b { probe c(); }; provider a { probe b__c(); };")
+                    .unwrap()
+                    .providers,
+                &["a.d", "b.d"]
+            ),
+            Err("The probe `c` of the provider `a_b` and the probe `b__c` of the provider `a` (in `b.d`) collide: both are named `Probe::ABC` in the generated code.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_split_output() {
+        let directory = tempfile::tempdir().unwrap();
+        let out_dir = directory.path().to_str().unwrap();
+        let rs_path = directory.path().join("sonde.rs");
+        let mut builder = Builder::new();
+        builder
+            .source("hello", "provider hello { probe you(); };")
+            .source("salut", "provider salut { probe toi(); };")
+            .split_output(true);
+
+        let Parsed {
+            header,
+            providers,
+            modules,
+            ..
+        } = builder.parse("", "");
+
+        builder.write_rust_api(
+            out_dir,
+            &rs_path,
+            &header,
+            &providers,
+            &modules,
+            Backend::Noop,
+        );
+
+        let hello_path = directory.path().join("sonde-hello.rs");
+        let salut_path = directory.path().join("sonde-salut.rs");
+        let hello = read_to_string(&hello_path).unwrap();
+        let salut = read_to_string(&salut_path).unwrap();
+        let index = read_to_string(&rs_path).unwrap();
+
+        assert!(hello.contains("pub mod r#hello {"));
+        assert!(hello.contains("pub fn r#you() {"));
+        assert!(!hello.contains("pub mod r#salut {"));
+        assert!(salut.contains("pub fn r#toi() {"));
+        assert!(!salut.contains("pub mod r#hello {"));
+        assert!(index.contains(&format!(
+            "include!({:?});",
+            hello_path.display().to_string()
+        )));
+        assert!(index.contains(&format!(
+            "include!({:?});",
+            salut_path.display().to_string()
+        )));
+        assert!(!index.contains("pub fn r#you() {"));
+
+        // The variables read by `include_api!`.
+        assert_eq!(
+            builder.rust_api_env(out_dir, &rs_path, &providers),
+            vec![
+                ("SONDE_RUST_API_FILE".to_string(), rs_path.clone()),
+                ("SONDE_RUST_API_FILE_HELLO".to_string(), hello_path),
+                ("SONDE_RUST_API_FILE_SALUT".to_string(), salut_path),
+            ]
+        );
+        assert_eq!(
+            builder
+                .split_output(false)
+                .rust_api_env(out_dir, &rs_path, &providers),
+            vec![("SONDE_RUST_API_FILE".to_string(), rs_path)]
+        );
+    }
+
+    #[test]
+    fn test_included_api() {
+        let mut builder = Builder::new();
+        builder
+            .source("hello", "provider hello { probe you(char *who, int); };")
+            .is_enabled_functions(true)
+            .os_str_functions(true);
+
+        // `include_api!` includes the file in a module: it can't have
+        // inner attributes, and a doc comment before the first item
+        // would document it.
+        for rust in [
+            builder.generate().rust,
+            builder.format_output(false).generate().rust,
+        ] {
+            assert!(!rust.contains("#!["));
+            assert!(rust.contains(
+                "// Bindings from Rust to the C FFI small library that calls the\n// probes.\n\n#[allow(unused)]\nuse std::os::raw::*;"
+            ));
+            assert!(syn::parse_file(&rust).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_format_rust() {
+        assert_eq!(
+            format_rust("// @generated\n// option: a=b\n/// A doc.\npub mod hello { pub fn  you( ) {} }"),
+            "// @generated\n// option: a=b\n\n/// A doc.\npub mod hello {\n    pub fn you() {}\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let script = d::parser::parse(
+            "provider hello {
+                 probe query__start(char *, int);
+                 probe query__end(char *, int);
+                 probe call__entry(int);
+                 probe call__return();
+                 probe mismatch__start(int);
+                 probe mismatch__end(char *);
+                 probe lonely__start();
+             };",
+        )
+        .unwrap();
+        let provider = &script.providers[0];
+
+        assert_eq!(
+            spans(provider)
+                .iter()
+                .map(|(base, start, end)| (*base, start.name.as_str(), end.name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("query", "query__start", "query__end"),
+                ("call", "call__entry", "call__return"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let script = d::parser::parse(
+            "provider hello {
+                 probe world();
+                 probe you__me(int);
+             };",
+        )
+        .unwrap();
+        let stats = Builder::new().stats(&script.providers[0]);
+
+        assert!(stats.contains("static INVOCATIONS: [std::sync::atomic::AtomicU64; 2]"));
+        assert!(stats.contains(r#"["world", "you__me"]"#));
+        assert!(stats.contains("pub fn reset_stats()"));
+    }
+}
@@ -0,0 +1,635 @@
+//! The validation of the names of the providers, of the probes and of
+//! the generated Rust items.
+
+use super::{
+    codegen::{has_integer_argument, probe_variant, rust_enum_name, spans},
+    Builder,
+};
+use crate::d::{
+    self,
+    ast::{camel_case, Names, Probe, Provider},
+    parser::ProviderSpans,
+};
+
+/// Whether `name` can be written as a raw identifier, e.g. `r#name`, in
+/// the generated Rust code.
+pub(crate) fn is_raw_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|first| first.is_alphabetic() || first == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+        && !["_", "self", "Self", "super", "crate"].contains(&name)
+}
+
+/// Suggest a name for a `kind` (`provider` or `probe`) named `name`
+/// that becomes a valid Rust identifier, e.g. `probe_2pc__commit` for
+/// the `2pc__commit` probe.
+pub(crate) fn suggested_name(kind: &str, name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("{}_{}", kind, name.trim_start_matches(['_', '-']))
+    } else if ["self", "super", "crate"].contains(&name.to_lowercase().as_str()) {
+        format!("{}_", name)
+    } else {
+        name
+    }
+}
+
+impl Builder {
+    /// Check that the names of a provider and of its probes become
+    /// valid Rust identifiers, and return an error pointing at each
+    /// invalid name otherwise. `spans` are the spans of the provider in
+    /// `content`, its source.
+    pub(crate) fn invalid_names(
+        &self,
+        provider: &Provider,
+        spans: &ProviderSpans,
+        content: &str,
+    ) -> Vec<d::parser::Error> {
+        let mut names = vec![(
+            "provider",
+            &provider.name,
+            spans.name.start,
+            vec![self.naming.rust_module(&provider.name)],
+        )];
+
+        for (probe, probe_spans) in provider.probes.iter().zip(&spans.probes) {
+            let mut identifiers = vec![self.naming.rust_function(&provider.name, &probe.name)];
+
+            if self.has_arguments_struct(probe) {
+                identifiers.push(self.naming.arguments_struct(&provider.name, &probe.name));
+            }
+
+            names.push(("probe", &probe.name, probe_spans.name.start, identifiers));
+        }
+
+        names
+            .into_iter()
+            .filter_map(|(kind, name, offset, identifiers)| {
+                let identifier = identifiers
+                    .into_iter()
+                    .find(|identifier| !is_raw_identifier(identifier))?;
+
+                Some(d::parser::Error::new(
+                    content,
+                    offset,
+                    format!(
+                        "the {kind} `{name}` can't become a Rust identifier (`{identifier}`); rename it, e.g. to `{suggestion}`",
+                        kind = kind,
+                        name = name,
+                        identifier = identifier,
+                        suggestion = suggested_name(kind, name),
+                    ),
+                ))
+            })
+            .collect()
+    }
+
+    /// Check that the providers, and the probes of each provider, have
+    /// unique names, even once turned into C and Rust names. `files`
+    /// are the files declaring the providers.
+    pub(crate) fn validate_names(
+        &self,
+        providers: &[Provider],
+        files: &[&str],
+    ) -> Result<(), String> {
+        for (nth, provider) in providers.iter().enumerate() {
+            for (other_nth, other) in providers.iter().enumerate().take(nth) {
+                if other.name == provider.name {
+                    return Err(format!(
+                        "The provider `{provider}` is declared twice, in `{file}` and in `{other_file}`.",
+                        provider = provider.name,
+                        file = files[other_nth],
+                        other_file = files[nth],
+                    ));
+                }
+
+                let generated = [
+                    (other.name_for_c_macro(), provider.name_for_c_macro()),
+                    (
+                        self.naming.rust_module(&other.name),
+                        self.naming.rust_module(&provider.name),
+                    ),
+                ];
+
+                if let Some((generated, _)) = generated.iter().find(|(a, b)| a == b) {
+                    return Err(format!(
+                        "The providers `{other}` (in `{other_file}`) and `{provider}` (in `{file}`) collide: both are named `{generated}` in the generated code.",
+                        other = other.name,
+                        other_file = files[other_nth],
+                        provider = provider.name,
+                        file = files[nth],
+                        generated = generated,
+                    ));
+                }
+            }
+
+            for (nth_probe, probe) in provider.probes.iter().enumerate() {
+                for other in provider.probes.iter().take(nth_probe) {
+                    if other.name == probe.name {
+                        return Err(format!(
+                            "The probe `{probe}` is declared twice in the provider `{provider}` (in `{file}`).",
+                            probe = probe.name,
+                            provider = provider.name,
+                            file = files[nth],
+                        ));
+                    }
+
+                    let generated = [
+                        (other.name_for_c_macro(), probe.name_for_c_macro()),
+                        (
+                            self.naming.c_function(provider, &other.name),
+                            self.naming.c_function(provider, &probe.name),
+                        ),
+                        (
+                            self.naming.rust_function(&provider.name, &other.name),
+                            self.naming.rust_function(&provider.name, &probe.name),
+                        ),
+                    ];
+
+                    if let Some((generated, _)) = generated.iter().find(|(a, b)| a == b) {
+                        return Err(format!(
+                            "The probes `{other}` and `{probe}` of the provider `{provider}` (in `{file}`) collide: both are named `{generated}` in the generated code.",
+                            other = other.name,
+                            probe = probe.name,
+                            provider = provider.name,
+                            file = files[nth],
+                            generated = generated,
+                        ));
+                    }
+                }
+            }
+
+            let functions = provider
+                .probes
+                .iter()
+                .flat_map(|probe| {
+                    self.derived_functions(provider, probe)
+                        .into_iter()
+                        .map(move |function| (probe, function))
+                })
+                .collect::<Vec<_>>();
+
+            for (nth_function, (probe, function)) in functions.iter().enumerate() {
+                if let Some(other) = provider.probes.iter().find(|other| {
+                    self.naming.rust_function(&provider.name, &other.name) == *function
+                }) {
+                    return Err(format!(
+                        "The probe `{other}` of the provider `{provider}` (in `{file}`) collides with a variant of the probe `{probe}`: both are named `{generated}` in the generated code.",
+                        other = other.name,
+                        probe = probe.name,
+                        provider = provider.name,
+                        file = files[nth],
+                        generated = function,
+                    ));
+                }
+
+                if let Some((other, _)) = functions[..nth_function]
+                    .iter()
+                    .find(|(_, other_function)| other_function == function)
+                {
+                    return Err(format!(
+                        "The probes `{other}` and `{probe}` of the provider `{provider}` (in `{file}`) collide: both have a variant named `{generated}` in the generated code.",
+                        other = other.name,
+                        probe = probe.name,
+                        provider = provider.name,
+                        file = files[nth],
+                        generated = function,
+                    ));
+                }
+            }
+
+            if self.dispatch {
+                for probe in &provider.probes {
+                    let variant = probe_variant(provider, probe);
+
+                    if let Some((other_provider, other)) = providers[..=nth]
+                        .iter()
+                        .flat_map(|other_provider| {
+                            other_provider
+                                .probes
+                                .iter()
+                                .map(move |other| (other_provider, other))
+                        })
+                        .take_while(|(_, other)| !std::ptr::eq(*other, probe))
+                        .find(|(other_provider, other)| {
+                            probe_variant(other_provider, other) == variant
+                        })
+                    {
+                        return Err(format!(
+                            "The probe `{other}` of the provider `{other_provider}` and the probe `{probe}` of the provider `{provider}` (in `{file}`) collide: both are named `Probe::{variant}` in the generated code.",
+                            other = other.name,
+                            other_provider = other_provider.name,
+                            probe = probe.name,
+                            provider = provider.name,
+                            file = files[nth],
+                            variant = variant,
+                        ));
+                    }
+                }
+            }
+
+            for (base, _, _) in spans(provider) {
+                let span_function = self
+                    .naming
+                    .rust_function(&provider.name, &format!("{}__span", base));
+
+                if let Some(probe) = provider.probes.iter().find(|probe| {
+                    self.naming.rust_function(&provider.name, &probe.name) == span_function
+                }) {
+                    return Err(format!(
+                        "The probe `{probe}` of the provider `{provider}` (in `{file}`) collides with the span of the `{base}` probes: both are named `{generated}` in the generated code.",
+                        probe = probe.name,
+                        provider = provider.name,
+                        file = files[nth],
+                        base = base,
+                        generated = span_function,
+                    ));
+                }
+            }
+
+            self.validate_items(provider, files[nth])?;
+        }
+
+        Ok(())
+    }
+
+    /// The names of the Rust functions generated for a probe besides
+    /// its own function, e.g. `you_bytes` for the `you` probe.
+    pub(crate) fn derived_functions(&self, provider: &Provider, probe: &Probe) -> Vec<String> {
+        let function = self.naming.rust_function(&provider.name, &probe.name);
+        let mut functions = Vec::new();
+
+        if self.is_enabled_functions {
+            functions.push(format!("{}_is_enabled", function));
+        }
+
+        if self.args_structs && !probe.arguments.is_empty() {
+            functions.push(format!("{}_with", function));
+        }
+
+        if self.has_os_str_function(probe) {
+            functions.push(format!("{}_os", function));
+        }
+
+        if self.has_bytes_function(probe) {
+            functions.push(format!("{}_bytes", function));
+        }
+
+        if self.integer_casts && has_integer_argument(probe) {
+            functions.push(format!("{}_cast", function));
+        }
+
+        if self.trampolines {
+            functions.push(format!("{}_trampoline", function));
+        }
+
+        if self.sampling {
+            let mut sampled = vec![function.clone()];
+
+            if self.has_os_str_function(probe) {
+                sampled.push(format!("{}_os", function));
+            }
+
+            if self.args_structs && !probe.arguments.is_empty() {
+                sampled.push(format!("{}_with", function));
+            }
+
+            for function in sampled {
+                functions.push(format!("{}_sampled", function));
+                functions.push(format!("{}_rate_limited", function));
+            }
+        }
+
+        functions
+    }
+
+    /// The names of the items generated once per provider module, as
+    /// `(values, types)`, e.g. `stats` or `CastInto`, which the items
+    /// generated for the probes must not shadow.
+    pub(crate) fn provider_items(
+        &self,
+        provider: &Provider,
+    ) -> (Vec<&'static str>, Vec<&'static str>) {
+        let mut values = Vec::new();
+        let mut types = Vec::new();
+
+        if self.is_enabled_functions && !provider.probes.is_empty() {
+            values.extend([
+                "on_enabled_change",
+                "poll_enabled_changes",
+                "ENABLED_CHANGE_CALLBACKS",
+            ]);
+
+            if !self.use_sonde_api {
+                types.push("EnabledChangeCallback");
+            }
+        }
+
+        if provider
+            .probes
+            .iter()
+            .any(|probe| self.has_os_str_function(probe))
+        {
+            values.push(if self.max_string_len.is_some() {
+                "os_str_to_c_buffer"
+            } else {
+                "os_str_to_c_string"
+            });
+        }
+
+        if self.integer_casts && provider.probes.iter().any(has_integer_argument) {
+            types.push("CastInto");
+        }
+
+        if self.sampling && !provider.probes.is_empty() {
+            values.extend(["__sonde_sampled", "__sonde_rate_limited"]);
+        }
+
+        if self.invocation_counters {
+            values.extend(["stats", "reset_stats", "INVOCATIONS"]);
+        }
+
+        if let Some(backend) = self.backend {
+            let (backend_values, backend_types) = backend.implementation().provider_item_names();
+
+            values.extend(backend_values);
+            types.extend(backend_types);
+        }
+
+        (values, types)
+    }
+
+    /// Check that the items generated in the module of a provider, i.e.
+    /// the functions, structs, enums and modules of its probes, of its
+    /// spans and of the provider itself, have unique names. The items
+    /// are compared in the namespace of the values, then of the types.
+    pub(crate) fn validate_items(&self, provider: &Provider, file: &str) -> Result<(), String> {
+        let (helper_values, helper_types) = self.provider_items(provider);
+        let mut values = Vec::new();
+        let mut types = Vec::new();
+
+        for probe in &provider.probes {
+            let owner = format!("the probe `{}`", probe.name);
+            let function = self.naming.rust_function(&provider.name, &probe.name);
+
+            values.push((function.clone(), owner.clone()));
+            values.extend(
+                self.derived_functions(provider, probe)
+                    .into_iter()
+                    .map(|function| (function, owner.clone())),
+            );
+            if self.probe_metadata {
+                types.push((
+                    function,
+                    format!("the metadata of the probe `{}`", probe.name),
+                ));
+            }
+
+            if self.has_arguments_struct(probe) {
+                types.push((
+                    self.naming.arguments_struct(&provider.name, &probe.name),
+                    format!("the arguments of the probe `{}`", probe.name),
+                ));
+            }
+        }
+
+        for (base, _, _) in spans(provider) {
+            let owner = format!("the span of the `{}` probes", base);
+
+            values.push((
+                self.naming
+                    .rust_function(&provider.name, &format!("{}__span", base)),
+                owner.clone(),
+            ));
+            types.push((
+                format!("{}Span", camel_case(&self.naming.name(base))),
+                owner,
+            ));
+        }
+
+        for enum_ in &provider.enums {
+            types.push((rust_enum_name(enum_), format!("the enum `{}`", enum_.name)));
+        }
+
+        let helpers = "an item of the provider".to_string();
+        values.extend(
+            helper_values
+                .iter()
+                .map(|name| (name.to_string(), helpers.clone())),
+        );
+        types.extend(
+            helper_types
+                .iter()
+                .map(|name| (name.to_string(), helpers.clone())),
+        );
+
+        for items in [&values, &types] {
+            for (nth, (name, owner)) in items.iter().enumerate() {
+                let name = name.trim_start_matches("r#");
+
+                if let Some((_, other)) = items[..nth].iter().find(|(other_name, other)| {
+                    other_name.trim_start_matches("r#") == name && other != owner
+                }) {
+                    return Err(format!(
+                        "In the provider `{provider}` (in `{file}`), {owner} collides with {other}: both are named `{name}` in the generated code.",
+                        provider = provider.name,
+                        file = file,
+                        owner = owner,
+                        other = other,
+                        name = name,
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::naming::NamingConfig;
+
+    #[test]
+    #[should_panic(
+        expected = "The probes `a-b` and `a_b` of the provider `my-app` (in `hello`) collide: both are named `A_B` in the generated code."
+    )]
+    fn test_hyphenated_names_collision() {
+        Builder::new()
+            .source("hello", "provider my-app { probe a-b(); probe a_b(); };")
+            .generate();
+    }
+
+    #[test]
+    #[should_panic(expected = "collide: both are named `gc_end`")]
+    fn test_renames_collision() {
+        Builder::new()
+            .source(
+                "svc",
+                "provider svc { probe gc__begin(); probe gc__end(); };",
+            )
+            .rename_probe("svc", "gc__begin", "gc_end")
+            .generate();
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        let content =
+            "provider db {\n    probe 2pc__commit(int);\n    probe self();\n    probe ok();\n};";
+        let (script, spans) = d::parser::parse_with_spans(content).unwrap();
+        let errors = Builder::new().invalid_names(&script.providers[0], &spans[0], content);
+
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| error.render("db.d"))
+                .collect::<Vec<_>>(),
+            [
+                "db.d:2:11: the probe `2pc__commit` can't become a Rust identifier (`2pc_commit`); rename it, e.g. to `probe_2pc__commit`\n  |\n2 |     probe 2pc__commit(int);\n  |           ^",
+                "db.d:3:11: the probe `self` can't become a Rust identifier (`self`); rename it, e.g. to `self_`\n  |\n3 |     probe self();\n  |           ^",
+            ]
+        );
+
+        let mut builder = Builder::new();
+        builder
+            .naming(NamingConfig {
+                rust_prefix: "fire_".to_string(),
+                ..Default::default()
+            })
+            .args_structs(true);
+
+        let errors = builder.invalid_names(&script.providers[0], &spans[0], content);
+
+        // The arguments struct of the probe isn't prefixed.
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("(`2pcCommitArgs`)"));
+    }
+
+    #[test]
+    #[should_panic(expected = "names that can't become Rust identifiers")]
+    fn test_invalid_names_panic() {
+        Builder::new()
+            .source("a.d", "provider crate { probe ok(); };")
+            .parse("", "");
+    }
+
+    #[test]
+    fn test_validate_names() {
+        let mut builder = Builder::new();
+        builder
+            .is_enabled_functions(true)
+            .args_structs(true)
+            .probe_metadata(true);
+        let validate = |sources: &[&str]| {
+            let providers = sources
+                .iter()
+                .flat_map(|source| d::parser::parse(source).unwrap().providers)
+                .collect::<Vec<_>>();
+            let files = (0..providers.len())
+                .map(|nth| if nth == 0 { "a.d" } else { "b.d" })
+                .collect::<Vec<_>>();
+
+            builder.validate_names(&providers, &files)
+        };
+
+        assert_eq!(
+            validate(&["provider hello { probe foo(); probe bar(); };"]),
+            Ok(())
+        );
+        assert_eq!(
+            validate(&["provider hello {};", "provider hello {};"]),
+            Err("The provider `hello` is declared twice, in `a.d` and in `b.d`.".to_string())
+        );
+        assert_eq!(
+            validate(&["provider Hello {};", "provider hello {};"]),
+            Err("The providers `Hello` (in `a.d`) and `hello` (in `b.d`) collide: both are named `HELLO` in the generated code.".to_string())
+        );
+        assert_eq!(
+            validate(&["provider hello { probe foo(); probe foo(int); };"]),
+            Err(
+                "The probe `foo` is declared twice in the provider `hello` (in `a.d`).".to_string()
+            )
+        );
+        assert_eq!(
+            validate(&["provider hello { probe foo__bar(); probe foo_bar(); };"]),
+            Err("The probes `foo__bar` and `foo_bar` of the provider `hello` (in `a.d`) collide: both are named `FOO_BAR` in the generated code.".to_string())
+        );
+        assert_eq!(
+            validate(&["provider my-app {};", "provider my_app {};"]),
+            Err("The providers `my-app` (in `a.d`) and `my_app` (in `b.d`) collide: both are named `MY_APP` in the generated code.".to_string())
+        );
+        assert_eq!(
+            validate(&["provider hello { probe a__start(); probe a__end(); probe a_span(); };"]),
+            Err("The probe `a_span` of the provider `hello` (in `a.d`) collides with the span of the `a` probes: both are named `a_span` in the generated code.".to_string())
+        );
+        assert_eq!(
+            validate(&["provider hello { probe send(char *, size_t); probe send_bytes(); };"]),
+            Ok(())
+        );
+
+        let mut builder = Builder::new();
+        builder.bytes_functions(true);
+
+        assert_eq!(
+            builder.validate_names(
+                &d::parser::parse("provider hello { probe send(char *, size_t); probe send_bytes(); };")
+                    .unwrap()
+                    .providers,
+                &["a.d"]
+            ),
+            Err("The probe `send_bytes` of the provider `hello` (in `a.d`) collides with a variant of the probe `send`: both are named `send_bytes` in the generated code.".to_string())
+        );
+
+        let mut builder = Builder::new();
+        builder.sampling(true);
+
+        assert_eq!(
+            builder.validate_names(
+                &d::parser::parse("provider hello { probe you(); probe you_sampled(); };")
+                    .unwrap()
+                    .providers,
+                &["a.d"]
+            ),
+            Err("The probe `you_sampled` of the provider `hello` (in `a.d`) collides with a variant of the probe `you`: both are named `you_sampled` in the generated code.".to_string())
+        );
+        assert_eq!(
+            validate(&["provider hello { probe you(); probe you_is_enabled(); };"]),
+            Err("The probe `you_is_enabled` of the provider `hello` (in `a.d`) collides with a variant of the probe `you`: both are named `you_is_enabled` in the generated code.".to_string())
+        );
+        assert_eq!(
+            validate(&["provider hello { probe on_enabled_change(); };"]),
+            Err("In the provider `hello` (in `a.d`), an item of the provider collides with the probe `on_enabled_change`: both are named `on_enabled_change` in the generated code.".to_string())
+        );
+        assert_eq!(
+            validate(&["enum you_args { A };\nprovider hello { probe you(enum you_args); };"]),
+            Err("In the provider `hello` (in `a.d`), the enum `you_args` collides with the arguments of the probe `you`: both are named `YouArgs` in the generated code.".to_string())
+        );
+
+        let mut builder = Builder::new();
+        builder.invocation_counters(true);
+
+        assert_eq!(
+            builder.validate_names(
+                &d::parser::parse("provider hello { probe stats(); };")
+                    .unwrap()
+                    .providers,
+                &["a.d"]
+            ),
+            Err("In the provider `hello` (in `a.d`), an item of the provider collides with the probe `stats`: both are named `stats` in the generated code.".to_string())
+        );
+    }
+}
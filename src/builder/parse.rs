@@ -0,0 +1,1319 @@
+//! The parsing of the `.d` sources, after their includes and the C
+//! preprocessor.
+
+use super::{shim::MAX_ARGUMENTS, Builder, Parsed, Strictness};
+use crate::{
+    d::{
+        self,
+        ast::{
+            enum_name, fallback_types, rust_identifier, DtraceOption, Enum, Probe, Provider,
+            ProviderAttributes, LEVELS,
+        },
+        cst::{self, TokenKind},
+    },
+    header::GeneratedHeader,
+};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env,
+    fs::read_to_string,
+    io::Write,
+    path::{Component, Path, PathBuf},
+    process::Stdio,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+/// Run a `.d` source named `name` through `preprocessor`, the C
+/// compiler returned by `Builder::preprocessor`, with the `-D` and `-I`
+/// `flags`, keeping the comments for the documentation and the
+/// annotations.
+pub(crate) fn preprocessed(
+    preprocessor: &cc::Tool,
+    flags: &[String],
+    source: &str,
+    name: &str,
+) -> String {
+    let mut child = preprocessor
+        .to_command()
+        .args(["-E", "-P", "-C", "-x", "c"])
+        .args(flags)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|error| panic!("Failed to run the C preprocessor: {}", error));
+
+    // The source is written from another thread, so that the
+    // preprocessor never blocks on a full output pipe.
+    let mut stdin = child.stdin.take().unwrap();
+    let source = source.to_string();
+    let writer = thread::spawn(move || stdin.write_all(source.as_bytes()));
+
+    let output = child.wait_with_output().unwrap();
+
+    if !output.status.success() {
+        panic!(
+            "Failed to preprocess `{}`:\n\n{}",
+            name,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    writer.join().unwrap().unwrap();
+
+    String::from_utf8(output.stdout).expect("The preprocessed `.d` file isn't valid UTF-8")
+}
+
+/// Map `f` over `items` on as many threads as there are CPUs, keeping
+/// the order of `items`. A panic of `f` is propagated.
+pub(crate) fn parallel_map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+
+    parallel_map_on(threads, items, f)
+}
+
+/// Map `f` over `items` on at most `threads` threads, keeping the order
+/// of `items`.
+pub(crate) fn parallel_map_on<T, R, F>(threads: usize, items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let threads = threads.min(items.len());
+
+    if threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+
+    let mut results = thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+
+                    loop {
+                        let nth = next.fetch_add(1, Ordering::Relaxed);
+
+                        match items.get(nth) {
+                            Some(item) => results.push((nth, f(item))),
+                            None => break,
+                        }
+                    }
+
+                    results
+                })
+            })
+            .collect::<Vec<_>>();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect::<Vec<_>>()
+    });
+
+    results.sort_by_key(|(nth, _)| *nth);
+
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Expand a glob pattern into the sorted list of matching files.
+pub(crate) fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    let mut paths = glob::glob(pattern)
+        .unwrap_or_else(|error| panic!("The glob pattern `{}` is invalid: {}", pattern, error))
+        .filter_map(Result::ok)
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+
+    paths.sort();
+
+    paths
+}
+
+/// The directory in which a glob pattern looks for files, i.e. its
+/// longest prefix without wildcards, e.g. `src/probes` for
+/// `src/probes/**/*.d`. Return `None` if the pattern has no such
+/// prefix.
+pub(crate) fn glob_base(pattern: &str) -> Option<PathBuf> {
+    let base = Path::new(pattern)
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .take_while(|component| {
+            !component
+                .as_os_str()
+                .to_string_lossy()
+                .contains(['*', '?', '['])
+        })
+        .collect::<PathBuf>();
+
+    if base.as_os_str().is_empty() {
+        None
+    } else {
+        Some(base)
+    }
+}
+
+/// Compute the path of the module of each `.d` file, relative to the
+/// deepest directory containing all the files, e.g. `["storage",
+/// "disk"]` for `probes/storage/disk.d` next to `probes/net.d`.
+pub(crate) fn module_paths(files: &[PathBuf]) -> Vec<Vec<String>> {
+    let directories = files
+        .iter()
+        .map(|file| {
+            file.parent()
+                .unwrap_or_else(|| Path::new(""))
+                .components()
+                .filter(|component| *component != Component::CurDir)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let base_length = directories
+        .iter()
+        .map(|directory| {
+            directory
+                .iter()
+                .zip(&directories[0])
+                .take_while(|(component, first)| component == first)
+                .count()
+        })
+        .min()
+        .unwrap_or(0);
+
+    files
+        .iter()
+        .zip(&directories)
+        .map(|(file, directory)| {
+            directory[base_length..]
+                .iter()
+                .map(|component| component.as_os_str())
+                .chain(file.file_stem())
+                .map(|name| {
+                    rust_identifier(
+                        &name
+                            .to_string_lossy()
+                            .to_lowercase()
+                            .replace(['-', '.', ' '], "_"),
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Read a `.d` file, and inline the files it includes, searched in its
+/// directory, then in `include_dirs`. The included files are pushed in
+/// `included`. With `keep_unresolved`, the files that can't be found
+/// are left to the C preprocessor.
+pub(crate) fn read_with_includes(
+    path: &Path,
+    include_dirs: &[PathBuf],
+    keep_unresolved: bool,
+    included: &mut Vec<(PathBuf, String)>,
+) -> String {
+    let source = read_to_string(path)
+        .unwrap_or_else(|error| panic!("Failed to read `{}`: {}", path.display(), error));
+
+    inline_includes(
+        &source,
+        &path.display().to_string(),
+        path.parent(),
+        include_dirs,
+        keep_unresolved,
+        included,
+    )
+}
+
+/// Inline the files included by a `.d` source named `name`, see
+/// `read_with_includes`. `directory` is the directory of the source,
+/// if it's a file.
+pub(crate) fn inline_includes(
+    source: &str,
+    name: &str,
+    directory: Option<&Path>,
+    include_dirs: &[PathBuf],
+    keep_unresolved: bool,
+    included: &mut Vec<(PathBuf, String)>,
+) -> String {
+    let mut output = String::with_capacity(source.len());
+
+    for token in cst::tokenize(source) {
+        let include = match token.kind {
+            TokenKind::Directive => include_directive(token.text),
+            _ => None,
+        };
+
+        let include = match include {
+            Some(include) => include,
+            None => {
+                output.push_str(token.text);
+
+                continue;
+            }
+        };
+
+        let included_path = match directory
+            .into_iter()
+            .chain(include_dirs.iter().map(PathBuf::as_path))
+            .map(|directory| directory.join(include))
+            .find(|candidate| candidate.is_file())
+        {
+            Some(included_path) => included_path,
+            None if keep_unresolved => {
+                output.push_str(token.text);
+
+                continue;
+            }
+            None => panic!(
+                "The file `{}` included by `{}` can't be found.",
+                include, name
+            ),
+        };
+
+        if included
+            .iter()
+            .any(|(already_included, _)| *already_included == included_path)
+        {
+            continue;
+        }
+
+        included.push((
+            included_path.clone(),
+            read_to_string(&included_path).unwrap(),
+        ));
+
+        let content = read_with_includes(&included_path, include_dirs, keep_unresolved, included);
+        output.push_str(&content);
+
+        if !content.ends_with('\n') {
+            output.push('\n');
+        }
+    }
+
+    output
+}
+
+/// Extract the path of an `#include "…"` or `#include <…>` directive.
+pub(crate) fn include_directive(directive: &str) -> Option<&str> {
+    let rest = directive.strip_prefix('#')?.trim_start();
+    let rest = rest.strip_prefix("include")?.trim();
+
+    match rest.chars().next()? {
+        '"' => rest[1..].split('"').next(),
+        '<' => rest[1..].split('>').next(),
+        _ => None,
+    }
+}
+
+/// Check that a probe, declared in the `file` file, can be compiled.
+/// Return all the problems otherwise.
+pub(crate) fn validate_probe(
+    file: &str,
+    provider: &Provider,
+    probe: &Probe,
+    enums: &[Enum],
+    allow_unknown_types: bool,
+    pack_arguments: bool,
+) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+
+    if let Some(level) = &probe.level {
+        if !LEVELS.contains(&level.as_str()) {
+            errors.push(format!(
+                "The probe `{probe}` of the provider `{provider}` (in `{file}`) has an unknown level `{level}`; expected one of: {levels}.",
+                probe = probe.name,
+                provider = provider.name,
+                file = file,
+                level = level,
+                levels = LEVELS.join(", "),
+            ));
+        }
+    }
+
+    if probe.arguments.len() > MAX_ARGUMENTS && !pack_arguments {
+        errors.push(format!(
+            "The probe `{probe}` of the provider `{provider}` (in `{file}`) has {count} arguments; DTrace supports at most {max}.",
+            probe = probe.name,
+            provider = provider.name,
+            file = file,
+            count = probe.arguments.len(),
+            max = MAX_ARGUMENTS,
+        ));
+    }
+
+    for ty in probe
+        .arguments
+        .iter()
+        .map(|argument| &argument.ty)
+        .filter(|ty| enum_name(ty).is_some_and(|name| enums.iter().all(|enum_| enum_.name != name)))
+    {
+        errors.push(format!(
+            "The probe `{probe}` of the provider `{provider}` (in `{file}`) has an argument of type `{ty}`, which isn't declared in the `.d` files.",
+            probe = probe.name,
+            provider = provider.name,
+            file = file,
+            ty = ty,
+        ));
+    }
+
+    for ty in probe
+        .unsupported_types()
+        .into_iter()
+        .filter(|_| !allow_unknown_types)
+    {
+        errors.push(format!(
+            "The probe `{probe}` of the provider `{provider}` (in `{file}`) has an argument of type `{ty}`, which isn't supported yet.",
+            probe = probe.name,
+            provider = provider.name,
+            file = file,
+            ty = ty,
+        ));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Rename the providers in the `#pragma D attributes` directives of
+/// `content`, given their namespaced names, see `Builder::namespace`.
+pub(crate) fn namespaced_pragmas<'c>(
+    content: &'c str,
+    namespaced: &HashMap<String, String>,
+) -> Cow<'c, str> {
+    if namespaced.is_empty() {
+        return Cow::Borrowed(content);
+    }
+
+    let mut renamed = String::with_capacity(content.len());
+
+    for line in content.split_inclusive('\n') {
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["#pragma", "D", "attributes", attributes, "provider", provider, component]
+                if namespaced.contains_key(provider) =>
+            {
+                renamed.push_str(&line[..line.len() - line.trim_start().len()]);
+                renamed.push_str(&format!(
+                    "#pragma D attributes {} provider {} {}",
+                    attributes, namespaced[provider], component
+                ));
+
+                if line.ends_with('\n') {
+                    renamed.push('\n');
+                }
+            }
+            _ => renamed.push_str(line),
+        }
+    }
+
+    Cow::Owned(renamed)
+}
+
+/// Whether the crate is compiled with debug assertions, i.e. whether
+/// the test files are compiled, see [`Builder::test_file`].
+pub(crate) fn debug_assertions() -> bool {
+    env::var_os("CARGO_CFG_DEBUG_ASSERTIONS").is_some()
+}
+
+impl Builder {
+    /// Parse like [`Builder::parse`], running the C preprocessor only if
+    /// `preprocess` is set, and including the test files only if
+    /// `debug_assertions` is set, see [`Builder::test_file`].
+    pub(crate) fn parse_with(
+        &self,
+        target: &str,
+        host: &str,
+        preprocess: bool,
+        debug_assertions: bool,
+    ) -> Parsed {
+        let mut contents = String::new();
+        let mut d_files = self.d_files.clone();
+
+        for pattern in &self.d_globs {
+            d_files.extend(expand_glob(pattern));
+        }
+
+        let all_d_files = d_files
+            .iter()
+            .chain(&self.test_d_files)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // The test files are only compiled with debug assertions.
+        if debug_assertions {
+            d_files.extend(self.test_d_files.iter().cloned());
+        }
+
+        let mut providers = Vec::with_capacity(d_files.len());
+        let mut provider_modules = Vec::with_capacity(d_files.len());
+        let mut header = GeneratedHeader::new();
+        header.options = self.options();
+
+        let mut watched = all_d_files;
+        let mut options: Vec<DtraceOption> = Vec::new();
+        let mut warnings = Vec::new();
+
+        // Cargo scans the directories, so that new files matching the
+        // patterns are picked up. The crate's directory itself isn't
+        // scanned, as it contains `target`.
+        watched.extend(self.d_globs.iter().filter_map(|pattern| glob_base(pattern)));
+
+        // Collect all contents of the `.d` files and of the inline
+        // sources, and parse the declared providers.
+        {
+            let mut inputs = Vec::with_capacity(d_files.len() + self.d_sources.len());
+            let mut included = Vec::new();
+
+            let mut input_modules = if self.module_tree {
+                module_paths(&d_files)
+            } else {
+                vec![Vec::new(); d_files.len()]
+            };
+
+            for d_file in &d_files {
+                let content =
+                    read_with_includes(d_file, &self.include_dirs, preprocess, &mut included);
+                inputs.push((d_file.display().to_string(), content));
+            }
+
+            for (name, source) in &self.d_sources {
+                input_modules.push(Vec::new());
+                let content = inline_includes(
+                    source,
+                    name,
+                    None,
+                    &self.include_dirs,
+                    preprocess,
+                    &mut included,
+                );
+                inputs.push((name.clone(), content));
+            }
+
+            for provider in &self.defined_providers {
+                input_modules.push(Vec::new());
+                inputs.push((
+                    format!("Builder::provider({:?})", provider.name),
+                    format!("{}\n", provider),
+                ));
+            }
+
+            watched.extend(
+                included
+                    .iter()
+                    .map(|(included_file, _)| included_file.clone()),
+            );
+
+            for (name, content) in &inputs {
+                header.add_source(name.clone(), content);
+            }
+
+            for (included_file, included_content) in &included {
+                header.add_source(included_file.display().to_string(), included_content);
+            }
+
+            let mut provider_files = Vec::new();
+            let mut unsupported = Vec::new();
+
+            let mut parse_errors = Vec::new();
+            let mut invalid_names = Vec::new();
+            let mut unstable = Vec::new();
+
+            // The providers declared in several inputs are merged into
+            // their first declaration, as `dtrace` rejects a provider
+            // declared twice. `declarations` maps a provider name to its
+            // index in `providers`, the input declaring it first, and
+            // the position of its closing `}` in this input. `edits` are
+            // the replacements to apply to each input, in the parsed
+            // contents.
+            let mut declarations: HashMap<String, (usize, usize, usize)> = HashMap::new();
+            let mut parsed_contents = Vec::with_capacity(inputs.len());
+            let mut edits: Vec<Vec<(d::parser::Span, String)>> = vec![Vec::new(); inputs.len()];
+
+            // The namespaced names of the providers, by name in the `.d`
+            // files, see `Builder::namespace`.
+            let mut namespaced = HashMap::new();
+
+            // Preprocessing and parsing the inputs are independent, and
+            // the slowest steps with many inputs: they run in parallel.
+            let preprocessor =
+                preprocess.then(|| (self.preprocessor(target, host), self.preprocessor_flags()));
+
+            let parsed_inputs = parallel_map(&inputs, |(name, content)| {
+                let content = match &preprocessor {
+                    Some((preprocessor, flags)) => preprocessed(preprocessor, flags, content, name),
+                    None => content.clone(),
+                };
+                let parsed = d::parser::parse_all(&content);
+
+                (content, parsed)
+            });
+
+            // The enums are shared by all the files, like in a single D
+            // script; the first declaration of a name wins, as `dtrace`
+            // rejects the others anyway.
+            let mut enums: Vec<Enum> = Vec::new();
+
+            for (_, (script, _, _)) in &parsed_inputs {
+                for enum_ in &script.enums {
+                    if enums.iter().all(|other| other.name != enum_.name) {
+                        enums.push(enum_.clone());
+                    }
+                }
+            }
+
+            for (nth_input, (((name, _), modules), (content, parsed))) in inputs
+                .iter()
+                .zip(&input_modules)
+                .zip(parsed_inputs)
+                .enumerate()
+            {
+                let (script, spans, errors) = parsed;
+                parse_errors.extend(errors.iter().map(|error| error.render(name)));
+
+                for option in script.options {
+                    if !option.is_compiler_option() {
+                        warnings.push(format!(
+                            "The `{option}` directive (in `{file}`) only applies when tracing, not when generating the probes: it is ignored.",
+                            option = option,
+                            file = name,
+                        ));
+                    } else if !options.contains(&option) {
+                        options.push(option);
+                    }
+                }
+
+                for (mut provider, spans) in script.providers.into_iter().zip(spans) {
+                    invalid_names.extend(
+                        self.invalid_names(&provider, &spans, &content)
+                            .iter()
+                            .map(|error| error.render(name)),
+                    );
+
+                    if let Some((minimum, _)) = self.stability_policy {
+                        for (component, attributes) in provider.attributes.iter() {
+                            if attributes.name.min(attributes.data) < minimum {
+                                unstable.push(format!(
+                                    "The provider `{provider}` (in `{file}`) declares `{attributes}` attributes for its `{component}`, below the `{minimum}` stability policy.",
+                                    provider = provider.name,
+                                    file = name,
+                                    attributes = attributes,
+                                    component = component,
+                                    minimum = minimum,
+                                ));
+                            }
+                        }
+                    }
+
+                    if let Some((_, version)) = self
+                        .provider_versions
+                        .iter()
+                        .rev()
+                        .find(|(name, _)| *name == provider.name)
+                    {
+                        provider.version = Some(version.clone());
+                    }
+
+                    let probes = std::mem::take(&mut provider.probes);
+
+                    // The declarations of the packed probes are replaced
+                    // in the `.d` file, see `Builder::pack_arguments`, and
+                    // the ones of the skipped probes are removed, so that
+                    // the header and the shim don't declare them.
+                    let mut packed = Vec::new();
+
+                    for (probe, probe_spans) in probes.into_iter().zip(&spans.probes) {
+                        if self.allow_unknown_types {
+                            for ty in probe.unsupported_types() {
+                                warnings.push(format!(
+                                    "The probe `{probe}` of the provider `{provider}` (in `{file}`) has an argument of unknown type `{ty}`, passed as `{rust_type}`.",
+                                    probe = probe.name,
+                                    provider = provider.name,
+                                    file = name,
+                                    ty = ty,
+                                    rust_type = fallback_types(ty).1,
+                                ));
+                            }
+                        }
+
+                        match validate_probe(
+                            name,
+                            &provider,
+                            &probe,
+                            &enums,
+                            self.allow_unknown_types,
+                            self.pack_arguments,
+                        ) {
+                            Ok(()) => {
+                                if self.packs(&probe) {
+                                    packed.push((
+                                        probe_spans.span.clone(),
+                                        format!(
+                                            "probe {}(struct {} *args);",
+                                            probe.name,
+                                            self.naming.packed_struct(
+                                                &self.naming.namespaced(&provider.name),
+                                                &probe.name
+                                            )
+                                        ),
+                                    ));
+                                }
+
+                                provider.probes.push(probe);
+                            }
+                            Err(errors) => {
+                                packed.push((probe_spans.span.clone(), String::new()));
+                                unsupported.extend(errors);
+                            }
+                        }
+                    }
+
+                    let declared_name = provider.name.clone();
+                    provider.name = self.naming.namespaced(&declared_name);
+
+                    match declarations.get(&provider.name) {
+                        Some(&(nth, first_input, closing_brace)) if first_input != nth_input => {
+                            let declared: &mut Provider = &mut providers[nth];
+                            declared.probes.append(&mut provider.probes);
+                            declared.doc = declared.doc.take().or(provider.doc);
+                            declared.version = declared.version.take().or(provider.version);
+
+                            for component in ProviderAttributes::COMPONENTS {
+                                let attributes = declared.attributes.get_mut(component).unwrap();
+
+                                if attributes.is_none() {
+                                    *attributes = *provider.attributes.get_mut(component).unwrap();
+                                }
+                            }
+
+                            let probes =
+                                spans
+                                    .probes
+                                    .iter()
+                                    .map(|probe| {
+                                        packed.iter().find(|(span, _)| *span == probe.span).map_or(
+                                            &content[probe.span.clone()],
+                                            |(_, declaration)| declaration.as_str(),
+                                        )
+                                    })
+                                    .filter(|declaration| !declaration.is_empty())
+                                    .map(|declaration| format!("    {}\n", declaration))
+                                    .collect::<String>();
+
+                            edits[first_input].push((closing_brace..closing_brace, probes));
+                            edits[nth_input].push((spans.span, String::new()));
+                        }
+                        _ => {
+                            edits[nth_input].append(&mut packed);
+
+                            if provider.name != declared_name {
+                                edits[nth_input].push((spans.name.clone(), provider.name.clone()));
+                                namespaced.insert(declared_name, provider.name.clone());
+                            }
+
+                            let closing_brace = content[spans.span.clone()]
+                                .rfind('}')
+                                .map(|offset| spans.span.start + offset)
+                                .unwrap();
+
+                            declarations.entry(provider.name.clone()).or_insert((
+                                providers.len(),
+                                nth_input,
+                                closing_brace,
+                            ));
+                            providers.push(provider);
+                            provider_files.push(name.as_str());
+                            provider_modules.push(modules.clone());
+                        }
+                    }
+                }
+
+                parsed_contents.push(content);
+            }
+
+            for provider in providers.iter_mut() {
+                provider.enums = provider.used_enums(&enums);
+            }
+
+            // The inputs without merged providers are given as is to
+            // `dtrace`, the others once edited. The edits are made on the
+            // parsed contents, which are already preprocessed if
+            // `preprocess` is enabled; preprocessing them again with
+            // `dtrace -C` is harmless.
+            for (((_, content), parsed), mut edits) in
+                inputs.iter().zip(&parsed_contents).zip(edits)
+            {
+                if edits.is_empty() {
+                    contents.push_str(&namespaced_pragmas(content, &namespaced));
+                    continue;
+                }
+
+                let mut parsed = parsed.clone();
+                edits.sort_by_key(|(span, _)| span.start);
+
+                for (span, replacement) in edits.into_iter().rev() {
+                    parsed.replace_range(span, &replacement);
+                }
+
+                contents.push_str(&namespaced_pragmas(&parsed, &namespaced));
+            }
+
+            // All the errors of all the files are reported at once, so
+            // that they can be fixed in one pass.
+            if !parse_errors.is_empty() {
+                panic!(
+                    "Failed to parse the `.d` files:\n\n{}",
+                    parse_errors.join("\n\n")
+                );
+            }
+
+            if !invalid_names.is_empty() {
+                panic!(
+                    "The `.d` files contain names that can't become Rust identifiers:\n\n{}",
+                    invalid_names.join("\n\n")
+                );
+            }
+
+            match self.stability_policy {
+                Some((_, Strictness::Error)) if !unstable.is_empty() => panic!(
+                    "The `.d` files declare providers below the stability policy:\n\n{}",
+                    unstable
+                        .iter()
+                        .map(|error| format!("  - {}", error))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ),
+                _ => warnings.append(&mut unstable),
+            }
+
+            if !unsupported.is_empty() {
+                match self.strictness {
+                    Strictness::Error => panic!(
+                        "The `.d` files contain unsupported constructs:\n\n{}",
+                        unsupported
+                            .iter()
+                            .map(|error| format!("  - {}", error))
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    ),
+                    Strictness::Warn => {
+                        for error in &unsupported {
+                            warnings.push(format!("{} The probe is skipped.", error));
+                        }
+                    }
+                }
+            }
+
+            if let Err(error) = self.validate_names(&providers, &provider_files) {
+                panic!("{}", error);
+            }
+
+            if providers.is_empty() && !self.allow_empty {
+                panic!(
+                    "No provider is declared in the scanned inputs:\n\n{}\n\nCheck the `provider` blocks and the paths of the `.d` files, or use `Builder::allow_empty(true)` to generate an empty module.",
+                    if inputs.is_empty() {
+                        "  (none)".to_string()
+                    } else {
+                        inputs
+                            .iter()
+                            .map(|(name, _)| format!("  - {}", name))
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    }
+                );
+            }
+        }
+
+        for (provider_name, _) in &self.naming.provider_renames {
+            if !providers
+                .iter()
+                .any(|provider| provider.name == self.naming.namespaced(provider_name))
+            {
+                warnings.push(format!(
+                    "The renamed provider `{}` isn't declared.",
+                    provider_name
+                ));
+            }
+        }
+
+        for (provider_name, probe_name, _) in &self.naming.probe_renames {
+            if !providers.iter().any(|provider| {
+                provider.name == self.naming.namespaced(provider_name)
+                    && provider
+                        .probes
+                        .iter()
+                        .any(|probe| probe.name == *probe_name)
+            }) {
+                warnings.push(format!(
+                    "The renamed probe `{}` of the provider `{}` isn't declared.",
+                    probe_name, provider_name
+                ));
+            }
+        }
+
+        Parsed {
+            contents,
+            header,
+            providers,
+            modules: provider_modules,
+            watched,
+            options,
+            warnings,
+        }
+    }
+
+    /// The `-D` and `-I` flags of the C preprocessor, shared by the C
+    /// compiler and `dtrace -C`.
+    pub(crate) fn preprocessor_flags(&self) -> Vec<String> {
+        self.defines
+            .iter()
+            .map(|(name, value)| format!("-D{}={}", name, value))
+            .chain(
+                self.include_dirs
+                    .iter()
+                    .map(|include_dir| format!("-I{}", include_dir.display())),
+            )
+            .collect()
+    }
+
+    /// The C compiler used as the C preprocessor of the `.d` sources,
+    /// see [`preprocessed`]. Outside of a build script, `target` and
+    /// `host` may be empty: `cc` uses its defaults then.
+    pub(crate) fn preprocessor(&self, target: &str, host: &str) -> cc::Tool {
+        let mut build = cc::Build::new();
+        build.cargo_metadata(false);
+
+        if !target.is_empty() {
+            build.target(target);
+        }
+
+        if !host.is_empty() {
+            build.host(host);
+        }
+
+        for hook in &self.cc_hooks {
+            hook(&mut build);
+        }
+
+        build.get_compiler()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_include_directive() {
+        assert_eq!(include_directive("#include \"common.d\""), Some("common.d"));
+        assert_eq!(
+            include_directive("# include <sys/types.d>"),
+            Some("sys/types.d")
+        );
+        assert_eq!(include_directive("#pragma D option quiet"), None);
+    }
+
+    #[test]
+    fn test_read_with_includes() {
+        let directory = tempfile::tempdir().unwrap();
+        let include_dir = directory.path().join("include");
+        fs::create_dir(&include_dir).unwrap();
+
+        fs::write(
+            directory.path().join("provider.d"),
+            "#include \"a.d\"\n/* #include \"nope.d\" */\n#include \"a.d\"\nprovider hello {};\n",
+        )
+        .unwrap();
+        fs::write(include_dir.join("a.d"), "#include \"b.d\"\n/* a */").unwrap();
+        fs::write(include_dir.join("b.d"), "/* b */\n").unwrap();
+
+        let mut included = Vec::new();
+        let content = read_with_includes(
+            &directory.path().join("provider.d"),
+            std::slice::from_ref(&include_dir),
+            false,
+            &mut included,
+        );
+
+        assert_eq!(
+            content,
+            "/* b */\n\n/* a */\n\n/* #include \"nope.d\" */\n\nprovider hello {};\n"
+        );
+        assert_eq!(
+            included
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect::<Vec<_>>(),
+            vec![include_dir.join("a.d"), include_dir.join("b.d")]
+        );
+    }
+
+    #[test]
+    fn test_read_with_includes_keeps_unresolved() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = "#include <sys/types.h>\nprovider hello {};\n";
+        fs::write(directory.path().join("provider.d"), source).unwrap();
+
+        let mut included = Vec::new();
+        let content = read_with_includes(
+            &directory.path().join("provider.d"),
+            &[],
+            true,
+            &mut included,
+        );
+
+        assert_eq!(content, source);
+        assert!(included.is_empty());
+    }
+
+    #[test]
+    fn test_parallel_map() {
+        let items = (0..100).collect::<Vec<u32>>();
+
+        assert_eq!(
+            parallel_map(&items, |item| item * 2),
+            (0..100).map(|item| item * 2).collect::<Vec<_>>(),
+        );
+        assert!(parallel_map(&[] as &[u32], |item| *item).is_empty());
+    }
+
+    #[test]
+    fn test_parallel_map_on_several_threads() {
+        let items = (0..100).collect::<Vec<u64>>();
+
+        // Uneven work, so that the threads interleave their items.
+        let results = parallel_map_on(4, &items, |item| {
+            thread::sleep(std::time::Duration::from_micros((item % 7) * 50));
+
+            item * 2
+        });
+
+        assert_eq!(results, (0..100).map(|item| item * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_preprocessor_flags() {
+        let mut builder = Builder::new();
+        builder
+            .define("EXTRA", "1")
+            .include_dir("probes/include")
+            .preprocess(true);
+
+        assert_eq!(
+            builder.preprocessor_flags(),
+            vec!["-DEXTRA=1".to_string(), "-Iprobes/include".to_string()]
+        );
+        assert_eq!(
+            builder
+                .dtrace_command("x86_64-unknown-linux-gnu", &[])
+                .get_args()
+                .collect::<Vec<_>>(),
+            vec!["-C", "-DEXTRA=1", "-Iprobes/include"]
+        );
+    }
+
+    #[test]
+    fn test_skipped_builds_are_not_preprocessed() {
+        let mut builder = Builder::new();
+        builder
+            .source("hello", "provider hello { probe world(); };")
+            .preprocess(true)
+            .configure_cc(|_| panic!("the C preprocessor must not run"));
+
+        let parsed = builder.parse_with("", "", false, false);
+
+        assert_eq!(parsed.providers.len(), 1);
+    }
+
+    #[test]
+    fn test_configure_cc() {
+        let mut builder = Builder::new();
+        builder
+            .configure_cc(|build| {
+                // Outside of a build script, `cc` can't read them from
+                // the environment.
+                build.opt_level(0).debug(false).define("FIRST", None);
+            })
+            .configure_cc(|build| {
+                build.flag("-Wno-unused");
+            });
+
+        let preprocessor =
+            builder.preprocessor("x86_64-unknown-linux-gnu", "x86_64-unknown-linux-gnu");
+        let arguments = preprocessor.args();
+
+        assert!(arguments.iter().any(|argument| argument == "-DFIRST"));
+        assert!(arguments.iter().any(|argument| argument == "-Wno-unused"));
+        assert!(builder
+            .options()
+            .contains(&("configure_cc".to_string(), "2".to_string())));
+        assert!(!Builder::new()
+            .options()
+            .iter()
+            .any(|(name, _)| name == "configure_cc"));
+    }
+
+    #[test]
+    fn test_test_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let test_d_file = directory.path().join("test.d");
+        fs::write(&test_d_file, "provider checks { probe reached(int); };").unwrap();
+
+        let mut builder = Builder::new();
+        builder
+            .source("hello", "provider hello { probe you(); };")
+            .test_file(&test_d_file);
+
+        let release = builder.parse_with("", "", false, false);
+        let debug = builder.parse_with("", "", false, true);
+
+        assert_eq!(
+            release
+                .providers
+                .iter()
+                .map(|provider| provider.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["hello"]
+        );
+        assert!(!release.contents.contains("provider checks"));
+        assert_eq!(
+            debug
+                .providers
+                .iter()
+                .map(|provider| provider.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["checks", "hello"]
+        );
+        assert!(debug.contents.contains("probe reached(int);"));
+
+        // The test file is watched even when it isn't compiled.
+        assert!(release.watched.contains(&test_d_file));
+    }
+
+    #[test]
+    fn test_option_pragmas() {
+        let mut builder = Builder::new();
+        builder
+            .source(
+                "hello",
+                "#pragma D option quiet\n#pragma D option lazyload\nprovider hello { probe world(); };",
+            )
+            .source("more", "#pragma D option incdir=/opt/probes\n#pragma D option lazyload");
+
+        let Parsed {
+            options, warnings, ..
+        } = builder.parse("", "");
+
+        assert_eq!(
+            warnings,
+            ["The `#pragma D option quiet` directive (in `hello`) only applies when tracing, not when generating the probes: it is ignored."]
+        );
+        assert_eq!(
+            builder
+                .dtrace_command("x86_64-unknown-linux-gnu", &options)
+                .get_args()
+                .collect::<Vec<_>>(),
+            vec!["-x", "lazyload", "-x", "incdir=/opt/probes"]
+        );
+    }
+
+    #[test]
+    fn test_module_paths() {
+        assert_eq!(
+            module_paths(&[
+                PathBuf::from("./probes/net.d"),
+                PathBuf::from("./probes/storage/Disk-IO.d"),
+                PathBuf::from("./probes/type.d"),
+            ]),
+            vec![
+                vec!["net".to_string()],
+                vec!["storage".to_string(), "disk_io".to_string()],
+                vec!["r#type".to_string()],
+            ]
+        );
+        assert_eq!(
+            module_paths(&[PathBuf::from("probes/net.d")]),
+            vec![vec!["net".to_string()]]
+        );
+    }
+
+    #[test]
+    fn test_skipped_probes_are_not_given_to_dtrace() {
+        let mut builder = Builder::new();
+        builder
+            .source(
+                "hello",
+                "provider hello { probe you(char *); probe me(string); };",
+            )
+            .source(
+                "more",
+                "provider hello { probe them(string); probe us(); };",
+            )
+            .strictness(Strictness::Warn);
+
+        let contents = builder.parse("", "").contents;
+
+        assert!(contents.contains("probe you(char *);"));
+        assert!(contents.contains("probe us();"));
+        assert!(!contents.contains("probe me("));
+        assert!(!contents.contains("probe them("));
+    }
+
+    #[test]
+    fn test_sources() {
+        let directory = tempfile::tempdir().unwrap();
+        let d_file = directory.path().join("hello.d");
+        fs::write(&d_file, "provider hello { probe you(int); };").unwrap();
+
+        let mut builder = Builder::new();
+        builder
+            .file(&d_file)
+            .source("generated", "provider salut { probe toi(char *); };");
+
+        let Parsed {
+            contents,
+            header,
+            providers,
+            ..
+        } = builder.parse("", "");
+
+        assert_eq!(
+            providers
+                .iter()
+                .map(|provider| provider.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["hello", "salut"]
+        );
+        assert!(contents.contains("provider hello { probe you(int); };"));
+        assert!(contents.contains("provider salut { probe toi(char *); };"));
+        assert_eq!(
+            header
+                .sources
+                .iter()
+                .map(|source| source.path.clone())
+                .collect::<Vec<_>>(),
+            vec![d_file.display().to_string(), "generated".to_string()]
+        );
+
+        let generated = builder.generate();
+
+        assert!(generated.rust.contains("pub fn r#toi(arg0: *mut c_char) {"));
+        assert!(generated
+            .c
+            .contains("void salut_probe_toi(char * arg0) {\n    SALUT_TOI(arg0);\n}"));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Failed to parse the `.d` files:\n\na.d:1:23: expected `;` after probe declaration\n  |\n1 | provider a { probe b() };\n  |                       ^\n\nb.d:1:22: expected `)` to close the probe arguments"
+    )]
+    fn test_parse_errors() {
+        Builder::new()
+            .source("a.d", "provider a { probe b() };\nprovider ok {};")
+            .source("b.d", "provider c { probe d(; };")
+            .generate();
+    }
+
+    #[test]
+    fn test_merged_providers() {
+        let mut builder = Builder::new();
+        builder
+            .source(
+                "a.d",
+                "provider hello { probe world(); };\nprovider other { };\n",
+            )
+            .source(
+                "b.d",
+                "/** More probes. */\nprovider hello {\n    probe you(char *);\n};\n",
+            );
+
+        let parsed = builder.parse("", "");
+
+        assert_eq!(
+            parsed.contents,
+            "provider hello { probe world();     probe you(char *);\n};\nprovider other { };\n/** More probes. */\n\n"
+        );
+        assert_eq!(parsed.providers.len(), 2);
+        assert_eq!(
+            parsed.providers[0]
+                .probes
+                .iter()
+                .map(|probe| probe.name.as_str())
+                .collect::<Vec<_>>(),
+            ["world", "you"]
+        );
+        assert_eq!(parsed.providers[0].doc.as_deref(), Some("More probes."));
+
+        let rust = builder.generate().rust;
+
+        assert_eq!(rust.matches("pub mod r#hello {").count(), 1);
+        assert!(rust.contains("pub fn r#world()"));
+        assert!(rust.contains("pub fn r#you(arg0: *mut c_char)"));
+    }
+
+    #[test]
+    fn test_glob_base() {
+        assert_eq!(
+            glob_base("src/probes/**/*.d"),
+            Some(PathBuf::from("src/probes"))
+        );
+        assert_eq!(glob_base("./probes/*.d"), Some(PathBuf::from("probes")));
+        assert_eq!(glob_base("./*.d"), None);
+    }
+
+    #[test]
+    fn test_validate_probe() {
+        let script = d::parser::parse(
+            "provider hello {
+                 /* @level debug */
+                 probe ok(int, int, int, int, int, int, int, int, int, int);
+                 /* @level loud */
+                 probe unknown_level();
+                 probe too_many(int, int, int, int, int, int, int, int, int, int, int);
+                 probe unknown_types(struct foo, int, string);
+             };",
+        )
+        .unwrap();
+        let provider = &script.providers[0];
+
+        assert_eq!(
+            validate_probe("a.d", provider, &provider.probes[0], &[], false, false),
+            Ok(())
+        );
+        assert_eq!(
+            validate_probe("a.d", provider, &provider.probes[1], &[], false, false),
+            Err(vec!["The probe `unknown_level` of the provider `hello` (in `a.d`) has an unknown level `loud`; expected one of: trace, debug, info, warn, error.".to_string()])
+        );
+        assert_eq!(
+            validate_probe("a.d", provider, &provider.probes[2], &[], false, false),
+            Err(vec!["The probe `too_many` of the provider `hello` (in `a.d`) has 11 arguments; DTrace supports at most 10.".to_string()])
+        );
+        assert_eq!(
+            validate_probe("a.d", provider, &provider.probes[3], &[], false, false),
+            Err(vec![
+                "The probe `unknown_types` of the provider `hello` (in `a.d`) has an argument of type `struct foo`, which isn't supported yet.".to_string(),
+                "The probe `unknown_types` of the provider `hello` (in `a.d`) has an argument of type `string`, which isn't supported yet.".to_string(),
+            ])
+        );
+        assert_eq!(
+            validate_probe("a.d", provider, &provider.probes[3], &[], true, false),
+            Ok(())
+        );
+    }
+}
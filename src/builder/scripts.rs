@@ -0,0 +1,703 @@
+//! The tracing scripts, debugger scripts, tapsets and benchmarks
+//! generated next to the probes.
+
+use super::codegen::{allow_deprecated, C_STRING_TYPE};
+use crate::{
+    d::ast::{decayed, integer_type, Names, Provider},
+    header::GeneratedHeader,
+    naming::NamingConfig,
+};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+/// How a tracing script prints an argument.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ScriptFormat {
+    String,
+    Signed,
+    Unsigned,
+    Hexadecimal,
+}
+
+impl ScriptFormat {
+    fn new(d_type: &str) -> Self {
+        let d_type = &*decayed(d_type);
+        let number_of_pointers = d_type.chars().filter(|c| *c == '*').count();
+        let base = d_type.trim_end_matches([' ', '*']);
+        let base = integer_type(base).unwrap_or(base);
+
+        match (base, number_of_pointers) {
+            ("char", 1) => Self::String,
+            (_, 0) if base.starts_with("uint") || base.starts_with("unsigned ") => Self::Unsigned,
+            ("size_t" | "bool" | "_Bool", 0) => Self::Unsigned,
+            (
+                "char" | "signed char" | "short" | "int" | "long" | "long long" | "intptr_t"
+                | "ssize_t" | "ptrdiff_t" | "wchar_t",
+                0,
+            ) => Self::Signed,
+            (_, 0) if base.starts_with("int") => Self::Signed,
+            _ => Self::Hexadecimal,
+        }
+    }
+
+    /// The `printf` conversion.
+    fn conversion(&self) -> &'static str {
+        match self {
+            Self::String => "%s",
+            Self::Signed => "%d",
+            Self::Unsigned => "%u",
+            Self::Hexadecimal => "0x%x",
+        }
+    }
+}
+
+/// Guess the path of the binary of the crate, i.e.
+/// `target/<profile>/<crate>`, from the `OUT_DIR` of the build script,
+/// i.e. `target/<profile>/build/<crate>-<hash>/out`.
+pub(crate) fn binary_path(out_dir: &str) -> Option<PathBuf> {
+    let profile_directory = Path::new(out_dir).ancestors().nth(3)?;
+
+    Some(profile_directory.join(env::var("CARGO_PKG_NAME").ok()?))
+}
+
+/// Generate a bpftrace script printing the arguments of all the
+/// probes of a provider. Without the `binary` path, the probes are
+/// looked up in all the binaries.
+pub(crate) fn bpftrace_script(provider: &Provider, binary: Option<&Path>) -> String {
+    let binary = binary
+        .map(|binary| binary.display().to_string())
+        .unwrap_or_else(|| "*".to_string());
+
+    format!(
+        "#!/usr/bin/env bpftrace\n// The probes of the `{provider}` provider. Run with `sudo bpftrace {file}.bt`.\n{probes}",
+        provider = provider.name,
+        file = provider.name_for_c(),
+        probes = provider
+            .probes
+            .iter()
+            .map(|probe| {
+                let formats = probe
+                    .arguments
+                    .iter()
+                    .map(|argument| ScriptFormat::new(&argument.ty))
+                    .collect::<Vec<_>>();
+
+                format!(
+                    "\nusdt:{binary}:{provider}:{probe}\n{{\n    printf(\"{provider}:{probe}{conversions}\\n\"{arguments});\n}}\n",
+                    binary = binary,
+                    provider = provider.name,
+                    probe = probe.name,
+                    conversions = formats
+                        .iter()
+                        .map(|format| format!(" {}", format.conversion()))
+                        .collect::<String>(),
+                    arguments = formats
+                        .iter()
+                        .enumerate()
+                        .map(|(nth, format)| match format {
+                            ScriptFormat::String => format!(", str(arg{})", nth),
+                            _ => format!(", arg{}", nth),
+                        })
+                        .collect::<String>(),
+                )
+            })
+            .collect::<String>(),
+    )
+}
+
+/// The probes as a Python list, shared by the debugger scripts: the
+/// provider, the probe, the C function of the shim firing it, and its
+/// arguments as `(name, kind)` pairs, the strings being printed as such.
+pub(crate) fn debugger_probes(naming: &NamingConfig, providers: &[Provider]) -> String {
+    providers
+        .iter()
+        .flat_map(|provider| {
+            provider.probes.iter().map(move |probe| {
+                format!(
+                    "    ({provider:?}, {probe:?}, {function:?}, [{arguments}]),\n",
+                    provider = provider.name,
+                    probe = probe.name,
+                    function = naming.c_function(provider, &probe.name),
+                    arguments = probe
+                        .arguments
+                        .iter()
+                        .zip(probe.names())
+                        .map(|(argument, name)| format!(
+                            "({:?}, {:?})",
+                            name,
+                            match ScriptFormat::new(&argument.ty) {
+                                ScriptFormat::String => "string",
+                                _ => "value",
+                            }
+                        ))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )
+            })
+        })
+        .collect()
+}
+
+/// The Python functions shared by the debugger scripts, selecting the
+/// probes given to the `sonde-break` command.
+pub(crate) const DEBUGGER_HELPERS: &str = r#"
+def matching_probes(command):
+    """The probes matching the patterns of a command, e.g. `hello` or
+    `hello:you*`, ignoring the case, and whether the breakpoints must
+    stop."""
+    patterns = [pattern.lower() for pattern in command.split() if pattern != "--stop"]
+    stop = "--stop" in command.split()
+
+    for probe in PROBES:
+        name = ("%s:%s" % (probe[0], probe[1])).lower()
+
+        if not patterns or any(
+            fnmatch.fnmatch(name, pattern) or fnmatch.fnmatch(name, pattern + ":*")
+            for pattern in patterns
+        ):
+            yield probe, stop
+
+
+def describe(probe):
+    provider, name, function, arguments = probe
+
+    return "%s:%s\t%s(%s)" % (
+        provider,
+        name,
+        function,
+        ", ".join(argument for argument, _ in arguments),
+    )
+"#;
+
+/// Render the header of a generated file as Python comments.
+pub(crate) fn python_comment(header: &GeneratedHeader) -> String {
+    header
+        .to_string()
+        .lines()
+        .map(|line| format!("#{}\n", line.trim_start_matches('/')))
+        .collect()
+}
+
+/// Generate a Python script for GDB, defining the `sonde-probes`
+/// command listing the probes, and the `sonde-break` command setting a
+/// breakpoint printing the arguments on each probe.
+pub(crate) fn gdb_script(
+    header: &GeneratedHeader,
+    naming: &NamingConfig,
+    providers: &[Provider],
+) -> String {
+    format!(
+        r#"{header}
+# The probes, for GDB. Load with `source sonde_gdb.py`, then run
+# `sonde-break [--stop] [provider[:probe]]...` to print the arguments of
+# the probes when they are fired, and `sonde-probes` to list them.
+
+import fnmatch
+
+import gdb
+
+PROBES = [
+{probes}]
+{helpers}
+
+def format_argument(frame, name, kind):
+    try:
+        value = frame.read_var(name)
+
+        if kind != "string":
+            return str(value)
+
+        if int(value) == 0:
+            return "NULL"
+
+        return '"%s"' % value.string(errors="replace")
+    except (gdb.error, ValueError):
+        return "<%s unavailable>" % name
+
+
+class ProbeBreakpoint(gdb.Breakpoint):
+    def __init__(self, probe, stop):
+        super().__init__(probe[2], internal=False)
+        self.probe = probe
+        self.stops = stop
+
+    def stop(self):
+        provider, name, _, arguments = self.probe
+        frame = gdb.selected_frame()
+        values = [format_argument(frame, argument, kind) for argument, kind in arguments]
+        print(" ".join(["%s:%s" % (provider, name)] + values))
+
+        return self.stops
+
+
+class ProbesCommand(gdb.Command):
+    """List the probes, with the C functions firing them."""
+
+    def __init__(self):
+        super().__init__("sonde-probes", gdb.COMMAND_USER)
+
+    def invoke(self, argument, from_tty):
+        for probe in PROBES:
+            print(describe(probe))
+
+
+class BreakCommand(gdb.Command):
+    """Print the arguments of the probes when they are fired, e.g.
+    `sonde-break hello` or `sonde-break hello:you`; all of them by
+    default. With `--stop`, stop on them too."""
+
+    def __init__(self):
+        super().__init__("sonde-break", gdb.COMMAND_BREAKPOINTS)
+
+    def invoke(self, argument, from_tty):
+        for probe, stop in matching_probes(argument):
+            ProbeBreakpoint(probe, stop)
+
+
+ProbesCommand()
+BreakCommand()
+"#,
+        header = python_comment(header),
+        probes = debugger_probes(naming, providers),
+        helpers = DEBUGGER_HELPERS,
+    )
+}
+
+/// Generate a Python script for LLDB, defining the same commands as
+/// [`gdb_script`].
+pub(crate) fn lldb_script(
+    header: &GeneratedHeader,
+    naming: &NamingConfig,
+    providers: &[Provider],
+) -> String {
+    format!(
+        r#"{header}
+# The probes, for LLDB. Load with `command script import sonde_lldb.py`,
+# then run `sonde-break [--stop] [provider[:probe]]...` to print the
+# arguments of the probes when they are fired, and `sonde-probes` to
+# list them.
+
+import fnmatch
+
+import lldb
+
+PROBES = [
+{probes}]
+{helpers}
+# The IDs of the breakpoints which stop, by `sonde-break --stop`.
+STOPPING = set()
+
+
+def format_argument(frame, name, kind):
+    value = frame.FindVariable(name)
+
+    if not value.IsValid() or value.GetError().Fail():
+        return "<%s unavailable>" % name
+
+    if kind != "string":
+        return value.GetValue() or value.GetSummary() or "?"
+
+    address = value.GetValueAsUnsigned()
+
+    if address == 0:
+        return "NULL"
+
+    error = lldb.SBError()
+    string = frame.GetThread().GetProcess().ReadCStringFromMemory(address, 4096, error)
+
+    return '"%s"' % string if error.Success() else "<%s unreadable>" % name
+
+
+def probe_fired(frame, location, *_):
+    function = frame.GetFunctionName()
+
+    for provider, name, probe_function, arguments in PROBES:
+        if probe_function == function:
+            values = [format_argument(frame, argument, kind) for argument, kind in arguments]
+            print(" ".join(["%s:%s" % (provider, name)] + values))
+
+    return location.GetBreakpoint().GetID() in STOPPING
+
+
+def sonde_probes(debugger, command, result, internal_dict):
+    """List the probes, with the C functions firing them."""
+    for probe in PROBES:
+        result.AppendMessage(describe(probe))
+
+
+def sonde_break(debugger, command, result, internal_dict):
+    """Print the arguments of the probes when they are fired, e.g.
+    `sonde-break hello` or `sonde-break hello:you`; all of them by
+    default. With `--stop`, stop on them too."""
+    target = debugger.GetSelectedTarget()
+
+    for probe, stop in matching_probes(command):
+        breakpoint = target.BreakpointCreateByName(probe[2])
+        breakpoint.SetScriptCallbackFunction("%s.probe_fired" % __name__)
+
+        if stop:
+            STOPPING.add(breakpoint.GetID())
+
+
+def __lldb_init_module(debugger, internal_dict):
+    debugger.HandleCommand("command script add -f %s.sonde_probes sonde-probes" % __name__)
+    debugger.HandleCommand("command script add -f %s.sonde_break sonde-break" % __name__)
+"#,
+        header = python_comment(header),
+        probes = debugger_probes(naming, providers),
+        helpers = DEBUGGER_HELPERS,
+    )
+}
+
+/// Generate a DTrace script printing the arguments of all the probes
+/// of a provider, in the process given with `-c` or `-p`.
+pub(crate) fn dtrace_script(provider: &Provider) -> String {
+    format!(
+        "#!/usr/sbin/dtrace -s\n/* The probes of the `{provider}` provider. Run with `sudo dtrace -s {file}.d -c <command>`. */\n\n#pragma D option quiet\n{probes}",
+        provider = provider.name,
+        file = provider.name_for_c(),
+        probes = provider
+            .probes
+            .iter()
+            .map(|probe| {
+                let formats = probe
+                    .arguments
+                    .iter()
+                    .map(|argument| ScriptFormat::new(&argument.ty))
+                    .collect::<Vec<_>>();
+
+                // DTrace exposes `__` as `-` in the probe names.
+                let probe_name = probe.name.replace("__", "-");
+
+                format!(
+                    "\n{provider}$target:::{probe}\n{{\n    printf(\"{provider}:::{probe}{conversions}\\n\"{arguments});\n}}\n",
+                    provider = provider.name,
+                    probe = probe_name,
+                    conversions = formats
+                        .iter()
+                        .map(|format| format!(" {}", format.conversion()))
+                        .collect::<String>(),
+                    arguments = formats
+                        .iter()
+                        .enumerate()
+                        .map(|(nth, format)| match format {
+                            ScriptFormat::String => format!(", copyinstr(arg{})", nth),
+                            _ => format!(", arg{}", nth),
+                        })
+                        .collect::<String>(),
+                )
+            })
+            .collect::<String>(),
+    )
+}
+
+/// SystemTap keywords, and the variables defined by the tapset, that
+/// can't be used as argument names.
+pub(crate) const STAP_RESERVED_NAMES: &[&str] = &[
+    "argstr", "break", "catch", "continue", "delete", "else", "for", "foreach", "function",
+    "global", "if", "in", "limit", "long", "name", "next", "private", "probe", "return", "string",
+    "try", "while",
+];
+
+/// Generate a SystemTap tapset declaring a probe alias per probe,
+/// with named arguments. Without the `binary` path, the probes are
+/// looked up in the binary named after the crate.
+pub(crate) fn tapset(
+    header: &GeneratedHeader,
+    providers: &[Provider],
+    binary: Option<&Path>,
+) -> String {
+    let binary = binary
+        .map(|binary| binary.display().to_string())
+        .or_else(|| env::var("CARGO_PKG_NAME").ok())
+        .unwrap_or_default();
+
+    format!(
+        "{header}\n{aliases}",
+        header = header,
+        aliases = providers
+            .iter()
+            .flat_map(|provider| {
+                let binary = &binary;
+
+                provider.probes.iter().map(move |probe| {
+                    let names = probe
+                        .names()
+                        .into_iter()
+                        .map(|name| {
+                            if STAP_RESERVED_NAMES.contains(&name.as_str()) {
+                                format!("{}_", name)
+                            } else {
+                                name
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    let formats = probe
+                        .arguments
+                        .iter()
+                        .map(|argument| ScriptFormat::new(&argument.ty))
+                        .collect::<Vec<_>>();
+
+                    format!(
+                        "\nprobe {provider}.{probe} = process({binary:?}).mark({mark:?})\n{{\n{variables}    name = {mark:?}\n    argstr = {argstr}\n}}\n",
+                        provider = provider.name_for_c(),
+                        probe = probe.name_for_c(),
+                        binary = binary,
+                        mark = probe.name,
+                        variables = names
+                            .iter()
+                            .zip(&formats)
+                            .enumerate()
+                            .map(|(nth, (name, format))| match format {
+                                ScriptFormat::String => {
+                                    format!("    {} = user_string($arg{})\n", name, nth + 1)
+                                }
+                                _ => format!("    {} = $arg{}\n", name, nth + 1),
+                            })
+                            .collect::<String>(),
+                        argstr = if names.is_empty() {
+                            "\"\"".to_string()
+                        } else {
+                            format!(
+                                "sprintf(\"{conversions}\", {names})",
+                                conversions = names
+                                    .iter()
+                                    .zip(&formats)
+                                    .map(|(name, format)| format!("{}={}", name, format.conversion()))
+                                    .collect::<Vec<_>>()
+                                    .join(" "),
+                                names = names.join(", "),
+                            )
+                        },
+                    )
+                })
+            })
+            .collect::<String>(),
+    )
+}
+
+/// Generate the Criterion benchmarks of the probes. `is_enabled` tells
+/// whether the `_is_enabled` functions are generated.
+pub(crate) fn benches(
+    header: &GeneratedHeader,
+    naming: &NamingConfig,
+    providers: &[Provider],
+    is_enabled: bool,
+) -> String {
+    format!(
+        r#"{header}
+use criterion::{{black_box, criterion_group, criterion_main, Criterion}};
+
+#[allow(unused)]
+mod tracing {{
+    include!(env!("SONDE_RUST_API_FILE"));
+}}
+
+{allow_deprecated}fn probes(c: &mut Criterion) {{
+{benches}
+}}
+
+criterion_group!(benches, probes);
+criterion_main!(benches);
+"#,
+        header = header,
+        allow_deprecated = allow_deprecated(providers),
+        benches = providers
+            .iter()
+            .flat_map(|provider| {
+                provider.probes.iter().map(move |probe| {
+                    let path = format!(
+                        "tracing::r#{module}::r#{function}",
+                        module = naming.rust_module(&provider.name),
+                        function = naming.rust_function(&provider.name, &probe.name),
+                    );
+                    let id = format!(
+                        "{module}::{function}",
+                        module = naming.rust_module(&provider.name),
+                        function = naming.rust_function(&provider.name, &probe.name),
+                    );
+
+                    format!(
+                        r#"{is_enabled}    c.bench_function("{id}/fire", |b| {{
+        b.iter(|| {path}({arguments}))
+    }});"#,
+                        is_enabled = if is_enabled {
+                            format!(
+                                "    c.bench_function(\"{id}/is_enabled\", |b| {{\n        b.iter(|| black_box({path}_is_enabled()))\n    }});\n",
+                                id = id,
+                                path = path,
+                            )
+                        } else {
+                            String::new()
+                        },
+                        id = id,
+                        path = path,
+                        arguments = probe
+                            .arguments_for_rust()
+                            .iter()
+                            .map(|(_, ty)| format!("black_box({})", bench_value(ty)))
+                            .collect::<Vec<String>>()
+                            .join(", "),
+                    )
+                })
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    )
+}
+
+/// A value of a Rust type given to the probes by the benchmarks.
+pub(crate) fn bench_value(ty: &str) -> &'static str {
+    match ty {
+        C_STRING_TYPE => "b\"sonde\\0\".as_ptr() as *mut _",
+        ty if ty.starts_with("*mut ") => "std::ptr::null_mut()",
+        "c_float" | "c_double" => "0.0",
+        "bool" => "false",
+        _ => "0",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d;
+
+    #[test]
+    fn test_script_format() {
+        assert_eq!(ScriptFormat::new("char *"), ScriptFormat::String);
+        assert_eq!(
+            ScriptFormat::new("long unsigned int"),
+            ScriptFormat::Unsigned
+        );
+        assert_eq!(ScriptFormat::new("signed char"), ScriptFormat::Signed);
+        assert_eq!(ScriptFormat::new("short"), ScriptFormat::Signed);
+        assert_eq!(
+            ScriptFormat::new("unsigned char *"),
+            ScriptFormat::Hexadecimal
+        );
+    }
+
+    #[test]
+    fn test_debugger_scripts() {
+        let script = d::parser::parse(
+            "provider hello { probe world(); probe you__me(char *who, int, struct request *); };",
+        )
+        .unwrap();
+        let naming = NamingConfig::default();
+        let probes = "PROBES = [\n    (\"hello\", \"world\", \"hello_probe_world\", []),\n    (\"hello\", \"you__me\", \"hello_probe_you_me\", [(\"who\", \"string\"), (\"arg1\", \"value\"), (\"arg2\", \"value\")]),\n]\n";
+
+        let gdb = gdb_script(&GeneratedHeader::new(), &naming, &script.providers);
+
+        assert!(gdb.starts_with("# @generated by sonde, do not edit.\n"));
+        assert!(gdb.contains(probes));
+        assert!(gdb.contains("\nProbesCommand()\nBreakCommand()\n"));
+
+        let lldb = lldb_script(&GeneratedHeader::new(), &naming, &script.providers);
+
+        assert!(lldb.contains(probes));
+        assert!(lldb.contains("def __lldb_init_module(debugger, internal_dict):"));
+    }
+
+    #[test]
+    fn test_benches() {
+        let script = d::parser::parse(
+            "provider hello { probe world(); probe you(char *, int, struct request *); };",
+        )
+        .unwrap();
+        let naming = NamingConfig::default();
+
+        let benches_source = benches(&GeneratedHeader::new(), &naming, &script.providers, false);
+
+        assert!(syn::parse_file(&benches_source).is_ok());
+        assert!(benches_source.contains(
+            "    c.bench_function(\"hello::world/fire\", |b| {\n        b.iter(|| tracing::r#hello::r#world())\n    });"
+        ));
+        assert!(benches_source.contains(
+            "        b.iter(|| tracing::r#hello::r#you(black_box(b\"sonde\\0\".as_ptr() as *mut _), black_box(0), black_box(std::ptr::null_mut())))"
+        ));
+        assert!(!benches_source.contains("is_enabled"));
+        assert!(benches_source
+            .contains("criterion_group!(benches, probes);\ncriterion_main!(benches);"));
+
+        let benches_source = benches(&GeneratedHeader::new(), &naming, &script.providers, true);
+
+        assert!(benches_source.contains(
+            "    c.bench_function(\"hello::world/is_enabled\", |b| {\n        b.iter(|| black_box(tracing::r#hello::r#world_is_enabled()))\n    });"
+        ));
+    }
+
+    #[test]
+    fn test_scripts() {
+        let script = d::parser::parse(
+            "provider hello {
+                 probe world();
+                 probe you__me(char *, int, size_t, struct request *);
+             };",
+        )
+        .unwrap();
+        let provider = &script.providers[0];
+
+        assert_eq!(
+            bpftrace_script(provider, Some(Path::new("/app"))),
+            r#"#!/usr/bin/env bpftrace
+// The probes of the `hello` provider. Run with `sudo bpftrace hello.bt`.
+
+usdt:/app:hello:world
+{
+    printf("hello:world\n");
+}
+
+usdt:/app:hello:you__me
+{
+    printf("hello:you__me %s %d %u 0x%x\n", str(arg0), arg1, arg2, arg3);
+}
+"#
+        );
+        assert!(dtrace_script(provider).ends_with(
+            r#"
+hello$target:::you-me
+{
+    printf("hello:::you-me %s %d %u 0x%x\n", copyinstr(arg0), arg1, arg2, arg3);
+}
+"#
+        ));
+    }
+
+    #[test]
+    fn test_tapset() {
+        let script = d::parser::parse(
+            "provider hello {
+                 probe world();
+                 probe request__start(char *method, int name);
+             };",
+        )
+        .unwrap();
+
+        assert_eq!(
+            tapset(
+                &GeneratedHeader::new(),
+                &script.providers,
+                Some(Path::new("/app"))
+            )
+            .split_once("\n\nprobe ")
+            .unwrap()
+            .1,
+            r#"hello.world = process("/app").mark("world")
+{
+    name = "world"
+    argstr = ""
+}
+
+probe hello.request_start = process("/app").mark("request__start")
+{
+    method = user_string($arg1)
+    name_ = $arg2
+    name = "request__start"
+    argstr = sprintf("method=%s name_=%d", method, name_)
+}
+"#
+        );
+    }
+}
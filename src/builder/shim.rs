@@ -0,0 +1,513 @@
+//! The generation of the C shim, wrapping the probe macros in
+//! exported functions.
+
+use super::{backends::Shim, Builder, LinkMode};
+use crate::{
+    d::ast::{opaque_type, Argument, Enum, Names, Probe, Provider},
+    header::GeneratedHeader,
+    naming::NamingConfig,
+};
+use std::{
+    borrow::Cow,
+    env,
+    path::{Path, PathBuf},
+};
+
+pub(crate) const FFI_LIBRARY_NAME: &str = "sonde-ffi";
+
+/// The name of the header generated by `dtrace -h`, as included by
+/// the C shim returned by `Builder::generate`.
+pub(crate) const GENERATED_DTRACE_HEADER_NAME: &str = "probes.h";
+
+/// The maximum number of arguments of a probe supported by DTrace.
+pub(crate) const MAX_ARGUMENTS: usize = 10;
+
+/// The prefix of the functions exported to C by the Rust API, see
+/// `Builder::export_c_abi`.
+pub(crate) const EXPORTED_FUNCTION_PREFIX: &str = "sonde_";
+
+/// Generate the C header declaring the functions of the FFI shim, and
+/// defining the structs of the packed arguments, see
+/// [`Builder::pack_arguments`].
+pub(crate) fn ffi_header(
+    header: &GeneratedHeader,
+    naming: &NamingConfig,
+    providers: &[Provider],
+    packed_structs: &str,
+) -> String {
+    format!(
+        r#"{header}
+#ifndef SONDE_PROBES_H
+#define SONDE_PROBES_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+{forward_declarations}{packed_structs}
+#ifdef __cplusplus
+extern "C" {{
+#endif
+{declarations}
+#ifdef __cplusplus
+}}
+#endif
+
+#endif
+"#,
+        header = header,
+        forward_declarations = forward_declarations(providers),
+        packed_structs = packed_structs,
+        declarations = providers
+            .iter()
+            .map(|provider| {
+                provider
+                    .probes
+                    .iter()
+                    .map(|probe| {
+                        format!(
+                            r#"
+/* Fire the `{probe}` probe of the `{provider}` provider. */
+void {function}({arguments});
+
+/* Check whether a tracer is attached to the `{probe}` probe. */
+int {function}_enabled(void);
+"#,
+                            probe = probe.name,
+                            provider = provider.name,
+                            function = naming.c_function(provider, &probe.name),
+                            arguments = if probe.arguments.is_empty() {
+                                "void".to_string()
+                            } else {
+                                probe.arguments_for_c()
+                            },
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join("")
+            })
+            .collect::<Vec<String>>()
+            .join("")
+    )
+}
+
+/// Define the enums of the arguments, e.g. `enum state { … };`, used
+/// by the prototypes of the header generated by `dtrace -h`.
+pub(crate) fn c_enums(providers: &[Provider]) -> String {
+    let mut enums = Vec::<&Enum>::new();
+
+    for enum_ in providers.iter().flat_map(|provider| &provider.enums) {
+        if enums.iter().all(|other| other.name != enum_.name) {
+            enums.push(enum_);
+        }
+    }
+
+    enums
+        .iter()
+        .map(|enum_| {
+            format!(
+                "\nenum {name} {{\n{variants}}};\n",
+                name = enum_.name,
+                variants = enum_
+                    .variants
+                    .iter()
+                    .map(|variant| format!("    {} = {},\n", variant.name, variant.value))
+                    .collect::<String>(),
+            )
+        })
+        .collect()
+}
+
+/// Declare the structs and unions pointed by the arguments, e.g.
+/// `struct request;`, so that the C compiler knows them.
+pub(crate) fn forward_declarations(providers: &[Provider]) -> String {
+    let mut types = providers
+        .iter()
+        .flat_map(|provider| &provider.probes)
+        .flat_map(|probe| &probe.arguments)
+        .filter_map(|argument| opaque_type(&argument.ty))
+        .collect::<Vec<_>>();
+    types.sort_unstable();
+    types.dedup();
+
+    types.iter().map(|ty| format!("\n{};\n", ty)).collect()
+}
+
+impl Builder {
+    /// Write a C header declaring the functions of the FFI shim, and a
+    /// `module.modulemap` next to it, so that Swift, Objective-C or C code
+    /// fires the same probes as Rust.
+    pub fn ffi_header<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.ffi_header = Some(path.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// Generate the C shim, wrapping the probe macros of the header
+    /// generated by `dtrace`, included from `header_file`, in C
+    /// functions: the macros can't be called from Rust.
+    pub(crate) fn ffi_source(
+        &self,
+        header: &GeneratedHeader,
+        providers: &[Provider],
+        header_file: &Path,
+    ) -> String {
+        let ffi_providers = self.ffi_providers(providers);
+
+        format!(
+            r#"{header}
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+{enums}{forward_declarations}{packed_structs}
+#include {header_file:?}
+{extern_checks}
+{wrappers}"#,
+            header = header,
+            header_file = header_file,
+            enums = c_enums(providers),
+            forward_declarations = forward_declarations(&ffi_providers),
+            packed_structs = self.packed_structs(providers),
+            extern_checks = self.extern_checks(providers),
+            wrappers = ffi_providers
+                .iter()
+                .map(|provider| {
+                    provider
+                        .probes
+                        .iter()
+                        .map(|probe| {
+                            format!(
+                                r#"
+void {function}({arguments}) {{
+    {macro_prefix}_{macro_suffix}({argument_names});
+}}
+
+int {function}_enabled(void) {{
+    return {macro_prefix}_{macro_suffix}_ENABLED();
+}}
+"#,
+                                function = self.naming.c_function(provider, &probe.name),
+                                macro_prefix = provider.name_for_c_macro(),
+                                macro_suffix = probe.name_for_c_macro(),
+                                arguments = probe.arguments_for_c(),
+                                argument_names = probe.argument_names(),
+                            )
+                        })
+                        .collect::<Vec<String>>()
+                        .join("")
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        )
+    }
+
+    /// Whether the arguments of a probe are packed in a struct, see
+    /// [`Builder::pack_arguments`].
+    pub(crate) fn packs(&self, probe: &Probe) -> bool {
+        self.pack_arguments && probe.arguments.len() > MAX_ARGUMENTS
+    }
+
+    /// The providers as seen by `dtrace` and by the C shim, i.e. with the
+    /// packed probes taking a single pointer to their packed arguments.
+    /// `providers` must already be namespaced.
+    pub(crate) fn ffi_providers<'p>(&self, providers: &'p [Provider]) -> Cow<'p, [Provider]> {
+        if !providers
+            .iter()
+            .flat_map(|provider| &provider.probes)
+            .any(|probe| self.packs(probe))
+        {
+            return Cow::Borrowed(providers);
+        }
+
+        let mut providers = providers.to_vec();
+
+        for provider in &mut providers {
+            for probe in &mut provider.probes {
+                if self.packs(probe) {
+                    probe.arguments = vec![Argument {
+                        ty: format!(
+                            "struct {} *",
+                            self.naming.packed_struct(&provider.name, &probe.name)
+                        ),
+                        name: Some("args".to_string()),
+                    }];
+                }
+            }
+        }
+
+        Cow::Owned(providers)
+    }
+
+    /// Generate the C definitions of the structs packing the arguments of
+    /// the probes, see [`Builder::pack_arguments`]. Their fields have the
+    /// C types of the fields of the Rust structs.
+    pub(crate) fn packed_structs(&self, providers: &[Provider]) -> String {
+        providers
+            .iter()
+            .flat_map(|provider| {
+                provider
+                    .probes
+                    .iter()
+                    .filter(|probe| self.packs(probe))
+                    .map(move |probe| {
+                        format!(
+                            "\n/* The arguments of the `{probe}` probe of the `{provider}` provider. */\nstruct {name} {{\n{fields}}};\n",
+                            probe = probe.name,
+                            provider = provider.name,
+                            name = self.naming.packed_struct(&provider.name, &probe.name),
+                            fields = probe
+                                .c_declarations_from_rust()
+                                .iter()
+                                .map(|field| format!("    {};\n", field))
+                                .collect::<String>(),
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    /// Generate the prototypes of the C functions, as declared by the
+    /// externs of the Rust API, and static assertions on the sizes of the
+    /// C types, so that a disagreement fails the build of the shim.
+    pub(crate) fn extern_checks(&self, providers: &[Provider]) -> String {
+        let mut assertions = Vec::new();
+
+        for assertion in providers
+            .iter()
+            .flat_map(|provider| &provider.probes)
+            .flat_map(Probe::c_type_assertions)
+        {
+            if !assertions.contains(&assertion) {
+                assertions.push(assertion);
+            }
+        }
+
+        let prototypes = self
+            .ffi_providers(providers)
+            .iter()
+            .flat_map(|provider| {
+                provider.probes.iter().map(move |probe| {
+                    format!(
+                        "void {function}({arguments});\nint {function}_enabled(void);\n",
+                        function = self.naming.c_function(provider, &probe.name),
+                        arguments = match probe.arguments_for_c_from_rust_externs() {
+                            arguments if arguments.is_empty() => "void".to_string(),
+                            arguments => arguments,
+                        },
+                    )
+                })
+            })
+            .collect::<String>();
+
+        format!(
+            "\n// The C functions as declared by the Rust API.\n{assertions}{prototypes}",
+            assertions = assertions
+                .iter()
+                .map(|assertion| format!("{}\n", assertion))
+                .collect::<String>(),
+            prototypes = prototypes,
+        )
+    }
+
+    /// Generate the C declarations of the functions exported by
+    /// [`Builder::export_c_abi`].
+    pub(crate) fn exported_declarations(&self, providers: &[Provider]) -> String {
+        format!(
+            r#"
+/* The probes exported by the Rust API. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+{forward_declarations}
+{declarations}"#,
+            forward_declarations = forward_declarations(providers),
+            declarations = providers
+                .iter()
+                .flat_map(|provider| {
+                    provider.probes.iter().map(move |probe| {
+                        format!(
+                            "void {function}({arguments});\nint {function}_enabled(void);\n",
+                            function = self.exported_function(provider, probe),
+                            arguments = match probe.arguments_for_c().as_str() {
+                                "" => "void".to_string(),
+                                arguments => arguments.to_string(),
+                            },
+                        )
+                    })
+                })
+                .collect::<String>(),
+        )
+    }
+
+    /// The name of the static library of the FFI shim, see
+    /// [`Builder::lib_name`].
+    pub(crate) fn ffi_library_name(&self) -> String {
+        match &self.lib_name {
+            Some(name) => name.clone(),
+            None => match env::var("CARGO_PKG_NAME") {
+                Ok(crate_name) => format!("{}-{}", FFI_LIBRARY_NAME, crate_name),
+                Err(_) => FFI_LIBRARY_NAME.to_string(),
+            },
+        }
+    }
+
+    /// Tell Cargo how to link the C shim, according to the link mode,
+    /// and what its probes need.
+    pub(crate) fn emit_link_directives(
+        &self,
+        out_dir: &str,
+        lib_name: &str,
+        objects: &[PathBuf],
+        shim: &dyn Shim,
+    ) {
+        for directive in self
+            .link_directives(out_dir, lib_name, objects)
+            .into_iter()
+            .chain(shim.link_directives())
+        {
+            println!("cargo:{}", directive);
+        }
+    }
+
+    /// The Cargo directives linking the C shim, according to the link
+    /// mode. `objects` are the objects of the shim, only linked one by
+    /// one with [`LinkMode::Object`].
+    pub(crate) fn link_directives(
+        &self,
+        out_dir: &str,
+        lib_name: &str,
+        objects: &[PathBuf],
+    ) -> Vec<String> {
+        match self.link_mode {
+            LinkMode::Default => vec![
+                format!("rustc-link-lib=static={}", lib_name),
+                format!("rustc-link-search=native={}", out_dir),
+            ],
+            LinkMode::WholeArchive => vec![
+                format!("rustc-link-lib=static:+whole-archive={}", lib_name),
+                format!("rustc-link-search=native={}", out_dir),
+            ],
+            LinkMode::Object => objects
+                .iter()
+                .map(|object| format!("rustc-link-arg={}", object.display()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d;
+
+    #[test]
+    fn test_ffi_header() {
+        let script =
+            d::parser::parse("provider hello { probe world(); probe you(char *who, int); };")
+                .unwrap();
+        let header = ffi_header(
+            &GeneratedHeader::new(),
+            &NamingConfig::default(),
+            &script.providers,
+            "",
+        );
+
+        assert!(header.contains("\nvoid hello_probe_world(void);\n"));
+        assert!(header.contains("\nvoid hello_probe_you(char * who, int arg1);\n"));
+        assert!(header.contains("#ifdef __cplusplus\nextern \"C\" {\n"));
+    }
+
+    #[test]
+    fn test_exported_declarations() {
+        let script = d::parser::parse(
+            "provider hello {
+                 probe world();
+                 probe you(struct request *, int);
+             };",
+        )
+        .unwrap();
+        let declarations = Builder::new().exported_declarations(&script.providers);
+
+        assert!(declarations.contains("\nstruct request;\n"));
+        assert!(declarations.contains(
+            "void sonde_hello_probe_world(void);\nint sonde_hello_probe_world_enabled(void);\n"
+        ));
+        assert!(
+            declarations.contains("void sonde_hello_probe_you(struct request * arg0, int arg1);\n")
+        );
+    }
+
+    #[test]
+    fn test_extern_checks() {
+        let c = Builder::new()
+            .source(
+                "hello",
+                "provider hello { probe you(char *, size_t); probe req(struct request *); probe me(); };",
+            )
+            .generate()
+            .c;
+
+        assert!(c.contains(
+            "\n// The C functions as declared by the Rust API.\n_Static_assert(sizeof(size_t) == sizeof(void *), \"`size_t` must have the size of a Rust `usize`\");\n_Static_assert(((size_t) -1 < 0) == 0, \"`size_t` must be unsigned like a Rust `usize`\");\nvoid hello_probe_you(char * arg0, size_t arg1);\nint hello_probe_you_enabled(void);\n"
+        ));
+        assert!(c.contains("void hello_probe_req(struct request * arg0);\n"));
+        assert!(c.contains("void hello_probe_me(void);\n"));
+
+        // The prototypes of the header of `dtrace` use the struct.
+        assert!(c.find("\nstruct request;\n") < c.find("#include \""));
+    }
+
+    #[test]
+    fn test_link_directives() {
+        let objects = [
+            PathBuf::from("/out/sonde-ffi.o"),
+            PathBuf::from("/out/probes.o"),
+        ];
+        let mut builder = Builder::new();
+
+        assert_eq!(
+            builder.link_directives("/out", "sonde-ffi", &objects),
+            vec![
+                "rustc-link-lib=static=sonde-ffi",
+                "rustc-link-search=native=/out"
+            ]
+        );
+        assert_eq!(
+            builder.link_mode(LinkMode::WholeArchive).link_directives(
+                "/out",
+                "sonde-ffi",
+                &objects
+            ),
+            vec![
+                "rustc-link-lib=static:+whole-archive=sonde-ffi",
+                "rustc-link-search=native=/out"
+            ]
+        );
+        assert_eq!(
+            builder
+                .link_mode(LinkMode::Object)
+                .link_directives("/out", "sonde-ffi", &objects),
+            vec![
+                "rustc-link-arg=/out/sonde-ffi.o",
+                "rustc-link-arg=/out/probes.o"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ffi_library_name() {
+        let mut builder = Builder::new();
+
+        assert!(builder.ffi_library_name().starts_with("sonde-ffi"));
+
+        builder.lib_name("myapp-probes");
+
+        assert_eq!(builder.ffi_library_name(), "myapp-probes");
+    }
+}
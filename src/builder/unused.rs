@@ -0,0 +1,181 @@
+//! The detection of the probes that no Rust source fires.
+
+use super::codegen::spans;
+use crate::{
+    d::ast::{Probe, Provider},
+    naming::NamingConfig,
+};
+use std::{
+    fs::{self, read_to_string},
+    path::{Path, PathBuf},
+};
+
+/// Read all the `.rs` files found recursively in `directory`, as
+/// `(path, contents)` pairs. Unreadable files are skipped.
+pub(crate) fn rust_sources(directory: &Path) -> Vec<(PathBuf, String)> {
+    let mut sources = Vec::new();
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return sources,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        if path.is_dir() {
+            sources.extend(rust_sources(&path));
+        } else if path.extension().and_then(|ext| ext.to_str()) == Some("rs") {
+            if let Ok(contents) = read_to_string(&path) {
+                sources.push((path, contents));
+            }
+        }
+    }
+
+    sources.sort();
+
+    sources
+}
+
+/// The functions firing a probe of a provider, see
+/// `Builder::report_unused_probes`.
+pub(crate) const FIRING_SUFFIXES: &[&str] = &[
+    "",
+    "_with",
+    "_os",
+    "_bytes",
+    "_cast",
+    "_sampled",
+    "_rate_limited",
+    "_os_sampled",
+    "_os_rate_limited",
+    "_with_sampled",
+    "_with_rate_limited",
+];
+
+/// Find the probes that no Rust source fires, i.e. calls or refers to
+/// by path one of their functions in a file referring to the module of
+/// their provider.
+pub(crate) fn unused_probes<'p>(
+    naming: &NamingConfig,
+    providers: &'p [Provider],
+    sources: &[(PathBuf, String)],
+) -> Vec<(&'p Provider, &'p Probe)> {
+    let mut unused = Vec::new();
+
+    for provider in providers {
+        let module = format!("{}::", naming.rust_module(&provider.name));
+        let sources = sources
+            .iter()
+            .filter(|(_, contents)| contents.contains(&module))
+            .collect::<Vec<_>>();
+        let spans = spans(provider);
+
+        for probe in &provider.probes {
+            let mut functions = FIRING_SUFFIXES
+                .iter()
+                .map(|suffix| {
+                    format!(
+                        "{}{}",
+                        naming.rust_function(&provider.name, &probe.name),
+                        suffix
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            for (base, start, end) in &spans {
+                if start.name == probe.name || end.name == probe.name {
+                    functions
+                        .push(naming.rust_function(&provider.name, &format!("{}__span", base)));
+                }
+            }
+
+            let is_fired = sources.iter().any(|(_, contents)| {
+                functions.iter().any(|function| {
+                    calls_function(contents, function)
+                        || refers_to_function(contents, &format!("{}{}", module, function))
+                })
+            });
+
+            if !is_fired {
+                unused.push((provider, probe));
+            }
+        }
+    }
+
+    unused
+}
+
+/// Check whether `source` calls `function`, i.e. contains `function(`
+/// not preceded nor followed by another identifier character.
+pub(crate) fn calls_function(source: &str, function: &str) -> bool {
+    let is_identifier = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    source.match_indices(function).any(|(index, _)| {
+        let before = source[..index].chars().next_back();
+        let after = source[index + function.len()..].trim_start();
+
+        !before.is_some_and(is_identifier) && after.starts_with('(')
+    })
+}
+
+/// Check whether `source` refers to the function at `path`, e.g.
+/// `hello::you` given to `#[sonde::trace(entry = hello::you)]` or to
+/// `#[probe(hello::you)]`, i.e. contains `path` not preceded nor
+/// followed by another identifier character, nor followed by `::`.
+pub(crate) fn refers_to_function(source: &str, path: &str) -> bool {
+    let is_identifier = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    source.match_indices(path).any(|(index, _)| {
+        let before = source[..index].chars().next_back();
+        let after = &source[index + path.len()..];
+
+        !before.is_some_and(is_identifier)
+            && !after.starts_with(is_identifier)
+            && !after.trim_start().starts_with("::")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::d;
+
+    #[test]
+    fn test_unused_probes() {
+        let script = d::parser::parse(
+            "provider hello {
+                 probe world();
+                 probe you__me(char *);
+                 probe query__start();
+                 probe query__end();
+                 probe never();
+                 probe elsewhere();
+                 probe traced();
+                 probe derived();
+             };",
+        )
+        .unwrap();
+        let sources = vec![
+            (
+                PathBuf::from("src/main.rs"),
+                "use probes::hello::{self, you_me_os};\n\nfn main() {\n    hello::world();\n    you_me_os(\"Gordon\");\n    let _span = hello::query_span ();\n    assert_eq!(hello::never::NAME, \"never\");\n    never_is_fired();\n}\n".to_string(),
+            ),
+            (
+                PathBuf::from("src/lib.rs"),
+                "pub fn run() { elsewhere(); }".to_string(),
+            ),
+            (
+                PathBuf::from("src/attributes.rs"),
+                "#[sonde::trace(entry = tracing::hello::traced)]\nfn work() {}\n\n#[derive(ProbeArgs)]\n#[probe(hello::derived)]\nstruct Args;\n".to_string(),
+            ),
+        ];
+
+        assert_eq!(
+            unused_probes(&NamingConfig::default(), &script.providers, &sources)
+                .iter()
+                .map(|(provider, probe)| (provider.name.as_str(), probe.name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("hello", "never"), ("hello", "elsewhere")]
+        );
+    }
+}
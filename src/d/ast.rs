@@ -1,8 +1,14 @@
-pub trait Names {
+//! The abstract syntax tree (AST) of `.d` files, as returned by
+//! [`parse`](super::parser::parse). Contrary to the [CST](super::cst),
+//! it only keeps the `provider` blocks and their probes.
+
+use std::{borrow::Cow, fmt, str::FromStr};
+
+pub(crate) trait Names {
     fn name(&self) -> &str;
 
     fn safe_name(&self) -> String {
-        self.name().replace("__", "_")
+        self.name().replace('-', "_").replace("__", "_")
     }
 
     fn name_for_c_macro(&self) -> String {
@@ -19,19 +25,414 @@ pub trait Names {
 }
 
 /// Contains `provider` blocks from a `.d` file.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Script {
     pub providers: Vec<Provider>,
+
+    /// The top-level `enum` declarations.
+    pub enums: Vec<Enum>,
+
+    /// The `#pragma D option` directives, in the order of the script.
+    pub options: Vec<DtraceOption>,
+}
+
+/// Describes a `#pragma D option` directive, e.g. `#pragma D option
+/// quiet` or `#pragma D option bufsize=16m`.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DtraceOption {
+    /// The option's name, e.g. `bufsize`.
+    pub name: String,
+
+    /// The option's value, e.g. `16m`, if any.
+    pub value: Option<String>,
+}
+
+impl DtraceOption {
+    /// The names of the compiler options of `dtrace`, which affect the
+    /// generation of the header and of the probes. The other options,
+    /// e.g. `quiet` or `bufsize`, only apply when tracing.
+    pub const COMPILER_OPTIONS: &'static [&'static str] = &[
+        "amin",
+        "argref",
+        "core",
+        "cpp",
+        "cpphdrs",
+        "cpppath",
+        "ctypes",
+        "define",
+        "dtypes",
+        "empty",
+        "errtags",
+        "evaltime",
+        "incdir",
+        "iregs",
+        "kdefs",
+        "knodefs",
+        "late",
+        "lazyload",
+        "ldpath",
+        "libdir",
+        "linkmode",
+        "linktype",
+        "nolibs",
+        "pgmax",
+        "pspec",
+        "stdc",
+        "strip",
+        "syslibdir",
+        "tree",
+        "tregs",
+        "udefs",
+        "undef",
+        "unodefs",
+        "verbose",
+        "version",
+        "zdefs",
+    ];
+
+    /// Whether the option is a compiler option, see
+    /// [`DtraceOption::COMPILER_OPTIONS`].
+    pub fn is_compiler_option(&self) -> bool {
+        Self::COMPILER_OPTIONS.contains(&self.name.as_str())
+    }
+}
+
+/// Describes a top-level `enum` declaration, e.g. `enum state { IDLE,
+/// BUSY = 4 };`. Its name can be the type of probe arguments, e.g.
+/// `enum state`, passed as an `int`.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Enum {
+    /// The enum's name, e.g. `state`.
+    pub name: String,
+
+    /// The enumerators, in the order of the declaration.
+    pub variants: Vec<Variant>,
+
+    /// The enum's documentation, written in a `/** … */` or `///`
+    /// comment before the enum.
+    pub doc: Option<String>,
+}
+
+/// An enumerator of an [`Enum`], e.g. `BUSY = 4`.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Variant {
+    /// The enumerator's name, e.g. `BUSY`.
+    pub name: String,
+
+    /// The enumerator's value, explicit or implicit, e.g. `4`.
+    pub value: i64,
 }
 
 /// Describes a `provider` block.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Provider {
     /// The provider's name.
     pub name: String,
 
     /// The probes defined inside the the block.
     pub probes: Vec<Probe>,
+
+    /// The provider's documentation, written in a `/** … */` or `///`
+    /// comment before the provider.
+    pub doc: Option<String>,
+
+    /// The provider's version (e.g. `1.2.0`), declared with a
+    /// `@version` annotation in a comment before the provider.
+    pub version: Option<String>,
+
+    /// The provider's stability attributes, declared with `#pragma D
+    /// attributes` directives.
+    pub attributes: ProviderAttributes,
+
+    /// The enums of the arguments of the probes, among the enums of the
+    /// script. They are rendered with the script, not with the
+    /// provider.
+    pub enums: Vec<Enum>,
+}
+
+/// The stability attributes of the components of a provider, declared
+/// with `#pragma D attributes Evolving/Evolving/ISA provider hello
+/// args`. A component without a directive is `None`.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProviderAttributes {
+    pub provider: Option<Attributes>,
+    pub module: Option<Attributes>,
+    pub function: Option<Attributes>,
+    pub name: Option<Attributes>,
+    pub args: Option<Attributes>,
+}
+
+impl ProviderAttributes {
+    /// The names of the components, as written in the directives.
+    pub const COMPONENTS: &'static [&'static str] =
+        &["provider", "module", "function", "name", "args"];
+
+    /// The declared attributes, with the names of their components.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &Attributes)> {
+        Self::COMPONENTS
+            .iter()
+            .zip(&[
+                &self.provider,
+                &self.module,
+                &self.function,
+                &self.name,
+                &self.args,
+            ])
+            .filter_map(|(component, attributes)| {
+                attributes
+                    .as_ref()
+                    .map(|attributes| (*component, attributes))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// The attributes of a component, by its name.
+    pub(crate) fn get_mut(&mut self, component: &str) -> Option<&mut Option<Attributes>> {
+        match component {
+            "provider" => Some(&mut self.provider),
+            "module" => Some(&mut self.module),
+            "function" => Some(&mut self.function),
+            "name" => Some(&mut self.name),
+            "args" => Some(&mut self.args),
+            _ => None,
+        }
+    }
+}
+
+/// Stability attributes, e.g. `Evolving/Evolving/ISA`: the stability of
+/// the names, the stability of the data, and the dependency class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Attributes {
+    pub name: Stability,
+    pub data: Stability,
+    pub class: DependencyClass,
+}
+
+impl fmt::Display for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.name, self.data, self.class)
+    }
+}
+
+impl FromStr for Attributes {
+    type Err = String;
+
+    fn from_str(attributes: &str) -> Result<Self, Self::Err> {
+        match attributes.split('/').collect::<Vec<_>>()[..] {
+            [name, data, class] => Ok(Self {
+                name: name.parse()?,
+                data: data.parse()?,
+                class: class.parse()?,
+            }),
+            _ => Err(format!(
+                "expected `name/data/class` attributes, e.g. `Evolving/Evolving/Common`, found `{}`",
+                attributes
+            )),
+        }
+    }
+}
+
+/// The stability of an interface, from the least to the most stable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Stability {
+    Internal,
+    Private,
+    Obsolete,
+    External,
+    Unstable,
+    Evolving,
+    Stable,
+    Standard,
+}
+
+impl Stability {
+    const ALL: &'static [Self] = &[
+        Self::Internal,
+        Self::Private,
+        Self::Obsolete,
+        Self::External,
+        Self::Unstable,
+        Self::Evolving,
+        Self::Stable,
+        Self::Standard,
+    ];
+}
+
+impl fmt::Display for Stability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl FromStr for Stability {
+    type Err = String;
+
+    fn from_str(stability: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|known| known.to_string().eq_ignore_ascii_case(stability))
+            .copied()
+            .ok_or_else(|| format!("unknown stability `{}`", stability))
+    }
+}
+
+/// The dependency class of an interface, i.e. on which architectures
+/// it exists, from the most specific to the most common.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DependencyClass {
+    Unknown,
+    Cpu,
+    Platform,
+    Group,
+    Isa,
+    Common,
+}
+
+impl DependencyClass {
+    const ALL: &'static [Self] = &[
+        Self::Unknown,
+        Self::Cpu,
+        Self::Platform,
+        Self::Group,
+        Self::Isa,
+        Self::Common,
+    ];
+}
+
+impl fmt::Display for DependencyClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unknown => "Unknown",
+            Self::Cpu => "CPU",
+            Self::Platform => "Platform",
+            Self::Group => "Group",
+            Self::Isa => "ISA",
+            Self::Common => "Common",
+        })
+    }
+}
+
+impl FromStr for DependencyClass {
+    type Err = String;
+
+    fn from_str(class: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|known| known.to_string().eq_ignore_ascii_case(class))
+            .copied()
+            .ok_or_else(|| format!("unknown dependency class `{}`", class))
+    }
+}
+
+impl Script {
+    /// Render the script as the content of a `.d` file. Parsing it
+    /// gives back the same script.
+    pub fn to_d_source(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for option in &self.options {
+            writeln!(f, "{}", option)?;
+        }
+
+        for (nth, enum_) in self.enums.iter().enumerate() {
+            if nth > 0 || !self.options.is_empty() {
+                writeln!(f)?;
+            }
+
+            writeln!(f, "{}", enum_)?;
+        }
+
+        for (nth, provider) in self.providers.iter().enumerate() {
+            if nth > 0 || !self.options.is_empty() || !self.enums.is_empty() {
+                writeln!(f)?;
+            }
+
+            writeln!(f, "{}", provider)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for DtraceOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "#pragma D option {}={}", self.name, value),
+            None => write!(f, "#pragma D option {}", self.name),
+        }
+    }
+}
+
+impl fmt::Display for Enum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_doc(f, self.doc.as_deref(), &[])?;
+        writeln!(f, "enum {} {{", self.name)?;
+
+        for variant in &self.variants {
+            writeln!(f, "    {} = {},", variant.name, variant.value)?;
+        }
+
+        write!(f, "}};")
+    }
+}
+
+impl Provider {
+    /// Find the enums of the arguments of the probes among `enums`.
+    pub(crate) fn used_enums(&self, enums: &[Enum]) -> Vec<Enum> {
+        enums
+            .iter()
+            .filter(|enum_| {
+                self.probes
+                    .iter()
+                    .flat_map(|probe| &probe.arguments)
+                    .any(|argument| enum_name(&argument.ty) == Some(enum_.name.as_str()))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_doc(
+            f,
+            self.doc.as_deref(),
+            &[("version", self.version.as_deref())],
+        )?;
+        writeln!(f, "provider {} {{", self.name)?;
+
+        for probe in &self.probes {
+            for line in probe.to_string().lines() {
+                writeln!(f, "    {}", line)?;
+            }
+        }
+
+        write!(f, "}};")?;
+
+        for (component, attributes) in self.attributes.iter() {
+            write!(
+                f,
+                "\n#pragma D attributes {} provider {} {}",
+                attributes, self.name, component
+            )?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Names for Provider {
@@ -41,13 +442,110 @@ impl Names for Provider {
 }
 
 /// Describes a `probe`.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Probe {
     /// THe probe's name.
     pub name: String,
 
     /// The probe's arguments.
-    pub arguments: Vec<String>,
+    pub arguments: Vec<Argument>,
+
+    /// The probe's level (e.g. `debug`), declared with a `@level`
+    /// annotation in a comment before the probe.
+    pub level: Option<String>,
+
+    /// The probe's deprecation note (e.g. `Use greeting__start
+    /// instead.`), declared with a `@deprecated` annotation in a comment
+    /// before the probe. It's empty if the annotation has no note.
+    pub deprecated: Option<String>,
+
+    /// The probe's documentation, written in a `/** … */` or `///`
+    /// comment before the probe.
+    pub doc: Option<String>,
+}
+
+/// Describes a probe's argument, e.g. `char *method`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Argument {
+    /// The argument's type, e.g. `char *`. The dimensions of an array
+    /// are part of it, e.g. `char [64]` for `char buf[64]`.
+    pub ty: String,
+
+    /// The argument's name, if any, e.g. `method`.
+    pub name: Option<String>,
+}
+
+impl From<&str> for Argument {
+    fn from(ty: &str) -> Self {
+        Self {
+            ty: ty.to_string(),
+            name: None,
+        }
+    }
+}
+
+impl fmt::Display for Argument {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (base, dimensions) = self.ty.split_at(self.ty.find('[').unwrap_or(self.ty.len()));
+        let base = base.trim_end();
+
+        match &self.name {
+            Some(name) if base.ends_with('*') => write!(f, "{}{}{}", base, name, dimensions),
+            Some(name) => write!(f, "{} {}{}", base, name, dimensions),
+            None => f.write_str(&self.ty),
+        }
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_doc(
+            f,
+            self.doc.as_deref(),
+            &[
+                ("level", self.level.as_deref()),
+                ("deprecated", self.deprecated.as_deref()),
+            ],
+        )?;
+        write!(
+            f,
+            "probe {}({});",
+            self.name,
+            self.arguments
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+    }
+}
+
+/// Write a documentation, and the annotations (e.g. `@level debug`)
+/// with a value, as `///` comments.
+fn write_doc(
+    f: &mut fmt::Formatter,
+    doc: Option<&str>,
+    annotations: &[(&str, Option<&str>)],
+) -> fmt::Result {
+    for line in doc.into_iter().flat_map(str::lines) {
+        if line.is_empty() {
+            writeln!(f, "///")?;
+        } else {
+            writeln!(f, "/// {}", line)?;
+        }
+    }
+
+    for (annotation, value) in annotations {
+        match value {
+            Some("") => writeln!(f, "/// @{}", annotation)?,
+            Some(value) => writeln!(f, "/// @{} {}", annotation, value)?,
+            None => (),
+        }
+    }
+
+    Ok(())
 }
 
 impl Names for Probe {
@@ -56,51 +554,637 @@ impl Names for Probe {
     }
 }
 
+/// The levels a probe can have.
+pub const LEVELS: &[&str] = &["trace", "debug", "info", "warn", "error"];
+
 impl Probe {
-    pub fn arguments_for_c(&self) -> String {
-        self.arguments
+    /// The names of the arguments, as declared in the `.d` file, or
+    /// `argN` when they are missing (or when they collide).
+    pub fn names(&self) -> Vec<String> {
+        let names = self
+            .arguments
             .iter()
             .enumerate()
-            .map(|(nth, argument)| format!("{ty} arg{nth}", ty = argument, nth = nth,))
+            .map(|(nth, argument)| match &argument.name {
+                Some(name) => name.clone(),
+                None => format!("arg{nth}", nth = nth),
+            })
+            .collect::<Vec<String>>();
+
+        let mut unique_names = names.clone();
+        unique_names.sort();
+        unique_names.dedup();
+
+        if unique_names.len() == names.len() {
+            names
+        } else {
+            (0..names.len())
+                .map(|nth| format!("arg{nth}", nth = nth))
+                .collect()
+        }
+    }
+
+    /// The names of the arguments, as given to the probe's C macro.
+    /// The arguments of unknown types are cast back to their D type.
+    pub(crate) fn argument_names(&self) -> String {
+        self.arguments
+            .iter()
+            .zip(self.names())
+            .map(|(argument, name)| match rust_type(&argument.ty) {
+                Some(_) => name,
+                None => format!("({ty}) {name}", ty = decayed(&argument.ty), name = name),
+            })
             .collect::<Vec<String>>()
             .join(", ")
     }
 
-    pub fn arguments_for_c_from_rust(&self) -> String {
+    pub(crate) fn argument_names_for_rust(&self) -> String {
+        self.names()
+            .iter()
+            .map(|name| rust_identifier(name))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    pub(crate) fn arguments_for_c(&self) -> String {
         self.arguments
             .iter()
-            .enumerate()
-            .map(|(nth, argument_ty)| {
-                let number_of_pointers = argument_ty.chars().filter(|c| *c == '*').count();
-                let ty = match argument_ty.trim_end_matches(|c| c == ' ' || c == '*') {
-                    "char" => "c_char",
-                    "short" => "c_short",
-                    "int" => "c_int",
-                    "long" => "c_long",
-                    "long long" => "c_longlong",
-                    "int8_t" => "i8",
-                    "int16_t" => "i16",
-                    "int32_t" => "i32",
-                    "int64_t" => "i64",
-                    "intptr_t" => "isize",
-                    "uint8_t" => "u8",
-                    "uint16_t" => "u16",
-                    "uint32_t" => "u32",
-                    "uint64_t" => "u64",
-                    "uintptr_t" => "usize",
-                    "float" => "c_float",
-                    "double" => "c_double",
-                    t => panic!("D type `{}` isn't supported yet", t),
+            .zip(self.names())
+            .map(|(argument, name)| format!("{ty} {name}", ty = c_type(&argument.ty), name = name))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// The arguments of the C function of the shim, as declared by the
+    /// Rust externs, e.g. `char * arg0, int arg1` for `*mut c_char` and
+    /// `c_int`.
+    pub(crate) fn arguments_for_c_from_rust_externs(&self) -> String {
+        self.c_declarations_from_rust().join(", ")
+    }
+
+    /// The C declarations of the arguments as seen by Rust, one per
+    /// argument, see [`Probe::arguments_for_c_from_rust_externs`].
+    pub(crate) fn c_declarations_from_rust(&self) -> Vec<String> {
+        self.arguments
+            .iter()
+            .zip(self.arguments_for_rust())
+            .zip(self.names())
+            .map(|((argument, (_, rust_type)), name)| {
+                let c_type = c_type(&argument.ty);
+                let number_of_pointers = rust_type.matches("*mut ").count();
+                let base = match rust_type.trim_start_matches("*mut ") {
+                    "c_void" if opaque_type(&c_type).is_none() => "void",
+                    rust_base => c_type_of_rust(rust_base)
+                        .unwrap_or_else(|| c_type.trim_end_matches([' ', '*'])),
                 };
 
-                format!(
-                    "arg{nth}: {ptr}{ty}",
-                    ty = ty,
-                    ptr = "*mut ".repeat(number_of_pointers),
-                    nth = nth
-                )
+                if number_of_pointers == 0 {
+                    format!("{base} {name}", base = base, name = name)
+                } else {
+                    format!(
+                        "{base} {pointers} {name}",
+                        base = base,
+                        pointers = "*".repeat(number_of_pointers),
+                        name = name
+                    )
+                }
+            })
+            .collect()
+    }
+
+    /// The C static assertions checking that the C types passed as Rust
+    /// types of a given size, e.g. `int64_t` as `i64`, have this size
+    /// and signedness.
+    pub(crate) fn c_type_assertions(&self) -> Vec<String> {
+        let mut assertions = Vec::new();
+
+        for (argument, (_, rust_type)) in self.arguments.iter().zip(self.arguments_for_rust()) {
+            let c_type = c_type(&argument.ty);
+            let c_base = c_type.trim_end_matches([' ', '*']);
+            let rust_base = rust_type.trim_start_matches("*mut ");
+
+            let (size, signed) = match rust_base {
+                "i8" => ("1", Some(true)),
+                "i16" => ("2", Some(true)),
+                "i32" => ("4", Some(true)),
+                "i64" => ("8", Some(true)),
+                "isize" => ("sizeof(void *)", Some(true)),
+                "u8" => ("1", Some(false)),
+                "u16" => ("2", Some(false)),
+                "u32" => ("4", Some(false)),
+                "u64" => ("8", Some(false)),
+                "usize" => ("sizeof(void *)", Some(false)),
+                "bool" => ("1", None),
+                _ => continue,
+            };
+
+            assertions.push(format!(
+                "_Static_assert(sizeof({c}) == {size}, \"`{c}` must have the size of a Rust `{rust}`\");",
+                c = c_base,
+                size = size,
+                rust = rust_base,
+            ));
+
+            // `wchar_t` is unsigned on some platforms, e.g. Linux on ARM.
+            if let (Some(signed), false) = (signed, c_base == "wchar_t") {
+                assertions.push(format!(
+                    "_Static_assert((({c}) -1 < 0) == {signed}, \"`{c}` must be {signedness} like a Rust `{rust}`\");",
+                    c = c_base,
+                    signed = signed as u8,
+                    signedness = if signed { "signed" } else { "unsigned" },
+                    rust = rust_base,
+                ));
+            }
+        }
+
+        assertions
+    }
+
+    /// The arguments as `(name, type)` pairs of Rust identifiers and
+    /// Rust types. The probes with unknown types have been rejected
+    /// before, unless they are allowed: they are passed through.
+    pub(crate) fn arguments_for_rust(&self) -> Vec<(String, String)> {
+        self.arguments
+            .iter()
+            .zip(self.names())
+            .map(|(argument, name)| {
+                let ty = rust_type(&argument.ty)
+                    .unwrap_or_else(|| fallback_types(&argument.ty).1.to_string());
+
+                (rust_identifier(&name), ty)
             })
+            .collect()
+    }
+
+    /// The types of the arguments that can't be mapped to Rust types.
+    pub(crate) fn unsupported_types(&self) -> Vec<&str> {
+        self.arguments
+            .iter()
+            .map(|argument| argument.ty.as_str())
+            .filter(|ty| rust_type(ty).is_none())
+            .collect()
+    }
+
+    pub(crate) fn arguments_for_c_from_rust(&self) -> String {
+        self.arguments_for_rust()
+            .iter()
+            .map(|(name, ty)| format!("{name}: {ty}", name = name, ty = ty))
             .collect::<Vec<String>>()
             .join(", ")
     }
 }
+
+/// Turn a `snake_case` name into a `CamelCase` name, e.g. `you_me` into
+/// `YouMe`.
+pub(crate) fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// Turn an array type into a pointer to its elements, e.g. `char [64]`
+/// into `char *`, like C does for the function parameters. All the
+/// dimensions decay, e.g. `int [2][3]` is `int *`. Other types are
+/// kept as is.
+pub(crate) fn decayed(d_type: &str) -> Cow<'_, str> {
+    match d_type.find('[') {
+        Some(n) if d_type[..n].trim_end().ends_with('*') => {
+            format!("{}*", d_type[..n].trim_end()).into()
+        }
+        Some(n) => format!("{} *", d_type[..n].trim_end()).into(),
+        None => d_type.into(),
+    }
+}
+
+/// Return the name of the enum of a D type, e.g. `state` for `enum
+/// state` or `enum state *`.
+pub(crate) fn enum_name(d_type: &str) -> Option<&str> {
+    d_type
+        .split('[')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches([' ', '*'])
+        .strip_prefix("enum ")
+        .map(str::trim)
+}
+
+/// Return the struct or union pointed by a D type, e.g. `struct
+/// request` for `struct request *`. Such pointers are opaque in Rust.
+pub(crate) fn opaque_type(d_type: &str) -> Option<&str> {
+    let is_array = d_type.contains('[');
+    let base = d_type
+        .split('[')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches([' ', '*']);
+
+    if (is_array || d_type.contains('*'))
+        && (base.starts_with("struct ") || base.starts_with("union "))
+    {
+        Some(base)
+    } else {
+        None
+    }
+}
+
+/// The C type of an argument in the shim: the D type, decayed, or the
+/// fallback C type of an unknown type.
+fn c_type(d_type: &str) -> Cow<'_, str> {
+    match rust_type(d_type) {
+        // The enums are passed to the shim as `int`s, like in the Rust
+        // API.
+        Some(_) if enum_name(d_type).is_some() => {
+            let ty = decayed(d_type);
+            let base = ty.trim_end_matches([' ', '*']);
+
+            format!("int{}", &ty[base.len()..]).into()
+        }
+        Some(_) => decayed(d_type),
+        None => fallback_types(d_type).0.into(),
+    }
+}
+
+/// The C type of a Rust type of the generated externs which is a C
+/// type itself, e.g. `int` for `c_int`. Return `None` for the Rust
+/// types standing for several C types, e.g. `usize` for `size_t` and
+/// `uintptr_t`.
+fn c_type_of_rust(rust_type: &str) -> Option<&'static str> {
+    Some(match rust_type {
+        "c_char" => "char",
+        "c_schar" => "signed char",
+        "c_uchar" => "unsigned char",
+        "c_short" => "short",
+        "c_ushort" => "unsigned short",
+        "c_int" => "int",
+        "c_uint" => "unsigned int",
+        "c_long" => "long",
+        "c_ulong" => "unsigned long",
+        "c_longlong" => "long long",
+        "c_ulonglong" => "unsigned long long",
+        "c_float" => "float",
+        "c_double" => "double",
+        _ => return None,
+    })
+}
+
+/// The C and Rust types through which an argument of an unknown type
+/// is passed: a pointer or an integer as large as a pointer.
+pub(crate) fn fallback_types(d_type: &str) -> (&'static str, &'static str) {
+    if decayed(d_type).contains('*') {
+        ("void *", "*mut c_void")
+    } else {
+        ("uintptr_t", "usize")
+    }
+}
+
+/// The keywords of the C integer types.
+const INTEGER_KEYWORDS: &[&str] = &["signed", "unsigned", "char", "short", "int", "long"];
+
+/// Normalize a C integer type, written with its keywords in any order,
+/// e.g. `long unsigned int` into `unsigned long`, or `signed` into
+/// `int`. Return `None` if it isn't a valid combination of these
+/// keywords.
+pub(crate) fn integer_type(base: &str) -> Option<&'static str> {
+    let words = base.split_whitespace().collect::<Vec<_>>();
+
+    if words.is_empty() || !words.iter().all(|word| INTEGER_KEYWORDS.contains(word)) {
+        return None;
+    }
+
+    let count = |keyword: &str| words.iter().filter(|word| **word == keyword).count();
+    let (signed, unsigned) = (count("signed"), count("unsigned"));
+    let (char, short, int, long) = (count("char"), count("short"), count("int"), count("long"));
+
+    if signed + unsigned > 1 || char > 1 || short > 1 || int > 1 || long > 2 {
+        return None;
+    }
+
+    Some(match (char, short, long, unsigned == 1) {
+        (1, 0, 0, _) if int == 1 => return None,
+        (1, 0, 0, _) if signed == 1 => "signed char",
+        (1, 0, 0, true) => "unsigned char",
+        (1, 0, 0, false) => "char",
+        (0, 1, 0, true) => "unsigned short",
+        (0, 1, 0, false) => "short",
+        (0, 0, 0, true) => "unsigned int",
+        (0, 0, 0, false) => "int",
+        (0, 0, 1, true) => "unsigned long",
+        (0, 0, 1, false) => "long",
+        (0, 0, 2, true) => "unsigned long long",
+        (0, 0, 2, false) => "long long",
+        _ => return None,
+    })
+}
+
+/// Map a D type to a Rust type, e.g. `char *` to `*mut c_char`.
+/// Return `None` if the type isn't supported.
+fn rust_type(d_type: &str) -> Option<String> {
+    let d_type = &*decayed(d_type);
+    let number_of_pointers = d_type.chars().filter(|c| *c == '*').count();
+
+    // A pointer to a struct or a union is a pointer to `c_void`.
+    if opaque_type(d_type).is_some() {
+        return Some(format!("{}c_void", "*mut ".repeat(number_of_pointers)));
+    }
+
+    let base = d_type.trim_end_matches([' ', '*']);
+
+    let ty = match integer_type(base).unwrap_or(base) {
+        "char" => "c_char",
+        "signed char" => "c_schar",
+        "unsigned char" => "c_uchar",
+        "short" => "c_short",
+        "unsigned short" => "c_ushort",
+        "int" => "c_int",
+        "unsigned int" => "c_uint",
+        "long" => "c_long",
+        "unsigned long" => "c_ulong",
+        "long long" => "c_longlong",
+        "unsigned long long" => "c_ulonglong",
+        "int8_t" => "i8",
+        "int16_t" => "i16",
+        "int32_t" => "i32",
+        "int64_t" => "i64",
+        "intptr_t" => "isize",
+        "uint8_t" => "u8",
+        "uint16_t" => "u16",
+        "uint32_t" => "u32",
+        "uint64_t" => "u64",
+        "uintptr_t" => "usize",
+        "size_t" => "usize",
+        "ssize_t" => "isize",
+        "ptrdiff_t" => "isize",
+        "bool" | "_Bool" => "bool",
+        // The enums are passed as their underlying integer type.
+        base if enum_name(base).is_some() => "c_int",
+        // `wchar_t` is 32 bits on all the platforms with USDT probes.
+        "wchar_t" => "i32",
+        "float" => "c_float",
+        "double" => "c_double",
+        _ => return None,
+    };
+
+    Some(format!("{}{}", "*mut ".repeat(number_of_pointers), ty))
+}
+
+/// Rust keywords that can't be used as identifiers as is.
+const RUST_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl",
+    "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+/// Turn a name into a valid Rust identifier, by escaping keywords.
+pub(crate) fn rust_identifier(name: &str) -> String {
+    match name {
+        "self" | "Self" | "super" | "crate" | "_" => format!("{}_", name),
+        name if RUST_KEYWORDS.contains(&name) => format!("r#{}", name),
+        name => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let script = Script {
+            providers: vec![
+                Provider {
+                    name: "hello".to_string(),
+                    probes: vec![
+                        Probe {
+                            name: "world".to_string(),
+                            ..Default::default()
+                        },
+                        Probe {
+                            name: "request__start".to_string(),
+                            arguments: vec![
+                                Argument {
+                                    ty: "char *".to_string(),
+                                    name: Some("method".to_string()),
+                                },
+                                Argument {
+                                    ty: "uint64_t".to_string(),
+                                    name: Some("id".to_string()),
+                                },
+                                "int".into(),
+                            ],
+                            level: Some("debug".to_string()),
+                            deprecated: Some("Use `request` instead.".to_string()),
+                            doc: Some("A request starts.\n\nIt's long.".to_string()),
+                        },
+                    ],
+                    doc: Some("Probes saying hello.".to_string()),
+                    version: Some("1.2.0".to_string()),
+                    attributes: ProviderAttributes {
+                        provider: Some(Attributes {
+                            name: Stability::Evolving,
+                            data: Stability::Evolving,
+                            class: DependencyClass::Isa,
+                        }),
+                        args: Some(Attributes {
+                            name: Stability::Unstable,
+                            data: Stability::Private,
+                            class: DependencyClass::Common,
+                        }),
+                        ..Default::default()
+                    },
+                    enums: vec![],
+                },
+                Provider {
+                    name: "empty".to_string(),
+                    ..Default::default()
+                },
+            ],
+            enums: vec![],
+            options: vec![
+                DtraceOption {
+                    name: "quiet".to_string(),
+                    value: None,
+                },
+                DtraceOption {
+                    name: "bufsize".to_string(),
+                    value: Some("16m".to_string()),
+                },
+            ],
+        };
+
+        let source = script.to_d_source();
+
+        assert_eq!(
+            source,
+            "#pragma D option quiet
+#pragma D option bufsize=16m
+
+/// Probes saying hello.
+/// @version 1.2.0
+provider hello {
+    probe world();
+    /// A request starts.
+    ///
+    /// It's long.
+    /// @level debug
+    /// @deprecated Use `request` instead.
+    probe request__start(char *method, uint64_t id, int);
+};
+#pragma D attributes Evolving/Evolving/ISA provider hello provider
+#pragma D attributes Unstable/Private/Common provider hello args
+
+provider empty {
+};
+"
+        );
+        assert_eq!(super::super::parser::parse(&source), Ok(script));
+    }
+
+    #[test]
+    fn test_arguments_for_rust() {
+        let probe = Probe {
+            name: "you__me".to_string(),
+            arguments: vec![
+                Argument {
+                    ty: "char *".to_string(),
+                    name: Some("type".to_string()),
+                },
+                "int".into(),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            probe.arguments_for_rust(),
+            vec![
+                ("r#type".to_string(), "*mut c_char".to_string()),
+                ("arg1".to_string(), "c_int".to_string()),
+            ]
+        );
+        assert_eq!(
+            probe.arguments_for_c_from_rust(),
+            "r#type: *mut c_char, arg1: c_int"
+        );
+    }
+
+    #[test]
+    fn test_unknown_types() {
+        let probe = Probe {
+            name: "you".to_string(),
+            arguments: vec!["my_handle_t".into(), "my_request_t *".into(), "int".into()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            probe.arguments_for_c(),
+            "uintptr_t arg0, void * arg1, int arg2"
+        );
+        assert_eq!(
+            probe.argument_names(),
+            "(my_handle_t) arg0, (my_request_t *) arg1, arg2"
+        );
+        assert_eq!(
+            probe.arguments_for_c_from_rust(),
+            "arg0: usize, arg1: *mut c_void, arg2: c_int"
+        );
+    }
+
+    #[test]
+    fn test_rust_type() {
+        assert_eq!(rust_type("size_t").as_deref(), Some("usize"));
+        assert_eq!(rust_type("ssize_t").as_deref(), Some("isize"));
+        assert_eq!(rust_type("ptrdiff_t").as_deref(), Some("isize"));
+        assert_eq!(rust_type("bool").as_deref(), Some("bool"));
+        assert_eq!(rust_type("_Bool").as_deref(), Some("bool"));
+        assert_eq!(rust_type("wchar_t *").as_deref(), Some("*mut i32"));
+        assert_eq!(rust_type("size_t **").as_deref(), Some("*mut *mut usize"));
+        assert_eq!(
+            rust_type("struct request *").as_deref(),
+            Some("*mut c_void")
+        );
+        assert_eq!(
+            rust_type("union value **").as_deref(),
+            Some("*mut *mut c_void")
+        );
+        assert_eq!(rust_type("struct request"), None);
+        assert_eq!(rust_type("string"), None);
+        assert_eq!(rust_type("char [64]").as_deref(), Some("*mut c_char"));
+        assert_eq!(
+            rust_type("unsigned long long").as_deref(),
+            Some("c_ulonglong")
+        );
+        assert_eq!(rust_type("long unsigned int").as_deref(), Some("c_ulong"));
+        assert_eq!(rust_type("signed char").as_deref(), Some("c_schar"));
+        assert_eq!(
+            rust_type("unsigned char *").as_deref(),
+            Some("*mut c_uchar")
+        );
+        assert_eq!(rust_type("short  int").as_deref(), Some("c_short"));
+        assert_eq!(rust_type("signed").as_deref(), Some("c_int"));
+        assert_eq!(rust_type("long int long").as_deref(), Some("c_longlong"));
+        assert_eq!(rust_type("unsigned signed int"), None);
+        assert_eq!(rust_type("long long long"), None);
+        assert_eq!(rust_type("char int"), None);
+        assert_eq!(rust_type("short long"), None);
+        assert_eq!(
+            rust_type("char *[2][3]").as_deref(),
+            Some("*mut *mut c_char")
+        );
+        assert_eq!(
+            rust_type("struct request [4]").as_deref(),
+            Some("*mut c_void")
+        );
+        assert_eq!(rust_type("enum state").as_deref(), Some("c_int"));
+        assert_eq!(rust_type("enum state *").as_deref(), Some("*mut c_int"));
+        assert_eq!(c_type("enum state"), "int");
+        assert_eq!(c_type("enum state *"), "int *");
+    }
+
+    #[test]
+    fn test_arrays() {
+        let probe = super::super::parser::parse(
+            "provider hello { probe dump(char buf[64], int len, my_t items[2]); };",
+        )
+        .unwrap()
+        .providers
+        .remove(0)
+        .probes
+        .remove(0);
+
+        assert_eq!(probe.arguments[0].ty, "char [64]");
+        assert_eq!(
+            probe.to_string(),
+            "probe dump(char buf[64], int len, my_t items[2]);"
+        );
+        assert_eq!(probe.arguments_for_c(), "char * buf, int len, void * items");
+        assert_eq!(probe.argument_names(), "buf, len, (my_t *) items");
+        assert_eq!(
+            probe.arguments_for_c_from_rust(),
+            "buf: *mut c_char, len: c_int, items: *mut c_void"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let script = super::super::parser::parse(
+            "enum state { IDLE, BUSY };\n/// Probes saying hello.\nprovider hello { probe you(char *who, enum state); };",
+        )
+        .unwrap();
+
+        let json = serde_json::to_string(&script).unwrap();
+
+        assert!(json.contains(r#""name":"hello""#));
+        assert!(json.contains(r#""doc":"Probes saying hello.""#));
+        assert!(json.contains(r#"{"ty":"char *","name":"who"}"#));
+        assert_eq!(serde_json::from_str::<Script>(&json).unwrap(), script);
+    }
+}
@@ -0,0 +1,497 @@
+//! A lossless, concrete syntax tree (CST) for `.d` files, keeping the
+//! whitespaces, comments and directives as _trivia_ tokens, so that
+//! printing it gives back the exact source, e.g. to format it.
+
+use std::fmt;
+
+/// The kind of a [`Token`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Spaces, tabulations, new lines…
+    Whitespace,
+
+    /// A `// …` or a `/* … */` comment.
+    Comment,
+
+    /// A line starting by `#`, e.g. `#pragma D option quiet` or a
+    /// shebang.
+    Directive,
+
+    /// A name or a keyword, e.g. `provider`, `probe`, `uint8_t`.
+    Identifier,
+
+    /// A number literal.
+    Number,
+
+    /// A string (`"…"`) or a character (`'…'`) literal.
+    Literal,
+
+    /// Any other single character, e.g. `{`, `(`, `*`, `;`.
+    Punctuation,
+}
+
+impl TokenKind {
+    /// Whether the token has no meaning for the grammar.
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, Self::Whitespace | Self::Comment)
+    }
+}
+
+/// A token, i.e. a slice of the source with a [`TokenKind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'i> {
+    /// The kind of the token.
+    pub kind: TokenKind,
+
+    /// The exact text of the token.
+    pub text: &'i str,
+
+    /// The byte offset of the token in the source.
+    pub offset: usize,
+}
+
+/// The kind of a [`Node`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    /// The root of the tree.
+    Script,
+
+    /// A `provider … { … };` block.
+    Provider,
+
+    /// A `probe …(…);` declaration inside a provider.
+    Probe,
+
+    /// A `translator … < … > { … };` declaration.
+    Translator,
+
+    /// An `inline … = …;` declaration.
+    Inline,
+}
+
+/// An element of a [`Node`]: either a sub-node or a token.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Element<'i> {
+    Node(Node<'i>),
+    Token(Token<'i>),
+}
+
+/// A node of the tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node<'i> {
+    /// The kind of the node.
+    pub kind: NodeKind,
+
+    /// The children of the node, in source order.
+    pub children: Vec<Element<'i>>,
+}
+
+impl<'i> Node<'i> {
+    /// Iterate over all the tokens of this node, recursively, in
+    /// source order.
+    pub fn tokens(&self) -> Vec<Token<'i>> {
+        let mut tokens = Vec::new();
+        self.collect_tokens(&mut tokens);
+
+        tokens
+    }
+
+    fn collect_tokens(&self, tokens: &mut Vec<Token<'i>>) {
+        for child in &self.children {
+            match child {
+                Element::Node(node) => node.collect_tokens(tokens),
+                Element::Token(token) => tokens.push(*token),
+            }
+        }
+    }
+
+    /// The name of a provider or a probe, i.e. the first identifier
+    /// after the keyword.
+    pub fn name(&self) -> Option<&'i str> {
+        self.tokens()
+            .into_iter()
+            .filter(|token| token.kind == TokenKind::Identifier)
+            .nth(1)
+            .map(|token| token.text)
+    }
+
+    /// Iterate over the direct sub-nodes of this node.
+    pub fn nodes(&self) -> impl Iterator<Item = &Node<'i>> {
+        self.children.iter().filter_map(|child| match child {
+            Element::Node(node) => Some(node),
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for Node<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for token in self.tokens() {
+            f.write_str(token.text)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Split a `.d` source into tokens. Concatenating the text of all
+/// the tokens gives back the source.
+pub fn tokenize(input: &str) -> Vec<Token<'_>> {
+    let bytes = input.as_bytes();
+    let mut tokens = Vec::new();
+    let mut offset = 0;
+    let mut at_line_start = true;
+
+    while offset < bytes.len() {
+        let rest = &input[offset..];
+        let current = rest.chars().next().unwrap();
+
+        let (kind, length) = if current.is_whitespace() {
+            let length = rest
+                .find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len());
+
+            (TokenKind::Whitespace, length)
+        } else if rest.starts_with("//") {
+            (TokenKind::Comment, rest.find('\n').unwrap_or(rest.len()))
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            let length = comment.find("*/").map(|n| n + 4).unwrap_or(rest.len());
+
+            (TokenKind::Comment, length)
+        } else if current == '#' && at_line_start {
+            (TokenKind::Directive, directive_length(rest))
+        } else if current == '"' || current == '\'' {
+            (TokenKind::Literal, literal_length(rest, current))
+        } else if current.is_ascii_alphabetic() || current == '_' {
+            (TokenKind::Identifier, identifier_length(rest))
+        } else if current.is_ascii_digit() {
+            let length = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+
+            (TokenKind::Number, length)
+        } else {
+            (TokenKind::Punctuation, current.len_utf8())
+        };
+
+        let text = &input[offset..offset + length];
+
+        if kind == TokenKind::Whitespace {
+            if text.contains('\n') {
+                at_line_start = true;
+            }
+        } else if kind != TokenKind::Comment {
+            at_line_start = false;
+        }
+
+        tokens.push(Token { kind, text, offset });
+        offset += length;
+    }
+
+    tokens
+}
+
+/// A directive runs until the end of the line, unless the line ends
+/// with a `\`.
+fn directive_length(input: &str) -> usize {
+    let mut length = 0;
+
+    for line in input.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\r', '\n']);
+
+        if content.ends_with('\\') {
+            length += line.len();
+        } else {
+            length += content.len();
+
+            break;
+        }
+    }
+
+    length
+}
+
+/// A literal runs until the next unescaped `delimiter`.
+fn literal_length(input: &str, delimiter: char) -> usize {
+    let mut escaped = false;
+
+    for (index, c) in input.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == delimiter => return index + c.len_utf8(),
+            _ => (),
+        }
+    }
+
+    input.len()
+}
+
+/// An identifier is made of alphanumeric characters and `_`. D
+/// provider and probe names can also contain `-` (e.g. `my-app`).
+fn identifier_length(input: &str) -> usize {
+    let bytes = input.as_bytes();
+    let is_identifier = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    let mut length = 0;
+
+    while length < bytes.len() {
+        if is_identifier(bytes[length])
+            || (bytes[length] == b'-' && bytes.get(length + 1).copied().is_some_and(is_identifier))
+        {
+            length += 1;
+        } else {
+            break;
+        }
+    }
+
+    length
+}
+
+/// Parse a `.d` source into a lossless [`Node`] of kind
+/// [`NodeKind::Script`]. This never fails: anything that isn't
+/// recognized is kept as plain tokens.
+pub fn parse(input: &str) -> Node<'_> {
+    let tokens = tokenize(input);
+    let mut position = 0;
+    let mut script = Node {
+        kind: NodeKind::Script,
+        children: Vec::new(),
+    };
+
+    while position < tokens.len() {
+        let token = tokens[position];
+
+        if token.kind == TokenKind::Identifier && token.text == "provider" {
+            script
+                .children
+                .push(Element::Node(provider(&tokens, &mut position)));
+        } else if token.kind == TokenKind::Identifier && token.text == "translator" {
+            script.children.push(Element::Node(declaration(
+                NodeKind::Translator,
+                &tokens,
+                &mut position,
+            )));
+        } else if token.kind == TokenKind::Identifier && token.text == "inline" {
+            script.children.push(Element::Node(declaration(
+                NodeKind::Inline,
+                &tokens,
+                &mut position,
+            )));
+        } else {
+            script.children.push(Element::Token(token));
+            position += 1;
+        }
+    }
+
+    script
+}
+
+fn is_punctuation(token: &Token, text: &str) -> bool {
+    token.kind == TokenKind::Punctuation && token.text == text
+}
+
+/// Collect a provider, from the `provider` keyword to the `;` after
+/// the closing `}`.
+fn provider<'i>(tokens: &[Token<'i>], position: &mut usize) -> Node<'i> {
+    let mut node = Node {
+        kind: NodeKind::Provider,
+        children: vec![Element::Token(tokens[*position])],
+    };
+    let mut depth = 0;
+    *position += 1;
+
+    while *position < tokens.len() {
+        let token = tokens[*position];
+
+        if depth == 1 && token.kind == TokenKind::Identifier && token.text == "probe" {
+            node.children.push(Element::Node(probe(tokens, position)));
+
+            continue;
+        }
+
+        node.children.push(Element::Token(token));
+        *position += 1;
+
+        if is_punctuation(&token, "{") {
+            depth += 1;
+        } else if is_punctuation(&token, "}") {
+            depth -= 1;
+
+            if depth <= 0 {
+                // Take the final `;` and the trivia before it, if any.
+                let next = tokens[*position..]
+                    .iter()
+                    .position(|token| !token.kind.is_trivia())
+                    .map(|offset| *position + offset);
+
+                if let Some(next) = next.filter(|next| is_punctuation(&tokens[*next], ";")) {
+                    node.children
+                        .extend(tokens[*position..=next].iter().copied().map(Element::Token));
+                    *position = next + 1;
+                }
+
+                break;
+            }
+        } else if depth == 0 && is_punctuation(&token, ";") {
+            break;
+        }
+    }
+
+    node
+}
+
+/// Collect a declaration kept as is, e.g. a translator, from its
+/// keyword to the final `;`, outside of any block.
+fn declaration<'i>(kind: NodeKind, tokens: &[Token<'i>], position: &mut usize) -> Node<'i> {
+    let mut node = Node {
+        kind,
+        children: Vec::new(),
+    };
+    let mut depth = 0usize;
+
+    while *position < tokens.len() {
+        let token = tokens[*position];
+
+        node.children.push(Element::Token(token));
+        *position += 1;
+
+        if is_punctuation(&token, "{") {
+            depth += 1;
+        } else if is_punctuation(&token, "}") {
+            depth = depth.saturating_sub(1);
+        } else if depth == 0 && is_punctuation(&token, ";") {
+            break;
+        }
+    }
+
+    node
+}
+
+/// Collect a probe, from the `probe` keyword to the final `;`.
+fn probe<'i>(tokens: &[Token<'i>], position: &mut usize) -> Node<'i> {
+    let mut node = Node {
+        kind: NodeKind::Probe,
+        children: vec![Element::Token(tokens[*position])],
+    };
+    *position += 1;
+
+    while *position < tokens.len() {
+        let token = tokens[*position];
+
+        // Never swallow the end of the provider.
+        if is_punctuation(&token, "}") {
+            break;
+        }
+
+        node.children.push(Element::Token(token));
+        *position += 1;
+
+        if is_punctuation(&token, ";") {
+            break;
+        }
+    }
+
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = r#"#!/usr/sbin/dtrace -s
+#pragma D option quiet
+
+/* The `provider` keyword in a comment. */
+provider hello {
+    // Say hello.
+    probe world ( void ) ;
+    probe you(char*, int); /* trailing */
+} ;
+
+BEGIN { printf("provider"); }
+"#;
+
+    #[test]
+    fn test_tokenize_is_lossless() {
+        let tokens = tokenize(SOURCE);
+
+        assert_eq!(
+            tokens.iter().map(|token| token.text).collect::<String>(),
+            SOURCE
+        );
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let kinds = tokenize("#pragma D\nprobe a-b(char *x, 42); // c")
+            .into_iter()
+            .map(|token| (token.kind, token.text))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            kinds,
+            vec![
+                (TokenKind::Directive, "#pragma D"),
+                (TokenKind::Whitespace, "\n"),
+                (TokenKind::Identifier, "probe"),
+                (TokenKind::Whitespace, " "),
+                (TokenKind::Identifier, "a-b"),
+                (TokenKind::Punctuation, "("),
+                (TokenKind::Identifier, "char"),
+                (TokenKind::Whitespace, " "),
+                (TokenKind::Punctuation, "*"),
+                (TokenKind::Identifier, "x"),
+                (TokenKind::Punctuation, ","),
+                (TokenKind::Whitespace, " "),
+                (TokenKind::Number, "42"),
+                (TokenKind::Punctuation, ")"),
+                (TokenKind::Punctuation, ";"),
+                (TokenKind::Whitespace, " "),
+                (TokenKind::Comment, "// c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_is_lossless() {
+        assert_eq!(parse(SOURCE).to_string(), SOURCE);
+    }
+
+    #[test]
+    fn test_parse() {
+        let script = parse(SOURCE);
+        let providers = script.nodes().collect::<Vec<_>>();
+
+        assert_eq!(providers.len(), 1);
+        assert_eq!(providers[0].kind, NodeKind::Provider);
+        assert_eq!(providers[0].name(), Some("hello"));
+
+        let probes = providers[0].nodes().collect::<Vec<_>>();
+
+        assert_eq!(probes.len(), 2);
+        assert_eq!(probes[0].name(), Some("world"));
+        assert_eq!(probes[0].to_string(), "probe world ( void ) ;");
+        assert_eq!(probes[1].to_string(), "probe you(char*, int);");
+        assert!(providers[0].to_string().ends_with("} ;"));
+    }
+
+    #[test]
+    fn test_parse_translator_and_inline() {
+        let source = "translator info_t < struct provider *provider > {
+    name = provider->name;
+};
+inline int provider = 1;
+provider hello { probe world(); };
+";
+        let script = parse(source);
+        let nodes = script.nodes().collect::<Vec<_>>();
+
+        assert_eq!(script.to_string(), source);
+        assert_eq!(
+            nodes.iter().map(|node| node.kind).collect::<Vec<_>>(),
+            vec![NodeKind::Translator, NodeKind::Inline, NodeKind::Provider]
+        );
+        assert!(nodes[0].to_string().ends_with("};"));
+        assert_eq!(nodes[1].to_string(), "inline int provider = 1;");
+    }
+}
@@ -0,0 +1,269 @@
+//! An opinionated formatter for the `provider` blocks of `.d` files,
+//! built on top of the [CST](super::cst) so that no comment is lost.
+
+use super::cst::{self, Element, Node, NodeKind, Token, TokenKind};
+
+/// The width after which probe arguments are wrapped.
+pub const MAX_WIDTH: usize = 80;
+
+const INDENTATION: &str = "    ";
+
+/// Format a `.d` source.
+pub fn format(input: &str) -> String {
+    let script = cst::parse(input);
+    let mut output = String::with_capacity(input.len());
+
+    for child in &script.children {
+        match child {
+            Element::Node(node) if node.kind == NodeKind::Provider => {
+                output.push_str(&provider(node))
+            }
+            Element::Node(node) => output.push_str(&node.to_string()),
+            Element::Token(token) => output.push_str(token.text),
+        }
+    }
+
+    let trimmed_length = output.trim_end().len();
+    output.truncate(trimmed_length);
+    output.push('\n');
+
+    output
+}
+
+fn is(token: &Token, kind: TokenKind, text: &str) -> bool {
+    token.kind == kind && token.text == text
+}
+
+fn provider(node: &Node) -> String {
+    let mut output = String::new();
+    let mut children = node.children.iter();
+
+    // The header, up to `{`.
+    let mut header = Vec::new();
+    let mut header_comments = Vec::new();
+
+    for child in children.by_ref() {
+        match child {
+            Element::Token(token) if is(token, TokenKind::Punctuation, "{") => break,
+            Element::Token(token) if token.kind == TokenKind::Comment => {
+                header_comments.push(token.text)
+            }
+            Element::Token(token) if token.kind == TokenKind::Whitespace => (),
+            Element::Token(token) => header.push(token.text),
+            Element::Node(_) => (),
+        }
+    }
+
+    output.push_str(&header.join(" "));
+    output.push_str(" {");
+
+    for comment in header_comments {
+        output.push('\n');
+        output.push_str(INDENTATION);
+        output.push_str(comment);
+    }
+
+    // The body, up to `}`.
+    let mut has_items = false;
+    let mut newlines = 1;
+
+    for child in children.by_ref() {
+        match child {
+            Element::Token(token) if is(token, TokenKind::Punctuation, "}") => break,
+            Element::Token(token) if token.kind == TokenKind::Whitespace => {
+                newlines += token.text.matches('\n').count();
+            }
+            Element::Token(token) if token.kind == TokenKind::Comment && newlines == 0 => {
+                output.push(' ');
+                output.push_str(token.text);
+            }
+            item => {
+                if has_items && newlines > 1 {
+                    output.push('\n');
+                }
+
+                output.push('\n');
+                output.push_str(INDENTATION);
+
+                match item {
+                    Element::Node(node) => output.push_str(&probe(node)),
+                    Element::Token(token) => output.push_str(token.text.trim()),
+                }
+
+                has_items = true;
+                newlines = 0;
+            }
+        }
+    }
+
+    output.push_str("\n}");
+
+    // The trailer, i.e. the `;` if any. The comments before it, if any,
+    // are kept as is with their surrounding whitespace.
+    let trailer = children.collect::<Vec<_>>();
+    let has_comments = trailer
+        .iter()
+        .any(|child| matches!(child, Element::Token(token) if token.kind == TokenKind::Comment));
+
+    for child in trailer {
+        match child {
+            Element::Token(token) if token.kind == TokenKind::Whitespace && !has_comments => (),
+            Element::Token(token) => output.push_str(token.text),
+            Element::Node(node) => output.push_str(&node.to_string()),
+        }
+    }
+
+    output
+}
+
+fn probe(node: &Node) -> String {
+    let tokens = node.tokens();
+
+    // Line comments can't be moved on a single line, let's not touch
+    // the probe.
+    if tokens
+        .iter()
+        .any(|token| token.kind == TokenKind::Comment && token.text.starts_with("//"))
+    {
+        return node.to_string().trim().to_string();
+    }
+
+    let significant = tokens
+        .iter()
+        .filter(|token| token.kind != TokenKind::Whitespace)
+        .collect::<Vec<_>>();
+
+    let opening = significant
+        .iter()
+        .position(|token| is(token, TokenKind::Punctuation, "("));
+    let closing = significant
+        .iter()
+        .rposition(|token| is(token, TokenKind::Punctuation, ")"));
+
+    let (opening, closing) = match (opening, closing) {
+        (Some(opening), Some(closing)) if opening < closing => (opening, closing),
+        _ => return node.to_string().trim().to_string(),
+    };
+
+    let head = join(&significant[..opening]);
+    let arguments = significant[opening + 1..closing]
+        .split(|token| is(token, TokenKind::Punctuation, ","))
+        .map(join)
+        .filter(|argument| !argument.is_empty())
+        .collect::<Vec<_>>();
+    let tail = join(&significant[closing + 1..]);
+
+    let single_line = format!(
+        "{head}({arguments}){tail}",
+        head = head,
+        arguments = arguments.join(", "),
+        tail = tail
+    );
+
+    if INDENTATION.len() + single_line.len() <= MAX_WIDTH || arguments.len() < 2 {
+        return single_line;
+    }
+
+    let alignment = format!(
+        "\n{indentation}{padding}",
+        indentation = INDENTATION,
+        padding = " ".repeat(head.len() + 1)
+    );
+
+    format!(
+        "{head}({arguments}){tail}",
+        head = head,
+        arguments = arguments.join(&format!(",{}", alignment)),
+        tail = tail
+    )
+}
+
+/// Join tokens with the canonical spacing: words are separated by a
+/// space, pointers are written `T *name`, and punctuations are glued.
+fn join(tokens: &[&Token]) -> String {
+    let mut output = String::new();
+    let mut previous: Option<&Token> = None;
+
+    for token in tokens {
+        let needs_space = match previous {
+            None => false,
+            Some(_) if token.kind == TokenKind::Comment => true,
+            Some(previous) if token.kind == TokenKind::Punctuation => {
+                token.text == "*" && previous.kind != TokenKind::Punctuation
+            }
+            Some(previous) => previous.kind != TokenKind::Punctuation,
+        };
+
+        if needs_space {
+            output.push(' ');
+        }
+
+        output.push_str(token.text);
+        previous = Some(token);
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let input = r#"#pragma D option quiet
+
+/* The provider. */
+provider   hello{
+probe world( void );  probe you(char*,int) ; // Who?
+
+
+  /* A long one. */
+  probe a_very_long_probe_name_to_wrap(char * first_argument, uint64_t second_argument, int third);
+}
+;"#;
+
+        let expected = r#"#pragma D option quiet
+
+/* The provider. */
+provider hello {
+    probe world(void);
+    probe you(char *, int); // Who?
+
+    /* A long one. */
+    probe a_very_long_probe_name_to_wrap(char *first_argument,
+                                         uint64_t second_argument,
+                                         int third);
+};
+"#;
+
+        assert_eq!(format(input), expected);
+        assert_eq!(format(expected), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_the_trailer() {
+        assert_eq!(
+            format("provider a {\n  probe b();\n}\n"),
+            "provider a {\n    probe b();\n}\n"
+        );
+        assert_eq!(
+            format("provider a { probe b(); } /* c */ ;\n"),
+            "provider a {\n    probe b();\n} /* c */ ;\n"
+        );
+        assert_eq!(
+            format("provider a { probe b(); } // c\n;\n"),
+            "provider a {\n    probe b();\n} // c\n;\n"
+        );
+    }
+
+    #[test]
+    fn test_format_keeps_line_comments_in_arguments() {
+        let input = "provider a {\n  probe b(int, // c\n  int);\n};\n";
+
+        assert_eq!(
+            format(input),
+            "provider a {\n    probe b(int, // c\n  int);\n};\n"
+        );
+    }
+}
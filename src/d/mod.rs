@@ -1,2 +1,4 @@
 pub mod ast;
+pub mod cst;
+pub mod fmt;
 pub mod parser;
@@ -4,16 +4,108 @@
 //! children, `provider_probe_list`, `provider_probe`, `function`
 //! etc.).
 
-use super::ast::*;
+use super::{
+    ast::*,
+    cst::{tokenize, TokenKind},
+};
 use nom::{
-    bytes::complete::{tag, take_until, take_while},
+    bytes::complete::{tag, take_while, take_while1},
     character::{complete::char, is_alphanumeric},
-    combinator::map,
-    error::{ParseError, VerboseError},
-    multi::{many0, separated_list0},
-    sequence::{delimited, preceded, terminated, tuple},
-    IResult,
+    combinator::{cut, map, opt},
+    error::{context, ContextError, ErrorKind, ParseError, VerboseError, VerboseErrorKind},
+    multi::{many0, separated_list0, separated_list1},
+    sequence::{preceded, tuple},
+    IResult, Offset,
 };
+use std::{fmt, ops::Range};
+
+/// A byte range in the source.
+pub type Span = Range<usize>;
+
+/// The spans of a [`Provider`], see [`parse_with_spans`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProviderSpans {
+    /// The whole provider, from `provider` to the final `;`.
+    pub span: Span,
+
+    /// The provider's name.
+    pub name: Span,
+
+    /// The spans of the probes, in the same order as
+    /// [`Provider::probes`].
+    pub probes: Vec<ProbeSpans>,
+}
+
+/// The spans of a [`Probe`], see [`parse_with_spans`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProbeSpans {
+    /// The whole probe, from `probe` to the final `;`.
+    pub span: Span,
+
+    /// The probe's name.
+    pub name: Span,
+
+    /// The spans of the arguments, in the same order as
+    /// [`Probe::arguments`].
+    pub arguments: Vec<ArgumentSpans>,
+}
+
+/// The spans of an [`Argument`], see [`parse_with_spans`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ArgumentSpans {
+    /// The whole argument, e.g. `char *method`.
+    pub span: Span,
+
+    /// The argument's type, e.g. `char *`.
+    pub ty: Span,
+
+    /// The argument's name, if any, e.g. `method`.
+    pub name: Option<Span>,
+}
+
+/// Move a span by `offset` bytes.
+fn shift(span: &Span, offset: usize) -> Span {
+    span.start + offset..span.end + offset
+}
+
+impl ProbeSpans {
+    fn shift(self, offset: usize) -> Self {
+        Self {
+            span: shift(&self.span, offset),
+            name: shift(&self.name, offset),
+            arguments: self
+                .arguments
+                .into_iter()
+                .map(|argument| ArgumentSpans {
+                    span: shift(&argument.span, offset),
+                    ty: shift(&argument.ty, offset),
+                    name: argument.name.map(|name| shift(&name, offset)),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl ProviderSpans {
+    fn shift(self, offset: usize) -> Self {
+        Self {
+            span: shift(&self.span, offset),
+            name: shift(&self.name, offset),
+            probes: self
+                .probes
+                .into_iter()
+                .map(|probe| probe.shift(offset))
+                .collect(),
+        }
+    }
+}
+
+/// The span of `slice`, a part of `input`, relatively to `input`.
+fn span_of(input: &str, slice: &str) -> Span {
+    let start = input.offset(slice);
+
+    start..start + slice.len()
+}
 
 // Canonicalization of a `$parser`, i.e. remove the whitespace before it.
 macro_rules! canon {
@@ -22,11 +114,29 @@ macro_rules! canon {
     };
 }
 
-/// Parse whitespaces.
+/// Parse whitespaces and comments.
 fn ws<'i, E: ParseError<&'i str>>(input: &'i str) -> IResult<&'i str, &'i str, E> {
-    let chars = "\t\r\n ";
+    let chars = ['\t', '\r', '\n', ' '];
+    let mut rest = input;
+
+    loop {
+        let trimmed = rest.trim_start_matches(chars);
+
+        if let Some(comment) = trimmed.strip_prefix("//") {
+            rest = &comment[comment.find('\n').unwrap_or(comment.len())..];
+        } else if let Some(comment) = trimmed
+            .strip_prefix("/*")
+            .and_then(|comment| comment.find("*/").map(|n| &comment[n + 2..]))
+        {
+            rest = comment;
+        } else {
+            rest = trimmed;
+
+            break;
+        }
+    }
 
-    take_while(move |c| chars.contains(c))(input)
+    Ok((rest, &input[..input.len() - rest.len()]))
 }
 
 /// Parse a name.
@@ -40,82 +150,781 @@ fn name<'i, E: ParseError<&'i str>>(input: &'i str) -> IResult<&'i str, &'i str,
 /// `parameter_type_list` from the official grammar (see module's
 /// documentation).
 fn ty<'i, E: ParseError<&'i str>>(input: &'i str) -> IResult<&'i str, &'i str, E> {
-    let chars = ",)";
+    let chars = ",);{}";
+    let mut rest = input;
+
+    while let Some(c) = rest.chars().next() {
+        if chars.contains(c) {
+            break;
+        }
+
+        if rest.starts_with("//") || rest.starts_with("/*") {
+            let (after_comment, _) = ws::<E>(rest)?;
+
+            // An unterminated `/*` isn't a comment.
+            if after_comment.len() < rest.len() {
+                rest = after_comment;
+
+                continue;
+            }
+        }
 
-    take_while(move |c| !chars.contains(c))(input)
+        rest = &rest[c.len_utf8()..];
+    }
+
+    Ok((rest, &input[..input.offset(rest)]))
+}
+
+/// Find an annotation, e.g. `@level debug`, in the comments of
+/// `trivia`, and return its value. A comment ending the line of the
+/// previous declaration is ignored.
+fn annotation<'i>(trivia: &'i str, name: &str) -> Option<&'i str> {
+    annotation_in_comments(&trivia[trivia.find('\n')?..], name)
+}
+
+/// Find an annotation in all the comments of `comments`.
+fn annotation_in_comments<'i>(comments: &'i str, name: &str) -> Option<&'i str> {
+    let mut words = comments
+        .split(|c: char| c.is_whitespace() || c == '*' || c == '/')
+        .filter(|word| !word.is_empty());
+
+    while let Some(word) = words.next() {
+        if word.strip_prefix('@') == Some(name) {
+            return words.next();
+        }
+    }
+
+    None
 }
 
-/// Parse a `probe`.
-fn probe<'i, E: ParseError<&'i str>>(input: &'i str) -> IResult<&'i str, Probe, E> {
-    map(
+/// Find an annotation whose value is the rest of its line, e.g.
+/// `@deprecated Use greeting__start instead.`, in the comments of
+/// `trivia`, and return its value, possibly empty. A comment ending the
+/// line of the previous declaration is ignored.
+fn line_annotation<'i>(trivia: &'i str, name: &str) -> Option<&'i str> {
+    let annotation = format!("@{}", name);
+
+    trivia[trivia.find('\n')?..].lines().find_map(|line| {
+        let line = line.trim_start_matches(|c: char| c.is_whitespace() || c == '*' || c == '/');
+        let rest = line.strip_prefix(&annotation)?;
+
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            return None;
+        }
+
+        Some(rest.trim().trim_end_matches("*/").trim_end())
+    })
+}
+
+/// Extract the documentation from the doc comments (`/** … */` or
+/// `/// …`) of `trivia`. The annotations, e.g. `@level debug`, are
+/// left out.
+fn doc(trivia: &str) -> Option<String> {
+    let mut lines = Vec::new();
+
+    for token in tokenize(trivia) {
+        if token.kind != TokenKind::Comment {
+            continue;
+        }
+
+        if let Some(line) = token.text.strip_prefix("///") {
+            lines.push(line.strip_prefix(' ').unwrap_or(line).trim_end());
+        } else if let Some(block) = token
+            .text
+            .strip_prefix("/**")
+            .and_then(|block| block.strip_suffix("*/"))
+        {
+            for line in block.lines() {
+                let line = match line.trim_start().strip_prefix('*') {
+                    Some(line) => line.strip_prefix(' ').unwrap_or(line),
+                    None => line.trim_start(),
+                };
+
+                lines.push(line.trim_end());
+            }
+        }
+    }
+
+    lines.retain(|line| !line.starts_with('@'));
+
+    while lines.first().is_some_and(|line| line.is_empty()) {
+        lines.remove(0);
+    }
+
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\n"))
+    }
+}
+
+/// Keywords that can only be part of a type, not an argument name.
+const TYPE_KEYWORDS: &[&str] = &[
+    "char", "short", "int", "long", "float", "double", "signed", "unsigned", "void", "bool",
+    "_Bool", "const", "volatile", "string",
+];
+
+/// Split an argument into its type and its optional name, e.g.
+/// `char *method` into `char *` and `method`. The dimensions of an
+/// array go to the type, e.g. `char buf[64]` is `char [64]` and `buf`.
+pub(crate) fn argument(input: &str) -> Argument {
+    if let Some(n) = input.find('[') {
+        let dimensions = input[n..].split_whitespace().collect::<String>();
+        let Argument { ty, name } = argument(input[..n].trim_end());
+
+        return Argument {
+            ty: if ty.ends_with('*') {
+                format!("{}{}", ty, dimensions)
+            } else {
+                format!("{} {}", ty, dimensions)
+            },
+            name,
+        };
+    }
+
+    let words = input
+        .split(|c: char| c.is_whitespace() || c == '*')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+
+    let name = match words.as_slice() {
+        [.., before, last]
+            if !input.ends_with('*')
+                && !TYPE_KEYWORDS.contains(last)
+                && !["struct", "union", "enum"].contains(before) =>
+        {
+            Some(*last)
+        }
+        _ => None,
+    };
+
+    match name {
+        Some(name) => Argument {
+            ty: input[..input.len() - name.len()].trim_end().to_string(),
+            name: Some(name.to_string()),
+        },
+        None => Argument::from(input),
+    }
+}
+
+/// Parse a `probe`, with its spans relative to `input`.
+fn spanned_probe<'i, E: ParseError<&'i str> + ContextError<&'i str>>(
+    input: &'i str,
+) -> IResult<&'i str, (Probe, ProbeSpans), E> {
+    let (rest, output) = map(
         tuple((
             preceded(tag("probe"), canon!(name)),
-            delimited(
-                canon!(char('(')),
-                separated_list0(char(','), canon!(ty)),
-                canon!(terminated(char(')'), canon!(char(';')))),
-            ),
+            cut(context("expected `(` after probe name", canon!(char('(')))),
+            separated_list0(char(','), canon!(ty)),
+            cut(context(
+                "expected `)` to close the probe arguments",
+                canon!(char(')')),
+            )),
+            cut(context(
+                "expected `;` after probe declaration",
+                canon!(char(';')),
+            )),
         )),
-        |(name, arguments)| Probe {
-            name: name.into(),
-            arguments: arguments
-                .iter()
-                .filter_map(|argument| {
-                    let argument = argument.trim();
+        |(name, _, arguments, _, _)| {
+            let (mut arguments, mut argument_spans): (Vec<Argument>, Vec<ArgumentSpans>) =
+                arguments
+                    .iter()
+                    .filter_map(|argument| {
+                        let offset = input.offset(argument);
 
-                    if argument.is_empty() {
-                        None
-                    } else {
-                        Some(argument.to_string())
-                    }
-                })
-                .collect(),
+                        // The comments inside an argument are left out,
+                        // and each run of whitespace becomes a single
+                        // space, e.g. `unsigned /* … */\n long` is
+                        // `unsigned long`.
+                        let tokens = tokenize(argument);
+                        let meaningful = tokens
+                            .iter()
+                            .filter(|token| !token.kind.is_trivia())
+                            .map(|token| {
+                                offset + token.offset..offset + token.offset + token.text.len()
+                            })
+                            .collect::<Vec<Span>>();
+                        let mut text = String::new();
+
+                        for token in &tokens {
+                            if token.kind.is_trivia() {
+                                if !text.is_empty() && !text.ends_with(' ') {
+                                    text.push(' ');
+                                }
+                            } else {
+                                text.push_str(token.text);
+                            }
+                        }
+
+                        let text = text.trim_end();
+                        let span = meaningful.first()?.start..meaningful.last()?.end;
+                        let parsed = self::argument(text);
+                        // The name of an array is before its dimensions,
+                        // which are part of the type.
+                        let dimensions = tokens
+                            .iter()
+                            .filter(|token| !token.kind.is_trivia())
+                            .position(|token| token.text == "[");
+                        let name = parsed
+                            .name
+                            .as_ref()
+                            .map(|_| dimensions.unwrap_or(meaningful.len()) - 1);
+                        let spans = ArgumentSpans {
+                            ty: match (name, dimensions) {
+                                (Some(name), None) => span.start..meaningful[name - 1].end,
+                                _ => span.clone(),
+                            },
+                            name: name.map(|name| meaningful[name].clone()),
+                            span,
+                        };
+
+                        Some((parsed, spans))
+                    })
+                    .unzip();
+
+            // A lone `void` means the probe has no argument, like in C.
+            if arguments.len() == 1 && arguments[0] == Argument::from("void") {
+                arguments.clear();
+                argument_spans.clear();
+            }
+
+            (
+                Probe {
+                    name: name.into(),
+                    arguments,
+                    ..Default::default()
+                },
+                ProbeSpans {
+                    name: span_of(input, name),
+                    arguments: argument_spans,
+                    ..Default::default()
+                },
+            )
         },
-    )(input)
+    )(input)?;
+
+    let (probe, spans) = output;
+
+    Ok((
+        rest,
+        (
+            probe,
+            ProbeSpans {
+                span: 0..input.offset(rest),
+                ..spans
+            },
+        ),
+    ))
 }
 
-/// Parse a `provider`.
-fn provider<'i, E: ParseError<&'i str>>(input: &'i str) -> IResult<&'i str, Provider, E> {
-    map(
+/// Parse a `provider`, with its spans relative to `input`.
+fn spanned_provider<'i, E: ParseError<&'i str> + ContextError<&'i str>>(
+    input: &'i str,
+) -> IResult<&'i str, (Provider, ProviderSpans), E> {
+    let (rest, output) = map(
         tuple((
             preceded(tag("provider"), canon!(name)),
-            delimited(
+            cut(context(
+                "expected `{` after provider name",
                 canon!(char('{')),
-                many0(canon!(probe)),
-                canon!(terminated(char('}'), canon!(char(';')))),
-            ),
+            )),
+            many0(map(
+                tuple((ws, spanned_probe)),
+                |(trivia, (mut probe, spans))| {
+                    probe.level = annotation(trivia, "level").map(ToString::to_string);
+                    probe.deprecated =
+                        line_annotation(trivia, "deprecated").map(ToString::to_string);
+                    probe.doc = trivia.find('\n').and_then(|n| doc(&trivia[n..]));
+
+                    (probe, spans.shift(input.offset(trivia) + trivia.len()))
+                },
+            )),
+            cut(context(
+                "expected `}` to close the provider, or a probe declaration",
+                canon!(char('}')),
+            )),
+            cut(context(
+                "expected `;` after provider declaration",
+                canon!(char(';')),
+            )),
         )),
-        |(name, probes)| Provider {
-            name: name.into(),
-            probes,
+        |(name, _, probes, _, _)| {
+            let (probes, probe_spans) = probes.into_iter().unzip();
+
+            (
+                Provider {
+                    name: name.into(),
+                    probes,
+                    ..Default::default()
+                },
+                ProviderSpans {
+                    name: span_of(input, name),
+                    probes: probe_spans,
+                    ..Default::default()
+                },
+            )
         },
-    )(input)
+    )(input)?;
+
+    let (provider, spans) = output;
+
+    Ok((
+        rest,
+        (
+            provider,
+            ProviderSpans {
+                span: 0..input.offset(rest),
+                ..spans
+            },
+        ),
+    ))
 }
 
-/// Parse a script. It collects only the `provider` blocks, nothing else.
-fn script<'i, E: ParseError<&'i str>>(mut input: &'i str) -> IResult<&'i str, Script, E> {
-    let mut script = Script { providers: vec![] };
+/// Parse a `#pragma D attributes <attributes> provider <name>
+/// <component>` directive of `input`, returning the provider name, the
+/// component and the attributes. The other directives are `None`.
+fn attributes_pragma<'i, E: ParseError<&'i str> + ContextError<&'i str>>(
+    input: &'i str,
+    directive: &'i str,
+) -> Result<Option<(&'i str, &'i str, Attributes)>, nom::Err<E>> {
+    let failure = |at: &'i str, message: &'static str| {
+        let rest = &input[input.offset(at)..];
 
-    loop {
-        match take_until::<_, _, E>("provider")(input) {
-            Ok((input_next, _)) => {
-                let (input_next, output) = provider(input_next)?;
+        nom::Err::Failure(E::add_context(
+            rest,
+            message,
+            E::from_error_kind(rest, ErrorKind::Verify),
+        ))
+    };
+
+    match directive.split_whitespace().collect::<Vec<_>>()[..] {
+        ["#pragma", "D", "attributes", attributes, "provider", provider, component] => {
+            let attributes = attributes.parse().map_err(|_| {
+                failure(
+                    attributes,
+                    "expected stability attributes, e.g. `Evolving/Evolving/Common`",
+                )
+            })?;
+
+            if !ProviderAttributes::COMPONENTS.contains(&component) {
+                return Err(failure(
+                    component,
+                    "expected `provider`, `module`, `function`, `name` or `args`",
+                ));
+            }
+
+            Ok(Some((provider, component, attributes)))
+        }
+        ["#pragma", "D", "attributes", _, "provider", ..] => Err(failure(
+            directive,
+            "expected `#pragma D attributes <attributes> provider <name> <component>`",
+        )),
+        _ => Ok(None),
+    }
+}
+
+/// Parse a `#pragma D option <name>[=<value>]` directive of `input`.
+/// The other directives are `None`.
+fn option_pragma<'i, E: ParseError<&'i str> + ContextError<&'i str>>(
+    input: &'i str,
+    directive: &'i str,
+) -> Result<Option<DtraceOption>, nom::Err<E>> {
+    match directive.split_whitespace().collect::<Vec<_>>()[..] {
+        ["#pragma", "D", "option", option] if !option.starts_with('=') => {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (option, None),
+            };
+
+            Ok(Some(DtraceOption {
+                name: name.to_string(),
+                value,
+            }))
+        }
+        ["#pragma", "D", "option", ..] => {
+            let rest = &input[input.offset(directive)..];
+
+            Err(nom::Err::Failure(E::add_context(
+                rest,
+                "expected `#pragma D option <name>` or `#pragma D option <name>=<value>`",
+                E::from_error_kind(rest, ErrorKind::Verify),
+            )))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Parse a script, with the spans of its providers. The script is
+/// tokenized first, so that only the top-level `provider` blocks,
+/// `enum` declarations and `#pragma D` directives are parsed. It
+/// fails on the first error, see [`recovering_script`].
+fn spanned_script<'i, E: ParseError<&'i str> + ContextError<&'i str>>(
+    input: &'i str,
+) -> IResult<&'i str, (Script, Vec<ProviderSpans>), E> {
+    let (script, spans, mut errors) = recovering_script::<E>(input);
+
+    match errors.is_empty() {
+        true => Ok(("", (script, spans))),
+        false => Err(errors.remove(0)),
+    }
+}
+
+/// Parse a script like [`spanned_script`], but recover from the errors:
+/// a malformed provider or directive is skipped, up to its closing `}`
+/// if any, and the rest of the script is parsed. Return the parsed
+/// providers, and all the errors.
+fn recovering_script<'i, E: ParseError<&'i str> + ContextError<&'i str>>(
+    input: &'i str,
+) -> (Script, Vec<ProviderSpans>, Vec<nom::Err<E>>) {
+    let mut errors = Vec::new();
+    let mut script = Script::default();
+    let mut spans = Vec::new();
+    let mut depth = 0usize;
+    let mut resume_at = 0;
+    let mut trivia_start = None;
+    let mut in_declaration = false;
+    let mut pragmas = Vec::new();
+
+    for token in tokenize(input) {
+        if token.offset < resume_at {
+            continue;
+        }
+
+        if token.kind.is_trivia() {
+            trivia_start.get_or_insert(token.offset);
+
+            continue;
+        }
+
+        match (token.kind, token.text) {
+            (TokenKind::Punctuation, "{") => depth += 1,
+            (TokenKind::Punctuation, "}") => depth = depth.saturating_sub(1),
+            (TokenKind::Identifier, "translator" | "inline") if depth == 0 => in_declaration = true,
+            (TokenKind::Punctuation, ";") if depth == 0 => in_declaration = false,
+            (TokenKind::Directive, directive) => {
+                match attributes_pragma(input, directive) {
+                    Ok(pragma) => pragmas.extend(pragma),
+                    Err(error) => errors.push(error),
+                }
+
+                match option_pragma(input, directive) {
+                    Ok(option) => script.options.extend(option),
+                    Err(error) => errors.push(error),
+                }
+            }
+            (TokenKind::Identifier, "enum") if depth == 0 && !in_declaration => {
+                // An `enum` without a body, e.g. a variable declaration,
+                // isn't an enum declaration: its tokens are skipped.
+                match enum_declaration::<E>(&input[token.offset..]) {
+                    Ok((input_next, mut output)) => {
+                        output.doc =
+                            leading_comments(input, trivia_start, token.offset).and_then(doc);
+                        script.enums.push(output);
+
+                        resume_at = input.len() - input_next.len();
+                    }
+                    Err(nom::Err::Error(_)) => (),
+                    Err(error) => errors.push(error),
+                }
+            }
+            (TokenKind::Identifier, "provider") if depth == 0 && !in_declaration => {
+                // The tokens of a malformed provider are skipped like
+                // those of any other block, so that the parsing resumes
+                // after its closing `}`.
+                let (input_next, (mut output, provider_spans)) =
+                    match spanned_provider::<E>(&input[token.offset..]) {
+                        Ok(parsed) => parsed,
+                        Err(error) => {
+                            errors.push(error);
+                            trivia_start = None;
+
+                            continue;
+                        }
+                    };
+
+                let comments = leading_comments(input, trivia_start, token.offset);
+
+                output.doc = comments.and_then(doc);
+                output.version = comments
+                    .and_then(|comments| annotation_in_comments(comments, "version"))
+                    .map(ToString::to_string);
 
                 script.providers.push(output);
+                spans.push(provider_spans.shift(token.offset));
 
-                input = input_next;
+                resume_at = input.len() - input_next.len();
             }
+            _ => (),
+        }
+
+        trivia_start = None;
+    }
+
+    // The directives can be written before or after their provider.
+    for (name, component, attributes) in pragmas {
+        for provider in script.providers.iter_mut().filter(|p| p.name == name) {
+            *provider.attributes.get_mut(component).unwrap() = Some(attributes);
+        }
+    }
+
+    for provider in &mut script.providers {
+        provider.enums = provider.used_enums(&script.enums);
+    }
+
+    (script, spans, errors)
+}
+
+/// The comments before a declaration at `offset`, if `trivia_start` is
+/// where the whitespace and comments before it start. A comment ending
+/// the line of the previous declaration isn't part of them.
+fn leading_comments(input: &str, trivia_start: Option<usize>, offset: usize) -> Option<&str> {
+    match trivia_start {
+        Some(0) => Some(&input[..offset]),
+        Some(start) => {
+            let trivia = &input[start..offset];
+
+            trivia.find('\n').map(|n| &trivia[n..])
+        }
+        None => None,
+    }
+}
+
+/// Parse an `enum` declaration, e.g. `enum state { IDLE, BUSY = 4 };`.
+/// The values are integers, or previous enumerators of the enum.
+fn enum_declaration<'i, E: ParseError<&'i str> + ContextError<&'i str>>(
+    input: &'i str,
+) -> IResult<&'i str, Enum, E> {
+    let identifier = || take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_');
+
+    let (rest, (name, _, enumerators, _, _, _)) = tuple((
+        preceded(tag("enum"), canon!(identifier())),
+        canon!(char('{')),
+        // An enum without enumerators is rejected by C, and would be an
+        // uninhabited Rust enum.
+        cut(context(
+            "expected at least one enumerator",
+            separated_list1(
+                canon!(char(',')),
+                tuple((
+                    canon!(identifier()),
+                    opt(preceded(
+                        canon!(char('=')),
+                        cut(context(
+                            "expected an integer, or a previous enumerator",
+                            canon!(take_while1(|c: char| {
+                                c.is_ascii_alphanumeric() || c == '_' || c == '-'
+                            })),
+                        )),
+                    )),
+                )),
+            ),
+        )),
+        opt(canon!(char(','))),
+        cut(context(
+            "expected `}` to close the enum, or an enumerator",
+            canon!(char('}')),
+        )),
+        cut(context(
+            "expected `;` after enum declaration",
+            canon!(char(';')),
+        )),
+    ))(input)?;
+
+    let mut variants: Vec<Variant> = Vec::with_capacity(enumerators.len());
+
+    for (name, value) in enumerators {
+        let value = match value {
+            Some(value) => integer(value)
+                .or_else(|| {
+                    variants
+                        .iter()
+                        .find(|variant| variant.name == value)
+                        .map(|variant| variant.value)
+                })
+                .ok_or_else(|| {
+                    // The error is located by the input remaining from
+                    // the value, not by the value alone.
+                    let remaining = &input[input.offset(value)..];
+
+                    nom::Err::Failure(E::add_context(
+                        remaining,
+                        "expected an integer, or a previous enumerator",
+                        E::from_error_kind(remaining, ErrorKind::Verify),
+                    ))
+                })?,
+            None => variants.last().map_or(0, |variant| variant.value + 1),
+        };
+
+        variants.push(Variant {
+            name: name.to_string(),
+            value,
+        });
+    }
+
+    Ok((
+        rest,
+        Enum {
+            name: name.to_string(),
+            variants,
+            ..Default::default()
+        },
+    ))
+}
+
+/// Parse a C integer literal, e.g. `-4`, `0x10`, `010` or `4UL`.
+fn integer(literal: &str) -> Option<i64> {
+    let (negative, literal) = match literal.strip_prefix('-') {
+        Some(literal) => (true, literal),
+        None => (false, literal),
+    };
+    let literal = literal.trim_end_matches(['u', 'U', 'l', 'L']);
+
+    let value = if let Some(hex) = literal
+        .strip_prefix("0x")
+        .or_else(|| literal.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if literal.len() > 1 && literal.starts_with('0') {
+        i64::from_str_radix(&literal[1..], 8).ok()?
+    } else {
+        literal.parse::<i64>().ok()?
+    };
 
-            _ => return Ok(("", script)),
+    Some(if negative { -value } else { value })
+}
+
+/// A parse error, located in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    /// What went wrong.
+    pub message: String,
+
+    /// The byte offset of the error in the source.
+    pub offset: usize,
+
+    /// The line of the error, starting from 1.
+    pub line: usize,
+
+    /// The column of the error, starting from 1.
+    pub column: usize,
+
+    /// The line of the source where the error is.
+    pub snippet: String,
+}
+
+impl Error {
+    pub(crate) fn new(source: &str, offset: usize, message: String) -> Self {
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map(|n| n + 1).unwrap_or(0);
+        let line_end = source[offset..]
+            .find('\n')
+            .map(|n| offset + n)
+            .unwrap_or_else(|| source.len());
+
+        Self {
+            message,
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            snippet: source[line_start..line_end].trim_end().to_string(),
         }
     }
+
+    /// Render the error as a diagnostic prefixed by `file_name`, with a
+    /// caret under the offending position.
+    pub fn render(&self, file_name: &str) -> String {
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        format!(
+            "{file}:{error}\n{gutter} |\n{line} | {snippet}\n{gutter} | {padding}^",
+            file = file_name,
+            error = self,
+            gutter = gutter,
+            line = self.line,
+            snippet = self.snippet,
+            padding = self
+                .snippet
+                .chars()
+                .take(self.column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect::<String>(),
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{line}:{column}: {message}",
+            line = self.line,
+            column = self.column,
+            message = self.message
+        )
+    }
 }
 
+impl std::error::Error for Error {}
+
 /// Parse a `.d` file and return a [`Script`] value.
-pub fn parse<'i>(input: &'i str) -> Result<Script, String> {
-    match script::<VerboseError<&'i str>>(input) {
+pub fn parse(input: &str) -> Result<Script, Error> {
+    parse_with_spans(input).map(|(script, _)| script)
+}
+
+/// Parse a `.d` file like [`parse`], and also return the spans of the
+/// providers, in the same order as [`Script::providers`], e.g. for an
+/// editor to highlight the exact probe or type a diagnostic refers
+/// to.
+pub fn parse_with_spans<'i>(input: &'i str) -> Result<(Script, Vec<ProviderSpans>), Error> {
+    match spanned_script::<VerboseError<&'i str>>(input) {
         Ok((_, output)) => Ok(output),
-        Err(e) => Err(e.to_string()),
+        Err(error) => Err(self::error(input, error)),
+    }
+}
+
+/// Parse a `.d` file like [`parse_with_spans`], but skip the malformed
+/// providers and return all the errors at once.
+pub fn parse_all(input: &str) -> (Script, Vec<ProviderSpans>, Vec<Error>) {
+    let (script, spans, errors) = recovering_script::<VerboseError<&str>>(input);
+    let mut errors = errors
+        .into_iter()
+        .map(|error| self::error(input, error))
+        .collect::<Vec<_>>();
+    errors.sort_by_key(|error| error.offset);
+
+    (script, spans, errors)
+}
+
+/// Turn a `nom` error into an [`Error`] located in `input`.
+fn error<'i>(input: &'i str, error: nom::Err<VerboseError<&'i str>>) -> Error {
+    match error {
+        nom::Err::Error(e) | nom::Err::Failure(e) => {
+            let (remaining, message) = e
+                .errors
+                .iter()
+                .find_map(|(remaining, kind)| match kind {
+                    VerboseErrorKind::Context(context) => Some((remaining, context.to_string())),
+                    _ => None,
+                })
+                .unwrap_or_else(|| match e.errors.first() {
+                    Some((remaining, VerboseErrorKind::Char(c))) => {
+                        (remaining, format!("expected `{}`", c))
+                    }
+                    Some((remaining, kind)) => {
+                        (remaining, format!("unexpected input ({:?})", kind))
+                    }
+                    None => (&input, "unexpected input".to_string()),
+                });
+
+            Error::new(input, input.len() - remaining.len(), message)
+        }
+        nom::Err::Incomplete(_) => {
+            Error::new(input, input.len(), "unexpected end of file".to_string())
+        }
     }
 }
 
@@ -123,6 +932,34 @@ pub fn parse<'i>(input: &'i str) -> Result<Script, String> {
 mod tests {
     use super::*;
 
+    fn probe<'i, E: ParseError<&'i str> + ContextError<&'i str>>(
+        input: &'i str,
+    ) -> IResult<&'i str, Probe, E> {
+        map(spanned_probe, |(probe, _)| probe)(input)
+    }
+
+    fn provider<'i, E: ParseError<&'i str> + ContextError<&'i str>>(
+        input: &'i str,
+    ) -> IResult<&'i str, Provider, E> {
+        map(spanned_provider, |(provider, _)| provider)(input)
+    }
+
+    fn script<'i, E: ParseError<&'i str> + ContextError<&'i str>>(
+        input: &'i str,
+    ) -> IResult<&'i str, Script, E> {
+        map(spanned_script, |(script, _)| script)(input)
+    }
+
+    #[test]
+    fn test_ws() {
+        assert_eq!(ws::<()>(" \t\n foo"), Ok(("foo", " \t\n ")));
+        assert_eq!(
+            ws::<()>(" // a\n /* b */ foo"),
+            Ok(("foo", " // a\n /* b */ "))
+        );
+        assert_eq!(ws::<()>("/* foo"), Ok(("/* foo", "")));
+    }
+
     #[test]
     fn test_name() {
         assert_eq!(name::<()>("foobar"), Ok(("", "foobar")));
@@ -139,6 +976,89 @@ mod tests {
         assert_eq!(ty::<()>("char *"), Ok(("", "char *")));
         assert_eq!(ty::<()>("foo bar *,"), Ok((",", "foo bar *")));
         assert_eq!(ty::<()>("foo bar *)"), Ok((")", "foo bar *")));
+        assert_eq!(
+            ty::<()>("int /* a, b) */ x,"),
+            Ok((",", "int /* a, b) */ x"))
+        );
+        assert_eq!(ty::<()>("int // a, b)\n,"), Ok((",", "int // a, b)\n")));
+        assert_eq!(ty::<()>("int /* a,"), Ok((",", "int /* a")));
+    }
+
+    #[test]
+    fn test_argument() {
+        let argument = |ty: &str, name: Option<&str>| Argument {
+            ty: ty.to_string(),
+            name: name.map(ToString::to_string),
+        };
+
+        assert_eq!(super::argument("char *"), argument("char *", None));
+        assert_eq!(super::argument("char*"), argument("char*", None));
+        assert_eq!(
+            super::argument("char *method"),
+            argument("char *", Some("method"))
+        );
+        assert_eq!(
+            super::argument("char* method"),
+            argument("char*", Some("method"))
+        );
+        assert_eq!(
+            super::argument("uint64_t id"),
+            argument("uint64_t", Some("id"))
+        );
+        assert_eq!(super::argument("uint64_t"), argument("uint64_t", None));
+        assert_eq!(
+            super::argument("unsigned long long"),
+            argument("unsigned long long", None)
+        );
+        assert_eq!(
+            super::argument("unsigned long long n"),
+            argument("unsigned long long", Some("n"))
+        );
+        assert_eq!(
+            super::argument("struct request *"),
+            argument("struct request *", None)
+        );
+        assert_eq!(
+            super::argument("struct request"),
+            argument("struct request", None)
+        );
+        assert_eq!(
+            super::argument("struct request *req"),
+            argument("struct request *", Some("req"))
+        );
+        assert_eq!(
+            super::argument("char buf[64]"),
+            argument("char [64]", Some("buf"))
+        );
+        assert_eq!(
+            super::argument("char *names[ 2 ][N]"),
+            argument("char *[2][N]", Some("names"))
+        );
+        assert_eq!(super::argument("int[4]"), argument("int [4]", None));
+    }
+
+    #[test]
+    fn test_probe_with_named_arguments() {
+        assert_eq!(
+            probe::<()>("probe request__start(char *method, uint64_t id);"),
+            Ok((
+                "",
+                Probe {
+                    name: "request__start".to_string(),
+                    arguments: vec![
+                        Argument {
+                            ty: "char *".to_string(),
+                            name: Some("method".to_string()),
+                        },
+                        Argument {
+                            ty: "uint64_t".to_string(),
+                            name: Some("id".to_string()),
+                        }
+                    ],
+                    ..Default::default()
+                }
+            ))
+        );
     }
 
     #[test]
@@ -150,6 +1070,22 @@ mod tests {
                 Probe {
                     name: "abc".to_string(),
                     arguments: vec![],
+                    ..Default::default()
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_probe_with_void_argument() {
+        assert_eq!(
+            probe::<()>("probe tick ( void ) ;"),
+            Ok((
+                "",
+                Probe {
+                    name: "tick".to_string(),
+                    arguments: vec![],
+                    ..Default::default()
                 }
             ))
         );
@@ -163,7 +1099,8 @@ mod tests {
                 "",
                 Probe {
                     name: "abc".to_string(),
-                    arguments: vec!["char *".to_string()],
+                    arguments: vec!["char *".into()],
+                    ..Default::default()
                 }
             ))
         );
@@ -177,12 +1114,273 @@ mod tests {
                 "",
                 Probe {
                     name: "abc".to_string(),
-                    arguments: vec!["char *".to_string(), "uint8_t".to_string()],
+                    arguments: vec!["char *".into(), "uint8_t".into()],
+                    ..Default::default()
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_probe_with_trailing_comma() {
+        assert_eq!(
+            probe::<()>("probe abc(char *, uint8_t,\n);"),
+            Ok((
+                "",
+                Probe {
+                    name: "abc".to_string(),
+                    arguments: vec!["char *".into(), "uint8_t".into()],
+                    ..Default::default()
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_probe_with_comments_and_line_breaks() {
+        assert_eq!(
+            probe::<()>(
+                "probe abc(
+                    char * /* the method, e.g. `GET` */ method, // (a string)
+                    unsigned
+                        long /**/ long,
+                    int/**/id
+                );"
+            ),
+            Ok((
+                "",
+                Probe {
+                    name: "abc".to_string(),
+                    arguments: vec![
+                        Argument {
+                            ty: "char *".to_string(),
+                            name: Some("method".to_string()),
+                        },
+                        "unsigned long long".into(),
+                        Argument {
+                            ty: "int".to_string(),
+                            name: Some("id".to_string()),
+                        },
+                    ],
+                    ..Default::default()
                 }
             ))
         );
     }
 
+    #[test]
+    fn test_annotation() {
+        assert_eq!(annotation("\n/* @level debug */\n", "level"), Some("debug"));
+        assert_eq!(
+            annotation("\n// Hello.\n// @level  info\n", "level"),
+            Some("info")
+        );
+        assert_eq!(annotation(" // @level debug\n", "level"), None);
+        assert_eq!(annotation("\n// @levels debug\n", "level"), None);
+    }
+
+    #[test]
+    fn test_provider_with_probe_levels() {
+        let (_, provider) = provider::<()>(
+            "provider foobar {
+                 probe abc(); // @level error
+
+                 /**
+                  * A probe.
+                  *
+                  * @level debug
+                  */
+                 probe def();
+                 probe ghi();
+             };",
+        )
+        .unwrap();
+
+        assert_eq!(
+            provider
+                .probes
+                .iter()
+                .map(|probe| probe.level.as_deref())
+                .collect::<Vec<_>>(),
+            vec![None, Some("debug"), None]
+        );
+    }
+
+    #[test]
+    fn test_provider_with_deprecated_probes() {
+        let (_, provider) = provider::<()>(
+            "provider foobar {
+                 probe abc(); // @deprecated Not this one.
+
+                 /**
+                  * A probe.
+                  *
+                  * @deprecated Use `ghi` instead.
+                  * @level debug
+                  */
+                 probe def();
+                 /* @deprecated */
+                 probe ghi();
+                 // @deprecatedness high
+                 probe jkl();
+             };",
+        )
+        .unwrap();
+
+        assert_eq!(
+            provider
+                .probes
+                .iter()
+                .map(|probe| probe.deprecated.as_deref())
+                .collect::<Vec<_>>(),
+            vec![None, Some("Use `ghi` instead."), Some(""), None]
+        );
+        assert_eq!(provider.probes[1].doc.as_deref(), Some("A probe."));
+        assert_eq!(provider.probes[1].level.as_deref(), Some("debug"));
+        assert_eq!(
+            provider.probes[1].to_string(),
+            "/// A probe.\n/// @level debug\n/// @deprecated Use `ghi` instead.\nprobe def();"
+        );
+        assert_eq!(
+            provider.probes[2].to_string(),
+            "/// @deprecated\nprobe ghi();"
+        );
+    }
+
+    #[test]
+    fn test_doc() {
+        assert_eq!(doc("\n    // Not a doc.\n    /* Neither. */\n"), None);
+        assert_eq!(
+            doc("/// The first line.\n///\n///   Indented.\n"),
+            Some("The first line.\n\n  Indented.".to_string())
+        );
+        assert_eq!(
+            doc("/**\n * A probe.\n *\n * @level debug\n */"),
+            Some("A probe.".to_string())
+        );
+        assert_eq!(doc("/**/"), None);
+    }
+
+    #[test]
+    fn test_docs() {
+        let script = parse(
+            "/* Not a doc. */
+
+             /// The provider.
+             provider foobar {
+                 probe abc(); /// Not for `def`.
+                 /** The `def` probe. */
+                 probe def();
+             }; /// Not for `hopla`.
+
+             provider hopla {};",
+        )
+        .unwrap();
+
+        assert_eq!(
+            script
+                .providers
+                .iter()
+                .map(|provider| provider.doc.as_deref())
+                .collect::<Vec<_>>(),
+            vec![Some("The provider."), None]
+        );
+        assert_eq!(
+            script.providers[0]
+                .probes
+                .iter()
+                .map(|probe| probe.doc.as_deref())
+                .collect::<Vec<_>>(),
+            vec![None, Some("The `def` probe.")]
+        );
+    }
+
+    #[test]
+    fn test_provider_versions() {
+        let script = parse(
+            "/** @version 1.2.0 */
+             provider foobar {
+                 probe abc();
+             }; // @version 0.1.0
+
+             provider hopla {};
+
+             /**
+              * The `hop` provider.
+              *
+              * @version 2.0.0-beta
+              */
+             provider hop {};",
+        )
+        .unwrap();
+
+        assert_eq!(
+            script
+                .providers
+                .iter()
+                .map(|provider| provider.version.as_deref())
+                .collect::<Vec<_>>(),
+            vec![Some("1.2.0"), None, Some("2.0.0-beta")]
+        );
+        assert_eq!(
+            script.providers[2].doc.as_deref(),
+            Some("The `hop` provider.")
+        );
+    }
+
+    #[test]
+    fn test_parse_with_spans() {
+        let input = "/* Hello. */
+provider foo {
+    probe abc(char *method, int);
+    probe def(void);
+};";
+        let (script, spans) = parse_with_spans(input).unwrap();
+        let text = |span: &Span| &input[span.clone()];
+
+        assert_eq!(script.providers.len(), spans.len());
+        assert_eq!(
+            text(&spans[0].span),
+            &input[input.find("provider").unwrap()..]
+        );
+        assert_eq!(text(&spans[0].name), "foo");
+
+        let probes = &spans[0].probes;
+
+        assert_eq!(probes.len(), 2);
+        assert_eq!(text(&probes[0].span), "probe abc(char *method, int);");
+        assert_eq!(text(&probes[0].name), "abc");
+        assert_eq!(text(&probes[0].arguments[0].span), "char *method");
+        assert_eq!(text(&probes[0].arguments[0].ty), "char *");
+        assert_eq!(
+            probes[0].arguments[0].name.as_ref().map(text),
+            Some("method")
+        );
+        assert_eq!(text(&probes[0].arguments[1].ty), "int");
+        assert_eq!(probes[0].arguments[1].name, None);
+        assert_eq!(text(&probes[1].span), "probe def(void);");
+        assert!(probes[1].arguments.is_empty());
+
+        let input = "provider foo { probe abc(char * /* c */\n name /* n */, int); };";
+        let (_, spans) = parse_with_spans(input).unwrap();
+        let text = |span: &Span| &input[span.clone()];
+        let arguments = &spans[0].probes[0].arguments;
+
+        assert_eq!(text(&arguments[0].span), "char * /* c */\n name");
+        assert_eq!(text(&arguments[0].ty), "char *");
+        assert_eq!(arguments[0].name.as_ref().map(text), Some("name"));
+        assert_eq!(text(&arguments[1].span), "int");
+
+        let input = "provider foo { probe dump(char buf[64], int len); };";
+        let (_, spans) = parse_with_spans(input).unwrap();
+        let text = |span: &Span| &input[span.clone()];
+        let arguments = &spans[0].probes[0].arguments;
+
+        assert_eq!(text(&arguments[0].ty), "char buf[64]");
+        assert_eq!(arguments[0].name.as_ref().map(text), Some("buf"));
+        assert_eq!(text(&arguments[1].ty), "int");
+    }
+
     #[test]
     fn test_empty_provider() {
         assert_eq!(
@@ -191,7 +1389,8 @@ mod tests {
                 "",
                 Provider {
                     name: "foobar".to_string(),
-                    probes: vec![]
+                    probes: vec![],
+                    ..Default::default()
                 }
             ))
         );
@@ -213,18 +1412,125 @@ mod tests {
                     probes: vec![
                         Probe {
                             name: "abc".to_string(),
-                            arguments: vec!["char*".to_string(), "int".to_string()],
+                            arguments: vec!["char*".into(), "int".into()],
+                            ..Default::default()
                         },
                         Probe {
                             name: "def".to_string(),
-                            arguments: vec!["string".to_string()],
+                            arguments: vec!["string".into()],
+                            ..Default::default()
                         }
-                    ]
+                    ],
+                    ..Default::default()
                 }
             ))
         );
     }
 
+    #[test]
+    fn test_parse_error() {
+        let error = parse("provider foobar {\n    probe abc(int)\n};").unwrap_err();
+
+        assert_eq!(error.message, "expected `;` after probe declaration");
+        assert_eq!((error.line, error.column), (2, 19));
+        assert_eq!(
+            error.to_string(),
+            "2:19: expected `;` after probe declaration"
+        );
+
+        let error = parse("provider foobar {\n\tprobe abc(int;\n};").unwrap_err();
+
+        assert_eq!(error.message, "expected `)` to close the probe arguments");
+        assert_eq!(
+            error.render("probes.d"),
+            "probes.d:2:15: expected `)` to close the probe arguments\n  |\n2 | \tprobe abc(int;\n  | \t             ^"
+        );
+    }
+
+    #[test]
+    fn test_parse_all() {
+        let (script, spans, errors) = parse_all(
+            "provider broken {\n    probe abc(int)\n};\n\
+             provider fine {\n    probe def();\n};\n\
+             #pragma D attributes Nope/Evolving/ISA provider fine provider\n\
+             provider unclosed {\n    probe ghi(;\n};\n\
+             provider last { probe jkl(); };",
+        );
+
+        assert_eq!(
+            script
+                .providers
+                .iter()
+                .map(|provider| provider.name.as_str())
+                .collect::<Vec<_>>(),
+            ["fine", "last"]
+        );
+        assert_eq!(spans.len(), 2);
+        assert_eq!(
+            errors.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "2:19: expected `;` after probe declaration",
+                "7:22: expected stability attributes, e.g. `Evolving/Evolving/Common`",
+                "9:15: expected `)` to close the probe arguments",
+            ]
+        );
+
+        assert_eq!(parse("provider fine {};").map(|_| ()), Ok(()));
+        assert_eq!(
+            parse("provider a { probe b() };\nprovider c { probe d( };")
+                .unwrap_err()
+                .line,
+            1
+        );
+    }
+
+    #[test]
+    fn test_enums() {
+        let script = parse(
+            "/** The states. */\nenum state { IDLE, BUSY = 0x4, DONE, ALIAS = IDLE, LOW = -2 };\n\
+             provider hello { probe change(enum state); };",
+        )
+        .unwrap();
+
+        assert_eq!(
+            script.enums,
+            [Enum {
+                name: "state".to_string(),
+                variants: [
+                    ("IDLE", 0),
+                    ("BUSY", 4),
+                    ("DONE", 5),
+                    ("ALIAS", 0),
+                    ("LOW", -2)
+                ]
+                .iter()
+                .map(|&(name, value)| Variant {
+                    name: name.to_string(),
+                    value,
+                })
+                .collect(),
+                doc: Some("The states.".to_string()),
+            }]
+        );
+        assert_eq!(script.providers[0].enums, script.enums);
+        assert_eq!(parse(&script.to_string()).unwrap(), script);
+
+        assert_eq!(integer("0755"), Some(493));
+        assert_eq!(integer("12UL"), Some(12));
+        assert_eq!(integer("1.5"), None);
+
+        assert_eq!(
+            parse("enum state { IDLE = UNKNOWN };")
+                .unwrap_err()
+                .to_string(),
+            "1:21: expected an integer, or a previous enumerator"
+        );
+        assert_eq!(
+            parse("enum state { };").unwrap_err().to_string(),
+            "1:13: expected at least one enumerator"
+        );
+    }
+
     #[test]
     fn test_script() {
         assert_eq!(
@@ -247,22 +1553,165 @@ mod tests {
                             probes: vec![
                                 Probe {
                                     name: "abc".to_string(),
-                                    arguments: vec!["char*".to_string(), "int".to_string()],
+                                    arguments: vec!["char*".into(), "int".into()],
+                                    ..Default::default()
                                 },
                                 Probe {
                                     name: "def".to_string(),
-                                    arguments: vec!["string".to_string()],
+                                    arguments: vec!["string".into()],
+                                    ..Default::default()
                                 }
-                            ]
+                            ],
+                            ..Default::default()
                         },
                         Provider {
                             name: "hopla".to_string(),
                             probes: vec![Probe {
                                 name: "xyz".to_string(),
-                                arguments: vec![]
+                                arguments: vec![],
+                                ..Default::default()
                             }],
+                            ..Default::default()
                         },
-                    ]
+                    ],
+                    ..Default::default()
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_attributes_pragmas() {
+        let (script, _) = parse_with_spans(
+            "#pragma D attributes Evolving/Evolving/ISA provider hello provider
+            provider hello {
+                probe world();
+            };
+            #pragma D attributes unstable/private/common provider hello args
+            #pragma D attributes Stable/Stable/Common provider other name
+            #pragma D attributes Evolving/Evolving/Common my_variable
+            #pragma D option quiet",
+        )
+        .unwrap();
+
+        assert_eq!(
+            script.providers[0].attributes,
+            ProviderAttributes {
+                provider: Some(Attributes {
+                    name: Stability::Evolving,
+                    data: Stability::Evolving,
+                    class: DependencyClass::Isa,
+                }),
+                args: Some(Attributes {
+                    name: Stability::Unstable,
+                    data: Stability::Private,
+                    class: DependencyClass::Common,
+                }),
+                ..Default::default()
+            }
+        );
+
+        let error = parse(
+            "provider hello {};\n#pragma D attributes Evolving/Sturdy/ISA provider hello args",
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.message,
+            "expected stability attributes, e.g. `Evolving/Evolving/Common`"
+        );
+        assert_eq!((error.line, error.column), (2, 22));
+
+        let error = parse(
+            "provider hello {};\n#pragma D attributes Evolving/Evolving/ISA provider hello probes",
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.message,
+            "expected `provider`, `module`, `function`, `name` or `args`"
+        );
+        assert_eq!((error.line, error.column), (2, 59));
+    }
+
+    #[test]
+    fn test_option_pragmas() {
+        let script = parse(
+            "#pragma D option quiet\n\
+             provider hello { probe world(); };\n\
+             #pragma D option bufsize=16m\n\
+             #pragma D attributes Evolving/Evolving/ISA provider hello provider",
+        )
+        .unwrap();
+
+        assert_eq!(
+            script.options,
+            [
+                DtraceOption {
+                    name: "quiet".to_string(),
+                    value: None,
+                },
+                DtraceOption {
+                    name: "bufsize".to_string(),
+                    value: Some("16m".to_string()),
+                },
+            ]
+        );
+        assert!(!script.options[0].is_compiler_option());
+
+        let error = parse("provider hello {};\n#pragma D option bufsize = 16m").unwrap_err();
+
+        assert_eq!(
+            error.message,
+            "expected `#pragma D option <name>` or `#pragma D option <name>=<value>`"
+        );
+        assert_eq!((error.line, error.column), (2, 1));
+    }
+
+    #[test]
+    fn test_script_ignores_provider_outside_of_declarations() {
+        assert_eq!(
+            script::<()>(
+                r#"#!/usr/sbin/dtrace -s
+                #pragma D option provider
+
+                /* This provider is great. */
+                // Another provider.
+                provider foobar {
+                    // The only probe.
+                    probe abc();
+                };
+
+                BEGIN
+                {
+                    printf("provider %s", "provider");
+                    provider = 1;
+                }
+
+                inline int my_provider = 1;
+                inline int provider = 1;
+
+                translator info_t < struct provider *provider > {
+                    name = provider->name;
+                };"#
+            ),
+            Ok((
+                "",
+                Script {
+                    providers: vec![Provider {
+                        name: "foobar".to_string(),
+                        probes: vec![Probe {
+                            name: "abc".to_string(),
+                            arguments: vec![],
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    }],
+                    options: vec![DtraceOption {
+                        name: "provider".to_string(),
+                        value: None,
+                    }],
+                    ..Default::default()
                 }
             ))
         );
@@ -0,0 +1,298 @@
+//! Probes registered at runtime with
+//! [libstapsdt](https://github.com/linux-usdt/libstapsdt), e.g. for
+//! plugin systems. It requires the `dynamic` feature.
+//!
+//! ```rust,no_run
+//! use sonde::dynamic::{ArgType, Provider};
+//!
+//! let mut provider = Provider::new("plugins")?;
+//! provider.add_probe("loaded", &[ArgType::U64])?;
+//! provider.load()?;
+//!
+//! if let Some(probe) = provider.probe("loaded") {
+//!     probe.fire(&[42]);
+//! }
+//! # Ok::<(), sonde::dynamic::Error>(())
+//! ```
+
+use std::{
+    ffi::CString,
+    fmt,
+    os::raw::{c_char, c_int},
+    ptr::NonNull,
+};
+
+/// The maximum number of arguments of a probe supported by libstapsdt.
+pub const MAX_ARGUMENTS: usize = 6;
+
+#[repr(C)]
+struct SdtProvider {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct SdtProbe {
+    _private: [u8; 0],
+}
+
+#[link(name = "stapsdt")]
+extern "C" {
+    fn providerInit(name: *const c_char) -> *mut SdtProvider;
+    fn providerAddProbe(
+        provider: *mut SdtProvider,
+        name: *const c_char,
+        arg_count: c_int,
+        ...
+    ) -> *mut SdtProbe;
+    fn providerLoad(provider: *mut SdtProvider) -> c_int;
+    fn providerUnload(provider: *mut SdtProvider) -> c_int;
+    fn providerDestroy(provider: *mut SdtProvider);
+    fn probeFire(probe: *mut SdtProbe, ...);
+    fn probeIsEnabled(probe: *mut SdtProbe) -> c_int;
+}
+
+/// The type of a probe's argument.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+}
+
+impl ArgType {
+    /// The `ArgType_t` value of libstapsdt.
+    fn raw(self) -> c_int {
+        match self {
+            Self::U8 => 1,
+            Self::I8 => -1,
+            Self::U16 => 2,
+            Self::I16 => -2,
+            Self::U32 => 4,
+            Self::I32 => -4,
+            Self::U64 => 8,
+            Self::I64 => -8,
+        }
+    }
+}
+
+/// An error of the dynamic probes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The name contains a NUL byte.
+    InvalidName(String),
+
+    /// The probe has more than [`MAX_ARGUMENTS`] arguments.
+    TooManyArguments(String),
+
+    /// Probes can't be added to a loaded provider.
+    AlreadyLoaded,
+
+    /// libstapsdt has failed, e.g. to create the provider.
+    Stapsdt(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidName(name) => write!(f, "the name `{}` contains a NUL byte", name),
+            Self::TooManyArguments(probe) => write!(
+                f,
+                "the probe `{}` has more than {} arguments",
+                probe, MAX_ARGUMENTS
+            ),
+            Self::AlreadyLoaded => write!(f, "the provider is already loaded"),
+            Self::Stapsdt(operation) => write!(f, "libstapsdt failed to {}", operation),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A provider whose probes are registered at runtime.
+pub struct Provider {
+    raw: NonNull<SdtProvider>,
+    probes: Vec<Probe>,
+    loaded: bool,
+}
+
+// libstapsdt doesn't hold any thread-local state, and a loaded probe
+// is only read.
+unsafe impl Send for Provider {}
+unsafe impl Sync for Provider {}
+
+impl Provider {
+    /// Create a provider. Its probes must be added before it's loaded.
+    pub fn new(name: &str) -> Result<Self, Error> {
+        let c_name = CString::new(name).map_err(|_| Error::InvalidName(name.to_string()))?;
+        let raw = NonNull::new(unsafe { providerInit(c_name.as_ptr()) })
+            .ok_or(Error::Stapsdt("create the provider"))?;
+
+        Ok(Self {
+            raw,
+            probes: Vec::new(),
+            loaded: false,
+        })
+    }
+
+    /// Add a probe, with the types of its arguments.
+    pub fn add_probe(&mut self, name: &str, arguments: &[ArgType]) -> Result<(), Error> {
+        if self.loaded {
+            return Err(Error::AlreadyLoaded);
+        }
+
+        let (c_name, raw) = probe_declaration(name, arguments)?;
+        let provider = self.raw.as_ptr();
+        let count = raw.len() as c_int;
+        let name_ptr = c_name.as_ptr();
+
+        let probe = unsafe {
+            match raw.as_slice() {
+                [] => providerAddProbe(provider, name_ptr, count),
+                [a] => providerAddProbe(provider, name_ptr, count, *a),
+                [a, b] => providerAddProbe(provider, name_ptr, count, *a, *b),
+                [a, b, c] => providerAddProbe(provider, name_ptr, count, *a, *b, *c),
+                [a, b, c, d] => providerAddProbe(provider, name_ptr, count, *a, *b, *c, *d),
+                [a, b, c, d, e] => providerAddProbe(provider, name_ptr, count, *a, *b, *c, *d, *e),
+                [a, b, c, d, e, f] => {
+                    providerAddProbe(provider, name_ptr, count, *a, *b, *c, *d, *e, *f)
+                }
+                _ => unreachable!(),
+            }
+        };
+
+        let raw = NonNull::new(probe).ok_or(Error::Stapsdt("add the probe"))?;
+
+        self.probes.push(Probe {
+            raw,
+            name: name.to_string(),
+            arity: arguments.len(),
+        });
+
+        Ok(())
+    }
+
+    /// Load the provider, so that its probes become visible to the
+    /// tracers.
+    pub fn load(&mut self) -> Result<(), Error> {
+        if self.loaded {
+            return Err(Error::AlreadyLoaded);
+        }
+
+        if unsafe { providerLoad(self.raw.as_ptr()) } != 0 {
+            return Err(Error::Stapsdt("load the provider"));
+        }
+
+        self.loaded = true;
+
+        Ok(())
+    }
+
+    /// Get a probe by its name.
+    pub fn probe(&self, name: &str) -> Option<&Probe> {
+        self.probes.iter().find(|probe| probe.name == name)
+    }
+}
+
+impl Drop for Provider {
+    fn drop(&mut self) {
+        unsafe {
+            if self.loaded {
+                providerUnload(self.raw.as_ptr());
+            }
+
+            providerDestroy(self.raw.as_ptr());
+        }
+    }
+}
+
+/// Check the declaration of a probe, and convert it to the C name and
+/// the `ArgType_t` values of its arguments.
+fn probe_declaration(name: &str, arguments: &[ArgType]) -> Result<(CString, Vec<c_int>), Error> {
+    if arguments.len() > MAX_ARGUMENTS {
+        return Err(Error::TooManyArguments(name.to_string()));
+    }
+
+    let c_name = CString::new(name).map_err(|_| Error::InvalidName(name.to_string()))?;
+    let raw = arguments.iter().map(|argument| argument.raw()).collect();
+
+    Ok((c_name, raw))
+}
+
+/// A probe registered at runtime, owned by its [`Provider`].
+pub struct Probe {
+    raw: NonNull<SdtProbe>,
+    name: String,
+    arity: usize,
+}
+
+impl Probe {
+    /// The probe's name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Check whether a tracer is attached to the probe.
+    pub fn is_enabled(&self) -> bool {
+        unsafe { probeIsEnabled(self.raw.as_ptr()) != 0 }
+    }
+
+    /// Fire the probe. The arguments are truncated to the types given
+    /// to [`Provider::add_probe`].
+    ///
+    /// Panics if the number of arguments isn't the number of arguments
+    /// of the probe.
+    pub fn fire(&self, arguments: &[u64]) {
+        assert_eq!(
+            arguments.len(),
+            self.arity,
+            "The probe `{}` expects {} arguments",
+            self.name,
+            self.arity
+        );
+
+        let probe = self.raw.as_ptr();
+
+        unsafe {
+            match *arguments {
+                [] => probeFire(probe),
+                [a] => probeFire(probe, a),
+                [a, b] => probeFire(probe, a, b),
+                [a, b, c] => probeFire(probe, a, b, c),
+                [a, b, c, d] => probeFire(probe, a, b, c, d),
+                [a, b, c, d, e] => probeFire(probe, a, b, c, d, e),
+                [a, b, c, d, e, f] => probeFire(probe, a, b, c, d, e, f),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_declaration() {
+        assert_eq!(
+            probe_declaration("loaded", &[ArgType::U8, ArgType::I16, ArgType::U64]),
+            Ok((CString::new("loaded").unwrap(), vec![1, -2, 8]))
+        );
+        assert_eq!(
+            probe_declaration("lo\0aded", &[]),
+            Err(Error::InvalidName("lo\0aded".to_string()))
+        );
+        assert_eq!(
+            probe_declaration("loaded", &[ArgType::I32; MAX_ARGUMENTS + 1]),
+            Err(Error::TooManyArguments("loaded".to_string()))
+        );
+        assert_eq!(
+            Error::TooManyArguments("loaded".to_string()).to_string(),
+            "the probe `loaded` has more than 6 arguments"
+        );
+    }
+}
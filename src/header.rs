@@ -0,0 +1,154 @@
+//! The header written at the top of the generated files, with the
+//! `@generated` marker, the `sonde` version, and the hashes of the `.d`
+//! sources and the options of the [`Builder`](crate::Builder):
+//!
+//! ```text
+//! // @generated by sonde, do not edit.
+//! // sonde-version: 0.1.1
+//! // source: fnv1a64:af63bd4c8601b7be ./provider.d
+//! // option: split_output=false
+//! ```
+
+use std::fmt;
+
+const MARKER: &str = "@generated by sonde, do not edit.";
+
+/// A `.d` source that has been used to generate a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedSource {
+    /// The path of the source, as given to the builder.
+    pub path: String,
+
+    /// The hash of the source's content, e.g. `fnv1a64:af63bd4c8601b7be`.
+    pub hash: String,
+}
+
+/// The header of a generated file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedHeader {
+    /// The version of `sonde` that has generated the file.
+    pub version: String,
+
+    /// The `.d` sources.
+    pub sources: Vec<GeneratedSource>,
+
+    /// The options of the builder, as `(name, value)` pairs.
+    pub options: Vec<(String, String)>,
+}
+
+impl GeneratedHeader {
+    pub(crate) fn new() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            sources: Vec::new(),
+            options: Vec::new(),
+        }
+    }
+
+    pub(crate) fn add_source(&mut self, path: String, content: &str) {
+        self.sources.push(GeneratedSource {
+            path,
+            hash: hash(content.as_bytes()),
+        });
+    }
+}
+
+impl fmt::Display for GeneratedHeader {
+    /// Render the header as `//` comments, valid in Rust and in C.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "// {}", MARKER)?;
+        writeln!(f, "// sonde-version: {}", self.version)?;
+
+        for source in &self.sources {
+            writeln!(f, "// source: {} {}", source.hash, source.path)?;
+        }
+
+        for (name, value) in &self.options {
+            writeln!(f, "// option: {}={}", name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse the header of a file generated by `sonde`. Return `None` if
+/// the file doesn't start with a `sonde` header.
+pub fn parse_generated_header(source: &str) -> Option<GeneratedHeader> {
+    let mut lines = source
+        .lines()
+        .map_while(|line| line.strip_prefix("//").map(str::trim));
+
+    if lines.next()? != MARKER {
+        return None;
+    }
+
+    let mut header = GeneratedHeader {
+        version: String::new(),
+        sources: Vec::new(),
+        options: Vec::new(),
+    };
+
+    for line in lines {
+        if let Some(version) = line.strip_prefix("sonde-version:") {
+            header.version = version.trim().to_string();
+        } else if let Some(source) = line.strip_prefix("source:") {
+            let (hash, path) = source.trim().split_once(' ')?;
+
+            header.sources.push(GeneratedSource {
+                path: path.to_string(),
+                hash: hash.to_string(),
+            });
+        } else if let Some(option) = line.strip_prefix("option:") {
+            let (name, value) = option.trim().split_once('=')?;
+
+            header.options.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    Some(header)
+}
+
+/// A hash that is stable across platforms and Rust versions (FNV-1a,
+/// 64 bits).
+fn hash(bytes: &[u8]) -> String {
+    format!("fnv1a64:{:016x}", fnv1a64(bytes))
+}
+
+/// Hash `bytes` with FNV-1a, 64 bits, which is stable across platforms
+/// and Rust versions, unlike `DefaultHasher`.
+pub(crate) fn fnv1a64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(hash(b""), "fnv1a64:cbf29ce484222325");
+        assert_eq!(hash(b"a"), "fnv1a64:af63dc4c8601ec8c");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut header = GeneratedHeader::new();
+        header.add_source("./my provider.d".to_string(), "provider a {};");
+        header
+            .options
+            .push(("split_output".to_string(), "true".to_string()));
+
+        let generated = format!("{}\nextern \"C\" {{}}\n", header);
+
+        assert!(generated.starts_with("// @generated by sonde, do not edit.\n"));
+        assert_eq!(parse_generated_header(&generated), Some(header));
+    }
+
+    #[test]
+    fn test_not_generated() {
+        assert_eq!(parse_generated_header("// Hello\nfn main() {}"), None);
+        assert_eq!(parse_generated_header(""), None);
+    }
+}
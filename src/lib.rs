@@ -1,4 +1,18 @@
 mod builder;
 mod d;
+#[cfg(all(feature = "dynamic", target_os = "linux"))]
+pub mod dynamic;
+mod header;
+mod naming;
+pub mod verify;
 
-pub use builder::Builder;
+pub use builder::{Backend, Builder, Generated, LinkMode, ProviderBuilder, Strictness};
+pub use d::ast::Stability;
+pub use d::{ast, cst, fmt, parser};
+pub use header::{parse_generated_header, GeneratedHeader, GeneratedSource};
+pub use naming::{DoubleUnderscore, NamingConfig};
+#[cfg(all(feature = "observe", target_os = "linux"))]
+pub use sonde_api::observe;
+pub use sonde_api::{
+    include_api, probe_from, set_error_policy, trace, ErrorPolicy, ProbeArgs, ProbeStr,
+};
@@ -0,0 +1,297 @@
+//! How the names of the providers and the probes are turned into the
+//! names of the generated C symbols and Rust items.
+
+use crate::d::ast::{camel_case, Provider};
+
+/// What to do with `__` in the names. DTrace itself exposes `__` as
+/// `-` in the probe names, e.g. `you__me` becomes `you-me`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DoubleUnderscore {
+    /// Collapse `__` into `_`, e.g. `you__me` becomes `you_me`.
+    #[default]
+    Collapse,
+
+    /// Keep `__` as is, e.g. `you__me` stays `you__me`.
+    Keep,
+}
+
+/// The naming strategy, given to
+/// [`Builder::naming`](crate::Builder::naming). By default, the
+/// `you__me` probe of `Hello` is fired by the `hello_probe_you_me` C
+/// function and the `hello::you_me` Rust function.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NamingConfig {
+    /// Keep the case of the names instead of lowercasing them.
+    pub preserve_case: bool,
+
+    /// What to do with `__` in the names.
+    pub double_underscore: DoubleUnderscore,
+
+    /// A prefix for the C functions of the FFI shim.
+    pub c_prefix: String,
+
+    /// A suffix for the C functions of the FFI shim.
+    pub c_suffix: String,
+
+    /// A prefix for the Rust functions firing the probes.
+    pub rust_prefix: String,
+
+    /// A suffix for the Rust functions firing the probes.
+    pub rust_suffix: String,
+
+    /// Suffix the C functions of a versioned provider with its version,
+    /// e.g. `net_probe_send_v1_2_0` for the `1.2.0` version of the `net`
+    /// provider, so that the symbols of a binary tell which version of
+    /// the probes it carries.
+    pub versioned_symbols: bool,
+
+    /// The names of the Rust modules of some providers, by provider
+    /// name, as `(provider, module)` pairs, instead of the names derived
+    /// from the provider names. See
+    /// [`Builder::rename_provider`](crate::Builder::rename_provider).
+    pub provider_renames: Vec<(String, String)>,
+
+    /// The names of the Rust functions of some probes, as `(provider,
+    /// probe, function)` triples, see
+    /// [`Builder::rename_probe`](crate::Builder::rename_probe).
+    pub probe_renames: Vec<(String, String, String)>,
+
+    /// A prefix for the names of the providers seen by the tracers, and
+    /// so of the C functions and macros, e.g. `mycrate` for
+    /// `mycrate_hello:::you`. The Rust modules keep the names of the
+    /// `.d` files. See [`Builder::namespace`](crate::Builder::namespace).
+    pub namespace: String,
+}
+
+impl NamingConfig {
+    /// Apply the case and `__` rules to a name. `-` isn't valid in C
+    /// and Rust identifiers, so it's always replaced by `_`.
+    pub(crate) fn name(&self, name: &str) -> String {
+        let name = name.replace('-', "_");
+        let name = match self.double_underscore {
+            DoubleUnderscore::Collapse => name.replace("__", "_"),
+            DoubleUnderscore::Keep => name,
+        };
+
+        if self.preserve_case {
+            name
+        } else {
+            name.to_lowercase()
+        }
+    }
+
+    /// The name of the C function of the FFI shim firing a probe,
+    /// e.g. `hello_probe_you_me`. The name of `provider` is the
+    /// namespaced one, see [`NamingConfig::namespaced`].
+    pub fn c_function(&self, provider: &Provider, probe: &str) -> String {
+        format!(
+            "{prefix}{provider}_probe_{probe}{version}{suffix}",
+            prefix = self.c_prefix,
+            provider = self.name(&provider.name),
+            probe = self.name(probe),
+            version = match &provider.version {
+                Some(version) if self.versioned_symbols => format!(
+                    "_v{}",
+                    version.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+                ),
+                _ => String::new(),
+            },
+            suffix = self.c_suffix,
+        )
+    }
+
+    /// The name of the C struct packing the arguments of a probe, see
+    /// [`Builder::pack_arguments`](crate::Builder::pack_arguments), e.g.
+    /// `hello_you_args`. `provider` is the namespaced name.
+    pub(crate) fn packed_struct(&self, provider: &str, probe: &str) -> String {
+        format!("{}_{}_args", self.name(provider), self.name(probe))
+    }
+
+    /// The name of a provider seen by the tracers, i.e. prefixed with
+    /// the namespace, if any, e.g. `mycrate_hello`.
+    pub fn namespaced(&self, provider: &str) -> String {
+        if self.namespace.is_empty() {
+            provider.to_string()
+        } else {
+            format!("{}_{}", self.namespace, provider)
+        }
+    }
+
+    /// The name of a provider in the `.d` files, i.e. without the
+    /// namespace given by [`NamingConfig::namespaced`].
+    fn unnamespaced<'p>(&self, provider: &'p str) -> &'p str {
+        if self.namespace.is_empty() {
+            return provider;
+        }
+
+        provider
+            .strip_prefix(self.namespace.as_str())
+            .and_then(|provider| provider.strip_prefix('_'))
+            .unwrap_or(provider)
+    }
+
+    /// The name of the Rust module of a provider, e.g. `hello`.
+    pub fn rust_module(&self, provider: &str) -> String {
+        let provider = self.unnamespaced(provider);
+
+        match self
+            .provider_renames
+            .iter()
+            .rev()
+            .find(|(name, _)| name == provider)
+        {
+            Some((_, module)) => module.clone(),
+            None => self.name(provider),
+        }
+    }
+
+    /// The name of the Rust function firing a probe of a provider,
+    /// e.g. `you_me`.
+    pub fn rust_function(&self, provider: &str, probe: &str) -> String {
+        format!(
+            "{prefix}{probe}{suffix}",
+            prefix = self.rust_prefix,
+            probe = self.renamed_probe(provider, probe),
+            suffix = self.rust_suffix,
+        )
+    }
+
+    /// The name of the Rust struct holding the arguments of a probe of a
+    /// provider, e.g. `YouMeArgs`.
+    pub(crate) fn arguments_struct(&self, provider: &str, probe: &str) -> String {
+        format!("{}Args", camel_case(&self.renamed_probe(provider, probe)))
+    }
+
+    /// The name of a probe in the Rust items, without the prefix and the
+    /// suffix, e.g. `you_me`.
+    fn renamed_probe(&self, provider: &str, probe: &str) -> String {
+        let provider = self.unnamespaced(provider);
+
+        match self
+            .probe_renames
+            .iter()
+            .rev()
+            .find(|(provider_name, name, _)| provider_name == provider && name == probe)
+        {
+            Some((_, _, function)) => function.clone(),
+            None => self.name(probe),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(name: &str, version: Option<&str>) -> Provider {
+        Provider {
+            name: name.to_string(),
+            version: version.map(ToString::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_default() {
+        let naming = NamingConfig::default();
+
+        assert_eq!(
+            naming.c_function(&provider("Hello", None), "you__me"),
+            "hello_probe_you_me"
+        );
+        assert_eq!(naming.rust_module("Hello"), "hello");
+        assert_eq!(naming.rust_function("Hello", "you__me"), "you_me");
+        assert_eq!(naming.arguments_struct("Hello", "you__me"), "YouMeArgs");
+        assert_eq!(naming.rust_module("my-app"), "my_app");
+        assert_eq!(
+            naming.c_function(&provider("my-app", None), "a-b"),
+            "my_app_probe_a_b"
+        );
+        assert_eq!(
+            naming.c_function(&provider("net", Some("1.2.0")), "send"),
+            "net_probe_send"
+        );
+    }
+
+    #[test]
+    fn test_custom() {
+        let naming = NamingConfig {
+            preserve_case: true,
+            double_underscore: DoubleUnderscore::Keep,
+            c_prefix: "my_".to_string(),
+            rust_suffix: "_probe".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            naming.c_function(&provider("Hello", None), "you__Me"),
+            "my_Hello_probe_you__Me"
+        );
+        assert_eq!(naming.rust_module("Hello"), "Hello");
+        assert_eq!(naming.rust_function("Hello", "you__Me"), "you__Me_probe");
+    }
+
+    #[test]
+    fn test_renames() {
+        let naming = NamingConfig {
+            rust_prefix: "fire_".to_string(),
+            provider_renames: vec![("my-svc".to_string(), "svc".to_string())],
+            probe_renames: vec![(
+                "my-svc".to_string(),
+                "gc__begin".to_string(),
+                "gc_start".to_string(),
+            )],
+            ..Default::default()
+        };
+
+        assert_eq!(naming.rust_module("my-svc"), "svc");
+        assert_eq!(naming.rust_module("other"), "other");
+        assert_eq!(naming.rust_function("my-svc", "gc__begin"), "fire_gc_start");
+        assert_eq!(
+            naming.arguments_struct("my-svc", "gc__begin"),
+            "GcStartArgs"
+        );
+        assert_eq!(naming.rust_function("other", "gc__begin"), "fire_gc_begin");
+
+        // The C functions keep the names of the `.d` files.
+        assert_eq!(
+            naming.c_function(&provider("my-svc", None), "gc__begin"),
+            "my_svc_probe_gc_begin"
+        );
+    }
+
+    #[test]
+    fn test_namespace() {
+        let naming = NamingConfig {
+            namespace: "my_crate".to_string(),
+            provider_renames: vec![("svc".to_string(), "service".to_string())],
+            ..Default::default()
+        };
+
+        assert_eq!(naming.namespaced("hello"), "my_crate_hello");
+        assert_eq!(naming.rust_module("my_crate_hello"), "hello");
+        assert_eq!(naming.rust_module("my_crate_svc"), "service");
+        assert_eq!(naming.rust_function("my_crate_hello", "you__me"), "you_me");
+        assert_eq!(
+            naming.c_function(&provider("my_crate_hello", None), "you__me"),
+            "my_crate_hello_probe_you_me"
+        );
+    }
+
+    #[test]
+    fn test_versioned_symbols() {
+        let naming = NamingConfig {
+            versioned_symbols: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            naming.c_function(&provider("net", Some("1.2.0-beta")), "send"),
+            "net_probe_send_v1_2_0_beta"
+        );
+        assert_eq!(
+            naming.c_function(&provider("net", None), "send"),
+            "net_probe_send"
+        );
+    }
+}
@@ -0,0 +1,643 @@
+//! Check that the probes have been emitted in a built binary, e.g.
+//! that the linker hasn't dropped the shim:
+//!
+//! ```rust,ignore
+//! sonde::verify::assert_probes_in_binary(
+//!     env!("CARGO_BIN_EXE_myapp"),
+//!     &["myapp:request__start", "myapp:request__end"],
+//! );
+//! ```
+
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt, fs, io,
+    path::Path,
+};
+
+const STAPSDT_SECTION: &str = ".note.stapsdt";
+const STAPSDT_NOTE_NAME: &[u8] = b"stapsdt\0";
+const STAPSDT_NOTE_TYPE: u32 = 3;
+
+const DOF_ELF_SECTION: &str = ".SUNW_dof";
+const DOF_MACHO_SECTION_PREFIX: &str = "__dof_";
+const DOF_SECT_STRTAB: u32 = 8;
+const DOF_SECT_PROVIDER: u32 = 15;
+const DOF_SECT_PROBES: u32 = 16;
+const DOF_PROBE_SIZE: u64 = 48;
+
+/// A probe recorded in a binary.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EmittedProbe {
+    /// The name of the provider.
+    pub provider: String,
+
+    /// The name of the probe, spelled as `dtrace -l` lists it, i.e. with
+    /// `-` instead of `__`.
+    pub name: String,
+}
+
+impl EmittedProbe {
+    fn new(provider: &[u8], name: &[u8]) -> Self {
+        Self {
+            provider: String::from_utf8_lossy(provider).into_owned(),
+            name: String::from_utf8_lossy(name).replace("__", "-"),
+        }
+    }
+}
+
+impl fmt::Display for EmittedProbe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.provider, self.name)
+    }
+}
+
+/// Read the probes recorded in the ELF or Mach-O binary at `path`,
+/// sorted and without duplicates.
+pub fn probes_in_binary<P: AsRef<Path>>(path: P) -> io::Result<Vec<EmittedProbe>> {
+    probes_in_bytes(&fs::read(path)?)
+}
+
+/// Like [`probes_in_binary`], for a binary already in memory.
+pub fn probes_in_bytes(bytes: &[u8]) -> io::Result<Vec<EmittedProbe>> {
+    let mut probes = Vec::new();
+
+    if bytes.starts_with(b"\x7fELF") {
+        elf_probes(bytes, &mut probes)?;
+    } else if is_macho(bytes) {
+        macho_probes(bytes, &mut probes)?;
+    } else {
+        return Err(invalid("not an ELF or a Mach-O file"));
+    }
+
+    probes.sort();
+    probes.dedup();
+
+    Ok(probes)
+}
+
+/// Assert that the binary at `path` contains all the `probes`, written
+/// `provider:name` with `__` or `-`.
+///
+/// Panics if the binary can't be read, or if probes are missing.
+pub fn assert_probes_in_binary<P: AsRef<Path>>(path: P, probes: &[&str]) {
+    let path = path.as_ref();
+    let emitted = probes_in_binary(path).unwrap_or_else(|error| {
+        panic!(
+            "Failed to read the probes of `{}`: {}",
+            path.display(),
+            error
+        )
+    });
+
+    let missing = probes
+        .iter()
+        .filter(|probe| {
+            let (provider, name) = probe
+                .split_once(':')
+                .unwrap_or_else(|| panic!("The probe `{}` must be written `provider:name`", probe));
+            let name = name.replace("__", "-");
+
+            !emitted
+                .iter()
+                .any(|emitted| emitted.provider == provider && emitted.name == name)
+        })
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        panic!(
+            "The binary `{}` is missing probes: {}.\n\nThe probes found are: {}.",
+            path.display(),
+            list(missing.iter()),
+            if emitted.is_empty() {
+                "none".to_string()
+            } else {
+                list(emitted.iter())
+            }
+        );
+    }
+}
+
+fn list<T: fmt::Display>(items: impl Iterator<Item = T>) -> String {
+    items
+        .map(|item| format!("`{}`", item))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// A view on the bytes of a binary, reading integers with its byte
+/// order. Every read is bounds-checked.
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn slice(&self, offset: u64, size: u64) -> io::Result<&'a [u8]> {
+        let start = usize::try_from(offset).map_err(|_| invalid("offset out of bounds"))?;
+        let size = usize::try_from(size).map_err(|_| invalid("size out of bounds"))?;
+
+        start
+            .checked_add(size)
+            .and_then(|end| self.bytes.get(start..end))
+            .ok_or_else(|| invalid("truncated binary"))
+    }
+
+    fn sub(&self, offset: u64, size: u64) -> io::Result<Self> {
+        Ok(Self {
+            bytes: self.slice(offset, size)?,
+            big_endian: self.big_endian,
+        })
+    }
+
+    fn u8(&self, offset: u64) -> io::Result<u8> {
+        Ok(self.slice(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: u64) -> io::Result<u16> {
+        let bytes = self.slice(offset, 2)?.try_into().unwrap();
+
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: u64) -> io::Result<u32> {
+        let bytes = self.slice(offset, 4)?.try_into().unwrap();
+
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u64(&self, offset: u64) -> io::Result<u64> {
+        let bytes = self.slice(offset, 8)?.try_into().unwrap();
+
+        Ok(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    /// Read a NUL-terminated string, without its NUL.
+    fn c_str(&self, offset: u64) -> io::Result<&'a [u8]> {
+        let rest = self.slice(offset, (self.bytes.len() as u64).saturating_sub(offset))?;
+
+        rest.iter()
+            .position(|byte| *byte == 0)
+            .map(|end| &rest[..end])
+            .ok_or_else(|| invalid("unterminated string"))
+    }
+}
+
+fn elf_probes(bytes: &[u8], probes: &mut Vec<EmittedProbe>) -> io::Result<()> {
+    let reader = Reader {
+        bytes,
+        big_endian: false,
+    };
+    let is_64 = match reader.u8(4)? {
+        1 => false,
+        2 => true,
+        _ => return Err(invalid("unknown ELF class")),
+    };
+    let reader = Reader {
+        bytes,
+        big_endian: match reader.u8(5)? {
+            1 => false,
+            2 => true,
+            _ => return Err(invalid("unknown ELF byte order")),
+        },
+    };
+
+    let (section_offset, section_size, section_count, names_index) = if is_64 {
+        (
+            reader.u64(0x28)?,
+            reader.u16(0x3a)?,
+            reader.u16(0x3c)?,
+            reader.u16(0x3e)?,
+        )
+    } else {
+        (
+            u64::from(reader.u32(0x20)?),
+            reader.u16(0x2e)?,
+            reader.u16(0x30)?,
+            reader.u16(0x32)?,
+        )
+    };
+
+    // `(name, type, offset, size)` of a section.
+    let section = |index: u16| -> io::Result<(u32, u32, u64, u64)> {
+        let header = section_offset + u64::from(index) * u64::from(section_size);
+
+        Ok(if is_64 {
+            (
+                reader.u32(header)?,
+                reader.u32(header + 4)?,
+                reader.u64(header + 24)?,
+                reader.u64(header + 32)?,
+            )
+        } else {
+            (
+                reader.u32(header)?,
+                reader.u32(header + 4)?,
+                u64::from(reader.u32(header + 16)?),
+                u64::from(reader.u32(header + 20)?),
+            )
+        })
+    };
+
+    if section_count == 0 {
+        return Ok(());
+    }
+
+    let (_, _, names_offset, names_size) = section(names_index)?;
+    let names = reader.sub(names_offset, names_size)?;
+
+    for index in 0..section_count {
+        let (name, kind, offset, size) = section(index)?;
+
+        // `SHT_NOBITS` sections have no content in the file.
+        if kind == 8 {
+            continue;
+        }
+
+        match names.c_str(u64::from(name))? {
+            name if name == STAPSDT_SECTION.as_bytes() => {
+                stapsdt_probes(reader.sub(offset, size)?, is_64, probes)?
+            }
+            name if name == DOF_ELF_SECTION.as_bytes() => {
+                dof_probes(reader.slice(offset, size)?, probes)?
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the probes of a `.note.stapsdt` section, i.e. a sequence of ELF
+/// notes, each one holding the addresses of a probe (its location, the
+/// `.stapsdt.base` section and its semaphore) followed by the provider,
+/// the probe and the arguments as NUL-terminated strings.
+fn stapsdt_probes(notes: Reader, is_64: bool, probes: &mut Vec<EmittedProbe>) -> io::Result<()> {
+    let align = |size: u64| (size + 3) & !3;
+    let mut offset = 0;
+
+    while offset < notes.bytes.len() as u64 {
+        let name_size = u64::from(notes.u32(offset)?);
+        let description_size = u64::from(notes.u32(offset + 4)?);
+        let kind = notes.u32(offset + 8)?;
+        let name = notes.slice(offset + 12, name_size)?;
+        let description = notes.sub(offset + 12 + align(name_size), description_size)?;
+
+        if name == STAPSDT_NOTE_NAME && kind == STAPSDT_NOTE_TYPE {
+            let addresses = 3 * if is_64 { 8 } else { 4 };
+            let provider = description.c_str(addresses)?;
+            let name = description.c_str(addresses + provider.len() as u64 + 1)?;
+
+            probes.push(EmittedProbe::new(provider, name));
+        }
+
+        offset += 12 + align(name_size) + align(description_size);
+    }
+
+    Ok(())
+}
+
+fn is_macho(bytes: &[u8]) -> bool {
+    matches!(
+        bytes.get(..4),
+        Some([0xce, 0xfa, 0xed, 0xfe])
+            | Some([0xcf, 0xfa, 0xed, 0xfe])
+            | Some([0xca, 0xfe, 0xba, 0xbe])
+    )
+}
+
+fn macho_probes(bytes: &[u8], probes: &mut Vec<EmittedProbe>) -> io::Result<()> {
+    let reader = Reader {
+        bytes,
+        big_endian: false,
+    };
+
+    // A universal binary: every architecture is read.
+    if bytes.starts_with(&[0xca, 0xfe, 0xba, 0xbe]) {
+        let reader = Reader {
+            bytes,
+            big_endian: true,
+        };
+
+        for nth in 0..u64::from(reader.u32(4)?) {
+            let arch = 8 + nth * 20;
+            let offset = u64::from(reader.u32(arch + 8)?);
+            let size = u64::from(reader.u32(arch + 12)?);
+
+            macho_probes(reader.slice(offset, size)?, probes)?;
+        }
+
+        return Ok(());
+    }
+
+    let is_64 = bytes[0] == 0xcf;
+    let command_count = reader.u32(16)?;
+    let mut command = if is_64 { 32 } else { 28 };
+
+    for _ in 0..command_count {
+        let kind = reader.u32(command)?;
+        let size = u64::from(reader.u32(command + 4)?);
+
+        // `LC_SEGMENT_64` and `LC_SEGMENT`.
+        if (is_64 && kind == 0x19) || (!is_64 && kind == 0x1) {
+            let (count, first, section_size) = if is_64 {
+                (reader.u32(command + 64)?, command + 72, 80)
+            } else {
+                (reader.u32(command + 48)?, command + 56, 68)
+            };
+
+            for nth in 0..u64::from(count) {
+                let section = first + nth * section_size;
+                let name = reader.slice(section, 16)?;
+
+                if !name.starts_with(DOF_MACHO_SECTION_PREFIX.as_bytes()) {
+                    continue;
+                }
+
+                let (size, offset) = if is_64 {
+                    (reader.u64(section + 40)?, reader.u32(section + 48)?)
+                } else {
+                    (
+                        u64::from(reader.u32(section + 36)?),
+                        reader.u32(section + 40)?,
+                    )
+                };
+
+                dof_probes(reader.slice(u64::from(offset), size)?, probes)?;
+            }
+        }
+
+        command += size;
+    }
+
+    Ok(())
+}
+
+/// Read the probes of a DOF (DTrace Object Format) blob: each provider
+/// section points to a string table and to a probe section.
+fn dof_probes(bytes: &[u8], probes: &mut Vec<EmittedProbe>) -> io::Result<()> {
+    if !bytes.starts_with(b"\x7fDOF") {
+        return Err(invalid("invalid DOF"));
+    }
+
+    let dof = Reader {
+        bytes,
+        big_endian: match bytes.get(5) {
+            Some(1) => false,
+            Some(2) => true,
+            _ => return Err(invalid("unknown DOF byte order")),
+        },
+    };
+    let section_size = u64::from(dof.u32(24)?);
+    let section_count = dof.u32(28)?;
+    let section_offset = dof.u64(32)?;
+
+    // `(type, entry size, offset, size)` of a section.
+    let section = |index: u32| -> io::Result<(u32, u64, u64, u64)> {
+        if index >= section_count {
+            return Err(invalid("DOF section out of bounds"));
+        }
+
+        let header = section_offset + u64::from(index) * section_size;
+
+        Ok((
+            dof.u32(header)?,
+            u64::from(dof.u32(header + 12)?),
+            dof.u64(header + 16)?,
+            dof.u64(header + 24)?,
+        ))
+    };
+
+    for index in 0..section_count {
+        let (kind, _, offset, _) = section(index)?;
+
+        if kind != DOF_SECT_PROVIDER {
+            continue;
+        }
+
+        let (strings_kind, _, strings_offset, strings_size) = section(dof.u32(offset)?)?;
+        let (probes_kind, probe_size, probes_offset, probes_size) = section(dof.u32(offset + 4)?)?;
+
+        if strings_kind != DOF_SECT_STRTAB || probes_kind != DOF_SECT_PROBES {
+            return Err(invalid("invalid DOF provider"));
+        }
+
+        let strings = dof.sub(strings_offset, strings_size)?;
+        let provider = strings.c_str(u64::from(dof.u32(offset + 16)?))?;
+        let probe_size = if probe_size == 0 {
+            DOF_PROBE_SIZE
+        } else {
+            probe_size
+        };
+
+        for nth in 0..probes_size / probe_size {
+            let name = dof.u32(probes_offset + nth * probe_size + 12)?;
+
+            probes.push(EmittedProbe::new(provider, strings.c_str(u64::from(name))?));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A little-endian ELF64 file with the given sections.
+    fn elf(sections: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut names = vec![0];
+        let mut section_names = Vec::new();
+
+        for (name, _) in sections.iter().chain(&[(".shstrtab", Vec::new())]) {
+            section_names.push(names.len() as u32);
+            names.extend(name.as_bytes());
+            names.push(0);
+        }
+
+        let contents = sections
+            .iter()
+            .map(|(_, content)| content.clone())
+            .chain(std::iter::once(names))
+            .collect::<Vec<_>>();
+
+        let mut bytes = vec![0; 64];
+        bytes[..6].copy_from_slice(b"\x7fELF\x02\x01");
+
+        let mut offsets = Vec::new();
+
+        for content in &contents {
+            offsets.push(bytes.len() as u64);
+            bytes.extend(content);
+        }
+
+        let section_offset = bytes.len() as u64;
+        bytes.extend([0; 64]);
+
+        for ((name, content), offset) in section_names.iter().zip(&contents).zip(offsets) {
+            let mut header = vec![0; 64];
+            header[..4].copy_from_slice(&name.to_le_bytes());
+            header[4..8].copy_from_slice(&1u32.to_le_bytes());
+            header[24..32].copy_from_slice(&offset.to_le_bytes());
+            header[32..40].copy_from_slice(&(content.len() as u64).to_le_bytes());
+            bytes.extend(header);
+        }
+
+        bytes[0x28..0x30].copy_from_slice(&section_offset.to_le_bytes());
+        bytes[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+        bytes[0x3c..0x3e].copy_from_slice(&(contents.len() as u16 + 1).to_le_bytes());
+        bytes[0x3e..0x40].copy_from_slice(&(contents.len() as u16).to_le_bytes());
+
+        bytes
+    }
+
+    fn stapsdt_note(provider: &str, name: &str) -> Vec<u8> {
+        let mut description = vec![0; 24];
+        description.extend(format!("{}\0{}\0-4@%edi\0", provider, name).as_bytes());
+
+        let mut note = Vec::new();
+        note.extend(&8u32.to_le_bytes());
+        note.extend(&(description.len() as u32).to_le_bytes());
+        note.extend(&STAPSDT_NOTE_TYPE.to_le_bytes());
+        note.extend(STAPSDT_NOTE_NAME);
+        note.extend(&description);
+        note.resize((note.len() + 3) & !3, 0);
+
+        note
+    }
+
+    /// A little-endian DOF blob with one provider.
+    fn dof(provider: &str, names: &[&str]) -> Vec<u8> {
+        let mut strings = vec![0];
+        let provider_name = strings.len() as u32;
+        strings.extend(provider.as_bytes());
+        strings.push(0);
+
+        let mut probes = Vec::new();
+
+        for name in names {
+            let mut probe = vec![0; DOF_PROBE_SIZE as usize];
+            probe[12..16].copy_from_slice(&(strings.len() as u32).to_le_bytes());
+            strings.extend(name.as_bytes());
+            strings.push(0);
+            probes.extend(probe);
+        }
+
+        let mut provider = vec![0; 44];
+        provider[4..8].copy_from_slice(&1u32.to_le_bytes());
+        provider[16..20].copy_from_slice(&provider_name.to_le_bytes());
+
+        let mut bytes = vec![0; 64];
+        bytes[..6].copy_from_slice(b"\x7fDOF\x02\x01");
+        bytes[24..28].copy_from_slice(&32u32.to_le_bytes());
+        bytes[28..32].copy_from_slice(&3u32.to_le_bytes());
+        bytes[32..40].copy_from_slice(&64u64.to_le_bytes());
+
+        let mut offset = 64 + 3 * 32;
+
+        for (kind, entry_size, content) in [
+            (DOF_SECT_STRTAB, 0, &strings),
+            (DOF_SECT_PROBES, DOF_PROBE_SIZE as u32, &probes),
+            (DOF_SECT_PROVIDER, 0, &provider),
+        ] {
+            let mut header = vec![0; 32];
+            header[..4].copy_from_slice(&kind.to_le_bytes());
+            header[12..16].copy_from_slice(&entry_size.to_le_bytes());
+            header[16..24].copy_from_slice(&(offset as u64).to_le_bytes());
+            header[24..32].copy_from_slice(&(content.len() as u64).to_le_bytes());
+            bytes.extend(header);
+            offset += content.len();
+        }
+
+        bytes.extend(strings);
+        bytes.extend(probes);
+        bytes.extend(provider);
+
+        bytes
+    }
+
+    fn probe(provider: &str, name: &str) -> EmittedProbe {
+        EmittedProbe {
+            provider: provider.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_stapsdt() {
+        let mut notes = stapsdt_note("myapp", "request__start");
+        notes.extend(stapsdt_note("myapp", "request__end"));
+        notes.extend(stapsdt_note("myapp", "request__start"));
+
+        let binary = elf(&[(".text", vec![0x90; 7]), (STAPSDT_SECTION, notes)]);
+
+        assert_eq!(
+            probes_in_bytes(&binary).unwrap(),
+            vec![
+                probe("myapp", "request-end"),
+                probe("myapp", "request-start"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dof() {
+        let binary = elf(&[(DOF_ELF_SECTION, dof("myapp", &["request-start", "tick"]))]);
+
+        assert_eq!(
+            probes_in_bytes(&binary).unwrap(),
+            vec![probe("myapp", "request-start"), probe("myapp", "tick")]
+        );
+    }
+
+    #[test]
+    fn test_no_probes() {
+        assert_eq!(
+            probes_in_bytes(&elf(&[(".text", vec![0x90])])).unwrap(),
+            vec![]
+        );
+        assert!(probes_in_bytes(b"#!/bin/sh\n").is_err());
+        assert!(probes_in_bytes(&elf(&[(STAPSDT_SECTION, vec![8, 0, 0])])).is_err());
+    }
+
+    #[test]
+    fn test_assert_probes_in_binary() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        io::Write::write_all(
+            &mut file,
+            &elf(&[(STAPSDT_SECTION, stapsdt_note("myapp", "request__start"))]),
+        )
+        .unwrap();
+
+        assert_probes_in_binary(
+            file.path(),
+            &["myapp:request__start", "myapp:request-start"],
+        );
+
+        let panic = std::panic::catch_unwind(|| {
+            assert_probes_in_binary(file.path(), &["myapp:request__start", "myapp:request__end"])
+        })
+        .unwrap_err();
+
+        assert!(panic
+            .downcast_ref::<String>()
+            .unwrap()
+            .contains("is missing probes: `myapp:request__end`.\n\nThe probes found are: `myapp:request-start`."));
+    }
+}